@@ -1,67 +1,207 @@
+use std::fmt;
+
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+
+
+const MAGIC: u64 = 0x9bc54fe57473f11eu64;
+/* Set in the version field if everything after it is gzip-compressed */
+const VERSION_COMPRESSED: u64 = 1u64 << 63;
+
+#[derive(Debug)]
+pub enum SaveStateError {
+    IO(std::io::Error),
+    /* Data could not be decoded */
+    Corrupt(String),
+    /* Save state does not belong to what it is being loaded into */
+    Incompatible(String),
+    UnsupportedVersion { version: u64, max_version: u64 },
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveStateError::IO(e) => write!(f, "{}", e),
+
+            SaveStateError::Corrupt(msg) => write!(f, "Corrupt: {}", msg),
+
+            SaveStateError::Incompatible(msg) => write!(f, "{}", msg),
+
+            SaveStateError::UnsupportedVersion { version, max_version } =>
+                write!(f, "Save state version ({}) unsupported (maximum \
+                           supported version: {})", version, max_version),
+        }
+    }
+}
+
+impl std::error::Error for SaveStateError {}
+
+impl From<std::io::Error> for SaveStateError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            /* Running out of data means the file has been truncated */
+            std::io::ErrorKind::UnexpectedEof =>
+                SaveStateError::Corrupt(String::from("Unexpected end of file")),
+
+            /* E.g. invalid compressed data */
+            std::io::ErrorKind::InvalidData |
+            std::io::ErrorKind::InvalidInput =>
+                SaveStateError::Corrupt(e.to_string()),
+
+            _ => SaveStateError::IO(e),
+        }
+    }
+}
+
+impl From<bincode::Error> for SaveStateError {
+    fn from(e: bincode::Error) -> Self {
+        match *e {
+            bincode::ErrorKind::Io(e) => e.into(),
+            e => SaveStateError::Corrupt(e.to_string()),
+        }
+    }
+}
+
+fn bincode_to_io(e: bincode::Error) -> std::io::Error {
+    match *e {
+        bincode::ErrorKind::Io(e) => e,
+        e => std::io::Error::new(std::io::ErrorKind::Other, e),
+    }
+}
+
+
 pub trait SaveState {
-    fn export<T: std::io::Write>(&self, stream: &mut T, version: u64);
-    fn import<T: std::io::Read>(&mut self, stream: &mut T, version: u64);
+    fn export<T: std::io::Write>(&self, stream: &mut T, version: u64)
+        -> std::io::Result<()>;
+    fn import<T: std::io::Read>(&mut self, stream: &mut T, version: u64)
+        -> Result<(), SaveStateError>;
 }
 
 
 impl<U: serde::ser::Serialize + serde::de::DeserializeOwned> SaveState for U {
-    fn export<T: std::io::Write>(&self, stream: &mut T, _version: u64) {
-        bincode::serialize_into(stream, self).unwrap();
+    fn export<T: std::io::Write>(&self, stream: &mut T, _version: u64)
+        -> std::io::Result<()>
+    {
+        bincode::serialize_into(stream, self).map_err(bincode_to_io)
     }
 
-    fn import<T: std::io::Read>(&mut self, stream: &mut T, _version: u64) {
-        *self = bincode::deserialize_from(stream).unwrap();
+    fn import<T: std::io::Read>(&mut self, stream: &mut T, _version: u64)
+        -> Result<(), SaveStateError>
+    {
+        *self = bincode::deserialize_from(stream)?;
+        Ok(())
     }
 }
 
+/*
+ * Slices are stored as raw memory, so T must be a plain integer type.
+ * Save states are always little-endian, so on big-endian hosts every
+ * element is byte-swapped.
+ */
 impl<T: Sized> SaveState for [T] {
-    fn export<S: std::io::Write>(&self, stream: &mut S, _version: u64) {
+    fn export<S: std::io::Write>(&self, stream: &mut S, _version: u64)
+        -> std::io::Result<()>
+    {
         let byte_len = std::mem::size_of::<T>() * self.len();
         let obj_u8 = unsafe {
             std::slice::from_raw_parts(self.as_ptr() as *const u8, byte_len)
         };
-        stream.write_all(obj_u8).unwrap();
+
+        if needs_swap::<T>() {
+            let mut le = obj_u8.to_vec();
+            swap_elements::<T>(&mut le);
+            stream.write_all(&le)
+        } else {
+            stream.write_all(obj_u8)
+        }
     }
 
-    fn import<S: std::io::Read>(&mut self, stream: &mut S, _version: u64) {
+    fn import<S: std::io::Read>(&mut self, stream: &mut S, _version: u64)
+        -> Result<(), SaveStateError>
+    {
         let byte_len = std::mem::size_of::<T>() * self.len();
         let obj_u8 = unsafe {
             std::slice::from_raw_parts_mut(self.as_mut_ptr() as *mut u8, byte_len)
         };
-        stream.read_exact(obj_u8).unwrap();
+        stream.read_exact(obj_u8)?;
+
+        if needs_swap::<T>() {
+            swap_elements::<T>(obj_u8);
+        }
+        Ok(())
+    }
+}
+
+fn needs_swap<T>() -> bool {
+    cfg!(target_endian = "big") && std::mem::size_of::<T>() > 1
+}
+
+/* Converts between host and little endian, for elements of type T */
+fn swap_elements<T>(bytes: &mut [u8]) {
+    for elem in bytes.chunks_exact_mut(std::mem::size_of::<T>()) {
+        elem.reverse();
     }
 }
 
 
 pub fn export_root<U: SaveState, V: std::io::Write>
-                  (obj: &U, mut stream: &mut V, version: u64)
+                  (obj: &U, mut stream: &mut V, version: u64, compress: bool)
+    -> std::io::Result<()>
 {
-    if version > 0 {
-        /* xgbc save state file */
-        bincode::serialize_into(&mut stream, &0x9bc54fe57473f11eu64).unwrap();
-        bincode::serialize_into(&mut stream, &version).unwrap();
+    if version == 0 {
+        /* No header, so there is no way to signal compression */
+        assert!(!compress);
+        return SaveState::export(obj, stream, version);
     }
 
-    SaveState::export(obj, stream, version);
+    let version_field =
+        if compress { version | VERSION_COMPRESSED } else { version };
+
+    /* xgbc save state file */
+    bincode::serialize_into(&mut stream, &MAGIC).map_err(bincode_to_io)?;
+    bincode::serialize_into(&mut stream, &version_field)
+        .map_err(bincode_to_io)?;
+
+    if compress {
+        let mut gz = GzEncoder::new(stream, Compression::default());
+        SaveState::export(obj, &mut gz, version)?;
+        gz.finish()?;
+        Ok(())
+    } else {
+        SaveState::export(obj, stream, version)
+    }
 }
 
+/*
+ * On error, @obj may have been partially overwritten, so callers that
+ * want to keep running should restore a previous state.
+ */
 pub fn import_root<U: SaveState, V: std::io::Read + std::io::Seek>
                   (obj: &mut U, mut stream: &mut V, max_version: u64)
+    -> Result<(), SaveStateError>
 {
-    let magic: u64 = bincode::deserialize_from(&mut stream).unwrap();
+    let magic: u64 = bincode::deserialize_from(&mut stream)?;
 
-    let version: u64 =
-        if magic == 0x9bc54fe57473f11eu64 {
-            bincode::deserialize_from(&mut stream).unwrap()
+    let version_field: u64 =
+        if magic == MAGIC {
+            bincode::deserialize_from(&mut stream)?
         } else {
-            stream.seek(std::io::SeekFrom::Start(0)).unwrap();
+            stream.seek(std::io::SeekFrom::Start(0))?;
             0u64
         };
 
+    let version = version_field & !VERSION_COMPRESSED;
     if version > max_version {
-        panic!("Save state version ({}) unsupported (maximum supported \
-                version: {})", version, max_version);
+        return Err(SaveStateError::UnsupportedVersion {
+            version,
+            max_version,
+        });
     }
 
-    SaveState::import(obj, stream, version);
+    if version_field & VERSION_COMPRESSED != 0 {
+        SaveState::import(obj, &mut GzDecoder::new(stream), version)
+    } else {
+        SaveState::import(obj, stream, version)
+    }
 }
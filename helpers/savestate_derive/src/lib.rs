@@ -12,21 +12,19 @@ pub fn save_state_derive(input: TokenStream) -> TokenStream {
     let ast: syn::DeriveInput = syn::parse(input).unwrap();
 
     match ast.data {
-        syn::Data::Struct(s) => {
-            match s.fields {
-                syn::Fields::Named(sf) =>
-                    save_state_derive_struct(ast.ident, sf),
+        syn::Data::Struct(s) =>
+            save_state_derive_struct(ast.ident, s.fields),
 
-                _ => panic!("Not implemented yet"),
-            }
-        },
+        syn::Data::Enum(e) =>
+            save_state_derive_enum(ast.ident, e),
 
         _ => panic!("Not implemented yet"),
     }
 }
 
 struct Attr {
-    name: syn::Ident,
+    /* How the field is accessed (self.#member, or a binding) */
+    member: syn::Member,
     post_import: Vec<syn::Expr>,
     neg_conditions: Vec<syn::Expr>,
     import_fn: Option<syn::Path>,
@@ -34,105 +32,181 @@ struct Attr {
     as_ref: bool,
 }
 
-fn save_state_derive_struct(name: syn::Ident, sf: syn::FieldsNamed)
-    -> TokenStream
-{
-    let mut v = Vec::<Attr>::new();
-
-    for field in &sf.named {
-        let mut post_import = Vec::new();
-        let mut neg_conditions = Vec::new();
-        let field_name = field.ident.as_ref().unwrap().to_string();
-        let mut skip = false;
-        let mut import_fn = None;
-        let mut export_fn = None;
-        let mut as_ref = false;
+/* Returns None for skipped fields */
+fn parse_field_attrs(field: &syn::Field, member: syn::Member) -> Option<Attr> {
+    let mut post_import = Vec::new();
+    let mut neg_conditions = Vec::new();
+    let field_name = quote!(#member).to_string();
+    let mut skip = false;
+    let mut import_fn = None;
+    let mut export_fn = None;
+    let mut as_ref = false;
 
-        for a in &field.attrs {
-            let attr_name = a.path.get_ident().as_ref().unwrap().to_string();
+    for a in &field.attrs {
+        let attr_name = a.path.get_ident().as_ref().unwrap().to_string();
 
-            if attr_name != "savestate" {
-                continue;
-            }
+        if attr_name != "savestate" {
+            continue;
+        }
 
-            match a.parse_meta().unwrap() {
-                syn::Meta::List(l) => {
-                    for opt in l.nested {
-                        match opt {
-                            syn::NestedMeta::Meta(m) => {
-                                match m {
-                                    syn::Meta::Path(p) => {
-                                        let opt_name = p.get_ident().as_ref().unwrap().to_string();
-
-                                        if opt_name == "skip" {
-                                            skip = true;
-                                        } else if opt_name == "ref" {
-                                            as_ref = true;
-                                        } else {
-                                            panic!("Unknown option {} for field {}",
-                                                   opt_name, field_name);
+        match a.parse_meta().unwrap() {
+            syn::Meta::List(l) => {
+                for opt in l.nested {
+                    match opt {
+                        syn::NestedMeta::Meta(m) => {
+                            match m {
+                                syn::Meta::Path(p) => {
+                                    let opt_name = p.get_ident().as_ref().unwrap().to_string();
+
+                                    if opt_name == "skip" {
+                                        skip = true;
+                                    } else if opt_name == "ref" {
+                                        as_ref = true;
+                                    } else {
+                                        panic!("Unknown option {} for field {}",
+                                               opt_name, field_name);
+                                    }
+                                },
+
+                                syn::Meta::List(l) => {
+                                    let opt_name = l.path.get_ident().as_ref().unwrap().to_string();
+
+                                    for opt in l.nested {
+                                        match opt {
+                                            syn::NestedMeta::Lit(syn::Lit::Str(ls)) => {
+                                                if opt_name == "post_import" {
+                                                    let s = syn::parse_str::<syn::Expr>(&ls.value()).unwrap();
+                                                    post_import.push(s);
+                                                } else if opt_name == "skip_if" {
+                                                    let s = syn::parse_str::<syn::Expr>(&ls.value()).unwrap();
+                                                    neg_conditions.push(s);
+                                                } else if opt_name == "import_fn" {
+                                                    let s = syn::parse_str::<syn::Path>(&ls.value()).unwrap();
+                                                    import_fn = Some(s);
+                                                } else if opt_name == "export_fn" {
+                                                    let s = syn::parse_str::<syn::Path>(&ls.value()).unwrap();
+                                                    export_fn = Some(s);
+                                                } else {
+                                                    panic!("Unknown option {} for field {}",
+                                                           opt_name, field_name);
+                                                }
+                                            },
+
+                                            _ => panic!("Invalid syntax"),
                                         }
-                                    },
-
-                                    syn::Meta::List(l) => {
-                                        let opt_name = l.path.get_ident().as_ref().unwrap().to_string();
-
-                                        for opt in l.nested {
-                                            match opt {
-                                                syn::NestedMeta::Lit(syn::Lit::Str(ls)) => {
-                                                    if opt_name == "post_import" {
-                                                        let s = syn::parse_str::<syn::Expr>(&ls.value()).unwrap();
-                                                        post_import.push(s);
-                                                    } else if opt_name == "skip_if" {
-                                                        let s = syn::parse_str::<syn::Expr>(&ls.value()).unwrap();
-                                                        neg_conditions.push(s);
-                                                    } else if opt_name == "import_fn" {
-                                                        let s = syn::parse_str::<syn::Path>(&ls.value()).unwrap();
-                                                        import_fn = Some(s);
-                                                    } else if opt_name == "export_fn" {
-                                                        let s = syn::parse_str::<syn::Path>(&ls.value()).unwrap();
-                                                        export_fn = Some(s);
-                                                    } else {
-                                                        panic!("Unknown option {} for field {}",
-                                                               opt_name, field_name);
-                                                    }
-                                                },
-
-                                                _ => panic!("Invalid syntax"),
-                                            }
-                                        }
-                                    },
+                                    }
+                                },
 
-                                    _ => panic!("Invalid syntax"),
-                                }
-                            },
+                                _ => panic!("Invalid syntax"),
+                            }
+                        },
 
-                            _ => panic!("Invalid syntax"),
-                        };
-                    }
+                        _ => panic!("Invalid syntax"),
+                    };
                 }
+            }
+
+            _ => panic!("Invalid syntax"),
+        }
+    }
+
+    if skip {
+        return None;
+    }
 
-                _ => panic!("Invalid syntax"),
+    Some(Attr {
+        member: member,
+        post_import: post_import,
+        neg_conditions: neg_conditions,
+        import_fn: import_fn,
+        export_fn: export_fn,
+        as_ref: as_ref,
+    })
+}
+
+/* @src is an expression giving a reference to the field */
+fn export_field(attr: &Attr, src: proc_macro2::TokenStream)
+    -> proc_macro2::TokenStream
+{
+    let ncond = &attr.neg_conditions;
+
+    let call =
+        if let Some(export_fn) = attr.export_fn.as_ref() {
+            quote! {
+                #export_fn(#src, stream, version)?;
+            }
+        } else {
+            quote! {
+                savestate::SaveState::export(#src, stream, version)?;
+            }
+        };
+
+    if ncond.is_empty() {
+        quote! {
+            #call
+        }
+    } else {
+        quote! {
+            if #(!(#ncond))&&* {
+                #call
             }
         }
+    }
+}
 
-        if skip {
-            continue;
+/* @dst is an expression giving a mutable reference to the field */
+fn import_field(attr: &Attr, dst: proc_macro2::TokenStream)
+    -> proc_macro2::TokenStream
+{
+    let ncond = &attr.neg_conditions;
+    let post = &attr.post_import;
+
+    let call =
+        if let Some(import_fn) = attr.import_fn.as_ref() {
+            quote! {
+                #import_fn(#dst, stream, version)?;
+            }
+        } else {
+            quote! {
+                savestate::SaveState::import(#dst, stream, version)?;
+            }
+        };
+
+    if ncond.is_empty() {
+        quote! {
+            #call
+            #(#post;)*
+        }
+    } else {
+        quote! {
+            if #(!(#ncond))&&* {
+                #call
+                #(#post;)*
+            }
         }
+    }
+}
 
-        v.push(Attr {
-            name: field.ident.as_ref().unwrap().clone(),
-            post_import: post_import,
-            neg_conditions: neg_conditions,
-            import_fn: import_fn,
-            export_fn: export_fn,
-            as_ref: as_ref,
-        });
+/* Named fields are accessed as self.name, unnamed ones as self.0 etc. */
+fn save_state_derive_struct(name: syn::Ident, fields: syn::Fields)
+    -> TokenStream
+{
+    let mut v = Vec::<Attr>::new();
+
+    for (i, field) in fields.iter().enumerate() {
+        let member =
+            match field.ident.as_ref() {
+                Some(ident) => syn::Member::Named(ident.clone()),
+                None => syn::Member::Unnamed(syn::Index::from(i)),
+            };
+
+        if let Some(attr) = parse_field_attrs(field, member) {
+            v.push(attr);
+        }
     }
 
     let export_list = v.iter().map(|attr| {
-        let name = &attr.name;
-        let ncond = &attr.neg_conditions;
+        let name = &attr.member;
 
         let src =
             if attr.as_ref {
@@ -147,34 +221,11 @@ fn save_state_derive_struct(name: syn::Ident, sf: syn::FieldsNamed)
                 }
             };
 
-        let call =
-            if let Some(export_fn) = attr.export_fn.as_ref() {
-                quote! {
-                    #export_fn(#src, stream, version);
-                }
-            } else {
-                quote! {
-                    savestate::SaveState::export(#src, stream, version);
-                }
-            };
-
-        if ncond.is_empty() {
-            quote! {
-                #call
-            }
-        } else {
-            quote! {
-                if #(!(#ncond))&&* {
-                    #call
-                }
-            }
-        }
+        export_field(attr, src)
     }).collect::<Vec<proc_macro2::TokenStream>>();
 
     let import_list = v.iter().map(|attr| {
-        let name = &attr.name;
-        let ncond = &attr.neg_conditions;
-        let post = &attr.post_import;
+        let name = &attr.member;
 
         let dst =
             if attr.as_ref {
@@ -189,42 +240,144 @@ fn save_state_derive_struct(name: syn::Ident, sf: syn::FieldsNamed)
                 }
             };
 
-        let call =
-            if let Some(import_fn) = attr.import_fn.as_ref() {
-                quote! {
-                    #import_fn(#dst, stream, version);
-                }
-            } else {
-                quote! {
-                    savestate::SaveState::import(#dst, stream, version);
-                }
-            };
+        import_field(attr, dst)
+    }).collect::<Vec<proc_macro2::TokenStream>>();
 
-        if ncond.is_empty() {
-            quote! {
-                #call
-                #(#post;)*
+    let result = quote! {
+        impl savestate::SaveState for #name {
+            fn export<T: std::io::Write>(&self, stream: &mut T, version: u64)
+                -> std::io::Result<()>
+            {
+                #(#export_list)*
+                Ok(())
             }
-        } else {
-            quote! {
-                if #(!(#ncond))&&* {
-                    #call
-                    #(#post;)*
-                }
+
+            fn import<T: std::io::Read>(&mut self, stream: &mut T,
+                                        version: u64)
+                -> Result<(), savestate::SaveStateError>
+            {
+                #(#import_list)*
+                Ok(())
             }
         }
-    }).collect::<Vec<proc_macro2::TokenStream>>();
+    };
+    result.into()
+}
+
+/*
+ * Enums are stored as the index of the variant (u32), followed by its
+ * fields.  On import, fields are default-initialized (so they must
+ * implement Default) before being imported, and then the whole variant
+ * is assigned to self.
+ */
+fn save_state_derive_enum(name: syn::Ident, de: syn::DataEnum)
+    -> TokenStream
+{
+    let mut export_arms = Vec::new();
+    let mut import_arms = Vec::new();
+
+    for (index, variant) in de.variants.iter().enumerate() {
+        let index = index as u32;
+        let vname = &variant.ident;
+
+        /* Fields are bound to __field_0, __field_1, ... */
+        let bindings = (0..variant.fields.len())
+            .map(|i| quote::format_ident!("__field_{}", i))
+            .collect::<Vec<syn::Ident>>();
+
+        let attrs = variant.fields.iter().zip(bindings.iter())
+            .filter_map(|(field, binding)| {
+                parse_field_attrs(field, syn::Member::Named(binding.clone()))
+            })
+            .collect::<Vec<Attr>>();
+
+        if attrs.iter().any(|attr| !attr.post_import.is_empty()) {
+            panic!("post_import is not supported for enum variant {}",
+                   vname);
+        }
+
+        let pattern =
+            match &variant.fields {
+                syn::Fields::Named(fields) => {
+                    let names = fields.named.iter()
+                        .map(|f| f.ident.as_ref().unwrap());
+                    quote! {
+                        #name::#vname { #(#names: #bindings),* }
+                    }
+                },
+
+                syn::Fields::Unnamed(_) => quote! {
+                    #name::#vname(#(#bindings),*)
+                },
+
+                syn::Fields::Unit => quote! {
+                    #name::#vname
+                },
+            };
+
+        let exports = attrs.iter().map(|attr| {
+            let binding = &attr.member;
+            if attr.as_ref {
+                export_field(attr, quote! { #binding.as_ref() })
+            } else {
+                export_field(attr, quote! { #binding })
+            }
+        });
+
+        export_arms.push(quote! {
+            #pattern => {
+                savestate::SaveState::export(&#index, stream, version)?;
+                #(#exports)*
+            }
+        });
+
+        let types = variant.fields.iter().map(|f| &f.ty);
+        let imports = attrs.iter().map(|attr| {
+            let binding = &attr.member;
+            if attr.as_ref {
+                import_field(attr, quote! { #binding.as_mut() })
+            } else {
+                import_field(attr, quote! { &mut #binding })
+            }
+        });
+
+        import_arms.push(quote! {
+            #index => {
+                #(let mut #bindings: #types = Default::default();)*
+                #(#imports)*
+                *self = #pattern;
+            }
+        });
+    }
 
     let result = quote! {
         impl savestate::SaveState for #name {
-            fn export<T: std::io::Write>(&self, stream: &mut T, version: u64) {
-                #(#export_list)*
+            #[allow(unused_variables)]
+            fn export<T: std::io::Write>(&self, stream: &mut T, version: u64)
+                -> std::io::Result<()>
+            {
+                match self {
+                    #(#export_arms)*
+                }
+                Ok(())
             }
 
+            #[allow(unused_mut)]
             fn import<T: std::io::Read>(&mut self, stream: &mut T,
                                         version: u64)
+                -> Result<(), savestate::SaveStateError>
             {
-                #(#import_list)*
+                let mut index = 0u32;
+                savestate::SaveState::import(&mut index, stream, version)?;
+
+                match index {
+                    #(#import_arms)*
+
+                    _ => return Err(savestate::SaveStateError::Corrupt(
+                        format!("Invalid variant {} for {}", index,
+                                stringify!(#name)))),
+                }
+                Ok(())
             }
         }
     };
@@ -0,0 +1,78 @@
+use savestate::{SaveState, SaveStateError};
+use savestate_derive::SaveState;
+
+
+#[derive(SaveState, Debug, PartialEq)]
+enum Mixed {
+    Unit,
+    Tuple(u8, #[savestate(skip)] u32, Vec<u16>),
+    Struct {
+        a: u16,
+        #[savestate(skip)]
+        b: u8,
+        #[savestate(skip_if("version < 2"))]
+        c: bool,
+    },
+}
+
+#[derive(SaveState, Debug, PartialEq)]
+struct Foo(u8, #[savestate(skip)] u32, Vec<u16>);
+
+fn round_trip<T: SaveState>(obj: &T, into: &mut T, version: u64) {
+    let mut data = Vec::new();
+    obj.export(&mut data, version).unwrap();
+
+    let mut stream = &data[..];
+    into.import(&mut stream, version).unwrap();
+    assert!(stream.is_empty());
+}
+
+
+#[test]
+fn enum_unit_variant() {
+    let mut obj = Mixed::Tuple(1, 2, vec![3]);
+    round_trip(&Mixed::Unit, &mut obj, 2);
+    assert_eq!(obj, Mixed::Unit);
+}
+
+#[test]
+fn enum_tuple_variant() {
+    let mut obj = Mixed::Unit;
+    round_trip(&Mixed::Tuple(0x12, 0xdeadbeef, vec![1, 2, 0xffff]),
+               &mut obj, 2);
+
+    /* Skipped fields come back as their default */
+    assert_eq!(obj, Mixed::Tuple(0x12, 0, vec![1, 2, 0xffff]));
+}
+
+#[test]
+fn enum_struct_variant() {
+    let orig = Mixed::Struct { a: 0x1234, b: 0x56, c: true };
+
+    let mut obj = Mixed::Unit;
+    round_trip(&orig, &mut obj, 2);
+    assert_eq!(obj, Mixed::Struct { a: 0x1234, b: 0, c: true });
+
+    /* Before version 2, c is not stored */
+    let mut obj = Mixed::Unit;
+    round_trip(&orig, &mut obj, 1);
+    assert_eq!(obj, Mixed::Struct { a: 0x1234, b: 0, c: false });
+}
+
+#[test]
+fn enum_invalid_variant() {
+    let data = 3u32.to_le_bytes();
+
+    let mut obj = Mixed::Unit;
+    let result = obj.import(&mut &data[..], 2);
+    assert!(matches!(result, Err(SaveStateError::Corrupt(_))));
+}
+
+#[test]
+fn tuple_struct() {
+    let mut obj = Foo(0, 42, Vec::new());
+    round_trip(&Foo(0xab, 0xdeadbeef, vec![0x1234, 0x5678]), &mut obj, 2);
+
+    /* The skipped field keeps its value */
+    assert_eq!(obj, Foo(0xab, 42, vec![0x1234, 0x5678]));
+}
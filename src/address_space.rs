@@ -1,22 +1,32 @@
 pub mod helpers;
 
 use std::fs;
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 
+use crate::cheats::GameGenieCode;
 use crate::rom::Cartridge;
-use savestate::SaveState;
+use savestate::{SaveState, SaveStateError};
 
 pub use helpers::U8Split;
+use helpers::in_boot_rom;
 
 
 pub const AS_BASE: usize = 0x100000000usize;
 
+/* Receives the RAM file's content whenever it should be persisted; only
+ * needed when the RAM is not backed by an actual file */
+pub type ExtRAMFlushFn = Box<dyn FnMut(&[u8])>;
+
 pub struct AddressSpace {
     pub rom_file: fs::File,
     pub extram_file: fs::File,
 
     pub cartridge: Cartridge,
 
+    /* Where to keep the MBC3 RTC; None to append it to the RAM file */
+    pub rtc_path: Option<String>,
+
     pub rom_bank: usize,
     pub vram_bank: usize,
     pub extram_bank: Option<usize>,
@@ -25,7 +35,7 @@ pub struct AddressSpace {
 
     pub full_vram: &'static mut [u8; 0x4000],
 
-    rom0_mapped: Option<()>,
+    rom0_mapped: Option<usize>,
     romn_mapped: Option<usize>,
     vram_mapped: Option<usize>,
     extram_mapped: Option<usize>,
@@ -38,8 +48,19 @@ pub struct AddressSpace {
     vram_shm: Option<RawFd>,
     wram_shm: Option<RawFd>,
 
-    // Always false because mmap'ed
+    // Always false because mmap'ed (unless there is an extram_flush)
     pub extram_dirty: bool,
+    /* Whether the RAM may have been modified since the last
+     * flush_extram() */
+    pub extram_unsaved: bool,
+
+    extram_flush: Option<ExtRAMFlushFn>,
+
+    /* Applied to the mapped ROM pages (which are private copies) */
+    rom_patches: Vec<GameGenieCode>,
+
+    /* Overlaid over ROM0 until the game writes to 0xff50 */
+    boot_rom: Option<Vec<u8>>,
 }
 
 
@@ -61,22 +82,59 @@ extern "C" fn close_shm() {
 
 impl AddressSpace {
     pub fn new(rom_path: &String, ram_path: &String) -> Self {
-        Self::register_shm_unlink_handler();
-
-        let mut addr_space = Self {
-            rom_file: std::fs::OpenOptions::new()
+        let rom_file = std::fs::OpenOptions::new()
                         .read(true)
-                        .open(rom_path).unwrap(),
+                        .open(rom_path).unwrap();
 
-            extram_file: std::fs::OpenOptions::new()
+        let extram_file = std::fs::OpenOptions::new()
                             .read(true)
                             .write(true)
                             .create(true)
                             .truncate(false)
-                            .open(ram_path).unwrap(),
+                            .open(ram_path).unwrap();
+
+        Self::with_files(rom_file, extram_file)
+    }
+
+    /*
+     * Like new(), but for ROM and RAM that are already in memory.  Use
+     * set_extram_flush() to learn about changes to the RAM.
+     */
+    #[allow(dead_code)]
+    pub fn from_bytes(rom: Vec<u8>, ram: Option<Vec<u8>>) -> Self {
+        let rom_file = Self::memfd("xgbcrew-rom", &rom);
+        let extram_file = Self::memfd("xgbcrew-extram",
+                                      ram.as_deref().unwrap_or(&[]));
+
+        Self::with_files(rom_file, extram_file)
+    }
+
+    fn memfd(name: &str, data: &[u8]) -> fs::File {
+        let name = format!("{}\0", name);
+        let fd = unsafe {
+            libc::memfd_create(name.as_bytes().as_ptr() as *const libc::c_char,
+                               0)
+        };
+        if fd < 0 {
+            panic!("memfd_create() failed");
+        }
+
+        let mut file = unsafe { fs::File::from_raw_fd(fd) };
+        file.write_all(data).unwrap();
+        file
+    }
+
+    fn with_files(rom_file: fs::File, extram_file: fs::File) -> Self {
+        Self::register_shm_unlink_handler();
+
+        let mut addr_space = Self {
+            rom_file,
+            extram_file,
 
             cartridge: Cartridge::new(),
 
+            rtc_path: None,
+
             rom_bank: 1,
             vram_bank: 0,
             extram_bank: None,
@@ -102,6 +160,13 @@ impl AddressSpace {
             wram_shm: None,
 
             extram_dirty: false,
+            extram_unsaved: false,
+
+            extram_flush: None,
+
+            rom_patches: Vec::new(),
+
+            boot_rom: None,
         };
 
         /* I/O must be mapped for further system initalization */
@@ -153,15 +218,53 @@ impl AddressSpace {
         }
     }
 
-    fn map_rom0(&mut self) {
-        if self.rom0_mapped.is_some() {
+    pub fn remap_rom0(&mut self) {
+        let bank = self.cartridge.rom0_bank;
+        if let Some(mapped) = self.rom0_mapped {
+            if mapped == bank {
+                return;
+            }
+            Self::munmap(AS_BASE, 0x4000);
+        }
+
+        Self::mmap(AS_BASE, self.rom_file.as_raw_fd(), bank * 0x4000usize,
+                   0x4000, libc::PROT_READ,
+                   libc::MAP_PRIVATE | libc::MAP_FIXED, false);
+        self.rom0_mapped = Some(bank);
+
+        Self::patch_rom(&self.rom_patches, 0x0000);
+        self.overlay_boot_rom();
+    }
+
+    fn overlay_boot_rom(&self) {
+        let boot_rom =
+            match self.boot_rom.as_ref() {
+                Some(boot_rom) => boot_rom,
+                None => return,
+            };
+
+        Self::mprotect(AS_BASE, 0x1000, libc::PROT_READ | libc::PROT_WRITE);
+        for (addr, byte) in boot_rom.iter().enumerate() {
+            if in_boot_rom(addr as u16, boot_rom.len()) {
+                unsafe {
+                    *((AS_BASE + addr) as *mut u8) = *byte;
+                }
+            }
+        }
+        Self::mprotect(AS_BASE, 0x1000, libc::PROT_READ);
+    }
+
+    /* Maps @boot_rom over the cartridge ROM (None to unmap it) */
+    pub fn set_boot_rom(&mut self, boot_rom: Option<Vec<u8>>) {
+        if boot_rom.is_none() && self.boot_rom.is_none() {
             return;
         }
+        self.boot_rom = boot_rom;
 
-        Self::mmap(AS_BASE, self.rom_file.as_raw_fd(), 0, 0x4000,
-                   libc::PROT_READ, libc::MAP_PRIVATE | libc::MAP_FIXED,
-                   false);
-        self.rom0_mapped = Some(());
+        if self.rom0_mapped.take().is_some() {
+            Self::munmap(AS_BASE, 0x4000);
+            self.remap_rom0();
+        }
     }
 
     pub fn remap_romn(&mut self) {
@@ -176,6 +279,46 @@ impl AddressSpace {
                    self.rom_bank * 0x4000usize, 0x4000,
                    libc::PROT_READ, libc::MAP_PRIVATE | libc::MAP_FIXED, false);
         self.romn_mapped = Some(self.rom_bank);
+
+        Self::patch_rom(&self.rom_patches, 0x4000);
+    }
+
+    /*
+     * Applies those of @patches that are in the given ROM region (0x0000
+     * or 0x4000) to what is mapped there right now.  Must be done on
+     * every remap, because the compare value may select the bank.
+     */
+    fn patch_rom(patches: &[GameGenieCode], region: u16) {
+        let mut patches =
+            patches.iter().filter(|p| p.address & 0x4000 == region)
+                   .peekable();
+        if patches.peek().is_none() {
+            return;
+        }
+
+        let base = AS_BASE + region as usize;
+        Self::mprotect(base, 0x4000, libc::PROT_READ | libc::PROT_WRITE);
+        for patch in patches {
+            unsafe {
+                let ptr = (AS_BASE + patch.address as usize) as *mut u8;
+                *ptr = patch.patch(*ptr);
+            }
+        }
+        Self::mprotect(base, 0x4000, libc::PROT_READ);
+    }
+
+    pub fn add_rom_patch(&mut self, patch: GameGenieCode) {
+        let mapped =
+            if patch.address < 0x4000 {
+                self.rom0_mapped.is_some()
+            } else {
+                self.romn_mapped.is_some()
+            };
+
+        if mapped {
+            Self::patch_rom(&[patch], patch.address & 0x4000);
+        }
+        self.rom_patches.push(patch);
     }
 
     pub fn remap_vram(&mut self) {
@@ -200,6 +343,17 @@ impl AddressSpace {
             return;
         }
 
+        /* We cannot see individual writes, but games disable RAM access
+         * once they are done writing */
+        if self.extram_mapped_rw && !self.extram_rw &&
+           self.extram_flush.is_some()
+        {
+            self.extram_dirty = true;
+        }
+        if self.extram_rw {
+            self.extram_unsaved = true;
+        }
+
         let prot =
             if self.extram_rw {
                 libc::PROT_READ | libc::PROT_WRITE
@@ -358,9 +512,11 @@ impl AddressSpace {
          * but we need at least 128.
          * Well, we can map everything as read-only (with an R/W
          * mirror somewhere else).  That's kind of stupid and it's
-         * just plain wrong for the mirrored WRAM region, but then
-         * again it's also plain wrong for games to access that
-         * region.
+         * just plain wrong for the mirrored WRAM region, so nothing
+         * may ever access AS_BASE + 0xf000..0xfe00 directly: mem!
+         * and get_raw_ptr() redirect the whole echo region to
+         * 0xc000..0xde00, so it stays coherent with WRAM0 and the
+         * currently mapped WRAMn bank.
          */
         Self::mmap(AS_BASE + 0x10000, self.hram_shm.unwrap(), 0, 0x1000,
                    libc::PROT_READ | libc::PROT_WRITE,
@@ -372,7 +528,7 @@ impl AddressSpace {
     }
 
     pub fn map(&mut self) {
-        self.map_rom0();
+        self.remap_rom0();
         self.remap_romn();
 
         self.ensure_vram_shm();
@@ -405,27 +561,61 @@ impl AddressSpace {
         }
     }
 
-    fn export_shm<T: std::io::Write>(fd: RawFd, size: usize, stream: &mut T) {
+    /* Writes to cartridge RAM (@ofs is the offset into the whole RAM),
+     * regardless of the current mapping */
+    pub fn write_extram(&mut self, ofs: usize, data: &[u8]) {
+        self.extram_file.seek(SeekFrom::Start(ofs as u64)).unwrap();
+        self.extram_file.write_all(data).unwrap();
+
+        self.extram_unsaved = true;
+        if self.extram_flush.is_some() {
+            self.extram_dirty = true;
+        }
+    }
+
+    pub fn set_virtual_extram_page(&mut self, page: &[u8; 0x2000]) {
+        assert!(self.extram_bank == Some(-1isize as usize));
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(page.as_ptr(),
+                                          (AS_BASE + 0xa000) as *mut u8,
+                                          0x2000);
+        }
+    }
+
+    fn export_shm<T: std::io::Write>(fd: RawFd, size: usize, stream: &mut T)
+        -> std::io::Result<()>
+    {
         let mapping = Self::mmap(0, fd, 0, size, libc::PROT_READ,
                                  libc::MAP_SHARED, false) as *const u8;
         let slice = unsafe {
             std::slice::from_raw_parts(mapping, size)
         };
-        stream.write_all(slice).unwrap();
+        let result = stream.write_all(slice);
         Self::munmap(mapping as usize, size);
+        result
     }
 
-    fn import_shm<T: std::io::Read>(fd: RawFd, size: usize, stream: &mut T) {
+    fn import_shm<T: std::io::Read>(fd: RawFd, size: usize, stream: &mut T)
+        -> std::io::Result<()>
+    {
         let mapping = Self::mmap(0, fd, 0, size, libc::PROT_WRITE,
                                  libc::MAP_SHARED, false) as *mut u8;
         let slice = unsafe {
             std::slice::from_raw_parts_mut(mapping, size)
         };
-        stream.read_exact(slice).unwrap();
+        let result = stream.read_exact(slice);
         Self::munmap(mapping as usize, size);
+        result
     }
 
-    /* Of course, this will only cover the current area */
+    /*
+     * Of course, this will only cover the current area.
+     * Must be kept in sync with mem!, especially regarding the echo
+     * region (0xe000..0xfe00), which is always redirected to WRAM
+     * (even though WRAM0 is also mapped at 0xe000, WRAMn is not mapped
+     * at 0xf000).
+     */
     fn get_raw_ptr(addr: u16) -> *mut u8 {
         let mem_addr = AS_BASE + (addr as usize);
 
@@ -453,54 +643,115 @@ impl AddressSpace {
         Self::get_raw_ptr(addr)
     }
 
-    pub fn flush_extram(&self) {
-        // For this implementation, the external RAM will never be dirty because the storage file
-        // is mmap'ed and so will always be in sync
-        unreachable!();
+    #[allow(dead_code)]
+    pub fn set_extram_flush(&mut self, flush: ExtRAMFlushFn) {
+        self.extram_flush = Some(flush);
+    }
+
+    /* The file is mmap'ed, so it is always in sync */
+    pub fn sync_extram(&mut self) {
+    }
+
+    pub fn flush_extram(&mut self) {
+        /* Writes while the RAM stays enabled are invisible to us */
+        self.extram_unsaved = self.extram_rw;
+
+        // With an actual file, the external RAM will never be dirty because the storage file is
+        // mmap'ed and so will always be in sync; just make sure it hits the
+        // disk
+        let flush =
+            match self.extram_flush.as_mut() {
+                Some(flush) => flush,
+                None => {
+                    self.extram_file.sync_data().unwrap_or(());
+                    return;
+                },
+            };
+
+        let mut data = Vec::new();
+        self.extram_file.seek(SeekFrom::Start(0)).unwrap();
+        self.extram_file.read_to_end(&mut data).unwrap();
+        flush(&data);
     }
 }
 
 
 impl SaveState for AddressSpace {
-    fn export<T: std::io::Write>(&self, stream: &mut T, version: u64) {
-        SaveState::export(&self.cartridge, stream, version);
+    fn export<T: std::io::Write>(&self, stream: &mut T, version: u64)
+        -> std::io::Result<()>
+    {
+        SaveState::export(&self.cartridge, stream, version)?;
 
-        Self::export_shm(self.wram_shm.unwrap(), 0x8000, stream);
-        Self::export_shm(self.hram_shm.unwrap(), 0x1000, stream);
+        Self::export_shm(self.wram_shm.unwrap(), 0x8000, stream)?;
+        Self::export_shm(self.hram_shm.unwrap(), 0x1000, stream)?;
 
         let extram_size = self.cartridge.extram_size * 0x2000;
         if extram_size != 0 {
-            Self::export_shm(self.extram_file.as_raw_fd(), extram_size, stream);
+            Self::export_shm(self.extram_file.as_raw_fd(), extram_size,
+                             stream)?;
         }
 
-        stream.write_all(self.full_vram).unwrap();
+        stream.write_all(self.full_vram)?;
 
-        SaveState::export(self.romn_mapped.as_ref().unwrap(), stream, version);
-        SaveState::export(self.vram_mapped.as_ref().unwrap(), stream, version);
-        SaveState::export(&self.extram_mapped, stream, version);
-        SaveState::export(&self.extram_mapped_rw, stream, version);
-        SaveState::export(self.wramn_mapped.as_ref().unwrap(), stream, version);
+        SaveState::export(self.romn_mapped.as_ref().unwrap(), stream, version)?;
+        SaveState::export(self.vram_mapped.as_ref().unwrap(), stream, version)?;
+        SaveState::export(&self.extram_mapped, stream, version)?;
+        SaveState::export(&self.extram_mapped_rw, stream, version)?;
+        SaveState::export(self.wramn_mapped.as_ref().unwrap(), stream,
+                          version)
     }
 
-    fn import<T: std::io::Read>(&mut self, stream: &mut T, version: u64) {
-        SaveState::import(&mut self.cartridge, stream, version);
+    fn import<T: std::io::Read>(&mut self, stream: &mut T, version: u64)
+        -> Result<(), SaveStateError>
+    {
+        SaveState::import(&mut self.cartridge, stream, version)?;
 
-        Self::import_shm(self.wram_shm.unwrap(), 0x8000, stream);
-        Self::import_shm(self.hram_shm.unwrap(), 0x1000, stream);
+        Self::import_shm(self.wram_shm.unwrap(), 0x8000, stream)?;
+        Self::import_shm(self.hram_shm.unwrap(), 0x1000, stream)?;
 
         let extram_size = self.cartridge.extram_size * 0x2000;
         if extram_size != 0 {
-            Self::import_shm(self.extram_file.as_raw_fd(), extram_size, stream);
+            Self::import_shm(self.extram_file.as_raw_fd(), extram_size,
+                             stream)?;
         }
 
-        stream.read_exact(self.full_vram).unwrap();
+        stream.read_exact(self.full_vram)?;
 
-        SaveState::import(&mut self.rom_bank, stream, version);
-        SaveState::import(&mut self.vram_bank, stream, version);
-        SaveState::import(&mut self.extram_bank, stream, version);
-        SaveState::import(&mut self.extram_rw, stream, version);
-        SaveState::import(&mut self.wram_bank, stream, version);
+        SaveState::import(&mut self.rom_bank, stream, version)?;
+        SaveState::import(&mut self.vram_bank, stream, version)?;
+        SaveState::import(&mut self.extram_bank, stream, version)?;
+        SaveState::import(&mut self.extram_rw, stream, version)?;
+        SaveState::import(&mut self.wram_bank, stream, version)?;
 
         self.map();
+        Ok(())
+    }
+}
+
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use crate::testing::{cgb_test_rom, test_system};
+
+    #[test]
+    fn echo_ram_aliases_banked_wram() {
+        let mut sys = test_system(cgb_test_rom(&[0x18, 0xfe]));
+
+        /* SVBK */
+        sys.poke(0xff70, 0x02);
+        sys.poke(0xf000, 0x42);
+        assert_eq!(sys.peek(0xd000), 0x42);
+
+        sys.poke(0xd001, 0x23);
+        assert_eq!(sys.peek(0xf001), 0x23);
+
+        /* Must follow the bank switch */
+        sys.poke(0xff70, 0x03);
+        sys.poke(0xf000, 0x17);
+        assert_eq!(sys.peek(0xd000), 0x17);
+
+        sys.poke(0xff70, 0x02);
+        assert_eq!(sys.peek(0xd000), 0x42);
+        assert_eq!(sys.peek(0xf000), 0x42);
     }
 }
@@ -1,3 +1,11 @@
+/* Whether a boot ROM of @len bytes covers @addr: 0x100 bytes on the DMG
+ * and SGB, 0x900 on the CGB, where 0x100..0x200 shows the cartridge header
+ * instead */
+pub fn in_boot_rom(addr: u16, len: usize) -> bool {
+    let addr = addr as usize;
+    addr < len && !(0x100..0x200).contains(&addr)
+}
+
 pub trait U8Split {
     fn split_into_u8<F: FnMut(u16, u8)>(self, base_addr: u16, func: F);
     fn construct_from_u8<F: FnMut(u16) -> u8>(base_addr: u16, func: F)
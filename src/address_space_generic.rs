@@ -7,12 +7,19 @@ use std::fs;
 use std::io::{Read, Seek, SeekFrom, Write};
 use base64::prelude::*;
 
+use crate::cheats::GameGenieCode;
 use crate::rom::Cartridge;
-use savestate::SaveState;
+use savestate::{SaveState, SaveStateError};
 
 pub use helpers::U8Split;
+use helpers::in_boot_rom;
 
 
+/* Receives the RAM file's content whenever it should be persisted; only
+ * needed when the RAM is not backed by an actual file */
+#[cfg(not(target_arch = "wasm32"))]
+pub type ExtRAMFlushFn = Box<dyn FnMut(&[u8])>;
+
 pub struct AddressSpace {
     #[cfg(not(target_arch = "wasm32"))]
     pub rom_file: fs::File,
@@ -21,6 +28,10 @@ pub struct AddressSpace {
 
     pub cartridge: Cartridge,
 
+    /* Where to keep the MBC3 RTC; None to append it to the RAM file */
+    #[cfg(not(target_arch = "wasm32"))]
+    pub rtc_path: Option<String>,
+
     pub rom_bank: usize,
     pub vram_bank: usize,
     pub extram_bank: Option<usize>,
@@ -40,26 +51,83 @@ pub struct AddressSpace {
     virt_extram_page: [u8; 0x2000],
 
     pub extram_dirty: bool,
+    /* Whether the RAM has been modified since the last flush_extram() */
+    pub extram_unsaved: bool,
     extram_invalid: bool,
+    /* Range of full_extram not yet written back to extram_file */
+    #[cfg(not(target_arch = "wasm32"))]
+    extram_unsynced: Option<(usize, usize)>,
+
+    #[cfg(not(target_arch = "wasm32"))]
+    extram_flush: Option<ExtRAMFlushFn>,
+
+    /* Only applied in rom_read(), so they are invisible to DMA */
+    rom_patches: Vec<GameGenieCode>,
+
+    /* Overlaid over ROM0 until the game writes to 0xff50 */
+    boot_rom: Option<Vec<u8>>,
 }
 
 
 impl AddressSpace {
     #[cfg(not(target_arch = "wasm32"))]
     pub fn new(rom_path: &String, ram_path: &String) -> Self {
-        Self {
-            rom_file: std::fs::OpenOptions::new()
+        let rom_file = std::fs::OpenOptions::new()
                         .read(true)
-                        .open(rom_path).unwrap(),
+                        .open(rom_path).unwrap();
 
-            extram_file: std::fs::OpenOptions::new()
+        let extram_file = std::fs::OpenOptions::new()
                             .read(true)
                             .write(true)
                             .create(true)
-                            .open(ram_path).unwrap(),
+                            .open(ram_path).unwrap();
+
+        Self::with_files(rom_file, extram_file)
+    }
+
+    /*
+     * Like new(), but for ROM and RAM that are already in memory.  Use
+     * set_extram_flush() to learn about changes to the RAM.
+     */
+    #[cfg(not(target_arch = "wasm32"))]
+    #[allow(dead_code)]
+    pub fn from_bytes(rom: Vec<u8>, ram: Option<Vec<u8>>) -> Self {
+        let rom_file = Self::temp_file("rom", &rom);
+        let extram_file = Self::temp_file("extram",
+                                          ram.as_deref().unwrap_or(&[]));
+
+        Self::with_files(rom_file, extram_file)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn temp_file(name: &str, data: &[u8]) -> fs::File {
+        let path = std::env::temp_dir().join(
+            format!("xgbcrew-{}-{}", name, std::process::id()));
+
+        let mut file = std::fs::OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .create(true)
+                        .truncate(true)
+                        .open(&path).unwrap();
+
+        /* We only need the handle (may fail on some systems, though) */
+        std::fs::remove_file(&path).unwrap_or(());
+
+        file.write_all(data).unwrap();
+        file
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn with_files(rom_file: fs::File, extram_file: fs::File) -> Self {
+        Self {
+            rom_file,
+            extram_file,
 
             cartridge: Cartridge::new(),
 
+            rtc_path: None,
+
             rom_bank: 1,
             vram_bank: 0,
             extram_bank: None,
@@ -75,7 +143,15 @@ impl AddressSpace {
             virt_extram_page: [0u8; 0x2000],
 
             extram_dirty: false,
+            extram_unsaved: false,
             extram_invalid: true,
+            extram_unsynced: None,
+
+            extram_flush: None,
+
+            rom_patches: Vec::new(),
+
+            boot_rom: None,
         }
     }
 
@@ -99,10 +175,18 @@ impl AddressSpace {
             virt_extram_page: [0u8; 0x2000],
 
             extram_dirty: false,
+            extram_unsaved: false,
             extram_invalid: true,
+
+            rom_patches: Vec::new(),
+
+            boot_rom: None,
         }
     }
 
+    pub fn remap_rom0(&mut self) {
+    }
+
     pub fn remap_romn(&mut self) {
     }
 
@@ -168,14 +252,38 @@ impl AddressSpace {
         Some(())
     }
 
-    pub fn rom_read(&self, addr: u16) -> u8 {
-        if addr < 0x4000 {
-            self.full_rom[addr as usize]
-        } else {
-            self.full_rom[self.rom_bank * 0x4000 + (addr as usize - 0x4000)]
+    /* Maps @boot_rom over the cartridge ROM (None to unmap it) */
+    pub fn set_boot_rom(&mut self, boot_rom: Option<Vec<u8>>) {
+        self.boot_rom = boot_rom;
+    }
+
+    fn boot_rom_mapped(&self, addr: u16) -> bool {
+        match self.boot_rom.as_ref() {
+            Some(boot_rom) => in_boot_rom(addr, boot_rom.len()),
+            None => false,
         }
     }
 
+    pub fn rom_read(&self, addr: u16) -> u8 {
+        let val =
+            if self.boot_rom_mapped(addr) {
+                self.boot_rom.as_ref().unwrap()[addr as usize]
+            } else if addr < 0x4000 {
+                self.full_rom[self.cartridge.rom0_bank * 0x4000 +
+                              addr as usize]
+            } else {
+                self.full_rom[self.rom_bank * 0x4000 +
+                              (addr as usize - 0x4000)]
+            };
+
+        self.rom_patches.iter().filter(|p| p.address == addr)
+            .fold(val, |val, p| p.patch(val))
+    }
+
+    pub fn add_rom_patch(&mut self, patch: GameGenieCode) {
+        self.rom_patches.push(patch);
+    }
+
     pub fn rom_write(&mut self, addr: u16, val: u8) {
         Cartridge::cart_write(self, addr, val);
     }
@@ -199,13 +307,22 @@ impl AddressSpace {
             } else if self.extram_rw {
                 let full_ofs = bank * 0x2000 + (addr as usize - 0xa000);
                 self.full_extram[full_ofs] = val;
+                self.extram_unsaved = true;
 
-                /* TODO: Batch writes, perhaps per frame? */
+                /* Written back to the file by sync_extram() */
                 #[cfg(not(target_arch = "wasm32"))]
                 {
-                    self.extram_file.seek(SeekFrom::Start(full_ofs as u64))
-                                    .unwrap();
-                    self.extram_file.write_all(&[val]).unwrap();
+                    self.extram_unsynced =
+                        match self.extram_unsynced {
+                            Some((start, end)) =>
+                                Some((start.min(full_ofs),
+                                      end.max(full_ofs + 1))),
+                            None => Some((full_ofs, full_ofs + 1)),
+                        };
+
+                    if self.extram_flush.is_some() {
+                        self.extram_dirty = true;
+                    }
                 }
 
                 #[cfg(target_arch = "wasm32")]
@@ -216,12 +333,49 @@ impl AddressSpace {
         }
     }
 
+    #[cfg(not(target_arch = "wasm32"))]
+    #[allow(dead_code)]
+    pub fn set_extram_flush(&mut self, flush: ExtRAMFlushFn) {
+        self.extram_flush = Some(flush);
+    }
+
+    /* Writes RAM modified since the last call back to the file; to be
+     * called once per frame */
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn sync_extram(&mut self) {
+        if let Some((start, end)) = self.extram_unsynced.take() {
+            self.extram_file.seek(SeekFrom::Start(start as u64)).unwrap();
+            self.extram_file.write_all(&self.full_extram[start..end])
+                            .unwrap();
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn sync_extram(&mut self) {
+    }
+
     pub fn flush_extram(&mut self) {
+        self.extram_unsaved = false;
+
         #[cfg(target_arch = "wasm32")]
         self.write_wasm_sav();
 
+        /* With an actual file, the RAM is never dirty once synced */
         #[cfg(not(target_arch = "wasm32"))]
-        unreachable!();
+        self.sync_extram();
+
+        #[cfg(not(target_arch = "wasm32"))]
+        if self.extram_flush.is_none() {
+            self.extram_file.sync_data().unwrap_or(());
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(flush) = self.extram_flush.as_mut() {
+            let mut data = Vec::new();
+            self.extram_file.seek(SeekFrom::Start(0)).unwrap();
+            self.extram_file.read_to_end(&mut data).unwrap();
+            flush(&data);
+        }
     }
 
     pub fn set_virtual_extram(&mut self, val: u8) {
@@ -232,6 +386,39 @@ impl AddressSpace {
         }
     }
 
+    /* Writes to cartridge RAM (@ofs is the offset into the whole RAM),
+     * regardless of the current mapping */
+    pub fn write_extram(&mut self, ofs: usize, data: &[u8]) {
+        let end = ofs + data.len();
+        self.full_extram[ofs..end].copy_from_slice(data);
+        self.extram_unsaved = true;
+
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            self.extram_unsynced =
+                match self.extram_unsynced {
+                    Some((start, old_end)) =>
+                        Some((start.min(ofs), old_end.max(end))),
+                    None => Some((ofs, end)),
+                };
+
+            if self.extram_flush.is_some() {
+                self.extram_dirty = true;
+            }
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        {
+            self.extram_dirty = true;
+        }
+    }
+
+    pub fn set_virtual_extram_page(&mut self, page: &[u8; 0x2000]) {
+        assert!(self.extram_bank == Some(-1isize as usize));
+
+        self.virt_extram_page.copy_from_slice(page);
+    }
+
     pub fn wram_read(&self, addr: u16) -> u8 {
         if addr < 0xd000 {
             self.full_wram[addr as usize - 0xc000]
@@ -268,8 +455,11 @@ impl AddressSpace {
 
     #[cfg(not(target_arch = "wasm32"))]
     pub fn raw_ptr(&self, addr: u16) -> *const u8 {
-        if addr < 0x4000 {
-            &self.full_rom[addr as usize] as *const u8
+        if self.boot_rom_mapped(addr) {
+            &self.boot_rom.as_ref().unwrap()[addr as usize] as *const u8
+        } else if addr < 0x4000 {
+            &self.full_rom[self.cartridge.rom0_bank * 0x4000 + addr as usize]
+                as *const u8
         } else if addr < 0x8000 {
             &self.full_rom[self.rom_bank * 0x4000 + (addr as usize - 0x4000)]
                 as *const u8
@@ -305,8 +495,10 @@ impl AddressSpace {
 
     #[cfg(target_arch = "wasm32")]
     pub fn read_u8(&self, addr: u16) -> u8 {
-        if addr < 0x4000 {
-            self.full_rom[addr as usize]
+        if self.boot_rom_mapped(addr) {
+            self.boot_rom.as_ref().unwrap()[addr as usize]
+        } else if addr < 0x4000 {
+            self.full_rom[self.cartridge.rom0_bank * 0x4000 + addr as usize]
         } else if addr < 0x8000 {
             self.full_rom[self.rom_bank * 0x4000 + (addr as usize - 0x4000)]
         } else if addr < 0xa000 {
@@ -338,8 +530,11 @@ impl AddressSpace {
 
     #[cfg(not(target_arch = "wasm32"))]
     pub fn raw_mut_ptr(&mut self, addr: u16) -> *mut u8 {
-        if addr < 0x4000 {
-            &mut self.full_rom[addr as usize] as *mut u8
+        if self.boot_rom_mapped(addr) {
+            &mut self.boot_rom.as_mut().unwrap()[addr as usize] as *mut u8
+        } else if addr < 0x4000 {
+            &mut self.full_rom[self.cartridge.rom0_bank * 0x4000 +
+                               addr as usize] as *mut u8
         } else if addr < 0x8000 {
             &mut self.full_rom[self.rom_bank * 0x4000 +
                                (addr as usize - 0x4000)]
@@ -381,7 +576,7 @@ impl AddressSpace {
     #[cfg(target_arch = "wasm32")]
     pub fn write_u8(&mut self, addr: u16, val: u8) {
         if addr < 0x4000 {
-            self.full_rom[addr as usize] = val;
+            self.full_rom[self.cartridge.rom0_bank * 0x4000 + addr as usize] = val;
         } else if addr < 0x8000 {
             self.full_rom[self.rom_bank * 0x4000 +
                           (addr as usize - 0x4000)]
@@ -422,53 +617,67 @@ impl AddressSpace {
 }
 
 
+/* In case we are not quit through System::quit() */
+#[cfg(not(target_arch = "wasm32"))]
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        self.sync_extram();
+    }
+}
+
 impl SaveState for AddressSpace {
-    fn export<T: std::io::Write>(&self, stream: &mut T, version: u64) {
-        SaveState::export(&self.cartridge, stream, version);
+    fn export<T: std::io::Write>(&self, stream: &mut T, version: u64)
+        -> std::io::Result<()>
+    {
+        SaveState::export(&self.cartridge, stream, version)?;
 
-        stream.write_all(&self.full_wram).unwrap();
-        stream.write_all(&self.full_hram).unwrap();
+        stream.write_all(&self.full_wram)?;
+        stream.write_all(&self.full_hram)?;
 
         let extram_size = self.cartridge.extram_size * 0x2000;
         if extram_size != 0 {
-            stream.write_all(self.full_extram.as_slice()).unwrap();
+            stream.write_all(self.full_extram.as_slice())?;
         }
 
-        stream.write_all(&self.full_vram).unwrap();
+        stream.write_all(&self.full_vram)?;
 
-        SaveState::export(&self.rom_bank, stream, version);
-        SaveState::export(&self.vram_bank, stream, version);
-        SaveState::export(&self.extram_bank, stream, version);
-        SaveState::export(&self.extram_rw, stream, version);
-        SaveState::export(&self.wram_bank, stream, version);
+        SaveState::export(&self.rom_bank, stream, version)?;
+        SaveState::export(&self.vram_bank, stream, version)?;
+        SaveState::export(&self.extram_bank, stream, version)?;
+        SaveState::export(&self.extram_rw, stream, version)?;
+        SaveState::export(&self.wram_bank, stream, version)
     }
 
-    fn import<T: std::io::Read>(&mut self, stream: &mut T, version: u64) {
-        SaveState::import(&mut self.cartridge, stream, version);
+    fn import<T: std::io::Read>(&mut self, stream: &mut T, version: u64)
+        -> Result<(), SaveStateError>
+    {
+        SaveState::import(&mut self.cartridge, stream, version)?;
 
-        stream.read_exact(&mut self.full_wram).unwrap();
-        stream.read_exact(&mut self.full_hram).unwrap();
+        stream.read_exact(&mut self.full_wram)?;
+        stream.read_exact(&mut self.full_hram)?;
 
         let extram_size = self.cartridge.extram_size * 0x2000;
         if extram_size != 0 {
-            stream.read_exact(self.full_extram.as_mut_slice()).unwrap();
+            stream.read_exact(self.full_extram.as_mut_slice())?;
             #[cfg(not(target_arch = "wasm32"))]
             {
-                self.extram_file.seek(SeekFrom::Start(0)).unwrap();
-                self.extram_file.write_all(self.full_extram.as_slice()).unwrap();
+                self.extram_file.seek(SeekFrom::Start(0))?;
+                self.extram_file.write_all(self.full_extram.as_slice())?;
+                self.extram_unsynced = None;
             }
             #[cfg(target_arch = "wasm32")]
             self.write_wasm_sav();
         }
 
-        stream.read_exact(&mut self.full_vram).unwrap();
+        stream.read_exact(&mut self.full_vram)?;
 
-        SaveState::import(&mut self.rom_bank, stream, version);
-        SaveState::import(&mut self.vram_bank, stream, version);
-        SaveState::import(&mut self.extram_bank, stream, version);
-        SaveState::import(&mut self.extram_rw, stream, version);
-        SaveState::import(&mut self.wram_bank, stream, version);
+        SaveState::import(&mut self.rom_bank, stream, version)?;
+        SaveState::import(&mut self.vram_bank, stream, version)?;
+        SaveState::import(&mut self.extram_bank, stream, version)?;
+        SaveState::import(&mut self.extram_rw, stream, version)?;
+        SaveState::import(&mut self.wram_bank, stream, version)?;
 
         self.map();
+        Ok(())
     }
 }
@@ -0,0 +1,61 @@
+use crate::input_log::{self, InputLog, InputPlayback};
+
+
+/*
+ * A bug report bundle: A save state plus all input from that point on,
+ * so a bug can be reproduced by replaying it.
+ *
+ * As with movies, replays will not be exact with --low-latency.
+ */
+#[derive(Serialize, Deserialize)]
+pub struct Bundle {
+    state: Vec<u8>,
+    inputs: InputLog,
+}
+
+pub struct BundleRecorder {
+    bundle: Bundle,
+}
+
+pub type BundleReplay = InputPlayback;
+
+
+impl Bundle {
+    pub fn load(path: &str) -> Result<Self, String> {
+        input_log::load(path)
+    }
+
+    /* Returns warnings about what may prevent an exact replay */
+    pub fn check(&self, rom_crc32: u32) -> Vec<String> {
+        self.inputs.check("Bundle", rom_crc32)
+    }
+
+    pub fn state(&self) -> &[u8] {
+        &self.state
+    }
+
+    pub fn into_replay(self) -> BundleReplay {
+        self.inputs.into_playback()
+    }
+}
+
+
+impl BundleRecorder {
+    pub fn new(rom_crc32: u32, state: Vec<u8>) -> Self {
+        Self {
+            bundle: Bundle {
+                state,
+                inputs: InputLog::new(rom_crc32),
+            },
+        }
+    }
+
+    /* To be called once per frame, after input has been polled */
+    pub fn frame(&mut self, lines: u8) {
+        self.bundle.inputs.frame(lines);
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        input_log::save(path, &self.bundle)
+    }
+}
@@ -0,0 +1,195 @@
+/*
+ * Game Boy Camera: M64282FP sensor registers and image processing.  The
+ * picture comes from a fixed image (a test pattern unless one is loaded),
+ * not from an actual camera.
+ *
+ * The sensor's edge enhancement is not emulated, only exposure,
+ * inversion, and the dither matrix.
+ */
+
+pub const CAMERA_WIDTH: usize = 128;
+pub const CAMERA_HEIGHT: usize = 112;
+
+/* Where the captured picture goes in cartridge RAM (bank 0) */
+pub const CAMERA_RAM_OFFSET: usize = 0x100;
+
+/* A000 (control), A001..A005 (sensor settings), A006..A035 (4x4 dither
+ * matrix, three thresholds per pixel) */
+const REG_COUNT: usize = 0x36;
+
+
+#[derive(SaveState)]
+pub struct CameraState {
+    /* 0x0a has been written to 0x0000..0x2000 */
+    pub ram_enable: bool,
+    /* Bit 4 was set in the last RAM bank selection */
+    pub regs_mapped: bool,
+
+    regs: Vec<u8>,
+    /* Remaining CPU cycles until the capture is done (0 if idle) */
+    busy_cycles: u32,
+
+    /* Grayscale, CAMERA_WIDTH x CAMERA_HEIGHT */
+    #[savestate(skip)]
+    image: Vec<u8>,
+}
+
+
+impl CameraState {
+    pub fn new() -> Self {
+        let mut image = Vec::with_capacity(CAMERA_WIDTH * CAMERA_HEIGHT);
+        for y in 0..CAMERA_HEIGHT {
+            for x in 0..CAMERA_WIDTH {
+                /* Diagonal gradient with a checkerboard on top */
+                let grad = (x + y) * 255 / (CAMERA_WIDTH + CAMERA_HEIGHT - 2);
+                let check = ((x / 16) ^ (y / 16)) & 1 != 0;
+                let val = if check { grad / 2 } else { grad };
+                image.push(val as u8);
+            }
+        }
+
+        Self {
+            ram_enable: false,
+            regs_mapped: false,
+
+            regs: vec![0u8; REG_COUNT],
+            busy_cycles: 0,
+
+            image,
+        }
+    }
+
+    /* Keeps the image */
+    pub fn reset(&mut self) {
+        self.ram_enable = false;
+        self.regs_mapped = false;
+
+        self.regs.fill(0);
+        self.busy_cycles = 0;
+    }
+
+    /* Scales @rgb8 to fill the sensor (cropping to keep the aspect
+     * ratio), converting it to grayscale */
+    pub fn set_image(&mut self, width: usize, height: usize, rgb8: &[u8]) {
+        /* Size of the source area used */
+        let (cw, ch) =
+            if width * CAMERA_HEIGHT > height * CAMERA_WIDTH {
+                (height * CAMERA_WIDTH / CAMERA_HEIGHT, height)
+            } else {
+                (width, width * CAMERA_HEIGHT / CAMERA_WIDTH)
+            };
+        let (cx, cy) = ((width - cw) / 2, (height - ch) / 2);
+
+        for y in 0..CAMERA_HEIGHT {
+            for x in 0..CAMERA_WIDTH {
+                let sx = cx + x * cw / CAMERA_WIDTH;
+                let sy = cy + y * ch / CAMERA_HEIGHT;
+                let px = &rgb8[(sy * width + sx) * 3..][..3];
+
+                let luma = 299 * px[0] as u32 +
+                           587 * px[1] as u32 +
+                           114 * px[2] as u32;
+                self.image[y * CAMERA_WIDTH + x] = (luma / 1000) as u8;
+            }
+        }
+    }
+
+    fn exposure(&self) -> u32 {
+        ((self.regs[2] as u32) << 8) | self.regs[3] as u32
+    }
+
+    /* In CPU cycles */
+    fn capture_cycles(&self) -> u32 {
+        let n = self.regs[1] & 0x80 != 0;
+        32446 + if n { 0 } else { 512 } + 16 * self.exposure()
+    }
+
+    /* @reg is the offset from 0xa000 (mirrored every 0x80 bytes) */
+    pub fn write_reg(&mut self, reg: u16, val: u8) {
+        let reg = reg as usize & 0x7f;
+
+        if reg == 0 {
+            self.regs[0] = val & 0x06;
+            self.busy_cycles =
+                if val & 0x01 == 0 {
+                    0
+                } else if self.busy_cycles == 0 {
+                    self.capture_cycles()
+                } else {
+                    self.busy_cycles
+                };
+        } else if reg < REG_COUNT {
+            self.regs[reg] = val;
+        }
+    }
+
+    /* Only A000 can be read, the rest reads as 0 */
+    pub fn regs_page(&self) -> Box<[u8; 0x2000]> {
+        let mut page = Box::new([0u8; 0x2000]);
+        let ctrl = self.regs[0] | if self.busy_cycles > 0 { 0x01 } else { 0 };
+
+        for (ofs, byte) in page.iter_mut().enumerate() {
+            if ofs & 0x7f == 0 {
+                *byte = ctrl;
+            }
+        }
+
+        page
+    }
+
+    /* Returns the captured picture (as tile data for CAMERA_RAM_OFFSET)
+     * once the capture is done */
+    pub fn add_cycles(&mut self, count: u32) -> Option<Vec<u8>> {
+        if self.busy_cycles == 0 {
+            return None;
+        }
+        if self.busy_cycles > count {
+            self.busy_cycles -= count;
+            return None;
+        }
+
+        self.busy_cycles = 0;
+        Some(self.capture())
+    }
+
+    /* 2 bpp tiles, 16 per row */
+    fn capture(&self) -> Vec<u8> {
+        let mut tiles = vec![0u8; CAMERA_WIDTH * CAMERA_HEIGHT / 4];
+        let exposure = self.exposure();
+        let invert = self.regs[4] & 0x80 != 0;
+
+        for y in 0..CAMERA_HEIGHT {
+            for x in 0..CAMERA_WIDTH {
+                let raw = self.image[y * CAMERA_WIDTH + x] as u32;
+                let mut val = (raw * exposure / 0x1000).min(255) as u8;
+                if invert {
+                    val = 255 - val;
+                }
+
+                let m = &self.regs[6 + ((y & 3) * 4 + (x & 3)) * 3..][..3];
+                let shade =
+                    if val < m[0] {
+                        3
+                    } else if val < m[1] {
+                        2
+                    } else if val < m[2] {
+                        1
+                    } else {
+                        0
+                    };
+
+                let tile = (y / 8) * (CAMERA_WIDTH / 8) + x / 8;
+                let ofs = tile * 16 + (y & 7) * 2;
+                let bit = 0x80 >> (x & 7);
+                if shade & 1 != 0 {
+                    tiles[ofs] |= bit;
+                }
+                if shade & 2 != 0 {
+                    tiles[ofs + 1] |= bit;
+                }
+            }
+        }
+
+        tiles
+    }
+}
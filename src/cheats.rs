@@ -0,0 +1,238 @@
+#![allow(unused_unsafe)]
+
+use crate::mem;
+#[cfg(target_os = "linux")]
+use crate::address_space::AS_BASE;
+use crate::io::io_write;
+use crate::system_state::SystemState;
+
+
+/* Patches a ROM byte as the CPU sees it (i.e. by address, not by offset
+ * in the ROM file) */
+#[derive(Clone, Copy)]
+pub struct GameGenieCode {
+    pub address: u16,
+    pub value: u8,
+    /* Only patch if the original byte has this value (which allows
+     * targeting a specific ROM bank) */
+    pub compare: Option<u8>,
+}
+
+/* Writes a RAM byte on every frame */
+#[derive(Clone, Copy)]
+pub struct GameSharkCode {
+    /* 0x01 for the current bank, 0x9n for WRAM bank n */
+    pub bank: u8,
+    pub address: u16,
+    pub value: u8,
+}
+
+pub enum Cheat {
+    GameGenie(GameGenieCode),
+    GameShark(GameSharkCode),
+}
+
+
+fn parse_hex_digits(code: &str) -> Option<Vec<u8>> {
+    code.chars()
+        .filter(|c| *c != '-')
+        .map(|c| c.to_digit(16).map(|d| d as u8))
+        .collect()
+}
+
+impl GameGenieCode {
+    /*
+     * Format: VVA-AAA[-CxC], with
+     *   V: new value
+     *   A: address, as (A2 A3 A4) (A1 ^ 0xf) -- i.e. the top nibble is
+     *      given last and inverted
+     *   C: compare value, as (C1 C2) rotated right by 2 and XORed with
+     *      0xba; the middle digit is ignored
+     */
+    pub fn parse(code: &str) -> Result<Self, String> {
+        let d =
+            parse_hex_digits(code)
+                .ok_or_else(|| format!("Invalid Game Genie code {}", code))?;
+
+        if d.len() != 6 && d.len() != 9 {
+            return Err(format!("Game Genie code {} must have 6 or 9 digits",
+                               code));
+        }
+
+        let value = (d[0] << 4) | d[1];
+        let address = (((d[5] ^ 0xf) as u16) << 12) |
+                      ((d[2] as u16) << 8) |
+                      ((d[3] as u16) << 4) |
+                      (d[4] as u16);
+
+        if address >= 0x8000 {
+            return Err(format!("Game Genie code {} does not target ROM",
+                               code));
+        }
+
+        let compare =
+            if d.len() == 9 {
+                Some(((d[6] << 4) | d[8]).rotate_right(2) ^ 0xba)
+            } else {
+                None
+            };
+
+        Ok(Self {
+            address,
+            value,
+            compare,
+        })
+    }
+
+    /* Returns what the CPU reads instead of @orig at our address */
+    pub fn patch(&self, orig: u8) -> u8 {
+        match self.compare {
+            Some(compare) if compare != orig => orig,
+            _ => self.value,
+        }
+    }
+}
+
+impl GameSharkCode {
+    /* Format: BBVVLLHH (bank, value, address low and high byte) */
+    pub fn parse(code: &str) -> Result<Self, String> {
+        let d =
+            parse_hex_digits(code)
+                .ok_or_else(|| format!("Invalid GameShark code {}", code))?;
+
+        if d.len() != 8 || code.contains('-') {
+            return Err(format!("GameShark code {} must have 8 digits", code));
+        }
+
+        Ok(Self {
+            bank: (d[0] << 4) | d[1],
+            value: (d[2] << 4) | d[3],
+            address: ((d[6] as u16) << 12) | ((d[7] as u16) << 8) |
+                     ((d[4] as u16) << 4) | (d[5] as u16),
+        })
+    }
+
+    pub fn apply(&self, sys_state: &mut SystemState) {
+        let wram_bank = (self.bank & 0x07) as usize;
+        let banked = self.bank & 0xf0 == 0x90;
+
+        if banked && (0xd000..0xe000).contains(&self.address) {
+            /* Temporarily switch to the requested WRAM bank */
+            let addr_space = &mut sys_state.addr_space;
+            let prev_bank = addr_space.wram_bank;
+
+            addr_space.wram_bank = std::cmp::max(wram_bank, 1);
+            addr_space.remap_wramn();
+            mem![sys_state; self.value => self.address];
+
+            let addr_space = &mut sys_state.addr_space;
+            addr_space.wram_bank = prev_bank;
+            addr_space.remap_wramn();
+        } else {
+            mem![sys_state; self.value => self.address];
+        }
+    }
+}
+
+impl Cheat {
+    /* Game Genie codes have dashes (or 6/9 digits), GameShark codes have
+     * 8 digits */
+    pub fn parse(code: &str) -> Result<Self, String> {
+        let code = code.trim();
+        let digits = code.chars().filter(|c| *c != '-').count();
+
+        if code.contains('-') || digits != 8 {
+            GameGenieCode::parse(code).map(Cheat::GameGenie)
+        } else {
+            GameSharkCode::parse(code).map(Cheat::GameShark)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Cheat, GameGenieCode, GameSharkCode};
+    use crate::testing::{test_rom, test_system};
+
+    #[test]
+    fn game_genie_address_scramble() {
+        /* Top address nibble comes last, inverted: 0xe ^ 0xf = 0x1 */
+        let gg = GameGenieCode::parse("3E1-23E").unwrap();
+        assert_eq!(gg.value, 0x3e);
+        assert_eq!(gg.address, 0x1123);
+        assert_eq!(gg.compare, None);
+
+        let gg = GameGenieCode::parse("00A-BCF").unwrap();
+        assert_eq!(gg.address, 0x0abc);
+
+        /* 0x7 ^ 0xf = 0x8, which is not ROM anymore */
+        assert!(GameGenieCode::parse("001-237").is_err());
+    }
+
+    #[test]
+    fn game_genie_compare_scramble() {
+        /* 0x45 rotated right by 2 is 0x51, ^ 0xba is 0xeb; the middle
+         * digit does not matter */
+        let gg = GameGenieCode::parse("3E1-23E-4A5").unwrap();
+        assert_eq!(gg.address, 0x1123);
+        assert_eq!(gg.compare, Some(0xeb));
+
+        let gg = GameGenieCode::parse("3E1-23E-405").unwrap();
+        assert_eq!(gg.compare, Some(0xeb));
+
+        assert_eq!(gg.patch(0xeb), 0x3e);
+        assert_eq!(gg.patch(0x12), 0x12);
+    }
+
+    #[test]
+    fn game_genie_invalid() {
+        assert!(GameGenieCode::parse("3E1-23").is_err());
+        assert!(GameGenieCode::parse("3E1-23E-4A").is_err());
+        assert!(GameGenieCode::parse("3G1-23E").is_err());
+    }
+
+    #[test]
+    fn game_shark() {
+        let gs = GameSharkCode::parse("01FF34C1").unwrap();
+        assert_eq!(gs.bank, 0x01);
+        assert_eq!(gs.value, 0xff);
+        assert_eq!(gs.address, 0xc134);
+
+        let gs = GameSharkCode::parse("9263a0d0").unwrap();
+        assert_eq!(gs.bank, 0x92);
+        assert_eq!(gs.value, 0x63);
+        assert_eq!(gs.address, 0xd0a0);
+
+        assert!(GameSharkCode::parse("01FF34C").is_err());
+        assert!(GameSharkCode::parse("01FF-34C1").is_err());
+        assert!(GameSharkCode::parse("01FF34CX").is_err());
+    }
+
+    #[test]
+    fn cheat_format_detection() {
+        assert!(matches!(Cheat::parse(" 01FF34C1 "),
+                         Ok(Cheat::GameShark(_))));
+        assert!(matches!(Cheat::parse("3E1-23E"), Ok(Cheat::GameGenie(_))));
+        assert!(matches!(Cheat::parse("3E123E"), Ok(Cheat::GameGenie(_))));
+        assert!(matches!(Cheat::parse("3E1-23E-4A5"),
+                         Ok(Cheat::GameGenie(_))));
+    }
+
+    #[test]
+    fn cheats_apply() {
+        let mut sys = test_system(test_rom(&[0x18, 0xfe]));
+        assert_eq!(sys.peek(0x1123), 0x00);
+
+        /* Compare value does not match, so nothing changes */
+        sys.add_cheat("3E1-23E-4A5").unwrap();
+        assert_eq!(sys.peek(0x1123), 0x00);
+
+        sys.add_cheat("3E1-23E").unwrap();
+        assert_eq!(sys.peek(0x1123), 0x3e);
+
+        sys.add_cheat("01FF34C1").unwrap();
+        sys.main_loop(true);
+        assert_eq!(sys.peek(0xc134), 0xff);
+    }
+}
@@ -0,0 +1,132 @@
+use std::path::{Path, PathBuf};
+
+use crate::io::lcd::ColorMode;
+use crate::io::sound::{DEFAULT_BUFCOUNT, WaveRamAccess};
+use crate::system_state::DEFAULT_AUTOSAVE_SECS;
+use crate::ui::ScaleMode;
+
+
+const CONFIG_FILE: &str = "xgbcrew.toml";
+
+/*
+ * Persistent settings, from xgbcrew/xgbcrew.toml in the XDG config
+ * directory.  Command line options take precedence.
+ */
+#[derive(Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Config {
+    /* Initial window size, in multiples of 160x144 (or 256x224 with an
+     * SGB border) */
+    pub scale: u32,
+    pub scale_mode: ScaleMode,
+    pub stretch: bool,
+    /* Defaults to the emulated model's LCD (agb on the GBA, raw
+     * otherwise) */
+    pub color_correction: Option<ColorMode>,
+    /* Honor SCX/SCY changes in the middle of a line (slower) */
+    pub accurate_ppu: bool,
+    /* Same format as --dmg-palette */
+    pub dmg_palette: String,
+    /* Speed multiplier while fast-forwarding (Space); 0 to run as fast
+     * as possible (without audio) */
+    pub speed: f32,
+    /* Rate to ask the audio driver for (it may choose a different one) */
+    pub sample_rate: usize,
+    /* Audio buffers to queue (of about 1/60 s each); more add latency,
+     * but make dropouts less likely */
+    pub audio_buffers: usize,
+    /* When the CPU can access wave RAM while channel 3 plays (auto, dmg,
+     * or cgb) */
+    pub wave_ram_access: WaveRamAccess,
+    /* Seconds (of emulated time) between writing the cartridge RAM to
+     * disk; 0 to disable */
+    pub autosave: f32,
+    /* Load the most recently written save state on startup */
+    pub auto_load_state: bool,
+    /* Dim the picture and show "PAUSED" while paused */
+    pub pause_overlay: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            scale: 3,
+            scale_mode: ScaleMode::Fit,
+            stretch: false,
+            color_correction: None,
+            accurate_ppu: false,
+            dmg_palette: String::from("grayscale"),
+            speed: 0.0,
+            sample_rate: 44100,
+            audio_buffers: DEFAULT_BUFCOUNT,
+            wave_ram_access: WaveRamAccess::Auto,
+            autosave: DEFAULT_AUTOSAVE_SECS,
+            auto_load_state: false,
+            pause_overlay: true,
+        }
+    }
+}
+
+
+/*
+ * $XDG_CONFIG_HOME/xgbcrew/@name, with $XDG_CONFIG_HOME defaulting to
+ * ~/.config; falls back to the working directory without $HOME
+ */
+pub fn config_file_path(name: &str) -> PathBuf {
+    let config_home =
+        match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+            _ => std::env::var_os("HOME")
+                     .filter(|home| !home.is_empty())
+                     .map(|home| PathBuf::from(home).join(".config")),
+        };
+
+    match config_home {
+        Some(dir) => dir.join("xgbcrew").join(name),
+        None => PathBuf::from(name),
+    }
+}
+
+impl Config {
+    /* Writes the default configuration if there is no file yet */
+    pub fn load() -> Self {
+        let path = config_file_path(CONFIG_FILE);
+        let text =
+            match std::fs::read_to_string(&path) {
+                Ok(text) => text,
+
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    let cfg = Self::default();
+                    cfg.write_default(&path);
+                    return cfg;
+                },
+
+                Err(e) => {
+                    eprintln!("Failed to load {}: {}", path.display(), e);
+                    return Self::default();
+                },
+            };
+
+        match toml::from_str(&text) {
+            Ok(cfg) => cfg,
+            Err(e) => {
+                eprintln!("Failed to parse {}: {}", path.display(), e);
+                Self::default()
+            },
+        }
+    }
+
+    fn write_default(&self, path: &Path) {
+        let text = toml::to_string(self).unwrap();
+
+        let result =
+            match path.parent() {
+                Some(dir) => std::fs::create_dir_all(dir),
+                None => Ok(()),
+            }.and_then(|_| std::fs::write(path, text));
+
+        if let Err(e) = result {
+            eprintln!("Failed to write {}: {}", path.display(), e);
+        }
+    }
+}
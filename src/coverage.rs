@@ -0,0 +1,84 @@
+use std::io::Write;
+
+use crate::address_space::AddressSpace;
+
+
+/* Tracks which ROM bytes have been executed as an opcode, so that we
+ * can tell romhackers which parts of the ROM have never been reached */
+pub struct Coverage {
+    path: String,
+
+    /* One bit per ROM byte, indexed by the byte's offset in the ROM
+     * file (i.e. rom_bank * 0x4000 + (pc & 0x3fff)) */
+    bitmap: Vec<u64>,
+}
+
+
+impl Coverage {
+    pub fn new(path: String, rom_size: usize) -> Self {
+        Self {
+            path,
+            bitmap: vec![0u64; (rom_size * 0x4000).div_ceil(64)],
+        }
+    }
+
+    fn rom_offset(addr_space: &AddressSpace, pc: u16) -> Option<usize> {
+        if pc < 0x4000 {
+            Some(addr_space.cartridge.rom0_bank * 0x4000 + pc as usize)
+        } else if pc < 0x8000 {
+            Some(addr_space.rom_bank * 0x4000 + (pc as usize - 0x4000))
+        } else {
+            None
+        }
+    }
+
+    pub fn mark(&mut self, addr_space: &AddressSpace, pc: u16) {
+        if let Some(ofs) = Self::rom_offset(addr_space, pc) {
+            if let Some(word) = self.bitmap.get_mut(ofs / 64) {
+                *word |= 1u64 << (ofs % 64);
+            }
+        }
+    }
+
+    fn is_covered(&self, ofs: usize) -> bool {
+        self.bitmap[ofs / 64] & (1u64 << (ofs % 64)) != 0
+    }
+
+    /* Writes one line per contiguous run of executed opcodes, in the form
+     * "bank:start-end" (with addresses as they appear on the bus) */
+    pub fn dump(&self) -> std::io::Result<()> {
+        let mut file = std::fs::File::create(&self.path)?;
+
+        let total = self.bitmap.len() * 64;
+        let mut ofs = 0;
+
+        while ofs < total {
+            if !self.is_covered(ofs) {
+                ofs += 1;
+                continue;
+            }
+
+            let bank = ofs / 0x4000;
+            let start = ofs;
+            while ofs + 1 < total && ofs + 1 < (bank + 1) * 0x4000 &&
+                  self.is_covered(ofs + 1)
+            {
+                ofs += 1;
+            }
+
+            let base = if bank == 0 { 0x0000 } else { 0x4000 };
+            writeln!(file, "{:02x}:{:04x}-{:04x}",
+                     bank,
+                     base + start % 0x4000,
+                     base + ofs % 0x4000)?;
+
+            ofs += 1;
+        }
+
+        Ok(())
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
@@ -2,22 +2,73 @@ mod disasm;
 mod insns;
 #[macro_use] mod macros;
 
+pub use disasm::{disassemble_range, ioreg_name};
+
 use crate::io::IOSpace;
-use crate::system_state::{IOReg, SystemState};
+use crate::system_state::{IOReg, Model, SystemState};
 
 
 #[derive(Serialize, Deserialize, Clone)]
 pub enum IIOperation {
     EnableInterrupts,
+    /* No longer injected (DI is immediate), but may be in old save states */
     DisableInterrupts,
 }
 
+/* What to do when encountering an illegal opcode */
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum IllegalOpcodeMode {
+    Panic,
+    /* Log it and treat it as a NOP */
+    Log,
+    /* Like Log, but also pause emulation */
+    Break,
+    /* Lock up the CPU, as the hardware does */
+    Lockup,
+}
+
+impl Default for IllegalOpcodeMode {
+    fn default() -> Self {
+        if cfg!(feature = "panic_on_illegal") {
+            IllegalOpcodeMode::Panic
+        } else {
+            IllegalOpcodeMode::Lockup
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 struct InternalInstruction {
+    /* Number of instructions to execute before this one takes effect
+     * (0: right before the next instruction) */
     delay: i8,
     op: IIOperation,
 }
 
+/* Register values, e.g. for test harnesses to check */
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CpuSnapshot {
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub sp: u16,
+    pub pc: u16,
+}
+
+/* 16-bit registers (and register pairs), for Cpu::reg16() */
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Reg16 {
+    AF,
+    BC,
+    DE,
+    HL,
+    SP,
+    PC,
+}
+
 #[derive(SaveState)]
 pub struct Cpu {
     /* Order here: f, a, c, b, e, d, l, h */
@@ -27,23 +78,49 @@ pub struct Cpu {
     pc: u16,
 
     halted: bool,
+    #[savestate(skip)]
+    halt_bug: bool,
+    #[savestate(skip)]
+    stopped: bool,
+    #[savestate(skip)]
+    locked: bool,
+
+    #[savestate(skip)]
+    illegal_opcode_mode: IllegalOpcodeMode,
 
     /* Should generally be small enough that a vec is best */
     internal_insns: Vec<InternalInstruction>,
 }
 
 impl Cpu {
-    pub fn new(cgb: bool, sgb: bool) -> Self {
+    /* @cgb: Whether the game runs in CGB mode */
+    pub fn new(model: Model, cgb: bool) -> Self {
         let (a, f, b, c, d, e, h, l) =
-            if cgb {
-                (0x11u8, 0xb0u8, 0x00u8, 0x00u8,
-                 0xffu8, 0x56u8, 0x00u8, 0x0du8)
-            } else if sgb {
-                (0xffu8, 0xb0u8, 0x00u8, 0x13u8,
-                 0x00u8, 0xd8u8, 0x01u8, 0x4du8)
-            } else {
-                (0x01u8, 0xb0u8, 0x00u8, 0x13u8,
-                 0x00u8, 0xd8u8, 0x01u8, 0x4du8)
+            match model {
+                Model::Dmg =>
+                    (0x01u8, 0xb0u8, 0x00u8, 0x13u8,
+                     0x00u8, 0xd8u8, 0x01u8, 0x4du8),
+
+                Model::Mgb | Model::Sgb =>
+                    (0xffu8, 0xb0u8, 0x00u8, 0x13u8,
+                     0x00u8, 0xd8u8, 0x01u8, 0x4du8),
+
+                Model::Cgb if cgb =>
+                    (0x11u8, 0xb0u8, 0x00u8, 0x00u8,
+                     0xffu8, 0x56u8, 0x00u8, 0x0du8),
+
+                Model::Cgb =>
+                    (0x11u8, 0x80u8, 0x00u8, 0x00u8,
+                     0x00u8, 0x08u8, 0x00u8, 0x7cu8),
+
+                /* Games tell the GBA apart by bit 0 of B */
+                Model::Agb if cgb =>
+                    (0x11u8, 0x00u8, 0x01u8, 0x00u8,
+                     0xffu8, 0x56u8, 0x00u8, 0x0du8),
+
+                Model::Agb =>
+                    (0x11u8, 0x00u8, 0x01u8, 0x00u8,
+                     0x00u8, 0x08u8, 0x00u8, 0x7cu8),
             };
 
         Self {
@@ -52,15 +129,132 @@ impl Cpu {
             pc: 0x0100u16,
 
             halted: false,
+            halt_bug: false,
+            stopped: false,
+            locked: false,
+
+            illegal_opcode_mode: IllegalOpcodeMode::default(),
 
             internal_insns: Vec::<InternalInstruction>::new(),
         }
     }
 
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    #[allow(dead_code)]
+    pub fn reg16(&self, reg: Reg16) -> u16 {
+        let cpu = self;
+
+        match reg {
+            Reg16::AF => regs16![cpu.af],
+            Reg16::BC => regs16![cpu.bc],
+            Reg16::DE => regs16![cpu.de],
+            Reg16::HL => regs16![cpu.hl],
+            Reg16::SP => regs16![cpu.sp],
+            Reg16::PC => regs16![cpu.pc],
+        }
+    }
+
+    /* The lower nibble of F always reads as 0, so it cannot be set */
+    #[allow(dead_code)]
+    pub fn set_reg16(&mut self, reg: Reg16, val: u16) {
+        let cpu = self;
+
+        match reg {
+            Reg16::AF => regs16![val & 0xfff0 => cpu.af],
+            Reg16::BC => regs16![val => cpu.bc],
+            Reg16::DE => regs16![val => cpu.de],
+            Reg16::HL => regs16![val => cpu.hl],
+            Reg16::SP => regs16![val => cpu.sp],
+            Reg16::PC => regs16![val => cpu.pc],
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn snapshot(&self) -> CpuSnapshot {
+        let [f, a, c, b, e, d, l, h] = self.regs8;
+        let r16 = |hi: u8, lo: u8| ((hi as u16) << 8) | lo as u16;
+
+        CpuSnapshot {
+            af: r16(a, f),
+            bc: r16(b, c),
+            de: r16(d, e),
+            hl: r16(h, l),
+            sp: self.sp,
+            pc: self.pc,
+        }
+    }
+
+    /* None if no instruction is going to be executed */
+    pub fn trace_line(&self, sys_state: &mut SystemState) -> Option<String> {
+        if self.halted || self.stopped || self.locked {
+            None
+        } else {
+            Some(insns::trace_line(self, sys_state))
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /* Power-on state, keeping the settings */
+    pub fn reset(&mut self, model: Model, cgb: bool) {
+        let illegal_opcode_mode = self.illegal_opcode_mode;
+        *self = Self::new(model, cgb);
+        self.illegal_opcode_mode = illegal_opcode_mode;
+    }
+
+    pub fn set_illegal_opcode_mode(&mut self, mode: IllegalOpcodeMode) {
+        self.illegal_opcode_mode = mode;
+    }
+
+    pub fn dump_json(&self) -> serde_json::Value {
+        let [f, a, c, b, e, d, l, h] = self.regs8;
+
+        serde_json::json!({
+            "a": format!("{:02x}", a),
+            "f": format!("{:02x}", f),
+            "b": format!("{:02x}", b),
+            "c": format!("{:02x}", c),
+            "d": format!("{:02x}", d),
+            "e": format!("{:02x}", e),
+            "h": format!("{:02x}", h),
+            "l": format!("{:02x}", l),
+            "sp": format!("{:04x}", self.sp),
+            "pc": format!("{:04x}", self.pc),
+            "halted": self.halted,
+        })
+    }
+
     pub fn exec(&mut self, sys_state: &mut SystemState) -> u32 {
+        if self.locked {
+            return 1;
+        }
+
+        /* STOP ends once a selected P1 line goes low, i.e. on a button
+         * press; until then, no time passes */
+        if self.stopped {
+            if sys_state.io_get_reg(IOReg::P1) & 0x0f == 0x0f {
+                return 0;
+            }
+            self.stopped = false;
+        }
+
         let cycles =
             if self.halted {
-                if !sys_state.ints_enabled {
+                /* A pending interrupt ends HALT even with IME clear (then
+                 * without being serviced); with IME set, the dispatch
+                 * below takes care of it */
+                let pending = sys_state.io_get_reg(IOReg::IF) &
+                              sys_state.io_get_reg(IOReg::IE) & 0x1f;
+                if pending != 0 && !sys_state.ints_enabled {
                     self.halted = false;
                 }
 
@@ -87,14 +281,20 @@ impl Cpu {
                 insns::exec(self, sys_state)
             };
 
+        /* Only the lower five bits correspond to interrupt sources; games
+         * do write 0xff to IE, so mask the rest lest we jump to 0x68+ */
         let (ime, irqs) = {
             (sys_state.ints_enabled,
-             sys_state.io_get_reg(IOReg::IF) & sys_state.io_get_reg(IOReg::IE))
+             sys_state.io_get_reg(IOReg::IF) & sys_state.io_get_reg(IOReg::IE)
+                & 0x1f)
         };
 
         if ime && irqs != 0 {
+            /* Lowest bit has the highest priority; only that one is
+             * serviced now, the rest stay pending in IF */
             let irq = irqs.trailing_zeros() as u16;
 
+            self.halted = false;
             sys_state.ints_enabled = false;
             { insns::push(self, sys_state, self.pc); }
             self.pc = 0x40 + irq * 8;
@@ -121,4 +321,178 @@ impl Cpu {
     fn inject_int_insn(&mut self, delay: i8, op: IIOperation) {
         self.internal_insns.push(InternalInstruction { delay, op });
     }
+
+    fn cancel_int_insns(&mut self) {
+        self.internal_insns.clear();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{IllegalOpcodeMode, Reg16};
+    use crate::debugger::BreakReason;
+    use crate::io::{IOReg, IOSpace};
+    use crate::system_state::StepResult;
+    use crate::testing::{test_rom, test_system, TestSystem};
+
+    #[test]
+    fn halt_bug_executes_next_opcode_twice() {
+        /* di; ld a, 1; ldh (IE), a; ldh (IF), a; xor a; halt; inc a; jr -2 */
+        let mut sys = test_system(test_rom(&[0xf3, 0x3e, 0x01, 0xe0, 0xff,
+                                             0xe0, 0x0f, 0xaf, 0x76, 0x3c,
+                                             0x18, 0xfe]));
+
+        for _ in 0..16 {
+            if sys.cpu.pc() == 0x015a {
+                break;
+            }
+            sys.step();
+        }
+
+        assert_eq!(sys.cpu.pc(), 0x015a);
+        assert_eq!(sys.get_reg16(Reg16::AF) >> 8, 0x02);
+    }
+
+    #[test]
+    fn halt_bug_rereads_opcode_as_operand() {
+        /* di; ld a, 1; ldh (IE), a; ldh (IF), a; ld d, 0; halt;
+         * ld a, 0x14; jr -2 */
+        let mut sys = test_system(test_rom(&[0xf3, 0x3e, 0x01, 0xe0, 0xff,
+                                             0xe0, 0x0f, 0x16, 0x00, 0x76,
+                                             0x3e, 0x14, 0x18, 0xfe]));
+
+        for _ in 0..16 {
+            if sys.cpu.pc() == 0x015c {
+                break;
+            }
+            sys.step();
+        }
+
+        /* Runs as ld a, 0x3e; inc d */
+        assert_eq!(sys.cpu.pc(), 0x015c);
+        assert_eq!(sys.get_reg16(Reg16::AF) >> 8, 0x3e);
+        assert_eq!(sys.get_reg16(Reg16::DE) >> 8, 0x01);
+    }
+
+    #[test]
+    fn ei_di_never_enables_interrupts() {
+        /* di; ld a, 4; ldh (IE), a; ldh (IF), a; ei; di; jr -2 */
+        let mut sys = test_system(test_rom(&[0xf3, 0x3e, 0x04, 0xe0, 0xff,
+                                             0xe0, 0x0f, 0xfb, 0xf3, 0x18,
+                                             0xfe]));
+
+        /* nop; jp; di */
+        for _ in 0..3 {
+            sys.step();
+        }
+
+        for _ in 0..16 {
+            assert!(!sys.sys_state.ints_enabled);
+            sys.step();
+            assert!(sys.cpu.pc() >= 0x0150);
+        }
+        assert_eq!(sys.sys_state.io_get_reg(IOReg::IF) & 0x04, 0x04);
+    }
+
+    #[test]
+    fn ei_halt_services_pending_interrupt() {
+        /* di; ld a, 4; ldh (IE), a; ldh (IF), a; ei; halt; jr -2 */
+        let mut sys = test_system(test_rom(&[0xf3, 0x3e, 0x04, 0xe0, 0xff,
+                                             0xe0, 0x0f, 0xfb, 0x76, 0x18,
+                                             0xfe]));
+
+        /* nop; jp; di; ld; ldh; ldh; ei */
+        for _ in 0..7 {
+            sys.step();
+        }
+        assert_eq!(sys.cpu.pc(), 0x0158);
+        assert!(!sys.sys_state.ints_enabled);
+
+        /* IME is set before the HALT, which then ends right away */
+        sys.step();
+        assert_eq!(sys.cpu.pc(), 0x0050);
+        assert!(!sys.cpu.halted);
+        assert!(!sys.sys_state.ints_enabled);
+        assert_eq!(sys.sys_state.io_get_reg(IOReg::IF) & 0x04, 0x00);
+    }
+
+    #[test]
+    fn timer_before_serial() {
+        /* di; ld a, 0x0c; ldh (IE), a; ldh (IF), a; ei; nop; jr -2 */
+        let mut sys = test_system(test_rom(&[0xf3, 0x3e, 0x0c, 0xe0, 0xff,
+                                             0xe0, 0x0f, 0xfb, 0x00, 0x18,
+                                             0xfe]));
+
+        /* nop; jp; di; ld; ldh; ldh; ei; nop */
+        for _ in 0..8 {
+            sys.step();
+        }
+
+        assert_eq!(sys.cpu.pc(), 0x0050);
+        assert_eq!(sys.sys_state.io_get_reg(IOReg::IF) & 0x1f, 0x08);
+    }
+
+    /* Illegal opcode; ld a, 0x42; jr -2 */
+    const ILLEGAL_CODE: [u8; 5] = [0xd3, 0x3e, 0x42, 0x18, 0xfe];
+
+    fn illegal_system(mode: IllegalOpcodeMode) -> TestSystem {
+        let mut sys = test_system(test_rom(&ILLEGAL_CODE));
+        sys.set_illegal_opcode_mode(mode);
+
+        /* nop; jp */
+        for _ in 0..2 {
+            sys.step();
+        }
+        assert_eq!(sys.cpu.pc(), 0x0150);
+        sys
+    }
+
+    #[test]
+    fn illegal_opcode_log_is_nop() {
+        let mut sys = illegal_system(IllegalOpcodeMode::Log);
+
+        assert!(matches!(sys.step(), StepResult::Executed(_)));
+        assert_eq!(sys.cpu.pc(), 0x0151);
+        sys.step();
+        assert_eq!(sys.get_reg16(Reg16::AF) >> 8, 0x42);
+    }
+
+    #[test]
+    fn illegal_opcode_break_pauses() {
+        let mut sys = illegal_system(IllegalOpcodeMode::Break);
+
+        match sys.step() {
+            StepResult::Break(BreakReason::Message(msg)) =>
+                assert!(msg.contains("Illegal opcode 0xd3"), "{}", msg),
+            _ => panic!("No break on illegal opcode"),
+        }
+        /* Continues after it, like Log */
+        assert_eq!(sys.cpu.pc(), 0x0151);
+    }
+
+    #[test]
+    fn illegal_opcode_lockup_freezes() {
+        let mut sys = illegal_system(IllegalOpcodeMode::Lockup);
+
+        for _ in 0..16 {
+            sys.step();
+        }
+        assert!(sys.cpu.is_locked());
+        assert_eq!(sys.cpu.pc(), 0x0150);
+        assert_ne!(sys.get_reg16(Reg16::AF) >> 8, 0x42);
+    }
+
+    #[test]
+    fn stop_ignores_second_byte() {
+        /* stop 0x42; jr -2 */
+        let mut sys = test_system(test_rom(&[0x10, 0x42, 0x18, 0xfe]));
+
+        /* nop; jp; stop */
+        for _ in 0..3 {
+            sys.step();
+        }
+        assert!(sys.cpu.is_stopped());
+        assert_eq!(sys.cpu.pc(), 0x0152);
+    }
 }
@@ -55,7 +55,7 @@ fn r16_af(code: u8) -> &'static str {
     }
 }
 
-fn ioreg_name(reg: u8) -> String {
+pub fn ioreg_name(reg: u8) -> String {
     match reg {
         0x00 => String::from("P1"),
         0x01 => String::from("SB"),
@@ -118,6 +118,7 @@ fn ioreg_name(reg: u8) -> String {
         0x4b => String::from("WX"),
         0x4d => String::from("KEY1"),
         0x4f => String::from("VBK"),
+        0x50 => String::from("BOOT"),
         0x51 => String::from("HDMA1"),
         0x52 => String::from("HDMA2"),
         0x53 => String::from("HDMA3"),
@@ -137,8 +138,8 @@ fn ioreg_name(reg: u8) -> String {
     }
 }
 
-fn disasm_prefix_0x10(sys_state: &mut SystemState, cpu: &Cpu) -> String {
-    let op = mem![sys_state; cpu.pc.wrapping_add(1)];
+fn disasm_prefix_0x10(sys_state: &mut SystemState, pc: u16) -> String {
+    let op = mem![sys_state; pc.wrapping_add(1)];
 
     String::from(
         match op {
@@ -148,8 +149,8 @@ fn disasm_prefix_0x10(sys_state: &mut SystemState, cpu: &Cpu) -> String {
     )
 }
 
-fn disasm_prefix_0xcb(sys_state: &mut SystemState, cpu: &Cpu) -> String {
-    let op = mem![sys_state; cpu.pc.wrapping_add(1)];
+fn disasm_prefix_0xcb(sys_state: &mut SystemState, pc: u16) -> String {
+    let op = mem![sys_state; pc.wrapping_add(1)];
     let r8_op = op & 0x07;
     let bit = (op & 0x38) >> 3;
 
@@ -179,12 +180,12 @@ fn disasm_prefix_0xcb(sys_state: &mut SystemState, cpu: &Cpu) -> String {
     }
 }
 
-fn disasm_block_misc_lo(sys_state: &mut SystemState, cpu: &Cpu, op: u8)
+fn disasm_block_misc_lo(sys_state: &mut SystemState, pc: u16, op: u8)
     -> String
 {
-    let n8 = peek8(sys_state, cpu.pc, 1);
-    let n16 = peek16(sys_state, cpu.pc, 1);
-    let jr_to = cpu.pc.wrapping_add(2).wrapping_add(n8 as i8 as u16);
+    let n8 = peek8(sys_state, pc, 1);
+    let n16 = peek16(sys_state, pc, 1);
+    let jr_to = pc.wrapping_add(2).wrapping_add(n8 as i8 as u16);
 
     let r8_op = (op >> 3) & 0x07;
     let r16_op = (op >> 4) & 0x03;
@@ -193,7 +194,7 @@ fn disasm_block_misc_lo(sys_state: &mut SystemState, cpu: &Cpu, op: u8)
         0x00 | 0x08 => match op {
             0x00 => String::from("nop"),
             0x08 => format!("ld     (0x{:04x}), sp", n16),
-            0x10 => disasm_prefix_0x10(sys_state, cpu),
+            0x10 => disasm_prefix_0x10(sys_state, pc),
             0x18 => format!("jr     0x{:04x}", jr_to),
             0x20 => format!("jrnz   0x{:04x}", jr_to),
             0x28 => format!("jrz    0x{:04x}", jr_to),
@@ -277,11 +278,12 @@ fn disasm_block_alu(_sys_state: &mut SystemState, _cpu: &Cpu, op: u8) -> String
     }
 }
 
-fn disasm_block_misc_hi(sys_state: &mut SystemState, cpu: &Cpu, op: u8)
+fn disasm_block_misc_hi(sys_state: &mut SystemState, cpu: &Cpu, pc: u16,
+                        op: u8)
     -> String
 {
-    let n8 = peek8(sys_state, cpu.pc, 1);
-    let n16 = peek16(sys_state, cpu.pc, 1);
+    let n8 = peek8(sys_state, pc, 1);
+    let n16 = peek16(sys_state, pc, 1);
     let r16_op = (op >> 4) & 0x03;
 
     match op & 0x0f {
@@ -335,7 +337,7 @@ fn disasm_block_misc_hi(sys_state: &mut SystemState, cpu: &Cpu, op: u8)
         },
 
         0x0b => match op {
-            0xcb => disasm_prefix_0xcb(sys_state, cpu),
+            0xcb => disasm_prefix_0xcb(sys_state, pc),
             0xfb => String::from("ei"),
 
             0xdb | 0xeb
@@ -386,15 +388,56 @@ fn disasm_block_misc_hi(sys_state: &mut SystemState, cpu: &Cpu, op: u8)
     }
 }
 
-pub fn disassemble(sys_state: &mut SystemState, cpu: &Cpu) -> String {
-    let op = mem![sys_state; cpu.pc];
+/* Including prefixes and immediate operands */
+fn insn_length(op: u8) -> u16 {
+    match op {
+        0x10 | 0xcb => 2,
+
+        0x06 | 0x0e | 0x16 | 0x1e | 0x26 | 0x2e | 0x36 | 0x3e |
+        0x18 | 0x20 | 0x28 | 0x30 | 0x38 |
+        0xc6 | 0xce | 0xd6 | 0xde | 0xe6 | 0xee | 0xf6 | 0xfe |
+        0xe0 | 0xe8 | 0xf0 | 0xf8 => 2,
+
+        0x01 | 0x08 | 0x11 | 0x21 | 0x31 |
+        0xc2 | 0xc3 | 0xc4 | 0xca | 0xcc | 0xcd |
+        0xd2 | 0xd4 | 0xda | 0xdc | 0xea | 0xfa => 3,
+
+        _ => 1,
+    }
+}
+
+/* Register values in comments (e.g. for jp hl) are the current ones */
+fn disassemble_at(sys_state: &mut SystemState, cpu: &Cpu, pc: u16) -> String {
+    let op = mem![sys_state; pc];
 
     match op & 0xc0 {
-        0x00 => disasm_block_misc_lo(sys_state, cpu, op),
+        0x00 => disasm_block_misc_lo(sys_state, pc, op),
         0x40 => disasm_block_mov(sys_state, cpu, op),
         0x80 => disasm_block_alu(sys_state, cpu, op),
-        0xc0 => disasm_block_misc_hi(sys_state, cpu, op),
+        0xc0 => disasm_block_misc_hi(sys_state, cpu, pc, op),
 
         _ => unreachable!(),
     }
 }
+
+pub fn disassemble(sys_state: &mut SystemState, cpu: &Cpu) -> String {
+    disassemble_at(sys_state, cpu, cpu.pc)
+}
+
+/* @count instructions starting at @start, with their addresses; wraps
+ * around at 0xffff */
+pub fn disassemble_range(sys_state: &mut SystemState, cpu: &Cpu, start: u16,
+                         count: usize)
+    -> Vec<(u16, String)>
+{
+    let mut lines = Vec::with_capacity(count);
+    let mut pc = start;
+
+    for _ in 0..count {
+        let op = mem![sys_state; pc];
+        lines.push((pc, disassemble_at(sys_state, cpu, pc)));
+        pc = pc.wrapping_add(insn_length(op));
+    }
+
+    lines
+}
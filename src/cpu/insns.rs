@@ -5,9 +5,12 @@ use crate::{mem, regs, regs8, regs16, regs16_split, flags, single_flag_mask};
 #[cfg(target_os = "linux")]
 use crate::address_space::AS_BASE;
 use crate::address_space::U8Split;
-use crate::cpu::{Cpu, IIOperation};
+use crate::cpu::{Cpu, IIOperation, IllegalOpcodeMode};
 use crate::cpu::disasm::disassemble;
 use crate::io::{IOSpace, io_read, io_write};
+use crate::io::timer;
+#[cfg(feature = "accurate-oam-bug")]
+use crate::io::lcd;
 use crate::system_state::{IOReg, SystemState};
 
 
@@ -22,17 +25,27 @@ pub fn cpu_panic(cpu: &Cpu, msg: &str) {
            regs![cpu.pc], regs![cpu.sp]);
 }
 
-#[allow(dead_code)]
+fn regs_line(cpu: &Cpu) -> String {
+    format!("PC={:04x} AF={:04x} BC={:04x} DE={:04x} HL={:04x} SP={:04x}",
+            regs![cpu.pc], regs![cpu.af], regs![cpu.bc], regs![cpu.de],
+            regs![cpu.hl], regs![cpu.sp])
+}
+
 pub fn cpu_debug(cpu: &Cpu, sys_state: &mut SystemState, msg: &str) {
     let disasm = { disassemble(sys_state, cpu) };
 
-    println!("{}PC={:04x} AF={:04x} BC={:04x} DE={:04x} HL={:04x} SP={:04x} \
-              [{}] -- {}",
-             msg,
-             regs![cpu.pc], regs![cpu.af], regs![cpu.bc], regs![cpu.de],
-             regs![cpu.hl], regs![cpu.sp],
-             sys_state.addr_space.rom_bank,
-             disasm);
+    println!("{}{} [{}] -- {}",
+             msg, regs_line(cpu), sys_state.addr_space.rom_bank, disasm);
+}
+
+/* One line for an execution trace, describing the instruction at PC */
+pub fn trace_line(cpu: &Cpu, sys_state: &mut SystemState) -> String {
+    let opcode = mem![sys_state; regs![cpu.pc]];
+    let disasm = { disassemble(sys_state, cpu) };
+
+    format!("{:02x}:{:04x}: {:02x}  {:<24} {}",
+            sys_state.addr_space.rom_bank, regs![cpu.pc], opcode, disasm,
+            regs_line(cpu))
 }
 
 
@@ -51,36 +64,34 @@ fn n16(cpu: &mut Cpu, sys_state: &mut SystemState) -> u16 {
 
 pub fn exec(cpu: &mut Cpu, sys_state: &mut SystemState) -> u32 {
     //cpu_debug(cpu, sys_state, "");
-    let basic_opcode = n8(cpu, sys_state) as usize;
+    let basic_opcode =
+        if cpu.halt_bug {
+            /* PC is not incremented, so this byte will be read twice */
+            cpu.halt_bug = false;
+            mem![sys_state; regs![cpu.pc]] as usize
+        } else {
+            n8(cpu, sys_state) as usize
+        };
 
     INSN_HANDLERS[basic_opcode](cpu, sys_state);
     INSN_CYCLES[basic_opcode] as u32
 }
 
 fn prefix0x10(cpu: &mut Cpu, sys_state: &mut SystemState) {
-    let sub_op = n8(cpu, sys_state);
+    /* Usually 0x00, but the hardware does not care and skips it either
+     * way */
+    n8(cpu, sys_state);
 
-    if sub_op != 0x00u8 {
-        cpu_panic(cpu,
-                  format!("Unknown opcode 0x10 0x{:02x}", sub_op).as_str());
-    }
-
-    let mut key1 = sys_state.io_get_reg(IOReg::KEY1);
+    let key1 = sys_state.io_get_reg(IOReg::KEY1);
 
     /* STOP */
-    if key1 & 0x01 == 0 {
-        cpu_panic(cpu, "STOP");
+    if !sys_state.cgb || key1 & 0x01 == 0 {
+        timer::reset_div(sys_state);
+        cpu.stopped = true;
+        return;
     }
 
-    sys_state.double_speed = !sys_state.double_speed;
-
-    if sys_state.double_speed {
-        key1 |= 0x80;
-    } else {
-        key1 &= !0x80;
-    }
-
-    sys_state.io_set_reg(IOReg::KEY1, key1);
+    sys_state.set_double_speed(!sys_state.double_speed);
 }
 
 macro_rules! acc_op_r8 {
@@ -539,7 +550,11 @@ macro_rules! swap8 {
 macro_rules! inc_r16 {
     ($r:ident) => {
         paste::item! {
-            fn [<inc_ $r>](cpu: &mut Cpu, _sys_state: &mut SystemState) {
+            #[allow(unused_variables)]
+            fn [<inc_ $r>](cpu: &mut Cpu, sys_state: &mut SystemState) {
+                #[cfg(feature = "accurate-oam-bug")]
+                lcd::oam_bug(sys_state, regs![cpu.$r]);
+
                 regs![regs![cpu.$r].wrapping_add(1u16) => cpu.$r];
             }
         }
@@ -549,7 +564,11 @@ macro_rules! inc_r16 {
 macro_rules! dec_r16 {
     ($r:ident) => {
         paste::item! {
-            fn [<dec_ $r>](cpu: &mut Cpu, _sys_state: &mut SystemState) {
+            #[allow(unused_variables)]
+            fn [<dec_ $r>](cpu: &mut Cpu, sys_state: &mut SystemState) {
+                #[cfg(feature = "accurate-oam-bug")]
+                lcd::oam_bug(sys_state, regs![cpu.$r]);
+
                 regs![regs![cpu.$r].wrapping_sub(1u16) => cpu.$r];
             }
         }
@@ -636,10 +655,36 @@ macro_rules! rstn {
 }
 
 
-fn not_implemented(cpu: &mut Cpu, sys_state: &mut SystemState) {
+fn illegal_opcode(cpu: &mut Cpu, sys_state: &mut SystemState) {
     regs![regs![cpu.pc].wrapping_sub(1u16) => cpu.pc];
     let insn = mem![sys_state; regs![cpu.pc]];
-    cpu_panic(cpu, format!("INSN 0x{:02x} not implemented", insn).as_str());
+    let msg = format!("Illegal opcode 0x{:02x}", insn);
+
+    match cpu.illegal_opcode_mode {
+        IllegalOpcodeMode::Panic => {
+            cpu_panic(cpu, msg.as_str());
+        },
+
+        IllegalOpcodeMode::Log | IllegalOpcodeMode::Break => {
+            cpu_debug(cpu, sys_state, format!("{}: ", msg).as_str());
+
+            if cpu.illegal_opcode_mode == IllegalOpcodeMode::Break {
+                let pc = regs![cpu.pc];
+                sys_state.debugger.request_break(
+                    format!("Break: {:04x}: {}", pc, msg));
+            }
+
+            /* Continue as if it were a NOP */
+            regs![regs![cpu.pc].wrapping_add(1u16) => cpu.pc];
+        },
+
+        IllegalOpcodeMode::Lockup => {
+            /* Like the hardware: The CPU stops, and nothing (not even an
+             * interrupt) will make it continue */
+            eprintln!("{} at {:04x}, CPU locked up", msg, regs![cpu.pc]);
+            cpu.locked = true;
+        },
+    }
 }
 
 fn nop(_cpu: &mut Cpu, _sys_state: &mut SystemState) {
@@ -997,36 +1042,35 @@ fn cpl(cpu: &mut Cpu, _sys_state: &mut SystemState) {
 }
 
 fn daa(cpu: &mut Cpu, _sys_state: &mut SystemState) {
-    let mut a = regs![cpu.a] as u32;
+    let mut a = regs![cpu.a];
+    let mut carry = flags![cpu.cf];
 
+    /* Both corrections are decided on the original value */
     if !flags![cpu.nf] {
-        if (a & 0xf) > 0x9 || flags![cpu.hf] {
-            a += 0x6;
+        if carry || a > 0x99 {
+            a = a.wrapping_add(0x60);
+            carry = true;
         }
-        if a > 0x99 || flags![cpu.cf] {
-            a += 0x60;
+        if flags![cpu.hf] || (regs![cpu.a] & 0xf) > 0x9 {
+            a = a.wrapping_add(0x6);
         }
-
-        flags! { cpu;
-            cf: (a & 0x100) != 0,
-            hf: false,
-            zf: a == 0
-        };
     } else {
+        /* Carry stays as it is */
+        if carry {
+            a = a.wrapping_sub(0x60);
+        }
         if flags![cpu.hf] {
             a = a.wrapping_sub(0x6);
         }
-        if flags![cpu.cf] {
-            a = a.wrapping_sub(0x60);
-        }
-
-        flags! { cpu;
-            hf: false,
-            zf: a == 0
-        };
     }
 
-    regs![a as u8 => cpu.a];
+    flags! { cpu;
+        cf: carry,
+        hf: false,
+        zf: a == 0
+    };
+
+    regs![a => cpu.a];
 }
 
 fn ccf(cpu: &mut Cpu, _sys_state: &mut SystemState) {
@@ -1127,16 +1171,29 @@ push_r16!(bc);
 push_r16!(de);
 push_r16!(hl);
 
+/* IME is set only after the following instruction, so an interrupt can
+ * be taken after that instruction at the earliest */
 fn ei(cpu: &mut Cpu, _sys_state: &mut SystemState) {
-    cpu.inject_int_insn(1, IIOperation::EnableInterrupts);
+    cpu.inject_int_insn(0, IIOperation::EnableInterrupts);
 }
 
-fn di(cpu: &mut Cpu, _sys_state: &mut SystemState) {
-    cpu.inject_int_insn(1, IIOperation::DisableInterrupts);
+/* Takes effect immediately, and cancels a directly preceding EI */
+fn di(cpu: &mut Cpu, sys_state: &mut SystemState) {
+    cpu.cancel_int_insns();
+    sys_state.ints_enabled = false;
 }
 
-fn halt(cpu: &mut Cpu, _sys_state: &mut SystemState) {
-    cpu.halted = true;
+fn halt(cpu: &mut Cpu, sys_state: &mut SystemState) {
+    let pending = sys_state.io_get_reg(IOReg::IF) &
+                  sys_state.io_get_reg(IOReg::IE) & 0x1f;
+
+    if !sys_state.ints_enabled && pending != 0 {
+        /* HALT bug: The CPU does not halt, but fails to increment PC
+         * when fetching the next opcode */
+        cpu.halt_bug = true;
+    } else {
+        cpu.halted = true;
+    }
 }
 
 
@@ -1352,7 +1409,7 @@ const INSN_HANDLERS: [fn(&mut Cpu, &mut SystemState); 256] = [
     retnc,              /* 0xd0 */
     pop_de,
     jpnc_n16,
-    not_implemented,
+    illegal_opcode,
     callnc_n16,
     push_de,
     sub_a_n8,
@@ -1360,32 +1417,32 @@ const INSN_HANDLERS: [fn(&mut Cpu, &mut SystemState); 256] = [
     retc,               /* 0xd8 */
     reti,
     jpc_n16,
-    not_implemented,
+    illegal_opcode,
     callc_n16,
-    not_implemented,
+    illegal_opcode,
     sbc_a_n8,
     rst_0x18,
     ld__ffn8_a,         /* 0xe0 */
     pop_hl,
     ld__ffc_a,
-    not_implemented,
-    not_implemented,
+    illegal_opcode,
+    illegal_opcode,
     push_hl,
     and_a_n8,
     rst_0x20,
     add_sp_n8,          /* 0xe8 */
     jp__hl,
     ld__n16_a,
-    not_implemented,
-    not_implemented,
-    not_implemented,
+    illegal_opcode,
+    illegal_opcode,
+    illegal_opcode,
     xor_a_n8,
     rst_0x28,
     ld_a__ffn8,         /* 0xf0 */
     pop_af,
     ld_a__ffc,
     di,
-    not_implemented,
+    illegal_opcode,
     push_af,
     or_a_n8,
     rst_0x30,
@@ -1393,8 +1450,8 @@ const INSN_HANDLERS: [fn(&mut Cpu, &mut SystemState); 256] = [
     ld_sp_hl,
     ld_a__n16,
     ei,
-    not_implemented,
-    not_implemented,
+    illegal_opcode,
+    illegal_opcode,
     cp_a_n8,
     rst_0x38
 ];
@@ -1488,3 +1545,64 @@ const INSN_CYCLES: [u8; 256] = [
     3, 3, 2, 0, 0, 4, 2, 4, 4, 1, 4, 0, 0, 0, 2, 4, /* e */
     3, 3, 2, 1, 0, 4, 2, 4, 3, 2, 4, 1, 0, 0, 2, 4, /* f */
 ];
+
+
+#[cfg(test)]
+mod tests {
+    use super::daa;
+    use crate::cpu::Reg16;
+    use crate::system_state::System;
+    use crate::testing::{test_rom, test_system};
+
+    /* Result and flags, decided via a correction value as in Pan Docs */
+    fn reference_daa(a: u8, n: bool, h: bool, c: bool) -> (u8, u8) {
+        let mut correction = 0u8;
+        let mut carry = false;
+
+        if h || (!n && a & 0xf > 0x9) {
+            correction |= 0x06;
+        }
+        if c || (!n && a > 0x99) {
+            correction |= 0x60;
+            carry = true;
+        }
+
+        let res =
+            if n {
+                a.wrapping_sub(correction)
+            } else {
+                a.wrapping_add(correction)
+            };
+
+        let flags =
+            if res == 0 { 0x80 } else { 0x00 } |
+            if n { 0x40 } else { 0x00 } |
+            if carry { 0x10 } else { 0x00 };
+
+        (res, flags)
+    }
+
+    #[test]
+    fn daa_all_values() {
+        let mut test_sys = test_system(test_rom(&[0x18, 0xfe]));
+        let sys: &mut System = &mut test_sys;
+
+        for a in 0..=0xffu8 {
+            /* N, H, C */
+            for flags in 0..8u8 {
+                let n = flags & 4 != 0;
+                let h = flags & 2 != 0;
+                let c = flags & 1 != 0;
+                let f = (flags << 4) | if a == 0 { 0x80 } else { 0x00 };
+                sys.cpu.set_reg16(Reg16::AF, ((a as u16) << 8) | f as u16);
+
+                daa(&mut sys.cpu, &mut sys.sys_state);
+
+                let (res, res_f) = reference_daa(a, n, h, c);
+                let expected = ((res as u16) << 8) | res_f as u16;
+                assert_eq!(sys.cpu.reg16(Reg16::AF), expected,
+                           "a={:02x} n={} h={} c={}", a, n, h, c);
+            }
+        }
+    }
+}
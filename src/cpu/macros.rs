@@ -15,6 +15,18 @@ macro_rules! mem {
             let gb_addr: u16 = $a;
             let value: u8 = $v;
 
+            #[cfg(feature = "debugger")]
+            if system_state.debugger.check_mem(gb_addr, value, true) {
+                let old =
+                    if gb_addr >= 0xff00 {
+                        $crate::io::IOSpace::io_get_addr(&*system_state,
+                                                         gb_addr - 0xff00)
+                    } else {
+                        mem!(system_state; gb_addr)
+                    };
+                system_state.debugger.set_watch_old_value(old);
+            }
+
             unsafe {
                 let mem_addr = AS_BASE + (gb_addr as usize);
 
@@ -39,6 +51,8 @@ macro_rules! mem {
                 } else if gb_addr >= 0xfea0 {
                     if gb_addr >= 0xff00 {
                         /* I/O */
+                        system_state.debugger.check_io(gb_addr - 0xff00, value,
+                                                       true);
                         io_write(system_state, gb_addr - 0xff00, value);
                     } else {
                         /* Illegal to access, mirror WRAM */
@@ -64,7 +78,7 @@ macro_rules! mem {
             let system_state: &mut $crate::system_state::SystemState = $ss;
             let gb_addr: u16 = $a;
 
-            unsafe {
+            let value: u8 = unsafe {
                 let mem_addr = AS_BASE + (gb_addr as usize);
 
                 if gb_addr < 0xe000 {
@@ -76,7 +90,10 @@ macro_rules! mem {
                 } else if gb_addr >= 0xfea0 {
                     if gb_addr >= 0xff00 {
                         /* I/O */
-                        io_read(system_state, gb_addr - 0xff00)
+                        let val = io_read(system_state, gb_addr - 0xff00);
+                        system_state.debugger.check_io(gb_addr - 0xff00, val,
+                                                       false);
+                        val
                     } else {
                         /* Illegal to access, mirror WRAM */
                         *((mem_addr - 0x2000) as *const u8)
@@ -88,7 +105,12 @@ macro_rules! mem {
                     /* Illegal to access, mirror WRAM */
                     *((mem_addr - 0x2000) as *const u8)
                 }
-            }
+            };
+
+            #[cfg(feature = "debugger")]
+            system_state.debugger.check_mem(gb_addr, value, false);
+
+            value
         }
     };
 
@@ -114,6 +136,18 @@ macro_rules! mem {
             let gb_addr: u16 = $a;
             let value: u8 = $v;
 
+            #[cfg(feature = "debugger")]
+            if system_state.debugger.check_mem(gb_addr, value, true) {
+                let old =
+                    if gb_addr >= 0xff00 {
+                        $crate::io::IOSpace::io_get_addr(&*system_state,
+                                                         gb_addr - 0xff00)
+                    } else {
+                        mem!(system_state; gb_addr)
+                    };
+                system_state.debugger.set_watch_old_value(old);
+            }
+
             unsafe {
                 if gb_addr < 0x8000 {
                     /* ROM */
@@ -135,6 +169,8 @@ macro_rules! mem {
                 } else if gb_addr >= 0xfea0 {
                     if gb_addr >= 0xff00 {
                         /* I/O */
+                        system_state.debugger.check_io(gb_addr - 0xff00, value,
+                                                       true);
                         io_write(system_state, gb_addr - 0xff00, value);
                     } else {
                         /* Illegal to access, mirror WRAM */
@@ -160,7 +196,7 @@ macro_rules! mem {
             let system_state: &mut $crate::system_state::SystemState = $ss;
             let gb_addr: u16 = $a;
 
-            unsafe {
+            let value: u8 = unsafe {
                 if gb_addr < 0x8000 {
                     /* ROM */
                     system_state.addr_space.rom_read(gb_addr)
@@ -181,7 +217,10 @@ macro_rules! mem {
                 } else if gb_addr >= 0xfea0 {
                     if gb_addr >= 0xff00 {
                         /* I/O */
-                        io_read(system_state, gb_addr - 0xff00)
+                        let val = io_read(system_state, gb_addr - 0xff00);
+                        system_state.debugger.check_io(gb_addr - 0xff00, val,
+                                                       false);
+                        val
                     } else {
                         /* Illegal to access, mirror WRAM */
                         system_state.addr_space.wram_read(gb_addr - 0x2000)
@@ -193,7 +232,12 @@ macro_rules! mem {
                     /* Illegal to access, mirror WRAM */
                     system_state.addr_space.wram_read(gb_addr - 0x2000)
                 }
-            }
+            };
+
+            #[cfg(feature = "debugger")]
+            system_state.debugger.check_mem(gb_addr, value, false);
+
+            value
         }
     };
 
@@ -0,0 +1,551 @@
+#![allow(unused_unsafe)]
+
+use std::fmt;
+
+use crate::cpu::ioreg_name;
+
+#[cfg(feature = "debugger")]
+use crate::mem;
+#[cfg(all(feature = "debugger", target_os = "linux"))]
+use crate::address_space::AS_BASE;
+#[cfg(feature = "debugger")]
+use crate::io::io_read;
+#[cfg(feature = "debugger")]
+use crate::system_state::System;
+#[cfg(all(feature = "debugger", not(target_arch = "wasm32")))]
+use crate::ui::{sigint_aborts_reads, take_interrupt};
+
+
+const IO_BREAK_READ: u8 = 0x01;
+const IO_BREAK_WRITE: u8 = 0x02;
+
+pub struct IOBreakpointHit {
+    pub reg: u8,
+    pub value: u8,
+    pub write: bool,
+}
+
+#[cfg(feature = "debugger")]
+#[derive(Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+#[cfg(feature = "debugger")]
+pub struct WatchpointHit {
+    pub addr: u16,
+    pub value: u8,
+    /* For writes, what was there before */
+    pub old_value: Option<u8>,
+}
+
+/* Why emulation stopped instead of executing the next instruction */
+pub enum BreakReason {
+    #[cfg(feature = "debugger")]
+    Breakpoint(u16),
+    #[cfg(feature = "debugger")]
+    Watchpoint { pc: u16, hit: WatchpointHit },
+    IORegister { pc: u16, hit: IOBreakpointHit },
+    Message(String),
+}
+
+pub struct Debugger {
+    /* Per I/O register (0xff00 + index), which accesses to break on;
+     * a flat table so the check in mem! stays cheap */
+    io_breakpoints: [u8; 256],
+    io_breakpoints_set: bool,
+
+    io_hit: Option<IOBreakpointHit>,
+    break_msg: Option<String>,
+
+    /* Kept sorted, so we can binary-search it for every instruction */
+    #[cfg(feature = "debugger")]
+    breakpoints: Vec<u16>,
+    /* Address ranges (inclusive) */
+    #[cfg(feature = "debugger")]
+    watchpoints: Vec<(u16, u16, WatchKind)>,
+    #[cfg(feature = "debugger")]
+    watch_hit: Option<WatchpointHit>,
+
+    /* Do not break on the breakpoint at this address once, so we can
+     * continue from it */
+    #[cfg(feature = "debugger")]
+    resume_pc: Option<u16>,
+}
+
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self {
+            io_breakpoints: [0u8; 256],
+            io_breakpoints_set: false,
+
+            io_hit: None,
+            break_msg: None,
+
+            #[cfg(feature = "debugger")]
+            breakpoints: Vec::new(),
+            #[cfg(feature = "debugger")]
+            watchpoints: Vec::new(),
+            #[cfg(feature = "debugger")]
+            watch_hit: None,
+            #[cfg(feature = "debugger")]
+            resume_pc: None,
+        }
+    }
+
+    pub fn add_io_breakpoint(&mut self, reg: u8, on_read: bool,
+                             on_write: bool)
+    {
+        let mut flags = 0;
+        if on_read {
+            flags |= IO_BREAK_READ;
+        }
+        if on_write {
+            flags |= IO_BREAK_WRITE;
+        }
+
+        self.io_breakpoints[reg as usize] |= flags;
+        self.io_breakpoints_set |= flags != 0;
+    }
+
+    #[inline(always)]
+    pub fn check_io(&mut self, addr: u16, value: u8, write: bool) {
+        if !self.io_breakpoints_set {
+            return;
+        }
+
+        let mask = if write { IO_BREAK_WRITE } else { IO_BREAK_READ };
+        if self.io_breakpoints[addr as usize & 0xff] & mask != 0 &&
+           self.io_hit.is_none()
+        {
+            self.io_hit = Some(IOBreakpointHit {
+                reg: addr as u8,
+                value,
+                write,
+            });
+        }
+    }
+
+    pub fn take_io_hit(&mut self) -> Option<IOBreakpointHit> {
+        self.io_hit.take()
+    }
+
+    /* Have emulation pause after the current instruction */
+    pub fn request_break(&mut self, msg: String) {
+        if self.break_msg.is_none() {
+            self.break_msg = Some(msg);
+        }
+    }
+
+    pub fn take_break(&mut self) -> Option<String> {
+        self.break_msg.take()
+    }
+
+    /* Returns the reason to break after an instruction, if any */
+    pub fn take_break_reason(&mut self, pc: u16) -> Option<BreakReason> {
+        #[cfg(feature = "debugger")]
+        if let Some(hit) = self.watch_hit.take() {
+            return Some(BreakReason::Watchpoint { pc, hit });
+        }
+
+        if let Some(hit) = self.take_io_hit() {
+            return Some(BreakReason::IORegister { pc, hit });
+        }
+
+        self.take_break().map(BreakReason::Message)
+    }
+
+    /* Drop hits caused by inspecting memory outside of the CPU (e.g. the
+     * debugger or a disassembly) */
+    pub fn discard_hits(&mut self) {
+        #[cfg(feature = "debugger")]
+        {
+            self.watch_hit = None;
+        }
+        self.io_hit = None;
+    }
+}
+
+
+#[cfg(feature = "debugger")]
+impl Debugger {
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        if let Err(i) = self.breakpoints.binary_search(&addr) {
+            self.breakpoints.insert(i, addr);
+        }
+    }
+
+    /* Returns whether there was a breakpoint at @addr */
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        match self.breakpoints.binary_search(&addr) {
+            Ok(i) => {
+                self.breakpoints.remove(i);
+                true
+            },
+
+            Err(_) => false,
+        }
+    }
+
+    pub fn add_watchpoint(&mut self, start: u16, end: u16, kind: WatchKind) {
+        self.watchpoints.push((start, end, kind));
+    }
+
+    /* Whether to break before executing the instruction at @pc */
+    #[inline(always)]
+    pub fn check_exec(&mut self, pc: u16) -> bool {
+        if self.breakpoints.is_empty() {
+            return false;
+        }
+
+        if self.resume_pc.take() == Some(pc) {
+            false
+        } else {
+            self.breakpoints.binary_search(&pc).is_ok()
+        }
+    }
+
+    /* Returns whether a write has hit, in which case the caller should
+     * report the old value through set_watch_old_value() */
+    #[inline(always)]
+    pub fn check_mem(&mut self, addr: u16, value: u8, write: bool) -> bool {
+        if self.watchpoints.is_empty() || self.watch_hit.is_some() {
+            return false;
+        }
+
+        let hit = self.watchpoints.iter().any(|(start, end, kind)| {
+            (*start..=*end).contains(&addr) && match kind {
+                WatchKind::Read => !write,
+                WatchKind::Write => write,
+                WatchKind::ReadWrite => true,
+            }
+        });
+
+        if hit {
+            self.watch_hit = Some(WatchpointHit {
+                addr,
+                value,
+                old_value: None,
+            });
+        }
+
+        hit && write
+    }
+
+    pub fn set_watch_old_value(&mut self, old_value: u8) {
+        if let Some(hit) = self.watch_hit.as_mut() {
+            hit.old_value = Some(old_value);
+        }
+    }
+
+    pub fn resume_at(&mut self, pc: u16) {
+        self.resume_pc = Some(pc);
+    }
+}
+
+
+impl fmt::Display for BreakReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "debugger")]
+            BreakReason::Breakpoint(pc) =>
+                write!(f, "Break: Breakpoint at {:04x}", pc),
+
+            #[cfg(feature = "debugger")]
+            BreakReason::Watchpoint { pc, hit } =>
+                match hit.old_value {
+                    Some(old) =>
+                        write!(f, "Break: {:04x}: Watchpoint: {:04x} <- {:02x} \
+                                   (was {:02x})",
+                               pc, hit.addr, hit.value, old),
+                    None =>
+                        write!(f, "Break: {:04x}: Watchpoint: {:04x} -> {:02x}",
+                               pc, hit.addr, hit.value),
+                },
+
+            BreakReason::IORegister { pc, hit } =>
+                if hit.write {
+                    write!(f, "Break: {:04x}: {} <- {:02x}",
+                           pc, ioreg_name(hit.reg), hit.value)
+                } else {
+                    write!(f, "Break: {:04x}: {} -> {:02x}",
+                           pc, ioreg_name(hit.reg), hit.value)
+                },
+
+            BreakReason::Message(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+
+/*
+ * A minimal command line on stdin, entered whenever emulation breaks:
+ *   c          continue
+ *   s          execute a single instruction
+ *   b ADDR     set a breakpoint at ADDR (hex)
+ *   d ADDR     delete the breakpoint at ADDR
+ *   mem ADDR   dump 16 bytes of memory starting at ADDR (hex)
+ *   regs       show the CPU registers
+ */
+#[cfg(feature = "debugger")]
+impl System {
+    pub fn debugger_repl(&mut self) {
+        let stdin = std::io::stdin();
+
+        #[cfg(not(target_arch = "wasm32"))]
+        sigint_aborts_reads(true);
+
+        loop {
+            /* Ctrl+C only aborts the current line here, so it must not
+             * quit once emulation continues */
+            #[cfg(not(target_arch = "wasm32"))]
+            take_interrupt();
+
+            eprint!("(xgbcrew) ");
+
+            let mut line = String::new();
+            match read_line(&mut stdin.lock(), &mut line) {
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {
+                    eprintln!();
+                    continue;
+                },
+
+                /* EOF: Just continue running */
+                Ok(0) | Err(_) => break,
+                Ok(_) => (),
+            }
+
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("c"), None) => break,
+
+                (Some("s"), None) => self.debugger_single_step(),
+
+                (Some("b"), Some(addr)) =>
+                    match parse_addr(addr) {
+                        Some(addr) => self.add_breakpoint(addr),
+                        None => eprintln!("Invalid address {}", addr),
+                    },
+
+                (Some("d"), Some(addr)) =>
+                    match parse_addr(addr) {
+                        Some(addr) =>
+                            if !self.remove_breakpoint(addr) {
+                                eprintln!("No breakpoint at {:04x}", addr);
+                            },
+                        None => eprintln!("Invalid address {}", addr),
+                    },
+
+                (Some("mem"), Some(addr)) =>
+                    match parse_addr(addr) {
+                        Some(addr) => self.debugger_dump_mem(addr),
+                        None => eprintln!("Invalid address {}", addr),
+                    },
+
+                (Some("regs"), None) => self.debugger_dump_regs(),
+
+                (None, _) => (),
+
+                _ => eprintln!("Commands: c, s, b ADDR, d ADDR, mem ADDR, \
+                                regs"),
+            }
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            sigint_aborts_reads(false);
+            take_interrupt();
+        }
+
+        self.sys_state.debugger.resume_at(self.cpu.pc());
+    }
+
+    fn debugger_single_step(&mut self) {
+        if let Some(line) = self.cpu.trace_line(&mut self.sys_state) {
+            eprintln!("{}", line);
+        }
+        self.sys_state.debugger.discard_hits();
+
+        self.sys_state.debugger.resume_at(self.cpu.pc());
+        if let crate::system_state::StepResult::Break(reason) = self.step() {
+            eprintln!("{}", reason);
+        }
+    }
+
+    fn debugger_dump_mem(&mut self, addr: u16) {
+        let bytes: Vec<String> =
+            (0..16u16).map(|i| {
+                let val = mem![&mut self.sys_state; addr.wrapping_add(i)];
+                format!("{:02x}", val)
+            }).collect();
+
+        eprintln!("{:04x}: {}", addr, bytes.join(" "));
+        self.sys_state.debugger.discard_hits();
+    }
+
+    fn debugger_dump_regs(&self) {
+        let regs = self.cpu.snapshot();
+
+        eprintln!("af={:04x} bc={:04x} de={:04x} hl={:04x} sp={:04x} \
+                   pc={:04x} ime={}",
+                  regs.af, regs.bc, regs.de, regs.hl, regs.sp, regs.pc,
+                  self.sys_state.ints_enabled as u8);
+    }
+}
+
+
+/* Hexadecimal, with or without 0x or $ prefix */
+pub fn parse_addr(addr: &str) -> Option<u16> {
+    let addr = addr.trim_start_matches("0x").trim_start_matches('$');
+    u16::from_str_radix(addr, 16).ok()
+}
+
+/* Like BufRead::read_line(), but a read failing with EINTR (i.e. SIGINT,
+ * see sigint_aborts_reads()) is not retried; instead, it aborts the whole
+ * line */
+#[cfg(feature = "debugger")]
+fn read_line<R: std::io::BufRead>(input: &mut R, line: &mut String)
+    -> std::io::Result<usize>
+{
+    let mut bytes = Vec::new();
+
+    loop {
+        let available = input.fill_buf()?;
+        let (used, done) =
+            match available.iter().position(|&b| b == b'\n') {
+                Some(i) => (i + 1, true),
+                /* Empty means EOF */
+                None => (available.len(), available.is_empty()),
+            };
+
+        bytes.extend_from_slice(&available[..used]);
+        input.consume(used);
+        if done {
+            break;
+        }
+    }
+
+    line.push_str(&String::from_utf8_lossy(&bytes));
+    Ok(bytes.len())
+}
+
+/* Resolves register names as used in the disassembly (e.g. "LCDC") */
+pub fn ioreg_by_name(name: &str) -> Option<u8> {
+    (0x00..=0xff).find(|reg| ioreg_name(*reg).eq_ignore_ascii_case(name))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::BreakReason;
+    use crate::io::io_write;
+    use crate::system_state::StepResult;
+    use crate::testing::{test_rom, test_system};
+
+    #[test]
+    fn io_breakpoint_on_lcdc_write() {
+        /* ld a, 0x91; ldh (LCDC), a; jr -2 */
+        let mut sys = test_system(test_rom(&[0x3e, 0x91, 0xe0, 0x40,
+                                             0x18, 0xfe]));
+        sys.add_io_breakpoint(0x40, false, true);
+
+        /* Internal writes (e.g. on reset) do not count */
+        io_write(&mut sys.sys_state, 0x40, 0x00);
+        assert!(sys.sys_state.debugger.take_io_hit().is_none());
+
+        /* nop; jp; ld; ldh */
+        for _ in 0..3 {
+            assert!(matches!(sys.step(), StepResult::Executed(_)));
+        }
+
+        match sys.step() {
+            StepResult::Break(BreakReason::IORegister { pc, hit }) => {
+                assert_eq!(pc, 0x0152);
+                assert_eq!(hit.reg, 0x40);
+                assert_eq!(hit.value, 0x91);
+                assert!(hit.write);
+            },
+            _ => panic!("No I/O breakpoint hit"),
+        }
+
+        /* Reads are not watched */
+        sys.peek(0xff40);
+        assert!(sys.sys_state.debugger.take_io_hit().is_none());
+    }
+
+    #[test]
+    fn disassembly_does_not_break() {
+        /* jr -2 */
+        let mut sys = test_system(test_rom(&[0x18, 0xfe]));
+        sys.add_io_breakpoint(0x40, true, false);
+
+        let insns = sys.disassemble_range(0xff40, 1);
+        assert_eq!(insns[0].0, 0xff40);
+
+        /* nop; jp; jr */
+        for _ in 0..3 {
+            assert!(matches!(sys.step(), StepResult::Executed(_)));
+        }
+    }
+
+    #[test]
+    fn memory_dump_does_not_break() {
+        /* jr -2 */
+        let mut sys = test_system(test_rom(&[0x18, 0xfe]));
+        sys.add_io_breakpoint(0x40, true, false);
+
+        let mut dump = Vec::new();
+        sys.dump_region(0x0000, 0xffff, &mut dump).unwrap();
+        assert_eq!(dump.len(), 0x10000);
+        assert_eq!(dump[0xff40], 0x91);
+
+        /* nop; jp; jr */
+        for _ in 0..3 {
+            assert!(matches!(sys.step(), StepResult::Executed(_)));
+        }
+    }
+
+    /* Hands out its chunks one by one; None stands for EINTR */
+    #[cfg(feature = "debugger")]
+    struct ChunkedInput {
+        chunks: Vec<Option<&'static [u8]>>,
+    }
+
+    #[cfg(feature = "debugger")]
+    impl std::io::Read for ChunkedInput {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.chunks.is_empty() {
+                return Ok(0);
+            }
+            match self.chunks.remove(0) {
+                Some(chunk) => {
+                    buf[..chunk.len()].copy_from_slice(chunk);
+                    Ok(chunk.len())
+                },
+                None => Err(std::io::ErrorKind::Interrupted.into()),
+            }
+        }
+    }
+
+    #[cfg(feature = "debugger")]
+    #[test]
+    fn interrupt_aborts_line() {
+        let input = ChunkedInput {
+            chunks: vec![Some(&b"mem c0"[..]), None, Some(&b"regs\nc\n"[..])],
+        };
+        let mut input = std::io::BufReader::new(input);
+        let mut read = || {
+            let mut line = String::new();
+            super::read_line(&mut input, &mut line).map(|_| line)
+        };
+
+        let err = read().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Interrupted);
+        assert_eq!(read().unwrap(), "regs\n");
+        assert_eq!(read().unwrap(), "c\n");
+        assert_eq!(read().unwrap(), "");
+    }
+}
@@ -0,0 +1,124 @@
+use std::sync::mpsc::{sync_channel, SyncSender};
+
+use crate::screenshot;
+
+
+/* Frames that may be queued for the writer thread before we block */
+const QUEUE_LEN: usize = 16;
+
+/* Writes presented frames as numbered PNG images into a directory */
+pub struct FrameDump {
+    queue: Option<SyncSender<(u64, Box<[u32; 160 * 144]>)>>,
+    writer: Option<std::thread::JoinHandle<()>>,
+
+    /* Only every n-th frame is written */
+    every: u64,
+    frame_i: u64,
+}
+
+
+impl FrameDump {
+    pub fn new(dir: String, every: u64) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+
+        /* Encoding and writing is done in a separate thread so as not to
+         * disturb emulation timing */
+        let (queue, frames) = sync_channel(QUEUE_LEN);
+        let writer = std::thread::spawn(move || {
+            for (index, pixels) in frames {
+                let path = format!("{}/frame_{:06}.png", dir, index);
+                let rgb8 = screenshot::lcd_rgb8(&pixels);
+                if let Err(e) = Self::write_png(&path, &rgb8) {
+                    eprintln!("Failed to write frame to {}: {}", path, e);
+                }
+            }
+        });
+
+        Ok(Self {
+            queue: Some(queue),
+            writer: Some(writer),
+
+            every: std::cmp::max(every, 1),
+            frame_i: 0,
+        })
+    }
+
+    pub fn frame(&mut self, pixels: &[u32; 160 * 144]) {
+        if self.frame_i % self.every == 0 {
+            if let Some(queue) = self.queue.as_ref() {
+                let index = self.frame_i / self.every;
+                queue.send((index, Box::new(*pixels))).unwrap();
+            }
+        }
+
+        self.frame_i += 1;
+    }
+
+    /* Waits until all queued frames have been written */
+    pub fn finish(&mut self) {
+        self.queue.take();
+        if let Some(writer) = self.writer.take() {
+            writer.join().unwrap();
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn write_png(path: &str, rgb8: &[u8]) -> Result<(), String> {
+        screenshot::write_png(path, 160, 144, rgb8)
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn write_png(_path: &str, _rgb8: &[u8]) -> Result<(), String> {
+        Err(String::from("Not supported on this platform"))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::FrameDump;
+    use crate::screenshot;
+
+    fn dump_frames(name: &str, count: u32, every: u64) -> Vec<String> {
+        let dir = std::env::temp_dir()
+                      .join(format!("xgbcrew-{}-{}", name,
+                                    std::process::id()));
+        let dir = dir.to_string_lossy().into_owned();
+
+        let mut fd = FrameDump::new(dir.clone(), every).unwrap();
+        for i in 0..count {
+            fd.frame(&[i * 0x010101; 160 * 144]);
+        }
+        fd.finish();
+
+        let mut files: Vec<String> =
+            std::fs::read_dir(&dir).unwrap()
+                .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+        files.sort();
+
+        for file in &files {
+            let path = format!("{}/{}", dir, file);
+            let (w, h, _) = screenshot::read_png(&path).unwrap();
+            assert_eq!((w, h), (160, 144));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        files
+    }
+
+    #[test]
+    fn one_file_per_frame() {
+        let expected: Vec<String> =
+            (0..5).map(|i| format!("frame_{:06}.png", i)).collect();
+        assert_eq!(dump_frames("frames", 5, 1), expected);
+    }
+
+    #[test]
+    fn every_nth_frame() {
+        /* Frames 0, 3, 6 */
+        let expected: Vec<String> =
+            (0..3).map(|i| format!("frame_{:06}.png", i)).collect();
+        assert_eq!(dump_frames("every-3rd", 7, 3), expected);
+    }
+}
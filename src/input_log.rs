@@ -0,0 +1,101 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::state_dump::crc32;
+
+
+/*
+ * What movies and bug report bundles have in common: The keypad state on
+ * every frame (i.e. VBlank, where input is polled, or every wait for
+ * input while the CPU is stopped), and what is needed to tell whether a
+ * replay can be exact.
+ */
+#[derive(Serialize, Deserialize)]
+pub struct InputLog {
+    emulator_version: String,
+    rom_crc32: u32,
+
+    /* Keypad lines (see KeypadState::lines()) per frame */
+    frames: Vec<u8>,
+}
+
+pub struct InputPlayback {
+    frames: Vec<u8>,
+    next_frame: usize,
+}
+
+
+/* E.g. of the ROM or the cartridge RAM file */
+pub fn file_crc32(mut file: &std::fs::File) -> u32 {
+    let mut data = Vec::new();
+
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_to_end(&mut data).unwrap();
+
+    crc32(&data)
+}
+
+pub fn load<T: DeserializeOwned>(path: &str) -> Result<T, String> {
+    let file = std::fs::File::open(path).map_err(|e| e.to_string())?;
+    bincode::deserialize_from(file).map_err(|e| e.to_string())
+}
+
+pub fn save<T: Serialize>(path: &str, data: &T) -> Result<(), String> {
+    let file = std::fs::File::create(path).map_err(|e| e.to_string())?;
+    bincode::serialize_into(file, data).map_err(|e| e.to_string())
+}
+
+
+impl InputLog {
+    pub fn new(rom_crc32: u32) -> Self {
+        Self {
+            emulator_version: String::from(env!("CARGO_PKG_VERSION")),
+            rom_crc32,
+
+            frames: Vec::new(),
+        }
+    }
+
+    /* To be called once per frame, after input has been polled */
+    pub fn frame(&mut self, lines: u8) {
+        self.frames.push(lines);
+    }
+
+    /* Returns warnings about what may prevent an exact replay; @what is
+     * what has been recorded (e.g. "Movie") */
+    pub fn check(&self, what: &str, rom_crc32: u32) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        if self.rom_crc32 != rom_crc32 {
+            warnings.push(format!("{} was recorded with a different ROM \
+                                   (CRC32 {:08x}, this is {:08x})",
+                                  what, self.rom_crc32, rom_crc32));
+        }
+        if self.emulator_version != env!("CARGO_PKG_VERSION") {
+            warnings.push(format!("{} was recorded with xgbcrew {}",
+                                  what, self.emulator_version));
+        }
+
+        warnings
+    }
+
+    pub fn into_playback(self) -> InputPlayback {
+        InputPlayback {
+            frames: self.frames,
+            next_frame: 0,
+        }
+    }
+}
+
+
+impl InputPlayback {
+    /* Returns the keypad lines for the current frame (None once the log
+     * is over), and advances to the next one */
+    pub fn next_frame(&mut self) -> Option<u8> {
+        let lines = self.frames.get(self.next_frame).copied();
+        self.next_frame += 1;
+        lines
+    }
+}
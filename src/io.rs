@@ -9,7 +9,7 @@ pub mod timer;
 #[cfg(target_os = "linux")]
 use crate::address_space::AS_BASE;
 use crate::address_space::AddressSpace;
-use crate::system_state::{IOReg, SystemState};
+use crate::system_state::{cpu_cycles, IOReg, Model, SystemState};
 
 
 pub trait IOSpace {
@@ -87,8 +87,16 @@ impl IOSpace for SystemState {
 }
 
 
+/*
+ * io_read() and io_write() are used for internal accesses, too; I/O
+ * breakpoints are only checked for accesses through mem!
+ */
 pub fn io_read(sys_state: &mut SystemState, addr: u16) -> u8 {
-    sys_state.io_get_addr(addr)
+    if (0x30..0x40).contains(&addr) {
+        sound::wave_ram_read(sys_state, addr)
+    } else {
+        sys_state.io_get_addr(addr)
+    }
 }
 
 pub fn io_write(sys_state: &mut SystemState, addr: u16, val: u8) {
@@ -105,10 +113,6 @@ fn iow_plain(sys_state: &mut SystemState, addr: u16, val: u8) {
     sys_state.io_set_addr(addr, val);
 }
 
-fn iow_clear(sys_state: &mut SystemState, addr: u16, _: u8) {
-    sys_state.io_set_addr(addr, 0u8);
-}
-
 fn vbk_write(sys_state: &mut SystemState, _: u16, val: u8) {
     if !sys_state.cgb {
         return;
@@ -146,9 +150,17 @@ fn dma_write(sys_state: &mut SystemState, _: u16, val: u8) {
         return;
     }
 
+    /* The DMA sees echo RAM wherever the CPU would see OAM and I/O */
+    let src_page =
+        if val >= 0xe0 {
+            val - 0x20
+        } else {
+            val
+        };
+
     #[cfg(not(target_arch = "wasm32"))]
     {
-        let src = sys_state.addr_space.raw_ptr((val as u16) << 8);
+        let src = sys_state.addr_space.raw_ptr((src_page as u16) << 8);
         let dst = sys_state.addr_space.raw_mut_ptr(0xfe00);
 
         unsafe {
@@ -160,7 +172,7 @@ fn dma_write(sys_state: &mut SystemState, _: u16, val: u8) {
 
     #[cfg(target_arch = "wasm32")]
     {
-        let src = (val as u16) << 8;
+        let src = (src_page as u16) << 8;
         let dst = 0xfe00;
 
         for i in 0..160 {
@@ -182,8 +194,9 @@ pub fn hdma_copy_16b(sys_state: &mut SystemState) -> bool {
     let it_src = src;
     let it_dst = dst;
 
-    src += 16;
-    dst += 16;
+    /* The destination always stays within VRAM */
+    src = src.wrapping_add(16);
+    dst = 0x8000 | (dst.wrapping_add(16) & 0x1ff0);
 
     sys_state.io_set_reg(IOReg::HDMA1, (src >> 8) as u8);
     sys_state.io_set_reg(IOReg::HDMA2, src as u8);
@@ -213,7 +226,8 @@ pub fn hdma_copy_16b(sys_state: &mut SystemState) -> bool {
     let (rem, done) = sys_state.io_get_reg(IOReg::HDMA5).overflowing_sub(1u8);
     sys_state.io_set_reg(IOReg::HDMA5, rem);
 
-    sys_state.add_cycles(if sys_state.double_speed { 16 } else { 8 });
+    sys_state.add_cycles(cpu_cycles(HDMA_BLOCK_DCYCLES,
+                                    sys_state.double_speed));
 
     done
 }
@@ -242,12 +256,16 @@ fn hdma_write(sys_state: &mut SystemState, addr: u16, mut val: u8) {
         },
 
         0x55 => {
-            if sys_state.io_get_reg(IOReg::HDMA5) & 0x80 == 0 {
+            let hdma5 = sys_state.io_get_reg(IOReg::HDMA5);
+            if hdma5 & 0x80 == 0 {
                 /* HDMA active */
                 if val & 0x80 == 0 {
-                    val = 0xff;
+                    /* Abort; the remaining length stays readable */
+                    val = 0x80 | (hdma5 & 0x7f);
                 } else {
-                    val = 0x7f;
+                    /* Continue with the new length (from the current
+                     * source and destination pointers) */
+                    val &= 0x7f;
                 }
             } else {
                 sys_state.io_set_reg(IOReg::HDMA5, val & 0x7f);
@@ -266,6 +284,95 @@ fn hdma_write(sys_state: &mut SystemState, addr: u16, mut val: u8) {
     sys_state.io_set_addr(addr, val);
 }
 
+fn boot_write(sys_state: &mut SystemState, _: u16, val: u8) {
+    /* Write-once: There is no way to map the boot ROM back in */
+    if sys_state.io_get_reg(IOReg::BOOT) & 0x01 != 0 || val & 0x01 == 0 {
+        return;
+    }
+
+    sys_state.io_set_reg(IOReg::BOOT, 0xff);
+    sys_state.addr_space.set_boot_rom(None);
+}
+
+/* Copying 16 bytes takes the same time in both speed modes */
+const HDMA_BLOCK_DCYCLES: u32 = 16;
+
+/*
+ * Register values after the boot ROM has run, per model (Pan Docs):
+ * (register, DMG, SGB, CGB).  Registers with side effects on write and
+ * CGB-only registers are initialized by their respective modules.
+ */
+const POWER_ON_REGS: [(u16, u8, u8, u8); 35] = [
+    (IOReg::SB as u16,   0x00, 0x00, 0x00),
+    (IOReg::SC as u16,   0x7e, 0x7e, 0x7f),
+    /* Not deterministic on SGB and CGB */
+    (IOReg::DIV as u16,  0xab, 0x00, 0x00),
+    (IOReg::TIMA as u16, 0x00, 0x00, 0x00),
+    (IOReg::TMA as u16,  0x00, 0x00, 0x00),
+    (IOReg::TAC as u16,  0xf8, 0xf8, 0xf8),
+    (IOReg::IF as u16,   0xe1, 0xe1, 0xe1),
+    (IOReg::NR10 as u16, 0x80, 0x80, 0x80),
+    (IOReg::NR11 as u16, 0xbf, 0xbf, 0xbf),
+    (IOReg::NR12 as u16, 0xf3, 0xf3, 0xf3),
+    (IOReg::NR13 as u16, 0xff, 0xff, 0xff),
+    (IOReg::NR14 as u16, 0xbf, 0xbf, 0xbf),
+    (IOReg::NR21 as u16, 0x3f, 0x3f, 0x3f),
+    (IOReg::NR22 as u16, 0x00, 0x00, 0x00),
+    (IOReg::NR23 as u16, 0xff, 0xff, 0xff),
+    (IOReg::NR24 as u16, 0xbf, 0xbf, 0xbf),
+    (IOReg::NR30 as u16, 0x7f, 0x7f, 0x7f),
+    (IOReg::NR31 as u16, 0xff, 0xff, 0xff),
+    (IOReg::NR32 as u16, 0x9f, 0x9f, 0x9f),
+    (IOReg::NR33 as u16, 0xff, 0xff, 0xff),
+    (IOReg::NR34 as u16, 0xbf, 0xbf, 0xbf),
+    (IOReg::NR41 as u16, 0xff, 0xff, 0xff),
+    (IOReg::NR42 as u16, 0x00, 0x00, 0x00),
+    (IOReg::NR43 as u16, 0x00, 0x00, 0x00),
+    (IOReg::NR44 as u16, 0xbf, 0xbf, 0xbf),
+    (IOReg::NR50 as u16, 0x77, 0x77, 0x77),
+    (IOReg::NR51 as u16, 0xf3, 0xf3, 0xf3),
+    (IOReg::NR52 as u16, 0xf1, 0xf0, 0xf1),
+    (IOReg::SCY as u16,  0x00, 0x00, 0x00),
+    (IOReg::SCX as u16,  0x00, 0x00, 0x00),
+    (IOReg::LYC as u16,  0x00, 0x00, 0x00),
+    (IOReg::WY as u16,   0x00, 0x00, 0x00),
+    (IOReg::WX as u16,   0x00, 0x00, 0x00),
+    (IOReg::DMA as u16,  0xff, 0xff, 0x00),
+    (IOReg::IE as u16,   0x00, 0x00, 0x00),
+];
+
+/* Must be called before the other init functions, which may override
+ * some of these values */
+pub fn init_power_on(sys_state: &mut SystemState) {
+    for (addr, dmg, sgb, cgb) in POWER_ON_REGS.iter() {
+        let val =
+            match sys_state.model {
+                Model::Dmg | Model::Mgb => *dmg,
+                Model::Sgb => *sgb,
+                Model::Cgb | Model::Agb => *cgb,
+            };
+
+        sys_state.io_set_addr(*addr, val);
+    }
+}
+
+pub fn init_boot(sys_state: &mut SystemState) {
+    /* We start after the boot ROM has run, so it is already disabled */
+    sys_state.io_set_reg(IOReg::BOOT, 0xff);
+}
+
+/*
+ * Instead of starting after the boot ROM, run @boot_rom from power-on.
+ * The other registers keep their post-boot values; the boot ROM
+ * overwrites those that matter, except for the LCD, which it expects to
+ * be off.
+ */
+pub fn init_boot_rom(sys_state: &mut SystemState, boot_rom: Vec<u8>) {
+    io_write(sys_state, IOReg::LCDC as u16, 0x00);
+    sys_state.io_set_reg(IOReg::BOOT, 0xfe);
+    sys_state.addr_space.set_boot_rom(Some(boot_rom));
+}
+
 pub fn init_dma(sys_state: &mut SystemState) {
     sys_state.io_set_reg(IOReg::HDMA1, 0x00);
     sys_state.io_set_reg(IOReg::HDMA2, 0x00);
@@ -279,7 +386,7 @@ const IOW_HANDLERS: [fn(&mut SystemState, u16, u8); 256] = [
     serial::serial_write,
     serial::serial_write,
     iow_not_implemented,
-    iow_clear, /* DIV */
+    timer::timer_write,
     timer::timer_write,
     timer::timer_write,
     timer::timer_write,
@@ -355,7 +462,7 @@ const IOW_HANDLERS: [fn(&mut SystemState, u16, u8); 256] = [
     key1_write,
     iow_not_implemented,
     vbk_write,
-    iow_not_implemented,                /* 0x50 */
+    boot_write,                         /* 0x50 */
     hdma_write,
     hdma_write,
     hdma_write,
@@ -532,3 +639,82 @@ const IOW_HANDLERS: [fn(&mut SystemState, u16, u8); 256] = [
     iow_not_implemented,
     iow_plain, /* interrupt enable */
 ];
+
+
+#[cfg(test)]
+mod tests {
+    use super::{IOReg, IOSpace};
+    use crate::system_state::Model;
+    use crate::testing::{cgb_test_rom, fix_header_checksum, test_rom,
+                         test_system_as};
+
+    fn check_power_on(rom: Vec<u8>, model: Model, nr52: u8) {
+        let sys = test_system_as(rom, Some(model));
+        let ss = &sys.sys_state;
+
+        assert_eq!(ss.io_get_reg(IOReg::NR52), nr52);
+        assert_eq!(ss.io_get_reg(IOReg::TAC), 0xf8);
+
+        assert_eq!(ss.io_get_reg(IOReg::LCDC), 0x91);
+        assert_eq!(ss.io_get_reg(IOReg::BGP), 0xfc);
+        assert_eq!(ss.io_get_reg(IOReg::OBP0), 0xff);
+        assert_eq!(ss.io_get_reg(IOReg::OBP1), 0xff);
+        assert_eq!(ss.io_get_reg(IOReg::SCY), 0x00);
+        assert_eq!(ss.io_get_reg(IOReg::SCX), 0x00);
+        assert_eq!(ss.io_get_reg(IOReg::LYC), 0x00);
+        assert_eq!(ss.io_get_reg(IOReg::WY), 0x00);
+        assert_eq!(ss.io_get_reg(IOReg::WX), 0x00);
+    }
+
+    #[test]
+    fn power_on_values_dmg() {
+        check_power_on(test_rom(&[0x18, 0xfe]), Model::Dmg, 0xf1);
+    }
+
+    #[test]
+    fn power_on_values_sgb() {
+        let mut rom = test_rom(&[0x18, 0xfe]);
+        rom[0x146] = 0x03;
+        rom[0x14b] = 0x33;
+        fix_header_checksum(&mut rom);
+
+        check_power_on(rom, Model::Sgb, 0xf0);
+    }
+
+    #[test]
+    fn power_on_values_cgb() {
+        check_power_on(cgb_test_rom(&[0x18, 0xfe]), Model::Cgb, 0xf1);
+    }
+
+    #[test]
+    fn hdma_registers_after_partial_transfer() {
+        let mut sys = test_system_as(cgb_test_rom(&[0x18, 0xfe]),
+                                     Some(Model::Cgb));
+
+        /* 0xc000 -> 0x8000, four blocks, one per HBlank */
+        sys.poke(0xff51, 0xc0);
+        sys.poke(0xff52, 0x00);
+        sys.poke(0xff53, 0x00);
+        sys.poke(0xff54, 0x00);
+        sys.poke(0xff55, 0x83);
+        assert_eq!(sys.peek(0xff55), 0x03);
+
+        /* Two blocks */
+        for _ in 0..1000 {
+            if sys.peek(0xff55) == 0x01 {
+                break;
+            }
+            sys.step();
+        }
+        let regs: Vec<u8> = (0xff51..=0xff55).map(|a| sys.peek(a)).collect();
+        assert_eq!(regs, [0xc0, 0x20, 0x80, 0x20, 0x01]);
+
+        /* Aborting keeps the pointers and the remaining length */
+        sys.poke(0xff55, 0x00);
+        for _ in 0..100 {
+            sys.step();
+        }
+        let regs: Vec<u8> = (0xff51..=0xff55).map(|a| sys.peek(a)).collect();
+        assert_eq!(regs, [0xc0, 0x20, 0x80, 0x20, 0x81]);
+    }
+}
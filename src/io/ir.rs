@@ -1,9 +1,186 @@
+use std::io::{Read, Write};
+
+use crate::address_space::AddressSpace;
 use crate::io::IOSpace;
 use crate::system_state::{IOReg, SystemState};
+use crate::ui::UI;
+
+
+const IR_PORT: u16 = 0x9bc2u16; /* next to the link port */
+
+/* RP bits 6 and 7 must both be set to read the sensor */
+const RP_READ_ENABLE: u8 = 0xc0;
+
+/*
+ * IR protocol over TCP: Every message is a single byte, sent whenever
+ * the sender's LED changes: 1 when it has been switched on, 0 when off.
+ * There is no timing information, so protocols relying on exact pulse
+ * lengths may not work.
+ */
+pub enum IrConnParam {
+    Disabled,
+    LocalAuto,
+    Client(String),
+    Server(String),
+}
+
+impl IrConnParam {
+    pub fn default() -> Self {
+        IrConnParam::Disabled
+    }
+}
+
+
+pub struct IrState {
+    con: Option<std::net::TcpStream>,
+    server: Option<std::net::TcpListener>,
+
+    /* Whether the peer's LED is on (i.e. our sensor sees light) */
+    remote_led: bool,
+}
+
+
+impl IrState {
+    pub fn new(ui: &mut UI, param: &IrConnParam) -> Option<Self> {
+        let (addr, create_server, create_client) =
+            match param {
+                IrConnParam::Disabled => return None,
+
+                IrConnParam::LocalAuto =>
+                    (format!("localhost:{}", IR_PORT), true, true),
+
+                IrConnParam::Client(addr) => (addr.clone(), false, true),
+                IrConnParam::Server(addr) => (addr.clone(), true, false),
+            };
+
+        let mut con = None;
+        let mut server = None;
+
+        if create_client {
+            if let Ok(stream) = std::net::TcpStream::connect(&addr) {
+                stream.set_nodelay(true).unwrap();
+                stream.set_nonblocking(true).unwrap();
+                con = Some(stream);
+                ui.osd_message(format!("Connected to IR server {}", addr));
+            }
+        }
+        if create_server && con.is_none() {
+            if let Ok(listener) = std::net::TcpListener::bind(&addr) {
+                listener.set_nonblocking(true).unwrap();
+                server = Some(listener);
+            }
+        }
+        if con.is_none() && server.is_none() {
+            match param {
+                IrConnParam::Disabled =>
+                    unreachable!(),
+
+                IrConnParam::LocalAuto | IrConnParam::Server(_) =>
+                    ui.osd_message(String::from("Failed to set up IR server")),
+
+                IrConnParam::Client(_) =>
+                    ui.osd_message(String::from("Failed to connect to IR \
+                                                 server")),
+            }
 
+            return None;
+        }
+
+        Some(IrState {
+            con,
+            server,
+
+            remote_led: false,
+        })
+    }
+
+    pub fn vblank_check(&mut self) {
+        if self.con.is_none() {
+            if let Some(server) = self.server.as_mut() {
+                if let Ok(con) = server.accept() {
+                    con.0.set_nodelay(true).unwrap();
+                    con.0.set_nonblocking(true).unwrap();
+                    self.con = Some(con.0);
+                }
+            }
+        }
+    }
+
+    /* Only polls while the game has enabled reading the sensor */
+    pub fn check_remote(&mut self, addr_space: &mut AddressSpace) {
+        let rp = addr_space.io_get_reg(IOReg::RP);
+        if rp & RP_READ_ENABLE != RP_READ_ENABLE {
+            return;
+        }
+
+        let mut buf = [0u8; 16];
+        loop {
+            let con =
+                match self.con.as_mut() {
+                    Some(con) => con,
+                    None => break,
+                };
+
+            match con.read(&mut buf) {
+                Ok(0) => {
+                    self.conn_down();
+                    break;
+                },
+
+                Ok(count) => self.remote_led = buf[count - 1] != 0,
+
+                Err(ref err)
+                    if err.kind() == std::io::ErrorKind::WouldBlock =>
+                    break,
+
+                Err(_) => {
+                    self.conn_down();
+                    break;
+                },
+            }
+        }
+
+        addr_space.io_set_reg(IOReg::RP, rp_value(rp, self.remote_led));
+    }
+
+    fn send_led(&mut self, on: bool) {
+        if let Some(con) = self.con.as_mut() {
+            if con.write_all(&[on as u8]).is_err() {
+                self.conn_down();
+            }
+        }
+    }
+
+    fn conn_down(&mut self) {
+        if let Some(con) = self.con.take() {
+            con.shutdown(std::net::Shutdown::Both).unwrap_or(());
+        }
+        self.remote_led = false;
+    }
+}
+
+
+/* Bit 1 reads as 0 while light is received (and reading is enabled) */
+fn rp_value(rp: u8, light: bool) -> u8 {
+    let receiving = light && rp & RP_READ_ENABLE == RP_READ_ENABLE;
+    (rp & 0xc1) | if receiving { 0x00 } else { 0x02 }
+}
 
 pub fn rp_write(sys_state: &mut SystemState, _: u16, val: u8)
 {
-    /* TODO */
-    sys_state.io_set_reg(IOReg::RP, val & 0xc1);
+    let old_rp = sys_state.io_get_reg(IOReg::RP);
+
+    let light =
+        match sys_state.ir.as_mut() {
+            Some(ir) => {
+                if (old_rp ^ val) & 0x01 != 0 {
+                    ir.send_led(val & 0x01 != 0);
+                }
+                ir.remote_led
+            },
+
+            None => false,
+        };
+
+    sys_state.io_set_reg(IOReg::RP, rp_value(val, light));
 }
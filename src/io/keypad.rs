@@ -34,7 +34,7 @@ pub struct KeypadState {
     ncp: NextControllerProcedure,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub enum KeypadKey {
     A,
     B,
@@ -60,6 +60,13 @@ impl KeypadState {
         }
     }
 
+    /* Keeps what is currently pressed */
+    pub fn reset(&mut self) {
+        let all_lines = self.all_lines;
+        *self = Self::new();
+        self.all_lines = all_lines;
+    }
+
     pub fn init_system_state(sys_state: &mut SystemState) {
         sys_state.keypad.update_p1(&mut sys_state.addr_space);
     }
@@ -123,6 +130,16 @@ impl KeypadState {
         self.update_p1(addr_space);
     }
 
+    /* All pressed keys, as a bit mask (same layout as key_event() uses) */
+    pub fn lines(&self) -> u8 {
+        self.all_lines
+    }
+
+    pub fn set_lines(&mut self, addr_space: &mut AddressSpace, lines: u8) {
+        self.all_lines = lines;
+        self.update_p1(addr_space);
+    }
+
     pub fn set_controller_count(&mut self, count: usize) {
         self.controller_count = count;
         self.controller_index = 0;
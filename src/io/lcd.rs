@@ -1,10 +1,74 @@
+#[cfg(not(target_arch = "wasm32"))]
+pub mod vram_view;
+
 #[cfg(target_arch = "wasm32")]
 use crate::address_space::AddressSpace;
+#[cfg(all(feature = "accurate-oam-bug", target_os = "linux"))]
+use crate::address_space::AS_BASE;
 use crate::io::{hdma_copy_16b, IOSpace, io_write};
+#[cfg(feature = "accurate-oam-bug")]
+use crate::io::io_read;
+#[cfg(feature = "accurate-oam-bug")]
+use crate::mem;
 use crate::io::int::Irq;
 use crate::sgb::sgb_buf_done;
-use crate::system_state::{IOReg, SystemState};
+use crate::system_state::{dcycles, IOReg, SystemState};
+
+
+const DMG_SHADES: [u32; 4] = [0xffffffff, 0xffa8a8a8, 0xff505050, 0xff000000];
+/* The original DMG's pea soup green */
+const DMG_GREEN_SHADES: [u32; 4] =
+    [0xff0fbc9b, 0xff0fac8b, 0xff306230, 0xff0f380f];
+/* The GB Pocket's greenish gray */
+const POCKET_SHADES: [u32; 4] =
+    [0xffa1cfc4, 0xff6d958b, 0xff3c534d, 0xff1f1f1f];
+/* What the CGB boot ROM sets up for DMG games (without a per-title
+ * palette, which are only for Nintendo's own games); RGB15 */
+const CGB_COMPAT_BG: [u16; 4] = [0x7fff, 0x1bef, 0x6180, 0x0000];
+const CGB_COMPAT_OBJ: [u16; 4] = [0x7fff, 0x421f, 0x1cf2, 0x0000];
+
+/* When the BG tiles of a line are fetched during mode 3, in line_timer
+ * units since its start (there are 21 fetches, including the one for the
+ * partially visible first tile) */
+const BG_FETCH_START: u32 = 6;
+const BG_FETCH_PERIOD: u32 = 4;
+
+/* Mode durations in double-speed cycles */
+const MODE2_DCYCLES: u32 = 40;
+const MODE3_DCYCLES: u32 = 86;
+const MODE0_DCYCLES: u32 = 102;
+const LINE_DCYCLES: u32 = 228;
+
+/* A line is 456 dots, i.e. 114 CPU cycles in normal speed mode and 228 in
+ * double speed mode */
+const _: () = assert!(MODE2_DCYCLES + MODE3_DCYCLES + MODE0_DCYCLES ==
+                      LINE_DCYCLES);
+const _: () = assert!(dcycles(114, false) == LINE_DCYCLES);
+const _: () = assert!(dcycles(228, true) == LINE_DCYCLES);
+
+
+/* How CGB colors are translated for display */
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ColorMode {
+    /* Linear scaling, very saturated */
+    Raw,
+    /* Approximates the CGB LCD */
+    Cgb,
+    /* Approximates the (darker) GBA LCD */
+    Agb,
+}
 
+/* Colors used for the four shades in DMG mode (unless SGB palettes are
+ * in use) */
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DmgPalette {
+    Grayscale,
+    DmgGreen,
+    Pocket,
+    /* ABGR8888, lightest first */
+    Custom([u32; 4]),
+}
 
 #[derive(Serialize, Deserialize, PartialEq)]
 pub enum DisplaySGBMask {
@@ -18,6 +82,10 @@ pub enum DisplaySGBMask {
 pub struct DisplayState {
     #[savestate(skip_if("version < 2"), ref)]
     pub lcd_pixels: [u32; 160 * 144],
+    /* The same in RGB15 as given by the CGB palettes, i.e. without color
+     * correction (only meaningful when cgb_colors()) */
+    #[savestate(skip_if("version < 17"), ref)]
+    lcd_pixels15: [u16; 160 * 144],
 
     enabled: bool,
     wnd_tile_map: usize,
@@ -30,6 +98,15 @@ pub struct DisplayState {
     obj_prio: bool,
 
     line_timer: u32,
+    /* Window line to draw next; only advances on lines where the window
+     * is actually drawn, so it resumes where it left off after having
+     * been disabled */
+    #[savestate(skip_if("version < 11"))]
+    wnd_line: u8,
+    /* All enabled STAT interrupt sources ORed together; the interrupt
+     * is only raised when this goes from low to high */
+    #[savestate(skip_if("version < 12"))]
+    stat_irq_line: bool,
 
     bcps: u8,
     ocps: u8,
@@ -38,6 +115,29 @@ pub struct DisplayState {
     obj_palette: [u32; 32],
     obj_palette15: [u16; 32],
 
+    #[savestate(skip)]
+    color_correction: ColorMode,
+    #[savestate(skip)]
+    dmg_palette: DmgPalette,
+
+    /*
+     * Normally, a line is drawn in one go at the start of mode 3, so
+     * changes to SCX/SCY during mode 3 have no effect until the next line.
+     * With this, the line is instead drawn at the end of mode 3, with each
+     * BG tile using the scroll values from when it would have been fetched.
+     * That is slower, and all other registers (e.g. LCDC and palettes) are
+     * then sampled at the end of mode 3 instead of at its start.
+     */
+    #[savestate(skip)]
+    accurate_ppu: bool,
+    /* (line_timer, SCX, SCY) whenever SCX/SCY changed during mode 3 */
+    #[savestate(skip)]
+    scroll_log: Vec<(u32, u8, u8)>,
+    /* Draw at most ten objects per line, like the hardware does (which
+     * causes flickering in some games) */
+    #[savestate(skip)]
+    sprite_limit: bool,
+
     #[savestate(skip_if("version < 1"))]
     bg_palette_mapping: [u8; 4],
     #[savestate(skip_if("version < 1"))]
@@ -85,6 +185,7 @@ impl DisplayState {
     pub fn new() -> Self {
         Self {
             lcd_pixels: [0; 160 * 144],
+            lcd_pixels15: [0; 160 * 144],
 
             enabled: false,
             wnd_tile_map: 0,
@@ -97,6 +198,8 @@ impl DisplayState {
             obj_prio: false,
 
             line_timer: 0,
+            wnd_line: 0,
+            stat_irq_line: false,
 
             bcps: 0,
             ocps: 0,
@@ -137,6 +240,13 @@ impl DisplayState {
                             0x7fff, 0x5294, 0x294a, 0x0000,
                             0x7fff, 0x5294, 0x294a, 0x0000],
 
+            color_correction: ColorMode::Raw,
+            dmg_palette: DmgPalette::Grayscale,
+
+            accurate_ppu: false,
+            scroll_log: Vec::new(),
+            sprite_limit: true,
+
             bg_palette_mapping: [0, 1, 2, 3],
             obj_palette_mapping: [0, 1, 2, 3, 4, 5, 6, 7],
 
@@ -151,6 +261,18 @@ impl DisplayState {
         }
     }
 
+    /* Keeps the settings (color correction, DMG palette, ...) */
+    pub fn reset(&mut self) {
+        let mut fresh = Self::new();
+
+        fresh.color_correction = self.color_correction;
+        fresh.dmg_palette = self.dmg_palette;
+        fresh.accurate_ppu = self.accurate_ppu;
+        fresh.sprite_limit = self.sprite_limit;
+
+        *self = fresh;
+    }
+
     pub fn init_system_state(sys_state: &mut SystemState) {
         sys_state.io_set_reg(IOReg::LCDC, 0x91);
         sys_state.io_set_reg(IOReg::STAT, 0x06);
@@ -163,6 +285,155 @@ impl DisplayState {
         io_write(sys_state, IOReg::BGP  as u16, 0xfc);
         io_write(sys_state, IOReg::OBP0 as u16, 0xff);
         io_write(sys_state, IOReg::OBP1 as u16, 0xff);
+
+        Self::apply_dmg_palette(sys_state);
+    }
+
+    /* To be called after sys_state.cgb has been changed at runtime */
+    pub fn color_mode_changed(sys_state: &mut SystemState) {
+        let cgb = sys_state.cgb;
+        let d = &mut sys_state.display;
+
+        for i in 0..32 {
+            if cgb {
+                d.bg_palette[i] = d.cgb_rgb24(d.bg_palette15[i]);
+                d.obj_palette[i] = d.cgb_rgb24(d.obj_palette15[i]);
+            } else {
+                d.bg_palette[i] = d.dmg_palette.shades()[i % 4];
+                d.obj_palette[i] = d.dmg_palette.shades()[i % 4];
+            }
+        }
+
+        if cgb {
+            d.bg_palette_mapping = [0, 1, 2, 3];
+            d.obj_palette_mapping = [0, 1, 2, 3, 4, 5, 6, 7];
+        }
+
+        /* Re-evaluate everything that depends on the mode (for DMG, this
+         * includes setting up the palette mappings again) */
+        for reg in [IOReg::LCDC, IOReg::BGP, IOReg::OBP0, IOReg::OBP1] {
+            let addr = reg as u16;
+            let val = sys_state.io_get_addr(addr);
+            io_write(sys_state, addr, val);
+        }
+    }
+
+    pub fn color_correction(&self) -> ColorMode {
+        self.color_correction
+    }
+
+    pub fn set_color_correction(sys_state: &mut SystemState,
+                                mode: ColorMode)
+    {
+        let d = &mut sys_state.display;
+        d.color_correction = mode;
+
+        if sys_state.cgb {
+            for i in 0..32 {
+                d.bg_palette[i] = d.cgb_rgb24(d.bg_palette15[i]);
+                d.obj_palette[i] = d.cgb_rgb24(d.obj_palette15[i]);
+            }
+        } else {
+            Self::apply_dmg_palette(sys_state);
+        }
+    }
+
+    pub fn set_accurate_ppu(&mut self, accurate: bool) {
+        self.accurate_ppu = accurate;
+    }
+
+    pub fn sprite_limit(&self) -> bool {
+        self.sprite_limit
+    }
+
+    pub fn set_sprite_limit(&mut self, limit: bool) {
+        self.sprite_limit = limit;
+    }
+
+    /* Updates the STAT interrupt line for the new @stat value, and
+     * returns whether the interrupt should be raised */
+    fn stat_irq_edge(&mut self, stat: u8) -> bool {
+        let mode_src =
+            match stat & 3 {
+                0 => 1 << 3,
+                1 => 1 << 4,
+                2 => 1 << 5,
+                _ => 0,
+            };
+
+        let line = stat & 0b01000100 == 0b01000100 /* LYC match */ ||
+                   stat & mode_src != 0;
+
+        let edge = line && !self.stat_irq_line;
+        self.stat_irq_line = line;
+        edge
+    }
+
+    pub fn dmg_palette(&self) -> DmgPalette {
+        self.dmg_palette
+    }
+
+    pub fn set_dmg_palette(sys_state: &mut SystemState, palette: DmgPalette) {
+        sys_state.display.dmg_palette = palette;
+        Self::apply_dmg_palette(sys_state);
+    }
+
+    /* Also to be called after a save state has been loaded (which
+     * includes the palettes it was created with) */
+    pub fn apply_dmg_palette(sys_state: &mut SystemState) {
+        /* SGB palettes take precedence */
+        if sys_state.cgb || sys_state.sgb {
+            return;
+        }
+
+        /* DMG games on a CGB (or GBA) are colorized by the boot ROM */
+        if Self::cgb_colors(sys_state) {
+            let d = &mut sys_state.display;
+            for i in 0..32 {
+                d.bg_palette15[i] = CGB_COMPAT_BG[i % 4];
+                d.obj_palette15[i] = CGB_COMPAT_OBJ[i % 4];
+                d.bg_palette[i] = d.cgb_rgb24(d.bg_palette15[i]);
+                d.obj_palette[i] = d.cgb_rgb24(d.obj_palette15[i]);
+            }
+            return;
+        }
+
+        let d = &mut sys_state.display;
+
+        let shades = d.dmg_palette.shades();
+        for i in 0..32 {
+            d.bg_palette[i] = shades[i % 4];
+            d.obj_palette[i] = shades[i % 4];
+        }
+    }
+
+    /*
+     * Whether colors come from the CGB palettes (and are thus subject to
+     * color correction): In CGB mode, and for DMG games on a CGB (but CGB
+     * games switched to DMG mode here should look like on a DMG)
+     */
+    pub fn cgb_colors(sys_state: &SystemState) -> bool {
+        sys_state.cgb ||
+            (sys_state.model.is_cgb() && !sys_state.cgb_capable &&
+             !sys_state.sgb)
+    }
+
+    /* The LCD as it would look without color correction (only valid if
+     * cgb_colors()) */
+    pub fn raw_lcd_pixels(&self) -> Box<[u32; 160 * 144]> {
+        let mut pixels = Box::new([0u32; 160 * 144]);
+        for (px, rgb15) in pixels.iter_mut().zip(self.lcd_pixels15.iter()) {
+            *px = rgb15_to_rgb24(*rgb15);
+        }
+        pixels
+    }
+
+    fn cgb_rgb24(&self, rgb15: u16) -> u32 {
+        match self.color_correction {
+            ColorMode::Raw => rgb15_to_rgb24(rgb15),
+            ColorMode::Cgb => rgb15_to_cgb_lcd(rgb15),
+            ColorMode::Agb => rgb15_to_agb_lcd(rgb15),
+        }
     }
 
     pub fn set_bg_pal(&mut self, index: usize, rgb15: u16) {
@@ -231,6 +502,44 @@ impl DisplayState {
 }
 
 
+impl DmgPalette {
+    pub fn shades(&self) -> [u32; 4] {
+        match self {
+            DmgPalette::Grayscale => DMG_SHADES,
+            DmgPalette::DmgGreen => DMG_GREEN_SHADES,
+            DmgPalette::Pocket => POCKET_SHADES,
+            DmgPalette::Custom(shades) => *shades,
+        }
+    }
+
+    /* Accepts the preset names, or four RRGGBB colors separated by
+     * commas (lightest first) */
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "grayscale" => return Some(DmgPalette::Grayscale),
+            "green" => return Some(DmgPalette::DmgGreen),
+            "pocket" => return Some(DmgPalette::Pocket),
+            _ => (),
+        }
+
+        let colors: Vec<u32> =
+            name.split(',')
+                .map(|c| u32::from_str_radix(c.trim_start_matches('#'), 16))
+                .collect::<Result<_, _>>().ok()?;
+        if colors.len() != 4 || colors.iter().any(|c| *c > 0xffffff) {
+            return None;
+        }
+
+        let mut shades = [0u32; 4];
+        for (shade, rgb) in shades.iter_mut().zip(colors) {
+            *shade = 0xff000000 | ((rgb >> 16) & 0xff) | (rgb & 0xff00) |
+                     ((rgb & 0xff) << 16);
+        }
+        Some(DmgPalette::Custom(shades))
+    }
+}
+
+
 fn fetch_tile_flags(full_vram: &[u8; 0x4000], tile_map: usize,
                     tile: usize, cgb: bool)
     -> u8
@@ -304,32 +613,35 @@ fn get_tile_prio(pixel: usize, flags: u8, obj_prio: bool) -> u8 {
 }
 
 
+/* Draws the BG for screen_x < bg_end (where the window starts) */
+/* @scroll holds (SCX, SCY) for each BG tile fetch; the fine scroll is
+ * taken from the first one */
 fn draw_bg_line(sys_state: &mut SystemState,
-                line: u8, screen_line: u8, window: bool,
-                bg_prio: &mut [u8; 160])
+                screen_line: u8, bg_end: usize,
+                bg_prio: &mut [u8; 160], scroll: &[(u8, u8); 21])
 {
-    let sx = sys_state.io_get_reg(IOReg::SCX);
-    let wx = sys_state.io_get_reg(IOReg::WX).wrapping_sub(7);
-
     let d = &mut sys_state.display;
     let sofs = screen_line as usize * 160;
     let eofs = sofs + 160;
     let pixels = &mut d.lcd_pixels[sofs..eofs];
+    let pixels15 = &mut d.lcd_pixels15[sofs..eofs];
 
     let full_vram = &sys_state.addr_space.full_vram;
     let tile_data_signed = d.tile_data == 0x1000;
 
-    let mut bx = sx & 0xf8;
-    let ex = sx.wrapping_add(167) & 0xf8;
-    let by = (line & 0xf8) as usize;
-    let ry = (line & 0x07) as usize;
-    let mut tile = (by << 2) + ((bx as usize) >> 3);
+    let fine_x = (scroll[0].0 & 0x07) as isize;
 
-    while bx != ex {
-        if window && wx <= bx {
+    for (fetch_i, &(sx, sy)) in scroll.iter().enumerate() {
+        let tile_screen_x = fetch_i as isize * 8 - fine_x;
+        if tile_screen_x >= bg_end as isize {
             break;
         }
 
+        let line = screen_line.wrapping_add(sy);
+        let by = (line & 0xf8) as usize;
+        let ry = (line & 0x07) as usize;
+        let tile = (by << 2) + (((sx as usize >> 3) + fetch_i) & 0x1f);
+
         let flags = fetch_tile_flags(full_vram, d.bg_tile_map,
                                      tile, sys_state.cgb);
         let (data, mut pal_bi) = get_tile_data_and_pal(full_vram, d.bg_tile_map,
@@ -339,10 +651,11 @@ fn draw_bg_line(sys_state: &mut SystemState,
                                                        sys_state.cgb);
 
         for rx in 0..8 {
-            let screen_x = (bx + rx).wrapping_sub(sx) as usize;
-            if screen_x >= 160 {
+            let screen_x = tile_screen_x + rx as isize;
+            if screen_x < 0 || screen_x >= bg_end as isize {
                 continue;
             }
+            let screen_x = screen_x as usize;
 
             if sys_state.sgb {
                 let pixel_bi = (screen_line as usize / 8) * 20 + screen_x / 8;
@@ -352,6 +665,7 @@ fn draw_bg_line(sys_state: &mut SystemState,
             let val = get_tile_obj_pixel(data, rx, flags);
             let pal_i = d.bg_palette_mapping[val] as usize;
             pixels[screen_x] = d.bg_palette[pal_bi + pal_i];
+            pixels15[screen_x] = d.bg_palette15[pal_bi + pal_i];
             bg_prio[screen_x] = get_tile_prio(val, flags, d.obj_prio);
 
             if d.filling_for_sgb_buf {
@@ -359,14 +673,6 @@ fn draw_bg_line(sys_state: &mut SystemState,
                 d.for_sgb_buf[pixel_i] = pal_i as u8;
             }
         }
-
-        let (nbx, wrap) = bx.overflowing_add(8);
-        bx = nbx;
-        if wrap {
-            tile -= 31;
-        } else {
-            tile += 1;
-        }
     }
 }
 
@@ -374,27 +680,31 @@ fn draw_bg_line(sys_state: &mut SystemState,
 fn draw_wnd_line(sys_state: &mut SystemState,
                  screen_line: u8, bg_prio: &mut [u8; 160])
 {
-    let wx = sys_state.io_get_reg(IOReg::WX) - 7;
+    /* With WX < 7, the window begins left of the screen, so its first
+     * 7 - WX pixels are cut off */
+    let wnd_x = sys_state.io_get_reg(IOReg::WX) as isize - 7;
     let wy = sys_state.io_get_reg(IOReg::WY);
 
     let d = &mut sys_state.display;
     let sofs = screen_line as usize * 160;
     let eofs = sofs + 160;
     let pixels = &mut d.lcd_pixels[sofs..eofs];
+    let pixels15 = &mut d.lcd_pixels15[sofs..eofs];
 
     if screen_line < wy {
         return;
     }
 
-    let by = (screen_line - wy) & 0xf8;
-    let ry = (screen_line - wy) & 0x07;
+    let by = d.wnd_line & 0xf8;
+    let ry = d.wnd_line & 0x07;
+    d.wnd_line = d.wnd_line.wrapping_add(1);
 
     let full_vram = &sys_state.addr_space.full_vram;
     let tile_data_signed = d.tile_data == 0x1000;
 
     let mut tile = (by as usize) << 2;
 
-    for bx in (wx..160).step_by(8) {
+    for bx in (wnd_x..160).step_by(8) {
         let flags = fetch_tile_flags(full_vram, d.wnd_tile_map,
                                      tile, sys_state.cgb);
         let (data, mut pal_bi) = get_tile_data_and_pal(full_vram, d.wnd_tile_map,
@@ -404,7 +714,11 @@ fn draw_wnd_line(sys_state: &mut SystemState,
                                                        sys_state.cgb);
 
         for rx in 0..8 {
-            let screen_x = (bx + rx) as usize;
+            if bx + (rx as isize) < 0 {
+                continue;
+            }
+
+            let screen_x = (bx + rx as isize) as usize;
             if screen_x >= 160 {
                 break;
             }
@@ -417,6 +731,7 @@ fn draw_wnd_line(sys_state: &mut SystemState,
             let val = get_tile_obj_pixel(data, rx, flags);
             let pal_i = d.bg_palette_mapping[val] as usize;
             pixels[screen_x] = d.bg_palette[pal_bi + pal_i];
+            pixels15[screen_x] = d.bg_palette15[pal_bi + pal_i];
             bg_prio[screen_x] = get_tile_prio(val, flags, d.obj_prio);
 
             if d.filling_for_sgb_buf {
@@ -432,7 +747,7 @@ fn draw_wnd_line(sys_state: &mut SystemState,
 
 #[cfg(not(target_arch = "wasm32"))]
 fn oam_search(objs: &mut Vec::<u32>, oam: *const u32,
-              line: i32, obj_height: i32, cgb: bool)
+              line: i32, obj_height: i32, cgb: bool, limit: bool)
 {
     for i in 0..40 {
         let obj = unsafe { *oam.offset(i) };
@@ -443,16 +758,21 @@ fn oam_search(objs: &mut Vec::<u32>, oam: *const u32,
         }
     }
 
+    /* The hardware selects the first ten objects in OAM order, and only
+     * then sorts them by X (sort_by_key() is stable, so objects with the
+     * same X stay in OAM order) */
+    if limit {
+        objs.truncate(10);
+    }
+
     if !cgb {
         objs.sort_by_key(|x| (x >> 8) & 0xffu32);
     }
-
-    objs.truncate(10);
 }
 
 #[cfg(target_arch = "wasm32")]
 fn oam_search(objs: &mut Vec::<u32>, addr_space: &AddressSpace,
-              line: i32, obj_height: i32, cgb: bool)
+              line: i32, obj_height: i32, cgb: bool, limit: bool)
 {
     for i in 0..40 {
         let obj = addr_space.read_u8(i * 4 + 0xfe00) as u32 |
@@ -467,11 +787,16 @@ fn oam_search(objs: &mut Vec::<u32>, addr_space: &AddressSpace,
         }
     }
 
+    /* The hardware selects the first ten objects in OAM order, and only
+     * then sorts them by X (sort_by_key() is stable, so objects with the
+     * same X stay in OAM order) */
+    if limit {
+        objs.truncate(10);
+    }
+
     if !cgb {
         objs.sort_by_key(|x| (x >> 8) & 0xffu32);
     }
-
-    objs.truncate(10);
 }
 
 fn draw_obj_line(sys_state: &mut SystemState, screen_line: u8,
@@ -481,6 +806,7 @@ fn draw_obj_line(sys_state: &mut SystemState, screen_line: u8,
     let sofs = screen_line as usize * 160;
     let eofs = sofs + 160;
     let pixels = &mut d.lcd_pixels[sofs..eofs];
+    let pixels15 = &mut d.lcd_pixels15[sofs..eofs];
     #[cfg(not(target_arch = "wasm32"))]
     let oam = sys_state.addr_space.raw_ptr(0xfe00) as *const u32;
     let full_vram = &sys_state.addr_space.full_vram;
@@ -489,10 +815,10 @@ fn draw_obj_line(sys_state: &mut SystemState, screen_line: u8,
 
     #[cfg(not(target_arch = "wasm32"))]
     oam_search(&mut objs, oam, screen_line as i32, d.obj_height as i32,
-               sys_state.cgb);
+               sys_state.cgb, d.sprite_limit);
     #[cfg(target_arch = "wasm32")]
     oam_search(&mut objs, &sys_state.addr_space, screen_line as i32,
-               d.obj_height as i32, sys_state.cgb);
+               d.obj_height as i32, sys_state.cgb, d.sprite_limit);
 
     for obj in objs.iter().rev() {
         let bx = ((obj >> 8) & 0xffu32) as i32 - 8;
@@ -537,6 +863,7 @@ fn draw_obj_line(sys_state: &mut SystemState, screen_line: u8,
             if val != 0 && bg_prio[screen_x] < 2 && (flags & (1 << 7) == 0 || bg_prio[screen_x] < 1) {
                 let pal_i = d.obj_palette_mapping[val] as usize;
                 pixels[screen_x] = d.obj_palette[pal_bi + pal_i];
+                pixels15[screen_x] = d.obj_palette15[pal_bi + pal_i];
 
                 if d.filling_for_sgb_buf {
                     let pixel_i = screen_line as usize * 160 + screen_x;
@@ -548,32 +875,59 @@ fn draw_obj_line(sys_state: &mut SystemState, screen_line: u8,
 }
 
 
+/* (SCX, SCY) for each BG tile fetch of the current line */
+fn bg_fetch_scroll(sys_state: &SystemState) -> [(u8, u8); 21] {
+    let d = &sys_state.display;
+    let current = (sys_state.io_get_reg(IOReg::SCX),
+                   sys_state.io_get_reg(IOReg::SCY));
+
+    if !d.accurate_ppu || d.scroll_log.is_empty() {
+        return [current; 21];
+    }
+
+    let mut scroll = [current; 21];
+    for (fetch_i, s) in scroll.iter_mut().enumerate() {
+        let t = BG_FETCH_START + fetch_i as u32 * BG_FETCH_PERIOD;
+        if let Some(&(_, sx, sy)) =
+            d.scroll_log.iter().rev().find(|(lt, _, _)| *lt <= t)
+        {
+            *s = (sx, sy);
+        }
+    }
+    scroll
+}
+
 fn draw_line(sys_state: &mut SystemState, line: u8) {
-    let sy = sys_state.io_get_reg(IOReg::SCY);
     let wx = sys_state.io_get_reg(IOReg::WX);
     let wy = sys_state.io_get_reg(IOReg::WY);
 
     let sofs = line as usize * 160;
     let eofs = sofs + 160;
     let pixels = &mut sys_state.display.lcd_pixels[sofs..eofs];
+    let pixels15 = &mut sys_state.display.lcd_pixels15[sofs..eofs];
     let mut bg_prio = [0u8; 160];
 
     if !sys_state.display.enabled {
-        for p in pixels {
-            *p = 0xffffffff;
-        }
+        pixels.fill(0xffffffff);
+        pixels15.fill(0x7fff);
         return;
     }
 
-    let abs_line = line.wrapping_add(sy);
-    let window_active = sys_state.display.wnd_enabled && (7..=166).contains(&wx) && wy <= line;
+    let window_active = sys_state.display.wnd_enabled && wx <= 166 && wy <= line;
+
+    let bg_end =
+        if window_active {
+            wx.saturating_sub(7) as usize
+        } else {
+            160
+        };
 
     if !sys_state.display.bg_enabled {
-        for p in pixels {
-            *p = 0xff000000;
-        }
+        pixels.fill(0xff000000);
+        pixels15.fill(0x0000);
     } else {
-        draw_bg_line(sys_state, abs_line, line, window_active, &mut bg_prio);
+        let scroll = bg_fetch_scroll(sys_state);
+        draw_bg_line(sys_state, line, bg_end, &mut bg_prio, &scroll);
     }
 
     if window_active {
@@ -603,29 +957,21 @@ fn stat_mode_transition(sys_state: &mut SystemState, ly: u8, from: Submode, to:
     addr_space.io_set_reg(IOReg::STAT, stat);
     addr_space.io_set_reg(IOReg::LY, ly);
 
-    /* Care must be taken to only generate each interrupt on the
-     * event's leading edge */
-    if stat & 0b01000100 == 0b01000100 /* LYC match */ &&
-       (to == Submode::VBlank || to == Submode::OamOnly) /* First submodes per line */
-    {
+    /* Care must be taken to only generate the interrupt on the leading
+     * edge of all sources combined: While one source keeps the line
+     * high, others cannot trigger it again ("STAT blocking") */
+    if d.stat_irq_edge(stat) {
         let iflag = addr_space.io_get_reg(IOReg::IF);
         addr_space.io_set_reg(IOReg::IF, iflag | (Irq::Lcdc as u8));
     }
 
     if to != from {
-        if stat & 0b00100011 == 0b00100010 /* Mode 2 */ ||
-           stat & 0b00010011 == 0b00010001 /* Mode 1 */ ||
-           stat & 0b00001011 == 0b00001000 /* Mode 0 */
-        {
-            let iflag = addr_space.io_get_reg(IOReg::IF);
-            addr_space.io_set_reg(IOReg::IF, iflag | (Irq::Lcdc as u8));
-        }
-
         if to == Submode::VBlank {
             let iflag = addr_space.io_get_reg(IOReg::IF);
             addr_space.io_set_reg(IOReg::IF, iflag | (Irq::VBlank as u8));
 
             sys_state.vblanked = true;
+            d.wnd_line = 0;
 
             match d.sgb_mask {
                 DisplaySGBMask::NoMask => (),
@@ -662,9 +1008,69 @@ fn stat_mode_transition(sys_state: &mut SystemState, ly: u8, from: Submode, to:
     }
 
     if to == Submode::OamVram {
-        draw_line(sys_state, ly);
-    } else if to == Submode::HBlank && hdma5 & 0x80 == 0 {
-        hdma_copy_16b(sys_state);
+        if sys_state.display.accurate_ppu {
+            /* Drawn at the end of mode 3 */
+            let d = &mut sys_state.display;
+            d.scroll_log.clear();
+            d.scroll_log.push((0, sys_state.addr_space.io_get_reg(IOReg::SCX),
+                               sys_state.addr_space.io_get_reg(IOReg::SCY)));
+        } else {
+            draw_line(sys_state, ly);
+        }
+    } else if to == Submode::HBlank {
+        if from == Submode::OamVram && sys_state.display.accurate_ppu {
+            draw_line(sys_state, ly);
+        }
+        if hdma5 & 0x80 == 0 {
+            hdma_copy_16b(sys_state);
+        }
+    }
+}
+
+/*
+ * The OAM bug: When the CPU increments or decrements a 16-bit register
+ * that points into OAM while the PPU scans it (mode 2), the OAM row the
+ * PPU is accessing at that time is corrupted.  @addr is the register's
+ * value before the operation.
+ */
+#[cfg(feature = "accurate-oam-bug")]
+#[allow(unused_unsafe)]
+pub fn oam_bug(sys_state: &mut SystemState, addr: u16) {
+    if !(0xfe00..0xff00).contains(&addr) || !sys_state.display.enabled {
+        return;
+    }
+
+    let mode: Submode = sys_state.io_get_reg(IOReg::STAT).into();
+    if mode != Submode::OamOnly {
+        return;
+    }
+
+    /* Mode 2 scans 20 rows of 8 bytes in 40 units; the first row is never
+     * corrupted */
+    let row = (sys_state.display.line_timer / 2) as u16;
+    if row == 0 || row >= 20 {
+        return;
+    }
+
+    let row_addr = 0xfe00 + row * 8;
+    let prev_addr = row_addr - 8;
+
+    let read16 = |ss: &mut SystemState, addr: u16| {
+        mem![ss; addr] as u16 | ((mem![ss; addr + 1] as u16) << 8)
+    };
+
+    let a = read16(sys_state, row_addr);
+    let b = read16(sys_state, prev_addr);
+    let c = read16(sys_state, prev_addr + 4);
+    let first = ((a ^ c) & (b ^ c)) ^ c;
+
+    mem![sys_state; first as u8 => row_addr];
+    mem![sys_state; (first >> 8) as u8 => row_addr + 1];
+
+    /* The rest of the row is copied from the preceding one */
+    for i in 2..8 {
+        let val = mem![sys_state; prev_addr + i];
+        mem![sys_state; val => row_addr + i];
     }
 }
 
@@ -682,7 +1088,7 @@ pub fn add_cycles(sys_state: &mut SystemState, cycles: u32) {
 
         match sys_state.io_get_reg(IOReg::STAT).into() {
             VBlank => {
-                if line_timer >= 228 {
+                if line_timer >= LINE_DCYCLES {
                     ly += 1;
                     if ly < 154 {
                         stat_mode_transition(sys_state, ly, VBlank, VBlank);
@@ -690,40 +1096,40 @@ pub fn add_cycles(sys_state: &mut SystemState, cycles: u32) {
                         ly = 0;
                         stat_mode_transition(sys_state, ly, VBlank, OamOnly);
                     }
-                    line_timer -= 228;
+                    line_timer -= LINE_DCYCLES;
                 } else {
                     break;
                 }
             }
 
             OamOnly => {
-                if line_timer >= 40 {
+                if line_timer >= MODE2_DCYCLES {
                     stat_mode_transition(sys_state, ly, OamOnly, OamVram);
-                    line_timer -= 40;
+                    line_timer -= MODE2_DCYCLES;
                 } else {
                     break;
                 }
             }
 
             OamVram => {
-                if line_timer >= 86 {
+                if line_timer >= MODE3_DCYCLES {
                     /* OAM+VRAM -> HBlank */
                     stat_mode_transition(sys_state, ly, OamVram, HBlank);
-                    line_timer -= 86;
+                    line_timer -= MODE3_DCYCLES;
                 } else {
                     break;
                 }
             }
 
             HBlank => {
-                if line_timer >= 102 {
+                if line_timer >= MODE0_DCYCLES {
                     ly += 1;
                     if ly < 144 {
                         stat_mode_transition(sys_state, ly, HBlank, OamOnly);
                     } else {
                         stat_mode_transition(sys_state, ly, HBlank, VBlank);
                     }
-                    line_timer -= 102;
+                    line_timer -= MODE0_DCYCLES;
                 } else {
                     break;
                 }
@@ -735,7 +1141,7 @@ pub fn add_cycles(sys_state: &mut SystemState, cycles: u32) {
 }
 
 
-/* TODO: Implement better translation function */
+/* Linear scaling; see rgb15_to_cgb_lcd() for what the CGB would show */
 pub fn rgb15_to_rgb24(rgb15: u16) -> u32 {
     let r =  rgb15        & 0x1f;
     let g = (rgb15 >>  5) & 0x1f;
@@ -748,6 +1154,48 @@ pub fn rgb15_to_rgb24(rgb15: u16) -> u32 {
     0xff000000 | r8 | (g8 << 8) | (b8 << 16)
 }
 
+fn rgb15_split_linear(rgb15: u16, gamma: f32) -> (f32, f32, f32) {
+    let lin = |c: u16| ((c & 0x1f) as f32 / 31.0).powf(gamma);
+
+    (lin(rgb15), lin(rgb15 >> 5), lin(rgb15 >> 10))
+}
+
+fn rgb24_from_float(r: f32, g: f32, b: f32) -> u32 {
+    let to8 = |c: f32| (c.clamp(0.0, 1.0) * 255.0).round() as u32;
+
+    0xff000000 | to8(r) | (to8(g) << 8) | (to8(b) << 16)
+}
+
+/*
+ * The CGB LCD mixes the channels quite a bit and is less bright; this is
+ * the usual matrix (rows sum up to 32), applied in linear space (gamma
+ * 2.2), with results above 960 (out of 992) clamped to full brightness.
+ */
+fn rgb15_to_cgb_lcd(rgb15: u16) -> u32 {
+    let (r, g, b) = rgb15_split_linear(rgb15, 2.2);
+    let mix = |vr: f32, vg: f32, vb: f32| {
+        let v = (vr * r + vg * g + vb * b) * 31.0;
+        (v.min(960.0) / 960.0).powf(1.0 / 2.2)
+    };
+
+    rgb24_from_float(mix(26.0, 4.0, 2.0),
+                     mix(0.0, 24.0, 8.0),
+                     mix(6.0, 4.0, 22.0))
+}
+
+/* The GBA LCD is darker (so games brightened their colors), hence the
+ * steeper input gamma */
+fn rgb15_to_agb_lcd(rgb15: u16) -> u32 {
+    let (r, g, b) = rgb15_split_linear(rgb15, 4.0);
+    let mix = |vr: f32, vg: f32, vb: f32| {
+        ((vr * r + vg * g + vb * b) / 255.0).powf(1.0 / 2.2) * (255.0 / 280.0)
+    };
+
+    rgb24_from_float(mix(255.0, 50.0, 0.0),
+                     mix(10.0, 230.0, 30.0),
+                     mix(50.0, 10.0, 220.0))
+}
+
 pub fn lcd_write(sys_state: &mut SystemState, addr: u16, mut val: u8) {
     let addr_space = &mut sys_state.addr_space;
 
@@ -777,6 +1225,8 @@ pub fn lcd_write(sys_state: &mut SystemState, addr: u16, mut val: u8) {
             d.obj_height    = if val & (1 << 2) != 0 { 16 } else { 8 };
 
             if !d.enabled {
+                d.wnd_line = 0;
+
                 let submode = sys_state.io_get_reg(IOReg::STAT).into();
                 stat_mode_transition(sys_state, 0, submode, Submode::HBlank);
             }
@@ -784,24 +1234,49 @@ pub fn lcd_write(sys_state: &mut SystemState, addr: u16, mut val: u8) {
 
         0x41 => {
             val = (addr_space.io_get_reg(IOReg::STAT) & 0x87) | val & 0x78;
+
+            if sys_state.display.enabled &&
+               sys_state.display.stat_irq_edge(val)
+            {
+                let iflag = addr_space.io_get_reg(IOReg::IF);
+                addr_space.io_set_reg(IOReg::IF, iflag | (Irq::Lcdc as u8));
+            }
         },
 
-        0x42 | 0x43 | 0x4a | 0x4b => (),
+        0x42 | 0x43 => {
+            let d = &mut sys_state.display;
+            let mode: Submode = addr_space.io_get_reg(IOReg::STAT).into();
+
+            if d.accurate_ppu && mode == Submode::OamVram {
+                let mut sx = addr_space.io_get_reg(IOReg::SCX);
+                let mut sy = addr_space.io_get_reg(IOReg::SCY);
+                if addr == 0x42 {
+                    sy = val;
+                } else {
+                    sx = val;
+                }
+                d.scroll_log.push((d.line_timer, sx, sy));
+            }
+        },
+
+        0x4a | 0x4b => (),
 
         0x44 => {
             val = 0;
         },
 
         0x45 => {
+            let mut stat = addr_space.io_get_reg(IOReg::STAT) & !(1 << 2);
             if val == addr_space.io_get_reg(IOReg::LY) {
-                let mut stat = addr_space.io_get_reg(IOReg::STAT);
                 stat |= 1 << 2;
-                addr_space.io_set_reg(IOReg::STAT, stat);
+            }
+            addr_space.io_set_reg(IOReg::STAT, stat);
 
-                if stat & 0b01000100 == 0b01000100 {
-                    let iflag = addr_space.io_get_reg(IOReg::IF);
-                    addr_space.io_set_reg(IOReg::IF, iflag | (Irq::Lcdc as u8));
-                }
+            if sys_state.display.enabled &&
+               sys_state.display.stat_irq_edge(stat)
+            {
+                let iflag = addr_space.io_get_reg(IOReg::IF);
+                addr_space.io_set_reg(IOReg::IF, iflag | (Irq::Lcdc as u8));
             }
         },
 
@@ -872,13 +1347,17 @@ pub fn lcd_write(sys_state: &mut SystemState, addr: u16, mut val: u8) {
                             ((val as u16) << 8);
                     }
 
-                    d.bg_palette[i] = rgb15_to_rgb24(d.bg_palette15[i]);
+                    d.bg_palette[i] = d.cgb_rgb24(d.bg_palette15[i]);
 
                     d.bcps
                 };
 
+                /* Index 0x3f wraps around to 0x00 (keeping bit 7); writing
+                 * BCPS also makes BCPD reflect the new index, which must
+                 * not be overwritten with the value just written */
                 if bcps & 0x80 != 0 {
                     lcd_write(sys_state, IOReg::BCPS as u16, (bcps + 1) & 0xbf);
+                    return;
                 }
             }
         },
@@ -917,13 +1396,15 @@ pub fn lcd_write(sys_state: &mut SystemState, addr: u16, mut val: u8) {
                             ((val as u16) << 8);
                     }
 
-                    d.obj_palette[i] = rgb15_to_rgb24(d.obj_palette15[i]);
+                    d.obj_palette[i] = d.cgb_rgb24(d.obj_palette15[i]);
 
                     d.ocps
                 };
 
+                /* Same as for BCPD */
                 if ocps & 0x80 != 0 {
                     lcd_write(sys_state, IOReg::OCPS as u16, (ocps + 1) & 0xbf);
+                    return;
                 }
             }
         },
@@ -935,3 +1416,328 @@ pub fn lcd_write(sys_state: &mut SystemState, addr: u16, mut val: u8) {
 
     sys_state.io_set_addr(addr, val);
 }
+
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "accurate-oam-bug")]
+    use super::Submode;
+    #[cfg(feature = "accurate-oam-bug")]
+    use crate::cpu::Reg16;
+    use super::{rgb15_to_rgb24, ColorMode, DisplayState, DmgPalette,
+                DMG_SHADES};
+    use crate::io::IOSpace;
+    use crate::io::int::Irq;
+    use crate::system_state::{IOReg, Model};
+    use crate::testing::{TestSystem, cgb_test_rom, test_rom, test_system,
+                         test_system_as};
+
+    #[test]
+    fn palette_auto_increment_wraps() {
+        let mut sys = test_system(cgb_test_rom(&[0x18, 0xfe]));
+
+        /* Start at index 0 with auto-increment, for BG and OBJ */
+        sys.poke(0xff68, 0x80);
+        sys.poke(0xff6a, 0x80);
+        for i in 0..64u8 {
+            sys.poke(0xff69, i);
+            sys.poke(0xff6b, !i);
+        }
+
+        let d = &sys.sys_state.display;
+        for i in 0..32 {
+            let lo = (i * 2) as u16;
+            let hi = (i * 2 + 1) as u16;
+            assert_eq!(d.bg_palette15[i], lo | (hi << 8));
+            assert_eq!(d.obj_palette15[i], !(lo | (hi << 8)));
+        }
+
+        /* Back at index 0, still auto-incrementing */
+        assert_eq!(d.bcps, 0x80);
+        assert_eq!(d.ocps, 0x80);
+        assert_eq!(sys.sys_state.io_get_reg(IOReg::BCPD), 0x00);
+        assert_eq!(sys.sys_state.io_get_reg(IOReg::OCPD), 0xff);
+
+        /* So the next write goes to the first color again */
+        sys.poke(0xff69, 0x42);
+        assert_eq!(sys.sys_state.display.bg_palette15[0], 0x0142);
+        assert_eq!(sys.sys_state.display.bcps, 0x81);
+    }
+
+    /* Runs until the PPU is in the given mode on the given line; e.g.
+     * mode 0 is right before the next line is drawn */
+    fn run_to(sys: &mut TestSystem, ly: u8, mode: u8) {
+        for _ in 0..100000 {
+            let stat = sys.sys_state.io_get_reg(IOReg::STAT);
+            if sys.sys_state.io_get_reg(IOReg::LY) == ly && stat & 3 == mode {
+                return;
+            }
+            sys.step();
+        }
+        panic!("Mode {} on line {} never reached", mode, ly);
+    }
+
+    #[test]
+    fn window_line_counter_skips_disabled_lines() {
+        let mut sys = test_system(test_rom(&[0x18, 0xfe]));
+
+        /* LCD off for unrestricted VRAM access */
+        sys.poke(0xff40, 0x00);
+        for addr in 0x8000..0xa000 {
+            sys.poke(addr, 0x00);
+        }
+        /* Tile 1 is solid color 3, used only for the window's first row
+         * (tile map at 0x9c00) */
+        for addr in 0x8010..0x8020 {
+            sys.poke(addr, 0xff);
+        }
+        for addr in 0x9c00..0x9c20 {
+            sys.poke(addr, 0x01);
+        }
+        sys.poke(0xff4a, 0); /* WY */
+        sys.poke(0xff4b, 7); /* WX */
+
+        /* Window disabled (but WY = 0) for the top 20 lines */
+        sys.poke(0xff40, 0xd1);
+        sys.main_loop(true);
+        run_to(&mut sys, 19, 0);
+        sys.poke(0xff40, 0xf1);
+        sys.main_loop(true);
+
+        let line = |l: usize| {
+            let pixels = &sys.sys_state.display.lcd_pixels;
+            pixels[(l * 160)..((l + 1) * 160)].to_vec()
+        };
+        let bg = line(0);
+        assert!(bg.iter().all(|&p| p == bg[0]));
+
+        /* The window starts with its first row where it is enabled,
+         * not with row 20 (as WY would suggest) */
+        assert_eq!(line(19), bg);
+        for l in 20..28 {
+            assert!(line(l).iter().all(|&p| p != bg[0]));
+        }
+        assert_eq!(line(28), bg);
+        assert_eq!(line(143), bg);
+    }
+
+    /* Color indices of the top line, with a window whose columns have
+     * the colors 0, 1, 2, 3, 0, ... */
+    fn window_line_colors(wx: u8) -> Vec<usize> {
+        let mut sys = test_system(test_rom(&[0x18, 0xfe]));
+
+        sys.poke(0xff40, 0x00);
+        for addr in 0x8000..0xa000 {
+            sys.poke(addr, 0x00);
+        }
+        /* Tile 1 for the whole window (tile map at 0x9c00) */
+        for row in 0..8 {
+            sys.poke(0x8010 + row * 2, 0x55);
+            sys.poke(0x8011 + row * 2, 0x33);
+        }
+        for addr in 0x9c00..0xa000 {
+            sys.poke(addr, 0x01);
+        }
+        sys.poke(0xff47, 0xe4); /* BGP */
+        sys.poke(0xff4a, 0); /* WY */
+        sys.poke(0xff4b, wx); /* WX */
+
+        sys.poke(0xff40, 0xf1);
+        sys.main_loop(true);
+        sys.main_loop(true);
+
+        let d = &sys.sys_state.display;
+        d.lcd_pixels[0..160].iter().map(|p| {
+            d.bg_palette[0..4].iter().position(|c| c == p).unwrap()
+        }).collect()
+    }
+
+    #[test]
+    fn window_left_of_screen() {
+        /* WX = 7 is the left edge, so the first 7 - WX columns are cut
+         * off; every other column is shown exactly once */
+        for wx in [0u8, 6] {
+            let colors = window_line_colors(wx);
+            for (x, color) in colors.iter().enumerate() {
+                assert_eq!(*color, (x + 7 - wx as usize) % 4,
+                           "WX = {}, x = {}", wx, x);
+            }
+        }
+    }
+
+    /* Runs `inc hl` with HL = 0xfe00 while the PPU is in the given mode,
+     * scanning OAM row @row */
+    #[cfg(feature = "accurate-oam-bug")]
+    fn inc_hl_in_oam(mode: Submode, row: u32) -> Vec<u8> {
+        /* ld hl, 0xfe00; inc hl; jr -2 */
+        let mut sys = test_system(test_rom(&[0x21, 0x00, 0xfe, 0x23,
+                                             0x18, 0xfe]));
+
+        for i in 0..0xa0 {
+            sys.poke(0xfe00 + i, i as u8);
+        }
+        /* Row 4, first word (b) and third word (c) */
+        sys.poke(0xfe20, 0x00);
+        sys.poke(0xfe21, 0xff);
+        sys.poke(0xfe24, 0x0f);
+        sys.poke(0xfe25, 0x0f);
+        /* Row 5, first word (a) */
+        sys.poke(0xfe28, 0x34);
+        sys.poke(0xfe29, 0x12);
+
+        /* nop; jp; ld hl */
+        for _ in 0..3 {
+            sys.step();
+        }
+        assert_eq!(sys.get_reg16(Reg16::HL), 0xfe00);
+
+        let stat = sys.sys_state.io_get_reg(IOReg::STAT);
+        sys.sys_state.io_set_reg(IOReg::STAT, (stat & !3) | mode as u8);
+        sys.sys_state.display.line_timer = row * 2;
+        sys.step();
+        assert_eq!(sys.get_reg16(Reg16::HL), 0xfe01);
+
+        (0..0xa0).map(|i| sys.peek(0xfe00 + i)).collect()
+    }
+
+    #[cfg(feature = "accurate-oam-bug")]
+    fn oam_before() -> Vec<u8> {
+        let mut oam: Vec<u8> = (0..0xa0).map(|i| i as u8).collect();
+        oam[0x20..0x22].copy_from_slice(&[0x00, 0xff]);
+        oam[0x24..0x26].copy_from_slice(&[0x0f, 0x0f]);
+        oam[0x28..0x2a].copy_from_slice(&[0x34, 0x12]);
+        oam
+    }
+
+    #[cfg(feature = "accurate-oam-bug")]
+    #[test]
+    fn oam_bug_corrupts_scanned_row() {
+        let oam = inc_hl_in_oam(Submode::OamOnly, 5);
+
+        /* ((a ^ c) & (b ^ c)) ^ c with a = 0x1234, b = 0xff00, c = 0x0f0f;
+         * the other three words come from the preceding row */
+        let mut expected = oam_before();
+        expected[0x28..0x30].copy_from_slice(&[0x04, 0x1f, 0x22, 0x23,
+                                               0x0f, 0x0f, 0x26, 0x27]);
+
+        assert_eq!(oam, expected);
+    }
+
+    #[cfg(feature = "accurate-oam-bug")]
+    #[test]
+    fn oam_bug_only_in_mode_2() {
+        assert_eq!(inc_hl_in_oam(Submode::HBlank, 5), oam_before());
+        /* Row 0 is never corrupted */
+        assert_eq!(inc_hl_in_oam(Submode::OamOnly, 0), oam_before());
+    }
+
+    fn lcdc_irq(sys: &mut TestSystem) -> bool {
+        let iflag = sys.sys_state.io_get_reg(IOReg::IF);
+        sys.sys_state.io_set_reg(IOReg::IF, 0);
+        iflag & (Irq::Lcdc as u8) != 0
+    }
+
+    #[test]
+    fn stat_irq_on_lyc() {
+        let mut sys = test_system(test_rom(&[0x18, 0xfe]));
+        sys.poke(0xff45, 10);
+        sys.poke(0xff41, 0x40);
+
+        run_to(&mut sys, 9, 0);
+        lcdc_irq(&mut sys);
+
+        run_to(&mut sys, 10, 2);
+        assert!(lcdc_irq(&mut sys));
+    }
+
+    #[test]
+    fn stat_irq_blocking() {
+        let mut sys = test_system(test_rom(&[0x18, 0xfe]));
+        sys.poke(0xff45, 10);
+        /* LYC and HBlank sources */
+        sys.poke(0xff41, 0x48);
+
+        run_to(&mut sys, 9, 0);
+        assert!(lcdc_irq(&mut sys));
+
+        /* HBlank still holds the line high when LY = LYC begins, and
+         * then LYC holds it through line 10's HBlank */
+        run_to(&mut sys, 10, 2);
+        assert!(!lcdc_irq(&mut sys));
+        run_to(&mut sys, 10, 0);
+        assert!(!lcdc_irq(&mut sys));
+
+        /* Low again in between, so the next HBlank raises it */
+        run_to(&mut sys, 11, 3);
+        assert!(!lcdc_irq(&mut sys));
+        run_to(&mut sys, 11, 0);
+        assert!(lcdc_irq(&mut sys));
+
+        /* Enabling another source while the line is high does nothing */
+        sys.poke(0xff41, 0x68);
+        assert!(!lcdc_irq(&mut sys));
+    }
+
+    /* Number of LCDC interrupts a ROM counts over one frame with the
+     * given STAT interrupt sources and LYC = 10 */
+    fn stat_irqs_per_frame(stat: u8) -> u8 {
+        /* ld a, 10; ldh (LYC), a; ld a, @stat; ldh (STAT), a;
+         * ld a, 0x02; ldh (IE), a; xor a; ldh (IF), a; ei;
+         * loop: halt; jr loop */
+        let mut rom = test_rom(&[0x3e, 10, 0xe0, 0x45, 0x3e, stat, 0xe0, 0x41,
+                                 0x3e, 0x02, 0xe0, 0xff, 0xaf, 0xe0, 0x0f,
+                                 0xfb, 0x76, 0x18, 0xfd]);
+        /* LCDC handler: push af; ldh a, (0x80); inc a; ldh (0x80), a;
+         * pop af; reti */
+        rom[0x48..0x50].copy_from_slice(&[0xf5, 0xf0, 0x80, 0x3c, 0xe0, 0x80,
+                                          0xf1, 0xd9]);
+        let mut sys = test_system(rom);
+
+        sys.main_loop(true);
+        sys.poke(0xff80, 0);
+        sys.main_loop(true);
+        sys.peek(0xff80)
+    }
+
+    #[test]
+    fn stat_irq_blocking_rom() {
+        /* Every HBlank */
+        assert_eq!(stat_irqs_per_frame(0x08), 144);
+        /* LY = LYC */
+        assert_eq!(stat_irqs_per_frame(0x40), 1);
+        /* Line 9's HBlank blocks the LYC interrupt, which in turn blocks
+         * line 10's HBlank interrupt (without blocking, this would be
+         * 145) */
+        assert_eq!(stat_irqs_per_frame(0x48), 143);
+    }
+
+    #[test]
+    fn dmg_game_on_cgb_gets_compat_palettes() {
+        let sys = test_system_as(test_rom(&[0x18, 0xfe]), Some(Model::Dmg));
+        assert_eq!(sys.sys_state.display.bg_palette[1], DMG_SHADES[1]);
+        drop(sys);
+
+        for model in [Model::Cgb, Model::Agb] {
+            let mut sys = test_system_as(test_rom(&[0x18, 0xfe]),
+                                         Some(model));
+            assert!(!sys.sys_state.cgb);
+
+            let d = &sys.sys_state.display;
+            assert_eq!(d.bg_palette[1], rgb15_to_rgb24(0x1bef));
+            assert_eq!(d.bg_palette[2], rgb15_to_rgb24(0x6180));
+            assert_eq!(d.obj_palette[1], rgb15_to_rgb24(0x421f));
+            /* OBP1 */
+            assert_eq!(d.obj_palette[5], rgb15_to_rgb24(0x421f));
+
+            /* Color correction applies, the DMG palette setting does not */
+            DisplayState::set_color_correction(&mut sys.sys_state,
+                                               ColorMode::Agb);
+            let corrected = sys.sys_state.display.cgb_rgb24(0x1bef);
+            assert_ne!(corrected, rgb15_to_rgb24(0x1bef));
+            assert_eq!(sys.sys_state.display.bg_palette[1], corrected);
+
+            sys.set_dmg_palette(DmgPalette::Pocket);
+            assert_eq!(sys.sys_state.display.bg_palette[1], corrected);
+        }
+    }
+}
@@ -0,0 +1,179 @@
+/*
+ * Debug view of the VRAM and OAM:
+ *
+ *   +--------------------+--------------------+
+ *   | Tile map 0x9800    | Tile map 0x9c00    |  256 lines
+ *   +---------+----------+--------------------+
+ *   | Tiles   | Tiles    | OAM (4 x 10)       |  192 lines
+ *   | bank 0  | bank 1   |                    |
+ *   +---------+----------+--------------------+
+ *
+ * Tiles are shown with BG palette 0, the maps with the current tile data
+ * area (and their attributes on the CGB).  The visible area of the current
+ * BG map is outlined.  Each OAM entry shows the object, its X and Y, and
+ * its tile and flags (in hex).
+ */
+
+use crate::io::IOSpace;
+use crate::io::lcd::{fetch_tile_flags, fetch_tile_obj_data,
+                     get_tile_data_and_pal, get_tile_obj_pixel};
+use crate::system_state::{IOReg, SystemState};
+
+
+pub const VRAM_VIEW_WIDTH: usize = 512;
+pub const VRAM_VIEW_HEIGHT: usize = 448;
+
+const BACKGROUND: u32 = 0xff202020;
+const TEXT: u32 = 0xffffffff;
+const VIEWPORT: u32 = 0xff0000ff;
+
+const OAM_CELL_WIDTH: usize = 64;
+const OAM_CELL_HEIGHT: usize = 19;
+
+/* 3x5 pixel hex digits, rows top to bottom, MSB left */
+const HEX_FONT: [u16; 16] = [
+    0x7b6f, 0x2c97, 0x73e7, 0x73cf, 0x5bc9, 0x79cf, 0x79ef, 0x7249,
+    0x7bef, 0x7bcf, 0x7bed, 0x6bae, 0x7927, 0x6b6e, 0x79e7, 0x79e4,
+];
+
+
+fn put(pixels: &mut [u32], x: usize, y: usize, color: u32) {
+    pixels[y * VRAM_VIEW_WIDTH + x] = color;
+}
+
+fn draw_hex(pixels: &mut [u32], x: usize, y: usize, val: u8) {
+    for (i, digit) in [val >> 4, val & 0xf].iter().enumerate() {
+        let glyph = HEX_FONT[*digit as usize];
+        for gy in 0..5 {
+            for gx in 0..3 {
+                if glyph & (1 << (14 - gy * 3 - gx)) != 0 {
+                    put(pixels, x + i * 4 + gx, y + gy, TEXT);
+                }
+            }
+        }
+    }
+}
+
+fn draw_maps(sys_state: &SystemState, pixels: &mut [u32]) {
+    let d = &sys_state.display;
+    let full_vram = &sys_state.addr_space.full_vram;
+    let tile_data_signed = d.tile_data == 0x1000;
+
+    for (map_i, tile_map) in [0x1800, 0x1c00].iter().enumerate() {
+        for tile in 0..(32 * 32) {
+            let flags = fetch_tile_flags(full_vram, *tile_map, tile,
+                                         sys_state.cgb);
+            let x0 = map_i * 256 + (tile % 32) * 8;
+            let y0 = (tile / 32) * 8;
+
+            for ry in 0..8 {
+                let (data, pal_bi) =
+                    get_tile_data_and_pal(full_vram, *tile_map, d.tile_data,
+                                          tile_data_signed, flags, tile, ry,
+                                          8, sys_state.cgb);
+
+                for rx in 0..8 {
+                    let val = get_tile_obj_pixel(data, rx, flags);
+                    let pal_i = d.bg_palette_mapping[val] as usize;
+                    put(pixels, x0 + rx as usize, y0 + ry,
+                        d.bg_palette[pal_bi + pal_i]);
+                }
+            }
+        }
+    }
+
+    /* The visible area, wrapping around */
+    let scx = sys_state.io_get_reg(IOReg::SCX) as usize;
+    let scy = sys_state.io_get_reg(IOReg::SCY) as usize;
+    let map_x = if d.bg_tile_map == 0x1c00 { 256 } else { 0 };
+
+    for i in 0..160 {
+        let x = map_x + (scx + i) % 256;
+        put(pixels, x, scy, VIEWPORT);
+        put(pixels, x, (scy + 143) % 256, VIEWPORT);
+    }
+    for i in 0..144 {
+        let y = (scy + i) % 256;
+        put(pixels, map_x + scx, y, VIEWPORT);
+        put(pixels, map_x + (scx + 159) % 256, y, VIEWPORT);
+    }
+}
+
+fn draw_tiles(sys_state: &SystemState, pixels: &mut [u32]) {
+    let d = &sys_state.display;
+    let full_vram = &sys_state.addr_space.full_vram;
+    let banks = if sys_state.cgb { 2 } else { 1 };
+
+    for bank in 0..banks {
+        for tile in 0..384 {
+            let ofs = bank * 0x2000 + tile * 16;
+            let x0 = bank * 128 + (tile % 16) * 8;
+            let y0 = 256 + (tile / 16) * 8;
+
+            for ry in 0..8 {
+                let data = fetch_tile_obj_data(full_vram, ofs, 0, ry, 8);
+                for rx in 0..8 {
+                    let val = get_tile_obj_pixel(data, rx, 0);
+                    let pal_i = d.bg_palette_mapping[val] as usize;
+                    put(pixels, x0 + rx as usize, y0 + ry,
+                        d.bg_palette[pal_i]);
+                }
+            }
+        }
+    }
+}
+
+fn draw_oam(sys_state: &SystemState, pixels: &mut [u32]) {
+    let d = &sys_state.display;
+    let full_vram = &sys_state.addr_space.full_vram;
+    let oam = unsafe {
+        std::slice::from_raw_parts(sys_state.addr_space.raw_ptr(0xfe00), 0xa0)
+    };
+
+    for (i, obj) in oam.chunks_exact(4).enumerate() {
+        let (y, x, tile, flags) = (obj[0], obj[1], obj[2], obj[3]);
+        let cx = 256 + (i % 4) * OAM_CELL_WIDTH;
+        let cy = 256 + (i / 4) * OAM_CELL_HEIGHT;
+
+        let mut ofs = tile as usize * 16;
+        if d.obj_height == 16 {
+            ofs &= !0x1f;
+        }
+
+        let (data_ofs, pal_bi) =
+            if sys_state.cgb {
+                (if flags & (1 << 3) != 0 { 0x2000 } else { 0 },
+                 (flags as usize & 7) * 4)
+            } else {
+                (0,
+                 ((flags as usize >> 4) & 1) * 4)
+            };
+
+        for ry in 0..d.obj_height {
+            let data = fetch_tile_obj_data(full_vram, data_ofs + ofs, flags,
+                                           ry, d.obj_height);
+            for rx in 0..8 {
+                let val = get_tile_obj_pixel(data, rx, flags);
+                if val != 0 {
+                    let pal_i = d.obj_palette_mapping[val] as usize;
+                    put(pixels, cx + 1 + rx as usize, cy + 1 + ry,
+                        d.obj_palette[pal_bi + pal_i]);
+                }
+            }
+        }
+
+        draw_hex(pixels, cx + 12, cy + 2, x);
+        draw_hex(pixels, cx + 24, cy + 2, y);
+        draw_hex(pixels, cx + 12, cy + 10, tile);
+        draw_hex(pixels, cx + 24, cy + 10, flags);
+    }
+}
+
+/* @pixels must be VRAM_VIEW_WIDTH x VRAM_VIEW_HEIGHT */
+pub fn render(sys_state: &SystemState, pixels: &mut [u32]) {
+    pixels.fill(BACKGROUND);
+
+    draw_maps(sys_state, pixels);
+    draw_tiles(sys_state, pixels);
+    draw_oam(sys_state, pixels);
+}
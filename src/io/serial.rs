@@ -1,3 +1,6 @@
+#[cfg(not(target_arch = "wasm32"))]
+mod printer;
+
 use std::io::{Read, Write};
 use std::sync::atomic::{AtomicU8, Ordering};
 
@@ -7,9 +10,29 @@ use crate::io::int::Irq;
 use crate::system_state::{IOReg, SystemState};
 use crate::ui::UI;
 
+#[cfg(not(target_arch = "wasm32"))]
+use printer::GbPrinter;
+
 
 const LINK_PORT: u16 = 0x9bc1u16; /* xgbc link */
 
+/*
+ * Link protocol over TCP: Every message is two bytes, a type followed by
+ * an SB value.
+ *   LINK_MSG_XFER:  The sender has started a transfer with the internal
+ *                   clock; data is its SB.
+ *   LINK_MSG_REPLY: Answer to an XFER from a side waiting with the
+ *                   external clock; data is its SB.
+ * An XFER is only answered once the receiving side has started a
+ * transfer with the external clock.  If both sides use the internal
+ * clock, neither answers, and both time out.
+ */
+const LINK_MSG_XFER: u8 = 0x01;
+const LINK_MSG_REPLY: u8 = 0x02;
+
+/* How long to wait for a reply before shifting in 1s (roughly 100 ms) */
+const LINK_TIMEOUT_CYCLES: u32 = 2097152 / 10;
+
 
 pub enum SerialConnParam {
     Disabled,
@@ -18,6 +41,9 @@ pub enum SerialConnParam {
     LocalSHM(usize),
     Client(String),
     Server(String),
+    /* Game Boy Printer, writing printouts into the given directory */
+    #[cfg(not(target_arch = "wasm32"))]
+    Printer(String),
 }
 
 impl SerialConnParam {
@@ -36,10 +62,18 @@ struct SerialSHM {
 pub struct SerialState {
     con: Option<std::net::TcpStream>,
     server: Option<std::net::TcpListener>,
+    /* Partially received message */
+    rx_buf: [u8; 2],
+    rx_len: usize,
+    /* Cycles spent waiting for a LINK_MSG_REPLY */
+    link_wait: Option<u32>,
 
     /* FIXME: Atomics */
     shm: Option<SerialSHM>,
 
+    #[cfg(not(target_arch = "wasm32"))]
+    printer: Option<GbPrinter>,
+
     cycles_rem: Option<u32>,
 }
 
@@ -66,9 +100,42 @@ impl SerialState {
                     return Some(SerialState {
                         con: None,
                         server: None,
+                        rx_buf: [0; 2],
+                        rx_len: 0,
+                        link_wait: None,
 
                         shm: Some(SerialSHM { remote_sb, remote_sc, remote_if }),
 
+                        #[cfg(not(target_arch = "wasm32"))]
+                        printer: None,
+
+                        cycles_rem: None,
+                    });
+                },
+
+                #[cfg(not(target_arch = "wasm32"))]
+                SerialConnParam::Printer(dir) => {
+                    let printer =
+                        match GbPrinter::new(dir.clone()) {
+                            Ok(printer) => printer,
+                            Err(e) => {
+                                ui.osd_message(format!("Failed to set up \
+                                                        printer: {}", e));
+                                return None;
+                            },
+                        };
+
+                    return Some(SerialState {
+                        con: None,
+                        server: None,
+                        rx_buf: [0; 2],
+                        rx_len: 0,
+                        link_wait: None,
+
+                        shm: None,
+
+                        printer: Some(printer),
+
                         cycles_rem: None,
                     });
                 },
@@ -106,6 +173,10 @@ impl SerialState {
                 SerialConnParam::LocalSHM(_) =>
                     unreachable!(),
 
+                #[cfg(not(target_arch = "wasm32"))]
+                SerialConnParam::Printer(_) =>
+                    unreachable!(),
+
                 SerialConnParam::LocalAuto | SerialConnParam::Server(_) =>
                     ui.osd_message(String::from("Failed to set up link server")),
 
@@ -119,9 +190,15 @@ impl SerialState {
         Some(SerialState {
             con,
             server,
+            rx_buf: [0; 2],
+            rx_len: 0,
+            link_wait: None,
 
             shm: None,
 
+            #[cfg(not(target_arch = "wasm32"))]
+            printer: None,
+
             cycles_rem: None,
         })
     }
@@ -146,80 +223,167 @@ impl SerialState {
     }
 
     fn try_recv(&mut self, addr_space: &mut AddressSpace) {
-        if let Some(con) = self.con.as_mut() {
-            let mut recv_data = [0u8];
+        let sc = addr_space.io_get_reg(IOReg::SC);
 
-            let result = con.read(&mut recv_data);
+        /* No transfer in progress (anymore), so nothing to complete */
+        if sc & 0x80 == 0 {
+            return;
+        }
 
-            let recv_count =
-                match result {
-                    Ok(count) => Some(count),
-                    Err(ref err) => {
-                        if err.kind() == std::io::ErrorKind::WouldBlock {
-                            Some(0)
-                        } else {
-                            None
-                        }
-                    },
-                };
+        let internal_clock = sc & 0x01 != 0;
 
-            if recv_count == Some(1) {
-                let sc = addr_space.io_get_reg(IOReg::SC);
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(printer) = self.printer.as_mut() {
+            /* The printer never drives the clock */
+            if internal_clock {
+                let sb = addr_space.io_get_reg(IOReg::SB);
+                Self::complete_transfer(addr_space, printer.exchange(sb));
+            }
+            return;
+        }
 
-                if sc & 0x01 == 0 {
-                    let send_data = [addr_space.io_get_reg(IOReg::SB)];
-                    if con.write_all(&send_data).is_err() {
-                        /* TODO: Print error somewhere */
-                        self.conn_down();
-                    }
-                }
+        if self.con.is_some() {
+            if internal_clock {
+                self.try_recv_reply(addr_space);
+            } else {
+                self.try_recv_xfer(addr_space);
+            }
+        } else if let Some(shm) = self.shm.as_mut() {
+            if internal_clock {
+                let sb = addr_space.io_get_reg(IOReg::SB);
+
+                let rsc = shm.remote_sc.load(Ordering::Relaxed);
+                let rsb =
+                    if rsc & 0x81 == 0x80 {
+                        let rsb = shm.remote_sb.swap(sb, Ordering::Relaxed);
+                        shm.remote_sc.store(rsc & 0x02, Ordering::Release);
 
-                addr_space.io_set_reg(IOReg::SB, recv_data[0]);
-                addr_space.io_set_reg(IOReg::SC, sc & !0x80);
+                        shm.remote_if.fetch_or(Irq::Serial as u8,
+                                               Ordering::AcqRel);
 
-                let iflag = addr_space.io_get_reg(IOReg::IF);
-                addr_space.io_set_reg(IOReg::IF, iflag | (Irq::Serial as u8));
-            } else if recv_count.is_none() {
-                /* TODO: Print this */
-                self.conn_down();
+                        println!("In: {:02x}; out: {:02x}", rsb, sb);
+                        rsb
+                    } else {
+                        0
+                    };
+
+                Self::complete_transfer(addr_space, rsb);
             }
-        } else if let Some(shm) = self.shm.as_mut() {
-            let sc = addr_space.io_get_reg(IOReg::SC);
+        } else if internal_clock {
+            /* Nobody on the other end, so we just shift in 1s; with an
+             * external clock, the transfer can never complete */
+            Self::complete_transfer(addr_space, 0xff);
+        }
+    }
+
+    /* We drive the clock, so wait for the peer's SB */
+    fn try_recv_reply(&mut self, addr_space: &mut AddressSpace) {
+        loop {
+            match self.recv_msg() {
+                Some((LINK_MSG_REPLY, data)) => {
+                    self.link_wait = None;
+                    Self::complete_transfer(addr_space, data);
+                    return;
+                },
+
+                /* The peer uses the internal clock, too, so it will not
+                 * answer; and neither do we */
+                Some(_) => (),
+
+                None => break,
+            }
+        }
+
+        let waited = self.link_wait.unwrap_or(0);
+        if self.con.is_none() || waited >= LINK_TIMEOUT_CYCLES {
+            self.link_wait = None;
+            Self::complete_transfer(addr_space, 0xff);
+        } else {
+            /* Peer has not answered yet, keep polling */
+            self.link_wait = Some(waited);
+            self.cycles_rem = Some(0);
+        }
+    }
 
-            if sc & 0x81 == 0x81 {
+    /* The peer drives the clock, so wait for it to start a transfer */
+    fn try_recv_xfer(&mut self, addr_space: &mut AddressSpace) {
+        while let Some((msg, data)) = self.recv_msg() {
+            /* Replies can only be stale here (from timed-out transfers) */
+            if msg == LINK_MSG_XFER {
+                /* The peer has clocked in all 8 bits, so it gets our SB in
+                 * exchange */
                 let sb = addr_space.io_get_reg(IOReg::SB);
+                self.send_msg(LINK_MSG_REPLY, sb);
 
-                let rsc = shm.remote_sc.load(Ordering::Relaxed);
-                if rsc & 0x81 == 0x80 {
-                    let rsb = shm.remote_sb.swap(sb, Ordering::Relaxed);
-                    shm.remote_sc.store(rsc & 0x02, Ordering::Release);
+                Self::complete_transfer(addr_space, data);
+                return;
+            }
+        }
+    }
 
-                    shm.remote_if.fetch_or(Irq::Serial as u8,
-                                           Ordering::AcqRel);
+    fn send_msg(&mut self, msg: u8, data: u8) {
+        if let Some(con) = self.con.as_mut() {
+            if con.write_all(&[msg, data]).is_err() {
+                /* TODO: Print error somewhere */
+                self.conn_down();
+            }
+        }
+    }
 
-                    addr_space.io_set_reg(IOReg::SB, rsb);
+    /* Returns a complete message, if one is available */
+    fn recv_msg(&mut self) -> Option<(u8, u8)> {
+        let con = self.con.as_mut()?;
 
-                    println!("In: {:02x}; out: {:02x}", rsb, sb);
-                } else {
-                    addr_space.io_set_reg(IOReg::SB, 0);
-                }
+        while self.rx_len < self.rx_buf.len() {
+            match con.read(&mut self.rx_buf[self.rx_len..]) {
+                Ok(0) => {
+                    /* TODO: Print this */
+                    self.conn_down();
+                    return None;
+                },
+
+                Ok(count) => self.rx_len += count,
 
-                addr_space.io_set_reg(IOReg::SC, sc & !0x80);
+                Err(ref err)
+                    if err.kind() == std::io::ErrorKind::WouldBlock =>
+                    return None,
 
-                let iflag = addr_space.io_get_reg(IOReg::IF);
-                addr_space.io_set_reg(IOReg::IF,
-                                      iflag | (Irq::Serial as u8));
+                Err(_) => {
+                    /* TODO: Print this */
+                    self.conn_down();
+                    return None;
+                },
             }
         }
+
+        self.rx_len = 0;
+        Some((self.rx_buf[0], self.rx_buf[1]))
+    }
+
+    /* Must only be called once per transfer, so the serial interrupt is
+     * raised exactly once */
+    fn complete_transfer(addr_space: &mut AddressSpace, sb: u8) {
+        addr_space.io_set_reg(IOReg::SB, sb);
+
+        let sc = addr_space.io_get_reg(IOReg::SC);
+        addr_space.io_set_reg(IOReg::SC, sc & !0x80);
+
+        let iflag = addr_space.io_get_reg(IOReg::IF);
+        addr_space.io_set_reg(IOReg::IF, iflag | (Irq::Serial as u8));
     }
 
     fn conn_down(&mut self) {
         if let Some(con) = self.con.take() {
             con.shutdown(std::net::Shutdown::Both).unwrap_or(());
         }
+        self.rx_len = 0;
     }
 
     pub fn add_cycles(&mut self, addr_space: &mut AddressSpace, dcycles: u32) {
+        if let Some(waited) = self.link_wait.as_mut() {
+            *waited = waited.saturating_add(dcycles);
+        }
+
         if let Some(cycles_rem) = self.cycles_rem {
             let (left, carry) = cycles_rem.overflowing_sub(dcycles);
             if carry {
@@ -247,38 +411,32 @@ pub fn serial_write(sys_state: &mut SystemState, addr: u16, mut val: u8)
 
             if let Some(serial) = sys_state.serial.as_mut() {
                 serial.cycles_rem = None;
+                serial.link_wait = None;
             }
 
             sys_state.io_set_reg(IOReg::SC, val & 0x83);
 
-            if val & 0x80 != 0 {
+            if val & 0x81 == 0x81 {
                 let sb = sys_state.io_get_reg(IOReg::SB);
 
                 if let Some(serial) = sys_state.serial.as_mut() {
-                    if let Some(con) = serial.con.as_mut() {
-                        let mut recv_data = [0u8];
-                        /* Drain remote */
-                        while con.read(&mut recv_data).unwrap_or(0) == 1 {
+                    if serial.con.is_some() {
+                        /* Drop stale replies (and transfers from a peer
+                         * that uses the internal clock, too) */
+                        while serial.recv_msg().is_some() {
                         }
-                    }
 
-                    if val & 0x01 != 0 {
-                        if let Some(con) = serial.con.as_mut() {
-                            let send_data = [sb];
-                            if con.write_all(&send_data).is_err() {
-                                serial.conn_down();
-                            }
-                        }
-
-                        /* Takes 16 cycles of the shift clock
-                         * (8 before start, then 8 to transfer) */
-                        serial.cycles_rem = Some(
-                            if sys_state.cgb && (val & 0x02 != 0) {
-                                16 * 16
-                            } else {
-                                16 * 512
-                            } - 1);
+                        serial.send_msg(LINK_MSG_XFER, sb);
                     }
+
+                    /* Takes 16 cycles of the shift clock
+                     * (8 before start, then 8 to transfer) */
+                    serial.cycles_rem = Some(
+                        if sys_state.cgb && (val & 0x02 != 0) {
+                            16 * 16
+                        } else {
+                            16 * 512
+                        } - 1);
                 }
             }
         }
@@ -289,3 +447,129 @@ pub fn serial_write(sys_state: &mut SystemState, addr: u16, mut val: u8)
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    use super::{LINK_MSG_REPLY, LINK_MSG_XFER, SerialState};
+    use crate::io::IOSpace;
+    use crate::io::int::Irq;
+    use crate::system_state::IOReg;
+    use crate::testing::{TestSystem, test_rom, test_system};
+
+    /* di; jr -2 */
+    const SPIN: [u8; 3] = [0xf3, 0x18, 0xfe];
+
+    /* Connects the system's link port to a socket that plays the peer */
+    fn linked_system() -> (TestSystem, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (con, _) = listener.accept().unwrap();
+
+        con.set_nodelay(true).unwrap();
+        con.set_nonblocking(true).unwrap();
+        peer.set_nodelay(true).unwrap();
+
+        let mut sys = test_system(test_rom(&SPIN));
+        sys.sys_state.serial = Some(SerialState {
+            con: Some(con),
+            server: None,
+            rx_buf: [0; 2],
+            rx_len: 0,
+            link_wait: None,
+
+            shm: None,
+
+            #[cfg(not(target_arch = "wasm32"))]
+            printer: None,
+
+            cycles_rem: None,
+        });
+
+        (sys, peer)
+    }
+
+    fn run_until_done(sys: &mut TestSystem) {
+        for _ in 0..1000000 {
+            if sys.sys_state.io_get_reg(IOReg::SC) & 0x80 == 0 {
+                return;
+            }
+
+            sys.step();
+            let ss = &mut sys.sys_state;
+            ss.serial.as_mut().unwrap().check_remote(&mut ss.addr_space);
+        }
+        panic!("Serial transfer did not complete");
+    }
+
+    fn recv_msg(peer: &mut TcpStream) -> [u8; 2] {
+        let mut msg = [0u8; 2];
+        peer.read_exact(&mut msg).unwrap();
+        msg
+    }
+
+    fn serial_irq(sys: &TestSystem) -> bool {
+        sys.sys_state.io_get_reg(IOReg::IF) & (Irq::Serial as u8) != 0
+    }
+
+    /* The transfer must have raised the serial interrupt, and must not
+     * raise it again later on */
+    fn assert_irq_once(sys: &mut TestSystem) {
+        assert!(serial_irq(sys));
+        sys.sys_state.io_set_reg(IOReg::IF, 0);
+
+        for _ in 0..100000 {
+            sys.step();
+            let ss = &mut sys.sys_state;
+            ss.serial.as_mut().unwrap().check_remote(&mut ss.addr_space);
+        }
+        assert!(!serial_irq(sys));
+    }
+
+    #[test]
+    fn trade_byte_with_internal_clock() {
+        let (mut sys, mut peer) = linked_system();
+
+        sys.poke(0xff01, 0x42);
+        sys.poke(0xff02, 0x81);
+        assert_eq!(recv_msg(&mut peer), [LINK_MSG_XFER, 0x42]);
+
+        peer.write_all(&[LINK_MSG_REPLY, 0x99]).unwrap();
+        run_until_done(&mut sys);
+
+        assert_eq!(sys.sys_state.io_get_reg(IOReg::SB), 0x99);
+        assert_irq_once(&mut sys);
+    }
+
+    #[test]
+    fn trade_byte_with_external_clock() {
+        let (mut sys, mut peer) = linked_system();
+
+        sys.poke(0xff01, 0x24);
+        sys.poke(0xff02, 0x80);
+
+        peer.write_all(&[LINK_MSG_XFER, 0x77]).unwrap();
+        run_until_done(&mut sys);
+
+        assert_eq!(recv_msg(&mut peer), [LINK_MSG_REPLY, 0x24]);
+        assert_eq!(sys.sys_state.io_get_reg(IOReg::SB), 0x77);
+        assert_irq_once(&mut sys);
+    }
+
+    #[test]
+    fn silent_peer_times_out_with_ones() {
+        let (mut sys, mut peer) = linked_system();
+
+        sys.poke(0xff01, 0x42);
+        sys.poke(0xff02, 0x81);
+        assert_eq!(recv_msg(&mut peer), [LINK_MSG_XFER, 0x42]);
+
+        run_until_done(&mut sys);
+
+        assert_eq!(sys.sys_state.io_get_reg(IOReg::SB), 0xff);
+        assert_irq_once(&mut sys);
+    }
+}
@@ -0,0 +1,303 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::screenshot::write_png;
+
+
+const MAGIC: [u8; 2] = [0x88, 0x33];
+
+const CMD_INIT: u8 = 0x01;
+const CMD_PRINT: u8 = 0x02;
+const CMD_DATA: u8 = 0x04;
+const CMD_STATUS: u8 = 0x0f;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_PRINTING: u8 = 0x02;
+const STATUS_DATA_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED: u8 = 0x08;
+
+/* Sent as the first of the two trailing bytes of each packet */
+const DEVICE_ID: u8 = 0x81;
+
+/* A single DATA packet holds at most two rows of 20 tiles */
+const MAX_DATA_LEN: usize = 0x280;
+/* The printer's RAM holds at most nine DATA packets */
+const MAX_IMAGE_LEN: usize = 9 * MAX_DATA_LEN;
+
+/* Number of STATUS packets for which we pretend to still be printing */
+const PRINT_BUSY_POLLS: u32 = 4;
+
+
+enum PacketPhase {
+    Magic(usize),
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    DeviceId,
+    Status,
+}
+
+/*
+ * The Game Boy Printer.  The game always drives the clock, sending
+ * packets of the form
+ *   88 33 | cmd | compression | length | data | checksum | 00 00
+ * (length and checksum being 16-bit little-endian)
+ * The printer answers the last two bytes with its device ID and its
+ * status, respectively.  The checksum is the sum of all bytes from cmd
+ * through the data.
+ */
+pub struct GbPrinter {
+    out_dir: String,
+
+    phase: PacketPhase,
+    command: u8,
+    compressed: bool,
+    length: usize,
+    data: Vec<u8>,
+    checksum: u16,
+    recv_checksum: u16,
+
+    status: u8,
+    busy_polls: u32,
+
+    /* 2bpp tile data, 20 tiles per row */
+    image: Vec<u8>,
+}
+
+
+impl GbPrinter {
+    pub fn new(out_dir: String) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&out_dir)?;
+
+        Ok(Self {
+            out_dir,
+
+            phase: PacketPhase::Magic(0),
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            recv_checksum: 0,
+
+            status: 0,
+            busy_polls: 0,
+
+            image: Vec::new(),
+        })
+    }
+
+    /* Takes a byte from the game and returns the byte shifted in
+     * exchange */
+    pub fn exchange(&mut self, val: u8) -> u8 {
+        let mut response = 0x00;
+
+        self.phase =
+            match self.phase {
+                PacketPhase::Magic(i) => {
+                    if val == MAGIC[i] {
+                        if i + 1 == MAGIC.len() {
+                            PacketPhase::Command
+                        } else {
+                            PacketPhase::Magic(i + 1)
+                        }
+                    } else if val == MAGIC[0] {
+                        PacketPhase::Magic(1)
+                    } else {
+                        PacketPhase::Magic(0)
+                    }
+                },
+
+                PacketPhase::Command => {
+                    self.command = val;
+                    self.checksum = val as u16;
+                    PacketPhase::Compression
+                },
+
+                PacketPhase::Compression => {
+                    self.compressed = val & 0x01 != 0;
+                    self.checksum = self.checksum.wrapping_add(val as u16);
+                    PacketPhase::LengthLow
+                },
+
+                PacketPhase::LengthLow => {
+                    self.length = val as usize;
+                    self.checksum = self.checksum.wrapping_add(val as u16);
+                    PacketPhase::LengthHigh
+                },
+
+                PacketPhase::LengthHigh => {
+                    self.length |= (val as usize) << 8;
+                    self.checksum = self.checksum.wrapping_add(val as u16);
+                    self.data.clear();
+
+                    if self.length > 0 {
+                        PacketPhase::Data
+                    } else {
+                        PacketPhase::ChecksumLow
+                    }
+                },
+
+                PacketPhase::Data => {
+                    self.data.push(val);
+                    self.checksum = self.checksum.wrapping_add(val as u16);
+
+                    if self.data.len() >= self.length {
+                        PacketPhase::ChecksumLow
+                    } else {
+                        PacketPhase::Data
+                    }
+                },
+
+                PacketPhase::ChecksumLow => {
+                    self.recv_checksum = val as u16;
+                    PacketPhase::ChecksumHigh
+                },
+
+                PacketPhase::ChecksumHigh => {
+                    self.recv_checksum |= (val as u16) << 8;
+                    PacketPhase::DeviceId
+                },
+
+                PacketPhase::DeviceId => {
+                    response = DEVICE_ID;
+                    PacketPhase::Status
+                },
+
+                PacketPhase::Status => {
+                    response = self.packet_done();
+                    PacketPhase::Magic(0)
+                },
+            };
+
+        response
+    }
+
+    /* Executes the packet just received, returns the status to report */
+    fn packet_done(&mut self) -> u8 {
+        if self.checksum != self.recv_checksum {
+            return self.status | STATUS_CHECKSUM_ERROR;
+        }
+
+        match self.command {
+            CMD_INIT => {
+                self.image.clear();
+                self.status = 0;
+                self.busy_polls = 0;
+            },
+
+            CMD_DATA => {
+                let data = std::mem::take(&mut self.data);
+                if self.compressed {
+                    Self::decompress_into(&data, &mut self.image);
+                } else {
+                    self.image.extend_from_slice(&data);
+                }
+                self.image.truncate(MAX_IMAGE_LEN);
+
+                if !self.image.is_empty() {
+                    self.status |= STATUS_UNPROCESSED;
+                }
+                if self.image.len() >= MAX_IMAGE_LEN {
+                    self.status |= STATUS_DATA_FULL;
+                }
+            },
+
+            CMD_PRINT => {
+                if self.data.len() >= 4 {
+                    let palette = self.data[2];
+                    self.print(palette);
+                }
+            },
+
+            CMD_STATUS => {
+                /* Report the current state first, then advance */
+                let status = self.status;
+
+                if self.busy_polls > 0 {
+                    self.busy_polls -= 1;
+                    if self.busy_polls == 0 {
+                        self.status &= !STATUS_PRINTING;
+                    }
+                }
+
+                return status;
+            },
+
+            _ => (),
+        }
+
+        self.status
+    }
+
+    /* Runs are encoded as 0x80 | (n - 2) followed by the byte to repeat,
+     * literal sequences as (n - 1) followed by the bytes */
+    fn decompress_into(data: &[u8], out: &mut Vec<u8>) {
+        let mut i = 0;
+
+        while i < data.len() {
+            let ctrl = data[i];
+            i += 1;
+
+            if ctrl & 0x80 != 0 {
+                let count = (ctrl & 0x7f) as usize + 2;
+                if let Some(val) = data.get(i) {
+                    out.extend(std::iter::repeat_n(*val, count));
+                }
+                i += 1;
+            } else {
+                let count = ctrl as usize + 1;
+                let end = std::cmp::min(i + count, data.len());
+                out.extend_from_slice(&data[i..end]);
+                i = end;
+            }
+        }
+    }
+
+    fn print(&mut self, palette: u8) {
+        self.status = (self.status & !STATUS_UNPROCESSED) | STATUS_PRINTING |
+                      STATUS_DATA_FULL;
+        self.busy_polls = PRINT_BUSY_POLLS;
+
+        /* Only complete tile rows (20 tiles of 16 bytes each) */
+        let rows = self.image.len() / (20 * 16);
+        if rows == 0 {
+            return;
+        }
+
+        /* Some games leave the palette at 0, meaning the default one */
+        let palette = if palette == 0 { 0xe4 } else { palette };
+
+        let width = 160;
+        let height = rows * 8;
+        let mut rgb8 = Vec::with_capacity(width * height * 3);
+
+        for y in 0..height {
+            for x in 0..width {
+                let tile = (y / 8) * 20 + x / 8;
+                let line = &self.image[tile * 16 + (y % 8) * 2..];
+                let bit = 7 - (x % 8);
+
+                let color = ((line[0] >> bit) & 1) |
+                            (((line[1] >> bit) & 1) << 1);
+                let shade = (palette >> (color * 2)) & 0x03;
+                let gray = 255 - shade * 85;
+
+                rgb8.extend_from_slice(&[gray, gray, gray]);
+            }
+        }
+        self.image.clear();
+
+        let stamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+                        .as_millis();
+        let path = format!("{}/print-{}.png", self.out_dir, stamp);
+
+        match write_png(&path, width as u32, height as u32, &rgb8) {
+            Ok(()) => eprintln!("Printed to {}", path),
+            Err(e) => eprintln!("Failed to write printout {}: {}", path, e),
+        }
+    }
+}
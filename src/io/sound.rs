@@ -1,5 +1,6 @@
 use std::cmp;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{channel, Sender, Receiver};
 
 use crate::address_space::AddressSpace;
@@ -7,19 +8,22 @@ use crate::io::IOSpace;
 use crate::system_state::{IOReg, SystemState};
 use crate::ui::AudioOutputParams;
 
+/* What we ask the audio driver for */
+const DEFAULT_SAMPLE_RATE: f32 = 44100.0;
+
 /*
- * We do real-time synchronization through audio, so we need at least
- * one sync point per frame (768 ~= 44100 / 60)
+ * Number of samples (two per frame) to feed the audio driver per sync
+ * point.  We do real-time synchronization through audio, so we need at
+ * least one sync point per frame; rounded up to a multiple of 128
+ * frames (768 at 44100 Hz).
  */
-
-/* Number of frames to feed the audio driver per sync point */
-const FRAMES: usize = 768;
-
-/* Number of samples to feed the audio driver per sync point */
-const SAMPLES: usize = FRAMES * 2;
+fn sync_samples(sample_rate: f32) -> usize {
+    let frames = (sample_rate / 60.0).ceil() as usize;
+    frames.div_ceil(128) * 128 * 2
+}
 
 /*
- * Number of buffers to use
+ * Number of buffers to use by default
  * 1: Generate a single buffer, wait for the audio driver to use it,
  *    then continue.
  * 2: Generate two buffers, wait for the audio driver to use the first
@@ -27,10 +31,68 @@ const SAMPLES: usize = FRAMES * 2;
  *    there is always one buffer in reserve.
  * (Higher numbers accordingly.)
  */
-const BUFCOUNT: usize = 2;
+pub const DEFAULT_BUFCOUNT: usize = 2;
 
-/* Total sample count of all sound buffers */
-const BUFSZ: usize = SAMPLES * BUFCOUNT;
+/*
+ * How the CPU can access wave RAM while CH3 plays: On the DMG, only while
+ * the channel reads it, and on the CGB, always (but both only get the byte
+ * being played)
+ */
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum WaveRamAccess {
+    /* Like the emulated model */
+    Auto,
+    Dmg,
+    Cgb,
+}
+
+/* Receives a copy of everything that is output */
+#[cfg(not(target_arch = "wasm32"))]
+pub type WavSink = hound::WavWriter<std::io::BufWriter<std::fs::File>>;
+
+/*
+ * Wave RAM contents after power-on.  On the DMG, these are actually
+ * random and differ between units, so this is just one sample dump.
+ * The CGB reliably initializes it to this pattern.
+ */
+const DMG_WAVE_RAM: [u8; 16] = [
+    0x84, 0x40, 0x43, 0xaa, 0x2d, 0x78, 0x92, 0x3c,
+    0x60, 0x59, 0x59, 0xb0, 0x34, 0xb8, 0x2e, 0xda,
+];
+const CGB_WAVE_RAM: [u8; 16] = [
+    0x00, 0xff, 0x00, 0xff, 0x00, 0xff, 0x00, 0xff,
+    0x00, 0xff, 0x00, 0xff, 0x00, 0xff, 0x00, 0xff,
+];
+
+/*
+ * The output goes through a capacitor, i.e. an RC high-pass that removes
+ * the DC offset.  Its cutoff differs between models.
+ */
+#[derive(Clone, Copy, PartialEq)]
+pub enum HighPassModel {
+    Dmg,
+    Cgb,
+}
+
+impl HighPassModel {
+    /*
+     * Fraction of the capacitor's charge (relative to the input) that is
+     * kept per 4 MHz clock: DMG 0.999958 (about 28 Hz cutoff), CGB
+     * 0.998943 (about 710 Hz)
+     */
+    fn clock_charge(self) -> f32 {
+        match self {
+            HighPassModel::Dmg => 0.999958,
+            HighPassModel::Cgb => 0.998943,
+        }
+    }
+
+    /* Charge kept per output frame */
+    fn charge(self, sample_rate: f32) -> f32 {
+        self.clock_charge().powf(4194304.0 / sample_rate)
+    }
+}
 
 
 #[derive(SaveState)]
@@ -50,9 +112,27 @@ impl SharedState {
     }
 }
 
+/*
+ * Correction for a unit step (-1 to 1) at phase 0, with @t being the
+ * current phase and @dt the phase increment per sample
+ */
+fn polyblep(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let t = t / dt;
+        2.0 * t - t * t - 1.0
+    } else if t > 1.0 - dt {
+        let t = (t - 1.0) / dt;
+        t * t + 2.0 * t + 1.0
+    } else {
+        0.0
+    }
+}
+
 #[derive(SaveState)]
 struct ToneSweep {
     channel: usize,
+    #[savestate(skip)]
+    sample_rate: f32,
     time: f32,
     enabled: bool,
 
@@ -80,12 +160,17 @@ struct ToneSweep {
     sweep_n: usize,
     sweep_time: f32,
     sweep_counter: f32,
+
+    /* Use PolyBLEP instead of a naive square wave */
+    #[savestate(skip)]
+    band_limited: bool,
 }
 
 impl ToneSweep {
-    fn new(channel: usize) -> Self {
+    fn new(channel: usize, sample_rate: f32) -> Self {
         let mut ts = Self {
             channel,
+            sample_rate,
             time: 0.0,
             enabled: false,
 
@@ -113,6 +198,8 @@ impl ToneSweep {
             sweep_n: 0,
             sweep_time: 0.0,
             sweep_counter: 0.0,
+
+            band_limited: false,
         };
 
         ts.update_freq(true);
@@ -143,12 +230,16 @@ impl ToneSweep {
         self.time = in_ofreq / self.freq;
     }
 
+    /* Writing NRx1 always loads the length counter, whether it is
+     * enabled (NRx4 bit 6) or not */
     fn update_len(&mut self) {
-        self.samples_limited = self.nrx4 & (1 << 6) != 0;
-        if self.samples_limited {
-            let x = self.nrx1 & 0x3f;
-            self.sample_count = ((64 - x) as f32 * (44100.0 / 256.0)) as usize;
-        }
+        let x = self.nrx1 & 0x3f;
+        self.sample_count =
+            ((64 - x) as f32 * (self.sample_rate / 256.0)) as usize;
+    }
+
+    fn dac_enabled(&self) -> bool {
+        self.nrx2 & 0xf8 != 0
     }
 
     fn update_duty(&mut self) {
@@ -167,7 +258,8 @@ impl ToneSweep {
         if self.nrx2 & 0x07 != 0 {
             self.env_enabled = true;
             self.env_amplify = self.nrx2 & 0x08 != 0;
-            self.env_len = (self.nrx2 & 0x07) as f32 * (44100.0 / 64.0);
+            self.env_len =
+                (self.nrx2 & 0x07) as f32 * (self.sample_rate / 64.0);
             self.env_counter = 0.0;
         } else {
             self.env_enabled = false;
@@ -183,19 +275,36 @@ impl ToneSweep {
         } else {
             self.sweep_enabled = true;
             self.sweep_up = self.nrx0 & (1 << 3) == 0;
-            self.sweep_time = time_x as f32 * (44100.0 / 128.0);
+            self.sweep_time = time_x as f32 * (self.sample_rate / 128.0);
             self.sweep_counter = 0.0;
         }
     }
 
     fn initialize(&mut self, addr_space: &mut AddressSpace) {
         self.update_envelope();
-        self.update_len();
         self.update_duty();
         self.update_freq(true);
         self.update_sweep();
 
-        self.set_enabled(addr_space, true);
+        /* Only an expired length counter is reloaded (to the maximum) */
+        self.samples_limited = self.nrx4 & (1 << 6) != 0;
+        if self.sample_count == 0 {
+            self.sample_count = (64.0 * (self.sample_rate / 256.0)) as usize;
+        }
+
+        /* With a non-zero shift, the sweep unit immediately calculates
+         * the next frequency and disables the channel on overflow */
+        let sweep_n = (self.nrx0 & 0x07) as usize;
+        if sweep_n != 0 && self.nrx0 & (1 << 3) == 0 &&
+           self.freq_x + (self.freq_x >> sweep_n) >= 2048
+        {
+            self.set_enabled(addr_space, false);
+            return;
+        }
+
+        /* Triggering does not enable a channel whose DAC is off */
+        let dac_enabled = self.dac_enabled();
+        self.set_enabled(addr_space, dac_enabled);
     }
 
     fn get_sample(&mut self, addr_space: &mut AddressSpace) -> f32 {
@@ -253,11 +362,21 @@ impl ToneSweep {
             }
         }
 
-        self.time += 1.0 / 44100.0;
+        self.time += 1.0 / self.sample_rate;
         let in_freq = (self.time * self.freq).fract();
         self.time = in_freq / self.freq;
 
-        if in_freq >= self.duty { self.vol } else { 0.0 }
+        let naive = if in_freq >= self.duty { self.vol } else { 0.0 };
+        if !self.band_limited {
+            return naive;
+        }
+
+        /* Smooth out the rising edge (at the duty value) and the falling
+         * edge (at 0) over one sample each */
+        let dt = (self.freq / self.sample_rate).min(0.5);
+        let rising = (in_freq - self.duty).rem_euclid(1.0);
+
+        naive + 0.5 * self.vol * (polyblep(rising, dt) - polyblep(in_freq, dt))
     }
 }
 
@@ -265,6 +384,8 @@ impl ToneSweep {
 #[derive(SaveState)]
 struct Wave {
     channel: usize,
+    #[savestate(skip)]
+    sample_rate: f32,
     enabled: bool,
     soft_stopped: bool,
 
@@ -285,12 +406,24 @@ struct Wave {
 
     out_sample_count: usize,
     out_samples_limited: bool,
+
+    /* Position in wave RAM in emulated time (sample_i follows the output
+     * samples instead), for CPU accesses while playing */
+    #[savestate(skip_if("version < 17"))]
+    ram_pos: usize,
+    /* 2 MHz ticks since ram_pos last changed */
+    #[savestate(skip_if("version < 17"))]
+    ram_pos_ticks: u32,
+    /* Whether ram_pos has been read yet (not right after a trigger) */
+    #[savestate(skip_if("version < 17"))]
+    ram_pos_read: bool,
 }
 
 impl Wave {
-    fn new(channel: usize) -> Self {
+    fn new(channel: usize, sample_rate: f32) -> Self {
         Self {
             channel,
+            sample_rate,
             enabled: false,
             soft_stopped: false,
 
@@ -311,6 +444,10 @@ impl Wave {
 
             out_sample_count: 0,
             out_samples_limited: false,
+
+            ram_pos: 0,
+            ram_pos_ticks: 0,
+            ram_pos_read: false,
         }
     }
 
@@ -332,11 +469,8 @@ impl Wave {
     }
 
     fn update_len(&mut self) {
-        self.out_samples_limited = self.nrx4 & (1 << 6) != 0;
-        if self.out_samples_limited {
-            self.out_sample_count = ((256 - self.nrx1 as u32) as f32 *
-                                    (44100.0 / 256.0)) as usize;
-        }
+        self.out_sample_count = ((256 - self.nrx1 as u32) as f32 *
+                                (self.sample_rate / 256.0)) as usize;
     }
 
     fn update_vol(&mut self) {
@@ -360,12 +494,66 @@ impl Wave {
         }
     }
 
+    /* @dcycles: 2 MHz ticks; the channel reads one sample per
+     * 2048 - frequency ticks */
+    fn add_cycles(&mut self, dcycles: u32) {
+        if !self.enabled {
+            return;
+        }
+
+        let freq_x = (self.nrx3 as u32) | ((self.nrx4 as u32 & 0x07) << 8);
+        let period = 2048 - freq_x;
+
+        self.ram_pos_ticks += dcycles;
+        while self.ram_pos_ticks >= period {
+            self.ram_pos_ticks -= period;
+            self.ram_pos = (self.ram_pos + 1) % 32;
+            self.ram_pos_read = true;
+        }
+    }
+
+    /*
+     * While playing, the channel keeps the wave RAM busy, so the CPU can
+     * only access the byte being played, regardless of @addr.  On the
+     * DMG, not even that, except right when the channel reads it (here:
+     * within the same M-cycle, i.e. two 2 MHz ticks).  Returns None if
+     * the access goes nowhere.
+     */
+    fn cpu_wave_ram_addr(&self, addr: u16, dmg: bool) -> Option<u16> {
+        if !self.enabled {
+            return Some(addr);
+        }
+
+        if dmg && !(self.ram_pos_read && self.ram_pos_ticks < 2) {
+            return None;
+        }
+
+        Some(0x30 + (self.ram_pos / 2) as u16)
+    }
+
     fn initialize(&mut self, addr_space: &mut AddressSpace) {
-        self.update_len();
         self.update_freq();
         self.update_vol();
 
-        self.set_enabled(addr_space, true);
+        self.out_samples_limited = self.nrx4 & (1 << 6) != 0;
+        if self.out_sample_count == 0 {
+            self.out_sample_count = self.sample_rate as usize;
+        }
+
+        /* Restart from the beginning of the wave RAM */
+        self.sample_i = 0;
+        self.sample_counter = 0.0;
+        self.pull_regs(addr_space);
+
+        self.ram_pos = 0;
+        self.ram_pos_ticks = 0;
+        self.ram_pos_read = false;
+
+        self.soft_stopped = false;
+
+        /* NR30 bit 7 is the DAC switch */
+        let dac_enabled = self.nrx0 & (1 << 7) != 0;
+        self.set_enabled(addr_space, dac_enabled);
     }
 
     fn get_sample(&mut self, addr_space: &mut AddressSpace) -> f32 {
@@ -394,7 +582,7 @@ impl Wave {
                               & 0x0f;
         let mut in_sample_count = 1.0;
 
-        self.sample_counter += 1.0 / 44100.0;
+        self.sample_counter += 1.0 / self.sample_rate;
         while self.sample_counter >= self.sample_time {
             self.sample_i = (self.sample_i + 1) % 32;
             self.sample_counter -= self.sample_time;
@@ -417,6 +605,8 @@ impl Wave {
 #[derive(SaveState)]
 struct Noise {
     channel: usize,
+    #[savestate(skip)]
+    sample_rate: f32,
     enabled: bool,
 
     nrx1: u8,
@@ -437,19 +627,13 @@ struct Noise {
     env_amplify: bool,
     env_len: f32,
     env_counter: f32,
-
-    #[savestate(skip)]
-    last_raw_sample: f32,
-    #[savestate(skip)]
-    position: f32,
-    #[savestate(skip)]
-    velocity: f32,
 }
 
 impl Noise {
-    fn new(channel: usize) -> Self {
+    fn new(channel: usize, sample_rate: f32) -> Self {
         Self {
             channel,
+            sample_rate,
             enabled: false,
 
             nrx1: 0xff,
@@ -470,10 +654,6 @@ impl Noise {
             env_amplify: false,
             env_len: 0.0,
             env_counter: 0.0,
-
-            last_raw_sample: 0.0,
-            position: 0.0,
-            velocity: 0.0,
         }
     }
 
@@ -501,12 +681,16 @@ impl Noise {
         self.shift_time = (r as f32 * ((s + 1) as f32).exp2()) / 1048576.0;
     }
 
+    /* Writing NRx1 always loads the length counter, whether it is
+     * enabled (NRx4 bit 6) or not */
     fn update_len(&mut self) {
-        self.samples_limited = self.nrx4 & (1 << 6) != 0;
-        if self.samples_limited {
-            let x = self.nrx1 & 0x3f;
-            self.sample_count = ((64 - x) as f32 * (44100.0 / 256.0)) as usize;
-        }
+        let x = self.nrx1 & 0x3f;
+        self.sample_count =
+            ((64 - x) as f32 * (self.sample_rate / 256.0)) as usize;
+    }
+
+    fn dac_enabled(&self) -> bool {
+        self.nrx2 & 0xf8 != 0
     }
 
     fn update_envelope(&mut self) {
@@ -514,7 +698,8 @@ impl Noise {
         if self.nrx2 & 0x07 != 0 {
             self.env_enabled = true;
             self.env_amplify = self.nrx2 & 0x08 != 0;
-            self.env_len = (self.nrx2 & 0x07) as f32 * (44100.0 / 64.0);
+            self.env_len =
+                (self.nrx2 & 0x07) as f32 * (self.sample_rate / 64.0);
             self.env_counter = 0.0;
         } else {
             self.env_enabled = false;
@@ -523,9 +708,13 @@ impl Noise {
 
     fn initialize(&mut self, addr_space: &mut AddressSpace) {
         self.update_envelope();
-        self.update_len();
         self.update_freq();
 
+        self.samples_limited = self.nrx4 & (1 << 6) != 0;
+        if self.sample_count == 0 {
+            self.sample_count = (64.0 * (self.sample_rate / 256.0)) as usize;
+        }
+
         self.bits15 = self.nrx3 & (1 << 3) == 0;
         if self.bits15 {
             self.lfsr = 0x7fff;
@@ -534,7 +723,8 @@ impl Noise {
         }
         self.output_counter = 0.0;
 
-        self.set_enabled(addr_space, true);
+        let dac_enabled = self.dac_enabled();
+        self.set_enabled(addr_space, dac_enabled);
     }
 
     fn shift(&mut self) {
@@ -551,7 +741,7 @@ impl Noise {
         }
     }
 
-    fn get_raw_sample(&mut self, addr_space: &mut AddressSpace) -> f32 {
+    fn get_sample(&mut self, addr_space: &mut AddressSpace) -> f32 {
         if !self.enabled {
             return 0.0;
         }
@@ -589,7 +779,7 @@ impl Noise {
             }
         }
 
-        self.output_counter += 1.0 / 44100.0;
+        self.output_counter += 1.0 / self.sample_rate;
         while self.output_counter >= self.shift_time {
             self.shift();
             self.output_counter -= self.shift_time;
@@ -597,17 +787,6 @@ impl Noise {
 
         if self.lfsr & 1 != 0 { self.vol } else { 0.0 }
     }
-
-    fn get_sample(&mut self, addr_space: &mut AddressSpace) -> f32 {
-        let raw_sample = self.get_raw_sample(addr_space);
-        let diff = raw_sample - self.last_raw_sample;
-
-        self.last_raw_sample = raw_sample;
-        self.velocity = self.velocity * 0.5 + diff * 0.5;
-        self.position += self.velocity;
-
-        self.position
-    }
 }
 
 
@@ -621,6 +800,20 @@ pub struct SoundState {
     outbuf_done: Receiver<usize>,
     #[savestate(skip)]
     outbuf_done_handout: Option<Sender<usize>>,
+    /* Total sample count of all sound buffers */
+    #[savestate(skip)]
+    bufsz: usize,
+    #[savestate(skip)]
+    bufcount: usize,
+    /* See sync_samples() */
+    #[savestate(skip)]
+    buf_step: usize,
+    /* Samples handed to the audio driver so far */
+    #[savestate(skip)]
+    samples_written: Arc<AtomicUsize>,
+    /* Number of times the audio driver found no new buffer */
+    #[savestate(skip)]
+    underruns: Arc<AtomicUsize>,
 
     ibuf_i: usize,
     #[savestate(skip)]
@@ -639,21 +832,57 @@ pub struct SoundState {
     #[savestate(skip)]
     postprocess: bool,
 
+    /* Output frames per second */
+    #[savestate(skip)]
+    sample_rate: f32,
+    /* Emulated time per real time; the output keeps its pitch, so this
+     * speeds up the game while still synchronizing to the audio driver */
+    #[savestate(skip)]
+    speed: f32,
+
+    /*
+     * Channels muted by the user (bit n: CH(n+1)), independently of what
+     * the game does through NR51.  A user setting, so not part of save
+     * states.
+     */
+    #[savestate(skip)]
+    mute_mask: u8,
+
+    /* See WaveRamAccess */
+    #[savestate(skip)]
+    dmg_wave_ram: bool,
+
+    #[cfg(not(target_arch = "wasm32"))]
     #[savestate(skip)]
-    last_raw_sample: (f32, f32),
+    wav_sink: Option<WavSink>,
+    /* Set when writing to the sink failed (which drops it), so the
+     * recording can be stopped */
+    #[cfg(not(target_arch = "wasm32"))]
     #[savestate(skip)]
-    velocity: (f32, f32),
+    wav_error: Option<String>,
+
+    /* Charge of the output coupling capacitors (L, R) */
     #[savestate(skip)]
-    position: (f32, f32),
+    hpf_cap: (f32, f32),
+    /* How much charge remains after one output frame */
+    #[savestate(skip)]
+    hpf_charge: f32,
+    #[savestate(skip)]
+    hpf_model: HighPassModel,
 }
 
 impl SoundState {
-    pub fn new() -> Self {
+    /* @bufcount: See DEFAULT_BUFCOUNT */
+    pub fn new(bufcount: usize) -> Self {
+        let bufcount = bufcount.max(1);
+        let buf_step = sync_samples(DEFAULT_SAMPLE_RATE);
+        let bufsz = buf_step * bufcount;
+
         let mut outbuf = Vec::<f32>::new();
-        outbuf.resize(BUFSZ, 0.0);
+        outbuf.resize(bufsz, 0.0);
 
         let mut intbuf = Vec::<f32>::new();
-        intbuf.resize(BUFSZ, 0.0);
+        intbuf.resize(bufsz, 0.0);
 
         let (snd, rcv) = channel();
 
@@ -662,28 +891,82 @@ impl SoundState {
             intbuf,
             outbuf_done: rcv,
             outbuf_done_handout: Some(snd),
+            bufsz,
+            bufcount,
+            buf_step,
+            samples_written: Arc::new(AtomicUsize::new(0)),
+            underruns: Arc::new(AtomicUsize::new(0)),
 
             ibuf_i: 0,
             obuf_i: 0,
             ibuf_i_cycles: 0.0,
             shared: SharedState::new(),
 
-            ch1: ToneSweep::new(0),
-            ch2: ToneSweep::new(1),
-            ch3: Wave::new(2),
-            ch4: Noise::new(3),
+            ch1: ToneSweep::new(0, DEFAULT_SAMPLE_RATE),
+            ch2: ToneSweep::new(1, DEFAULT_SAMPLE_RATE),
+            ch3: Wave::new(2, DEFAULT_SAMPLE_RATE),
+            ch4: Noise::new(3, DEFAULT_SAMPLE_RATE),
 
             ch3_l: false,
             ch3_r: false,
 
             postprocess: false,
 
-            last_raw_sample: (0.0, 0.0),
-            velocity: (0.0, 0.0),
-            position: (0.0, 0.0),
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            speed: 1.0,
+
+            mute_mask: 0,
+
+            dmg_wave_ram: false,
+
+            #[cfg(not(target_arch = "wasm32"))]
+            wav_sink: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            wav_error: None,
+
+            hpf_cap: (0.0, 0.0),
+            hpf_charge: HighPassModel::Dmg.charge(DEFAULT_SAMPLE_RATE),
+            hpf_model: HighPassModel::Dmg,
         }
     }
 
+    /* Follows the hardware, not the mode the game runs in */
+    pub fn init_system_state(sys_state: &mut SystemState) {
+        let cgb_hw = sys_state.model.is_cgb();
+
+        let wave_ram =
+            if cgb_hw {
+                &CGB_WAVE_RAM
+            } else {
+                &DMG_WAVE_RAM
+            };
+
+        for (i, val) in wave_ram.iter().enumerate() {
+            sys_state.addr_space.io_set_addr(0x30 + i as u16, *val);
+        }
+        sys_state.sound.ch3.samples = *wave_ram;
+
+        let hpf_model =
+            if cgb_hw {
+                HighPassModel::Cgb
+            } else {
+                HighPassModel::Dmg
+            };
+        sys_state.sound.set_high_pass_model(hpf_model);
+    }
+
+    /* For a system reset; the output side (buffers, sample rate,
+     * postprocessing, muted channels, recording) stays as it is */
+    pub fn reset(sys_state: &mut SystemState) {
+        sys_state.sound.reset_regs(&mut sys_state.addr_space);
+        Self::init_system_state(sys_state);
+    }
+
+    pub fn set_high_pass_model(&mut self, model: HighPassModel) {
+        self.hpf_model = model;
+        self.hpf_charge = model.charge(self.sample_rate);
+    }
+
     fn reset_regs(&mut self, addr_space: &mut AddressSpace) {
         addr_space.io_set_reg(IOReg::NR10, 0x80);
         addr_space.io_set_reg(IOReg::NR11, 0xbf);
@@ -707,28 +990,85 @@ impl SoundState {
         self.ibuf_i = 0;
         self.ibuf_i_cycles = 0.0;
         self.shared = SharedState::new();
-        self.ch1 = ToneSweep::new(0);
-        self.ch2 = ToneSweep::new(1);
-        self.ch3 = Wave::new(2);
-        self.ch4 = Noise::new(3);
+        self.ch1 = ToneSweep::new(0, self.sample_rate);
+        self.ch2 = ToneSweep::new(1, self.sample_rate);
+        self.ch1.band_limited = self.postprocess;
+        self.ch2.band_limited = self.postprocess;
+        self.ch3 = Wave::new(2, self.sample_rate);
+        self.ch4 = Noise::new(3, self.sample_rate);
+    }
+
+    /*
+     * To be called with the rate the audio driver actually uses.  The
+     * buffer size follows the rate until the buffers have been handed to
+     * the driver (get_audio_params()); after that, only the timing does.
+     */
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        if self.outbuf_done_handout.is_some() {
+            self.buf_step = sync_samples(sample_rate);
+            self.bufsz = self.buf_step * self.bufcount;
+            self.outbuf.lock().unwrap().resize(self.bufsz, 0.0);
+            self.intbuf.resize(self.bufsz, 0.0);
+            self.ibuf_i %= self.bufsz;
+        }
+
+        self.sample_rate = sample_rate;
+        self.ch1.sample_rate = sample_rate;
+        self.ch2.sample_rate = sample_rate;
+        self.ch3.sample_rate = sample_rate;
+        self.ch4.sample_rate = sample_rate;
+        self.hpf_charge = self.hpf_model.charge(sample_rate);
+    }
+
+    /* Whether the CPU can access wave RAM only at the right time while
+     * CH3 plays */
+    pub fn set_dmg_wave_ram(&mut self, dmg: bool) {
+        self.dmg_wave_ram = dmg;
+    }
+
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = speed;
+    }
+
+    /* Save states may come from a run with a different buffer count */
+    pub fn post_import(&mut self) {
+        self.ibuf_i %= self.bufsz;
+    }
+
+    /* Delay between generating a sample and the audio driver getting it,
+     * when all buffers are queued */
+    pub fn latency_ms(&self) -> f32 {
+        (self.bufsz / 2) as f32 * 1000.0 / self.sample_rate
+    }
+
+    pub fn underruns(&self) -> usize {
+        self.underruns.load(Ordering::Relaxed)
     }
 
     pub fn get_audio_params(&mut self) -> AudioOutputParams {
         AudioOutputParams {
-            freq: 44100,
+            freq: self.sample_rate as usize,
             channels: 2,
 
             buf: self.outbuf.clone(),
-            buf_step: SAMPLES,
+            buf_step: self.buf_step,
             buf_done: self.outbuf_done_handout.take().unwrap(),
+            written: self.samples_written.clone(),
+            underruns: self.underruns.clone(),
         }
     }
 
     fn gen_one_frame(&mut self, addr_space: &mut AddressSpace) -> (f32, f32) {
-        let ch1 = self.ch1.get_sample(addr_space);
-        let ch2 = self.ch2.get_sample(addr_space);
-        let ch3 = self.ch3.get_sample(addr_space);
-        let ch4 = self.ch4.get_sample(addr_space);
+        /* Muted channels must still be run, so they keep their state */
+        let mm = self.mute_mask;
+        let unmuted = |i: usize, sample: f32| {
+            if mm & (1 << i) != 0 { 0.0 } else { sample }
+        };
+
+        let ch1 = unmuted(0, self.ch1.get_sample(addr_space));
+        let ch2 = unmuted(1, self.ch2.get_sample(addr_space));
+        let ch3 = unmuted(2, self.ch3.get_sample(addr_space));
+        let ch4 = unmuted(3, self.ch4.get_sample(addr_space));
 
         let cm = self.shared.channel_mask;
         let ch1_f = (if cm & (1 << 4) != 0 { ch1 } else { 0.0 },
@@ -752,42 +1092,43 @@ impl SoundState {
                     self.shared.rvol * 0.005
             );
 
-        let diff = (cht_f.0 - self.last_raw_sample.0,
-                    cht_f.1 - self.last_raw_sample.1);
-
-        let force = (diff.0 - self.position.0 * 0.04,
-                     diff.1 - self.position.1 * 0.04);
+        /* One-pole high-pass: The capacitor charges towards the input,
+         * and only the difference reaches the output */
+        let out = (cht_f.0 - self.hpf_cap.0,
+                   cht_f.1 - self.hpf_cap.1);
 
-        self.last_raw_sample.0 = cht_f.0;
-        self.last_raw_sample.1 = cht_f.1;
+        self.hpf_cap.0 = cht_f.0 - out.0 * self.hpf_charge;
+        self.hpf_cap.1 = cht_f.1 - out.1 * self.hpf_charge;
 
-        self.velocity.0 = self.velocity.0 * 0.1 + force.0 * 0.9;
-        self.velocity.1 = self.velocity.1 * 0.1 + force.1 * 0.9;
-
-        self.position.0 += self.velocity.0;
-        self.position.1 += self.velocity.1;
-
-        (self.position.0, self.position.1)
+        out
     }
 
-    /* @cycles must be in double-speed cycles */
+    /*
+     * @cycles must be in double-speed cycles.
+     * Returns true if we had to wait for the audio driver.
+     */
     pub fn add_cycles(&mut self, addr_space: &mut AddressSpace,
-                      cycles: u32, realtime: bool)
+                      cycles: u32, realtime: bool) -> bool
     {
+        let mut waited = false;
+
+        self.ch3.add_cycles(cycles);
         self.ibuf_i_cycles += cycles as f32;
 
-        while self.ibuf_i_cycles >= (2097152.0 / 44100.0) {
+        let cycles_per_frame = 2097152.0 * self.speed / self.sample_rate;
+        while self.ibuf_i_cycles >= cycles_per_frame {
             let (l, r) = self.gen_one_frame(addr_space);
 
             self.intbuf[self.ibuf_i] = l;
             self.intbuf[self.ibuf_i + 1] = r;
 
-            self.ibuf_i_cycles -= 2097152.0 / 44100.0;
-            self.ibuf_i = (self.ibuf_i + 2) % BUFSZ;
+            self.ibuf_i_cycles -= cycles_per_frame;
+            self.ibuf_i = (self.ibuf_i + 2) % self.bufsz;
 
-            if self.ibuf_i % SAMPLES == 0 {
-                let start = (self.ibuf_i + BUFSZ - SAMPLES) % BUFSZ;
-                let end = if self.ibuf_i == 0 { BUFSZ } else { self.ibuf_i };
+            if self.ibuf_i % self.buf_step == 0 {
+                let bufsz = self.bufsz;
+                let start = (self.ibuf_i + bufsz - self.buf_step) % bufsz;
+                let end = if self.ibuf_i == 0 { bufsz } else { self.ibuf_i };
 
                 {
                     let mut out_guard = self.outbuf.lock().unwrap();
@@ -795,10 +1136,16 @@ impl SoundState {
 
                     out[start..end].copy_from_slice(&self.intbuf[start..end]);
                 }
+                self.samples_written.fetch_add(self.buf_step,
+                                               Ordering::Release);
+
+                #[cfg(not(target_arch = "wasm32"))]
+                self.write_wav(start, end);
 
                 if self.ibuf_i == self.obuf_i {
                     self.obuf_i =
                         if realtime {
+                            waited = true;
                             self.outbuf_done.recv().unwrap()
                         } else {
                             self.outbuf_done.try_recv().unwrap_or(0)
@@ -806,6 +1153,8 @@ impl SoundState {
                 }
             }
         }
+
+        waited
     }
 
     #[cfg(target_arch = "wasm32")]
@@ -818,8 +1167,66 @@ impl SoundState {
         }
     }
 
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn wav_params(&self) -> hound::WavSpec {
+        hound::WavSpec {
+            channels: 2,
+            sample_rate: self.sample_rate as u32,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        }
+    }
+
+    /* Finalizes the previous sink, if any */
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn set_wav_sink(&mut self, sink: Option<WavSink>)
+        -> Result<(), String>
+    {
+        let old = std::mem::replace(&mut self.wav_sink, sink);
+        match old {
+            Some(old) => old.finalize().map_err(|e| e.to_string()),
+            None => Ok(()),
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn write_wav(&mut self, start: usize, end: usize) {
+        let sink =
+            match self.wav_sink.as_mut() {
+                Some(sink) => sink,
+                None => return,
+            };
+
+        /* Buffered, so this will not hold up the realtime sync */
+        let mut writer = sink.get_i16_writer((end - start) as u32);
+        for sample in &self.intbuf[start..end] {
+            writer.write_sample((sample.clamp(-1.0, 1.0) * 32767.0) as i16);
+        }
+
+        if let Err(e) = writer.flush() {
+            self.wav_error = Some(e.to_string());
+            self.wav_sink = None;
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn take_wav_error(&mut self) -> Option<String> {
+        self.wav_error.take()
+    }
+
+    /* Returns the new mute mask */
+    pub fn toggle_channel_mute(&mut self, channel: usize) -> u8 {
+        if channel < 4 {
+            self.mute_mask ^= 1 << channel;
+        }
+        self.mute_mask
+    }
+
+    /* For now, this only makes the tone channels band-limited */
     pub fn set_postprocessing(&mut self, postprocess: bool) {
         self.postprocess = postprocess;
+        self.ch1.band_limited = postprocess;
+        self.ch2.band_limited = postprocess;
     }
 }
 
@@ -830,7 +1237,8 @@ pub fn sound_write(sys_state: &mut SystemState, addr: u16, mut val: u8)
     let addr_space = &mut sys_state.addr_space;
     let nr52 = addr_space.io_get_reg(IOReg::NR52);
 
-    if nr52 & 0x80 == 0 && addr != 0x26 {
+    /* Wave RAM is not affected by the power switch */
+    if nr52 & 0x80 == 0 && addr != 0x26 && !(0x30..0x40).contains(&addr) {
         return;
     }
 
@@ -851,6 +1259,9 @@ pub fn sound_write(sys_state: &mut SystemState, addr: u16, mut val: u8)
         0x12 => {
             s.ch1.nrx2 = val;
             s.ch1.update_envelope();
+            if !s.ch1.dac_enabled() {
+                s.ch1.set_enabled(addr_space, false);
+            }
         },
 
         0x13 => {
@@ -886,6 +1297,9 @@ pub fn sound_write(sys_state: &mut SystemState, addr: u16, mut val: u8)
         0x17 => {
             s.ch2.nrx2 = val;
             s.ch2.update_envelope();
+            if !s.ch2.dac_enabled() {
+                s.ch2.set_enabled(addr_space, false);
+            }
         },
 
         0x18 => {
@@ -965,6 +1379,9 @@ pub fn sound_write(sys_state: &mut SystemState, addr: u16, mut val: u8)
         0x21 => {
             s.ch4.nrx2 = val;
             s.ch4.update_envelope();
+            if !s.ch4.dac_enabled() {
+                s.ch4.set_enabled(addr_space, false);
+            }
         },
 
         0x22 => {
@@ -1001,10 +1418,337 @@ pub fn sound_write(sys_state: &mut SystemState, addr: u16, mut val: u8)
             }
         },
 
-        0x30..=0x3f => (),
+        0x30..=0x3f => {
+            if let Some(addr) = s.ch3.cpu_wave_ram_addr(addr, s.dmg_wave_ram) {
+                s.ch3.samples[addr as usize - 0x30] = val;
+                addr_space.io_set_addr(addr, val);
+            }
+            return;
+        },
 
         _ => unreachable!(),
     }
 
     addr_space.io_set_addr(addr, val);
 }
+
+pub fn wave_ram_read(sys_state: &mut SystemState, addr: u16) -> u8 {
+    let s = &sys_state.sound;
+    match s.ch3.cpu_wave_ram_addr(addr, s.dmg_wave_ram) {
+        Some(addr) => sys_state.io_get_addr(addr),
+        None => 0xff,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{sync_samples, SoundState, ToneSweep};
+    use crate::testing::{test_rom, test_system, TestSystem};
+
+    /* Power spectrum (Hann window) of @samples at DFT bin @k */
+    fn bin_power(samples: &[f32], k: usize) -> f64 {
+        let n = samples.len();
+        let mean = samples.iter().map(|x| *x as f64).sum::<f64>() / n as f64;
+
+        let (mut re, mut im) = (0.0f64, 0.0f64);
+        for (i, x) in samples.iter().enumerate() {
+            let pos = i as f64 / n as f64;
+            let window = 0.5 - 0.5 * (2.0 * std::f64::consts::PI * pos).cos();
+            let phase = 2.0 * std::f64::consts::PI * (k as f64) * pos;
+            re += (*x as f64 - mean) * window * phase.cos();
+            im -= (*x as f64 - mean) * window * phase.sin();
+        }
+        re * re + im * im
+    }
+
+    /* (Energy at the fundamental, energy away from all harmonics) */
+    fn spectrum_energy(samples: &[f32], f0: f64, rate: f64) -> (f64, f64) {
+        let n = samples.len();
+        let bin_width = rate / n as f64;
+
+        let mut fundamental = 0.0;
+        let mut aliased = 0.0;
+        for k in 1..(n / 2) {
+            let freq = k as f64 * bin_width;
+            let harmonic = (freq / f0).round();
+            let distance = (freq - harmonic * f0).abs() / bin_width;
+
+            if distance >= 3.0 {
+                aliased += bin_power(samples, k);
+            } else if harmonic == 1.0 {
+                fundamental += bin_power(samples, k);
+            }
+        }
+        (fundamental, aliased)
+    }
+
+    fn tone_samples(band_limited: bool) -> (Vec<f32>, f64) {
+        let mut sys = test_system(test_rom(&[0x18, 0xfe]));
+        let addr_space = &mut sys.sys_state.addr_space;
+
+        let mut ch = ToneSweep::new(0, 44100.0);
+        /* About 2080 Hz, which does not divide the sample rate */
+        ch.nrx3 = (1985 & 0xff) as u8;
+        ch.nrx4 = (1985 >> 8) as u8;
+        ch.update_freq(true);
+        ch.duty = 0.5;
+        ch.vol = 15.0;
+        ch.enabled = true;
+        ch.band_limited = band_limited;
+
+        let samples = (0..2048).map(|_| ch.get_sample(addr_space)).collect();
+        (samples, ch.freq as f64)
+    }
+
+    #[test]
+    fn polyblep_reduces_aliasing() {
+        let (naive, f0) = tone_samples(false);
+        let (band_limited, _) = tone_samples(true);
+
+        let (naive_fund, naive_aliased) =
+            spectrum_energy(&naive, f0, 44100.0);
+        let (bl_fund, bl_aliased) =
+            spectrum_energy(&band_limited, f0, 44100.0);
+
+        /* The tone itself stays, the inharmonic energy goes down */
+        assert!(bl_fund > naive_fund * 0.8 && bl_fund < naive_fund * 1.2);
+        assert!(bl_aliased < naive_aliased * 0.5,
+                "naive: {}, band-limited: {}", naive_aliased, bl_aliased);
+    }
+
+    #[test]
+    fn buffers_follow_sample_rate() {
+        assert_eq!(sync_samples(44100.0), 768 * 2);
+        assert_eq!(sync_samples(48000.0), 896 * 2);
+
+        let mut sound = SoundState::new(3);
+        sound.set_sample_rate(48000.0);
+        assert_eq!(sound.intbuf.len(), 896 * 2 * 3);
+
+        let params = sound.get_audio_params();
+        assert_eq!(params.freq, 48000);
+        assert_eq!(params.buf_step, 896 * 2);
+        assert_eq!(params.buf.lock().unwrap().len(), 896 * 2 * 3);
+
+        /* The driver has the buffers now, so they must stay as they are */
+        sound.set_sample_rate(44100.0);
+        assert_eq!(sound.intbuf.len(), 896 * 2 * 3);
+    }
+
+    /* Rising zero crossings in one second of a 1 kHz square wave on CH1 */
+    fn square_crossings(sample_rate: f32) -> usize {
+        let mut sys = test_system(test_rom(&[0x18, 0xfe]));
+        sys.sys_state.sound.set_sample_rate(sample_rate);
+
+        /* 131072 / (2048 - 1917) = 1000.5 Hz */
+        let freq_x = 1917;
+        sys.poke(0xff26, 0x80);
+        sys.poke(0xff24, 0x77);
+        sys.poke(0xff25, 0x11);
+        sys.poke(0xff11, 0x80);
+        sys.poke(0xff12, 0xf0);
+        sys.poke(0xff13, (freq_x & 0xff) as u8);
+        sys.poke(0xff14, 0x80 | (freq_x >> 8) as u8);
+
+        let sys_state = &mut *sys.sys_state;
+        let sound = &mut sys_state.sound;
+        let samples: Vec<f32> = (0..sample_rate as usize)
+            .map(|_| sound.gen_one_frame(&mut sys_state.addr_space).0)
+            .collect();
+
+        samples.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count()
+    }
+
+    #[test]
+    fn pitch_independent_of_sample_rate() {
+        for rate in [44100.0, 48000.0] {
+            let crossings = square_crossings(rate);
+            assert!((998..=1002).contains(&crossings),
+                    "{} Hz: {} crossings", rate, crossings);
+        }
+    }
+
+    #[test]
+    fn power_on_state_follows_model() {
+        use super::{HighPassModel, CGB_WAVE_RAM, DMG_WAVE_RAM};
+        use crate::io::IOSpace;
+        use crate::system_state::Model;
+        use crate::testing::test_system_as;
+
+        for (model, cgb_hw) in [(Model::Dmg, false), (Model::Mgb, false),
+                                (Model::Sgb, false), (Model::Cgb, true),
+                                (Model::Agb, true)]
+        {
+            /* A DMG-only game, so the CGB ones run it in DMG mode */
+            let sys = test_system_as(test_rom(&[0x18, 0xfe]), Some(model));
+
+            let wave_ram: Vec<u8> =
+                (0x30..0x40).map(|a| sys.sys_state.io_get_addr(a)).collect();
+            let (pattern, hpf) =
+                if cgb_hw {
+                    (CGB_WAVE_RAM, HighPassModel::Cgb)
+                } else {
+                    (DMG_WAVE_RAM, HighPassModel::Dmg)
+                };
+
+            assert_eq!(wave_ram, pattern, "CGB hardware: {}", cgb_hw);
+            assert_eq!(sys.sys_state.sound.ch3.samples, pattern);
+            assert!(sys.sys_state.sound.hpf_model == hpf);
+        }
+    }
+
+    /* Channels currently enabled according to NR52 */
+    fn nr52_channels(sys: &mut crate::system_state::System) -> u8 {
+        sys.peek(0xff26) & 0x0f
+    }
+
+    #[test]
+    fn trigger_reloads_only_expired_length() {
+        let mut sys = test_system(test_rom(&[0x18, 0xfe]));
+        sys.poke(0xff26, 0x80);
+
+        /* Length 16, DAC on, trigger with length enabled */
+        sys.poke(0xff11, 0x30);
+        sys.poke(0xff12, 0xf0);
+        sys.poke(0xff14, 0xc0);
+
+        let ch1 = &sys.sys_state.sound.ch1;
+        let len_unit = ch1.sample_rate / 256.0;
+        assert_eq!(ch1.sample_count, (16.0 * len_unit) as usize);
+        assert!(ch1.samples_limited);
+        assert!(ch1.enabled);
+
+        /* Re-triggering keeps a running length counter */
+        sys.sys_state.sound.ch1.sample_count = 42;
+        sys.poke(0xff14, 0xc0);
+        assert_eq!(sys.sys_state.sound.ch1.sample_count, 42);
+
+        /* An expired one is reloaded to the maximum */
+        sys.sys_state.sound.ch1.sample_count = 0;
+        sys.poke(0xff14, 0xc0);
+        assert_eq!(sys.sys_state.sound.ch1.sample_count,
+                   (64.0 * len_unit) as usize);
+        assert!(sys.sys_state.sound.ch1.enabled);
+
+        /* Same for the wave channel, with its 256 steps */
+        sys.poke(0xff1a, 0x80);
+        sys.poke(0xff1b, 0x00);
+        sys.sys_state.sound.ch3.out_sample_count = 0;
+        sys.poke(0xff1e, 0xc0);
+        let ch3 = &sys.sys_state.sound.ch3;
+        assert_eq!(ch3.out_sample_count, (256.0 * len_unit) as usize);
+        assert!(ch3.out_samples_limited);
+    }
+
+    #[test]
+    fn trigger_with_dac_off_does_not_enable() {
+        let mut sys = test_system(test_rom(&[0x18, 0xfe]));
+        sys.poke(0xff26, 0x80);
+
+        /* Volume 0 and decreasing envelope: DAC off */
+        sys.poke(0xff12, 0x00);
+        sys.poke(0xff14, 0x80);
+        assert!(!sys.sys_state.sound.ch1.enabled);
+        assert_eq!(nr52_channels(&mut sys) & 0x01, 0);
+
+        sys.poke(0xff21, 0x00);
+        sys.poke(0xff23, 0x80);
+        assert!(!sys.sys_state.sound.ch4.enabled);
+        assert_eq!(nr52_channels(&mut sys) & 0x08, 0);
+
+        /* NR30 bit 7 off */
+        sys.poke(0xff1a, 0x00);
+        sys.poke(0xff1e, 0x80);
+        assert!(!sys.sys_state.sound.ch3.enabled);
+        assert_eq!(nr52_channels(&mut sys) & 0x04, 0);
+
+        /* With the DAC on, the same trigger enables the channel... */
+        sys.poke(0xff12, 0xf0);
+        sys.poke(0xff14, 0x80);
+        assert!(sys.sys_state.sound.ch1.enabled);
+        assert_eq!(nr52_channels(&mut sys) & 0x01, 0x01);
+
+        /* ...and turning the DAC off disables it again */
+        sys.poke(0xff12, 0x00);
+        assert!(!sys.sys_state.sound.ch1.enabled);
+        assert_eq!(nr52_channels(&mut sys) & 0x01, 0);
+    }
+
+    /* Wave RAM holds 0x00, 0x11, ..., 0xff; CH3 is triggered with one
+     * sample per 16 2 MHz ticks */
+    fn wave_system(dmg_wave_ram: bool) -> TestSystem {
+        let mut sys = test_system(test_rom(&[0x18, 0xfe]));
+        sys.sys_state.sound.set_dmg_wave_ram(dmg_wave_ram);
+
+        sys.poke(0xff26, 0x80);
+        for i in 0..16 {
+            sys.poke(0xff30 + i, i as u8 * 0x11);
+        }
+
+        let freq_x = 2048 - 16;
+        sys.poke(0xff1a, 0x80);
+        sys.poke(0xff1c, 0x20);
+        sys.poke(0xff1d, (freq_x & 0xff) as u8);
+        sys.poke(0xff1e, 0x80 | (freq_x >> 8) as u8);
+        assert!(sys.sys_state.sound.ch3.enabled);
+        sys
+    }
+
+    #[test]
+    fn wave_ram_while_inactive() {
+        let mut sys = test_system(test_rom(&[0x18, 0xfe]));
+        sys.sys_state.sound.set_dmg_wave_ram(true);
+
+        sys.poke(0xff35, 0x42);
+        assert_eq!(sys.peek(0xff35), 0x42);
+        assert_eq!(sys.sys_state.sound.ch3.samples[5], 0x42);
+
+        /* Stopping the channel (DAC off) gives access back */
+        let mut sys = wave_system(true);
+        sys.poke(0xff1a, 0x00);
+        sys.poke(0xff1e, 0x80);
+        assert!(!sys.sys_state.sound.ch3.enabled);
+        assert_eq!(sys.peek(0xff3a), 0xaa);
+    }
+
+    #[test]
+    fn wave_ram_while_active_cgb() {
+        let mut sys = wave_system(false);
+
+        /* Any address gives the byte being played */
+        assert_eq!(sys.peek(0xff3a), 0x00);
+        sys.sys_state.sound.ch3.add_cycles(16 * 3);
+        assert_eq!(sys.peek(0xff30), 0x11);
+        sys.sys_state.sound.ch3.add_cycles(16 * 8 + 5);
+        assert_eq!(sys.peek(0xff3f), 0x55);
+
+        sys.poke(0xff30, 0x42);
+        assert_eq!(sys.sys_state.sound.ch3.samples[5], 0x42);
+        assert_eq!(sys.sys_state.sound.ch3.samples[0], 0x00);
+    }
+
+    #[test]
+    fn wave_ram_while_active_dmg() {
+        let mut sys = wave_system(true);
+
+        /* Nothing read yet after the trigger */
+        assert_eq!(sys.peek(0xff30), 0xff);
+
+        /* Only within the M-cycle of the channel's read */
+        sys.sys_state.sound.ch3.add_cycles(16 * 3);
+        assert_eq!(sys.peek(0xff3a), 0x11);
+        sys.sys_state.sound.ch3.add_cycles(1);
+        assert_eq!(sys.peek(0xff3a), 0x11);
+        sys.sys_state.sound.ch3.add_cycles(1);
+        assert_eq!(sys.peek(0xff3a), 0xff);
+
+        /* Same for writes */
+        sys.poke(0xff30, 0x42);
+        assert_eq!(sys.sys_state.sound.ch3.samples[1], 0x11);
+        sys.sys_state.sound.ch3.add_cycles(14);
+        sys.poke(0xff30, 0x42);
+        assert_eq!(sys.sys_state.sound.ch3.samples[2], 0x42);
+        assert_eq!(sys.sys_state.sound.ch3.samples[0], 0x00);
+    }
+}
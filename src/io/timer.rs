@@ -1,79 +1,337 @@
+use crate::address_space::AddressSpace;
 use crate::io::IOSpace;
 use crate::io::int::Irq;
 use crate::system_state::{IOReg, SystemState};
 
 
-#[derive(Serialize, Deserialize)]
+#[derive(SaveState)]
 pub struct TimerState {
+    /* Pre-version 13 timer state; only the DIV phase survives the
+     * conversion to @counter */
+    #[savestate(skip_if("version >= 13"),
+                post_import("self.counter = (self.div_counter * 4) as u16"))]
     div_counter: u32,
-
+    #[savestate(skip_if("version >= 13"))]
     timer_counter: u32,
+    #[savestate(skip_if("version >= 13"))]
     timer_enabled: bool,
+    #[savestate(skip_if("version >= 13"))]
     timer_divider: u32,
+
+    /* Internal divider, incremented at 4 MHz (8 MHz in double speed
+     * mode).  DIV is its top byte. */
+    #[savestate(skip_if("version < 13"))]
+    counter: u16,
+
+    /* TIMA has overflowed and reads 0x00 for one cycle; it is reloaded
+     * from TMA (and the interrupt raised) after that */
+    #[savestate(skip_if("version < 14"))]
+    overflowed: bool,
+    /* TIMA has been reloaded during the last cycle, so TIMA writes are
+     * ignored and TMA writes go to TIMA, too */
+    #[savestate(skip_if("version < 14"))]
+    reloaded: bool,
 }
 
 
+/* Counter bit whose falling edge increments TIMA, or None if the timer is
+ * disabled */
+fn tac_bit(tac: u8) -> Option<u32> {
+    if tac & (1 << 2) == 0 {
+        return None;
+    }
+
+    Some(match tac & 0x3 {
+        0 => 9, /*   4096 Hz */
+        1 => 3, /* 262144 Hz */
+        2 => 5, /*  65536 Hz */
+        3 => 7, /*  16384 Hz */
+
+        _ => unreachable!(),
+    })
+}
+
 impl TimerState {
     pub fn new() -> Self {
         Self {
             div_counter: 0,
-
             timer_counter: 0,
             timer_enabled: false,
-            timer_divider: 256,
+            timer_divider: 0,
+
+            counter: 0,
+
+            overflowed: false,
+            reloaded: false,
+        }
+    }
+
+    /* DIV's initial value is set by io::init_power_on() */
+    pub fn init_system_state(sys_state: &mut SystemState) {
+        let div = sys_state.io_get_reg(IOReg::DIV);
+        sys_state.timer.counter = (div as u16) << 8;
+    }
+
+    /* Old save states only have the DIV phase, so take the rest from the
+     * register */
+    pub fn post_import(&mut self, addr_space: &AddressSpace) {
+        let div = addr_space.io_get_reg(IOReg::DIV);
+        self.counter = ((div as u16) << 8) | (self.counter & 0xff);
+    }
+
+    fn inc_tima(&mut self, addr_space: &mut AddressSpace) {
+        let cur = addr_space.io_get_reg(IOReg::TIMA);
+        let (res, overflow) = cur.overflowing_add(1u8);
+
+        self.overflowed = overflow;
+        addr_space.io_set_reg(IOReg::TIMA, res);
+    }
+
+    fn reload_tima(&mut self, addr_space: &mut AddressSpace) {
+        let tma = addr_space.io_get_reg(IOReg::TMA);
+        addr_space.io_set_reg(IOReg::TIMA, tma);
+
+        let iflag = addr_space.io_get_reg(IOReg::IF);
+        addr_space.io_set_reg(IOReg::IF, iflag | (Irq::Timer as u8));
+
+        self.overflowed = false;
+        self.reloaded = true;
+    }
+
+    /* The timer input is the AND of the enable bit and the selected
+     * counter bit */
+    fn timer_input(&self, tac: u8) -> bool {
+        match tac_bit(tac) {
+            Some(bit) => self.counter & (1 << bit) != 0,
+            None => false,
         }
     }
 }
 
+/* @count is in CPU cycles */
 pub fn add_cycles(sys_state: &mut SystemState, count: u32) {
     let timer = &mut sys_state.timer;
     let addr_space = &mut sys_state.addr_space;
+    let tac = addr_space.io_get_reg(IOReg::TAC);
 
-    timer.div_counter += count;
-    while timer.div_counter >= 64 {
-        let cur = addr_space.io_get_reg(IOReg::DIV);
-        addr_space.io_set_reg(IOReg::DIV, cur.wrapping_add(1u8));
+    for _ in 0..count {
+        timer.reloaded = false;
+        if timer.overflowed {
+            timer.reload_tima(addr_space);
+        }
 
-        timer.div_counter -= 64;
+        let input = timer.timer_input(tac);
+        timer.counter = timer.counter.wrapping_add(4);
+        if input && !timer.timer_input(tac) {
+            timer.inc_tima(addr_space);
+        }
     }
 
-    if timer.timer_enabled {
-        timer.timer_counter += count;
-        while timer.timer_counter >= timer.timer_divider {
-            let cur = addr_space.io_get_reg(IOReg::TIMA);
-            let (mut res, overflow) = cur.overflowing_add(1u8);
-
-            if overflow {
-                let iflag = addr_space.io_get_reg(IOReg::IF);
-                addr_space.io_set_reg(IOReg::IF, iflag | (Irq::Timer as u8));
-
-                res = addr_space.io_get_reg(IOReg::TMA);
-            }
+    addr_space.io_set_reg(IOReg::DIV, (timer.counter >> 8) as u8);
+}
 
-            addr_space.io_set_reg(IOReg::TIMA, res);
-            timer.timer_counter -= timer.timer_divider;
-        }
+/* Resetting the divider (by writing DIV or by STOP) makes the timer input
+ * fall if it was high, which increments TIMA */
+pub fn reset_div(sys_state: &mut SystemState) {
+    let tac = sys_state.io_get_reg(IOReg::TAC);
+    if sys_state.timer.timer_input(tac) {
+        sys_state.timer.inc_tima(&mut sys_state.addr_space);
     }
+
+    sys_state.timer.counter = 0;
+    sys_state.io_set_reg(IOReg::DIV, 0);
 }
 
 pub fn timer_write(sys_state: &mut SystemState, addr: u16, mut val: u8)
 {
-    let timer = &mut sys_state.timer;
+    if addr == 0x04 {
+        /* DIV */
+        reset_div(sys_state);
+        return;
+    }
 
     if addr == 0x07 {
         /* TAC */
         val &= 0x7;
 
-        timer.timer_enabled = val & (1 << 2) != 0;
-        timer.timer_divider = match val & 0x3 {
-            0 => 256,
-            1 =>   4,
-            2 =>  16,
-            3 =>  64,
-
-            _ => unreachable!(),
-        };
+        /* Disabling the timer or switching to a bit that is clear can
+         * make the input fall, too (as on the DMG) */
+        let old_tac = sys_state.io_get_reg(IOReg::TAC);
+        if sys_state.timer.timer_input(old_tac) &&
+           !sys_state.timer.timer_input(val)
+        {
+            sys_state.timer.inc_tima(&mut sys_state.addr_space);
+        }
+    } else if addr == 0x05 {
+        /* TIMA: Writing during the delay cancels the reload, writing
+         * right after the reload has no effect */
+        if sys_state.timer.reloaded {
+            return;
+        }
+        sys_state.timer.overflowed = false;
+    } else if addr == 0x06 && sys_state.timer.reloaded {
+        /* TMA: TIMA is still being loaded from it */
+        sys_state.io_set_reg(IOReg::TIMA, val);
     }
 
     sys_state.io_set_addr(addr, val);
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::add_cycles;
+    use crate::io::IOSpace;
+    use crate::io::int::Irq;
+    use crate::system_state::IOReg;
+    use crate::testing::{TestSystem, test_rom, test_system};
+
+    /* Sets the registers directly, so there are no write side effects */
+    fn timer_system(tac: u8, counter: u16) -> TestSystem {
+        let mut sys = test_system(test_rom(&[0x18, 0xfe]));
+        sys.sys_state.io_set_reg(IOReg::TAC, tac);
+        sys.sys_state.io_set_reg(IOReg::TIMA, 0x00);
+        sys.sys_state.io_set_reg(IOReg::IF, 0x00);
+        sys.sys_state.timer.counter = counter;
+        sys
+    }
+
+    fn tima(sys: &TestSystem) -> u8 {
+        sys.sys_state.io_get_reg(IOReg::TIMA)
+    }
+
+    #[test]
+    fn tima_frequencies() {
+        for (tac, incs) in [(0x04, 1), (0x05, 64), (0x06, 16), (0x07, 4)] {
+            let mut sys = timer_system(tac, 0x0000);
+            add_cycles(&mut sys.sys_state, 256);
+            assert_eq!(tima(&sys), incs, "TAC {:02x}", tac);
+        }
+
+        let mut sys = timer_system(0x00, 0x0000);
+        add_cycles(&mut sys.sys_state, 256);
+        assert_eq!(tima(&sys), 0);
+    }
+
+    #[test]
+    fn tac_change_to_low_bit_increments() {
+        /* Bit 9 is high, bit 3 is low */
+        let mut sys = timer_system(0x04, 0x0200);
+        sys.poke(0xff07, 0x05);
+        assert_eq!(tima(&sys), 1);
+
+        /* Both high */
+        let mut sys = timer_system(0x04, 0x0208);
+        sys.poke(0xff07, 0x05);
+        assert_eq!(tima(&sys), 0);
+
+        /* Disabling counts as the input falling, too */
+        let mut sys = timer_system(0x04, 0x0200);
+        sys.poke(0xff07, 0x00);
+        assert_eq!(tima(&sys), 1);
+
+        /* Low before, so nothing falls */
+        let mut sys = timer_system(0x05, 0x0200);
+        sys.poke(0xff07, 0x04);
+        assert_eq!(tima(&sys), 0);
+    }
+
+    #[test]
+    fn tac_change_mid_count() {
+        /* Bit 9 is just about to fall, but after switching to bit 3,
+         * only its falling edge (at 0x0200) counts, exactly once */
+        let mut sys = timer_system(0x04, 0x01fc);
+        sys.poke(0xff07, 0x05);
+        assert_eq!(tima(&sys), 0);
+
+        add_cycles(&mut sys.sys_state, 4);
+        assert_eq!(tima(&sys), 1);
+
+        /* Then every 4 cycles */
+        add_cycles(&mut sys.sys_state, 16);
+        assert_eq!(tima(&sys), 5);
+    }
+
+    #[test]
+    fn div_write_increments() {
+        let mut sys = timer_system(0x04, 0x0200);
+        sys.poke(0xff04, 0x12);
+        assert_eq!(tima(&sys), 1);
+        assert_eq!(sys.sys_state.io_get_reg(IOReg::DIV), 0x00);
+        assert_eq!(sys.sys_state.timer.counter, 0x0000);
+
+        let mut sys = timer_system(0x04, 0x01fc);
+        sys.poke(0xff04, 0x12);
+        assert_eq!(tima(&sys), 0);
+    }
+
+    fn timer_irq(sys: &TestSystem) -> bool {
+        sys.sys_state.io_get_reg(IOReg::IF) & (Irq::Timer as u8) != 0
+    }
+
+    /* TIMA = 0xff, about to be incremented on the next cycle */
+    fn overflow_system() -> TestSystem {
+        let mut sys = timer_system(0x05, 0x000c);
+        sys.sys_state.io_set_reg(IOReg::TIMA, 0xff);
+        sys.sys_state.io_set_reg(IOReg::TMA, 0x23);
+        sys
+    }
+
+    #[test]
+    fn tima_reload() {
+        let mut sys = overflow_system();
+
+        /* One cycle with TIMA = 0 and no interrupt yet */
+        add_cycles(&mut sys.sys_state, 1);
+        assert_eq!(tima(&sys), 0x00);
+        assert!(!timer_irq(&sys));
+
+        add_cycles(&mut sys.sys_state, 1);
+        assert_eq!(tima(&sys), 0x23);
+        assert!(timer_irq(&sys));
+    }
+
+    #[test]
+    fn tima_write_reloading() {
+        /* Writing TIMA during the delay cancels the reload */
+        let mut sys = overflow_system();
+        add_cycles(&mut sys.sys_state, 1);
+        sys.poke(0xff05, 0x42);
+        add_cycles(&mut sys.sys_state, 1);
+        assert_eq!(tima(&sys), 0x42);
+        assert!(!timer_irq(&sys));
+
+        /* Writing it in the reload cycle is ignored */
+        let mut sys = overflow_system();
+        add_cycles(&mut sys.sys_state, 2);
+        sys.poke(0xff05, 0x42);
+        assert_eq!(tima(&sys), 0x23);
+
+        /* But one cycle later, it works again */
+        add_cycles(&mut sys.sys_state, 1);
+        sys.poke(0xff05, 0x42);
+        assert_eq!(tima(&sys), 0x42);
+    }
+
+    #[test]
+    fn tma_write_reloading() {
+        /* Writing TMA during the delay: The new value is loaded */
+        let mut sys = overflow_system();
+        add_cycles(&mut sys.sys_state, 1);
+        sys.poke(0xff06, 0x66);
+        add_cycles(&mut sys.sys_state, 1);
+        assert_eq!(tima(&sys), 0x66);
+
+        /* Writing TMA in the reload cycle goes to TIMA, too */
+        let mut sys = overflow_system();
+        add_cycles(&mut sys.sys_state, 2);
+        sys.poke(0xff06, 0x55);
+        assert_eq!(tima(&sys), 0x55);
+        assert_eq!(sys.sys_state.io_get_reg(IOReg::TMA), 0x55);
+
+        /* But not later */
+        add_cycles(&mut sys.sys_state, 1);
+        sys.poke(0xff06, 0x77);
+        assert_eq!(tima(&sys), 0x55);
+    }
+}
@@ -9,11 +9,27 @@
 
 #[cfg_attr(not(target_os = "linux"), path = "address_space_generic.rs")]
 mod address_space;
+mod bundle;
+mod camera;
+mod cheats;
+#[cfg(not(target_arch = "wasm32"))]
+mod config;
+mod coverage;
 mod cpu;
+mod debugger;
+mod frame_dump;
+mod input_log;
 mod io;
+mod movie;
+mod rewind;
 mod rom;
+mod screenshot;
+mod scripting;
 mod sgb;
+mod state_dump;
 mod system_state;
+#[cfg(test)]
+mod testing;
 mod ui;
 
 #[cfg(target_arch = "wasm32")]
@@ -21,9 +37,13 @@ use wasm_bindgen::prelude::*;
 
 use address_space::AddressSpace;
 use io::serial::SerialConnParam;
-use system_state::{System, SystemState};
+use system_state::SystemState;
 use ui::UI;
 
+/* Scripting interface (System::peek() etc.) */
+pub use cpu::Reg16;
+pub use system_state::System;
+
 
 #[cfg(target_arch = "wasm32")]
 #[wasm_bindgen]
@@ -56,6 +76,11 @@ impl XGBCSystem {
         self.sys.main_loop(true);
     }
 
+    /* The current frame as 160x144 RGB8 */
+    pub fn screenshot(&self) -> Vec<u8> {
+        screenshot::lcd_rgb8(&self.sys.sys_state.display.lcd_pixels)
+    }
+
     pub fn get_sound_ringbuf(&self) -> *const f32 {
         self.sys.ui.get_sound_ringbuf().map(|s| &s[0] as *const f32).unwrap_or_else(std::ptr::null)
     }
@@ -67,4 +92,8 @@ impl XGBCSystem {
     pub fn get_sound_ringbuf_ptrs(&mut self) -> *mut u32 {
         self.sys.ui.get_sound_ringbuf_ptrs().map(|s| &mut s[0] as *mut u32).unwrap_or_else(std::ptr::null_mut)
     }
+
+    pub fn get_sound_sample_rate(&self) -> usize {
+        self.sys.ui.get_sound_sample_rate()
+    }
 }
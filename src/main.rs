@@ -3,20 +3,40 @@
 
 #[cfg_attr(not(target_os = "linux"), path = "address_space_generic.rs")]
 mod address_space;
+mod bundle;
+mod camera;
+mod cheats;
+mod config;
+mod coverage;
 mod cpu;
+mod debugger;
+mod frame_dump;
+mod input_log;
 mod io;
+mod movie;
+mod rewind;
 mod rom;
+mod screenshot;
+mod scripting;
 mod sgb;
+mod state_dump;
 mod system_state;
+#[cfg(test)]
+mod testing;
 mod ui;
 
 use std::env;
+use std::io::BufWriter;
 use std::process::exit;
 use regex::Regex;
 
 use address_space::AddressSpace;
+use config::Config;
+use cpu::IllegalOpcodeMode;
+use io::lcd::{ColorMode, DmgPalette};
+use io::ir::IrConnParam;
 use io::serial::SerialConnParam;
-use system_state::{System, SystemState};
+use system_state::{MinimizedBehavior, Model, System, SystemState};
 use ui::UI;
 
 
@@ -27,6 +47,45 @@ fn main() {
     let mut base_path = None;
     let mut ram_path = None;
     let mut scp = SerialConnParam::Disabled;
+    let mut icp = IrConnParam::Disabled;
+    let mut coverage_path = None;
+    let mut state_json_path = None;
+    let mut frame_dump_dir = None;
+    let mut replay_bundle_path = None;
+    let mut record_movie_path = None;
+    let mut play_movie_path = None;
+    let mut trace_path = None;
+    let mut frame_dump_every = 1;
+    let mut low_latency = false;
+    let mut compress_states = false;
+    let mut illegal_opcode_mode = IllegalOpcodeMode::default();
+    let mut minimized_behavior = MinimizedBehavior::Run;
+    let mut dmg_palette = None;
+    let mut io_breakpoints = Vec::new();
+    let mut rtc_sidecar = true;
+    let mut rumble = false;
+    let mut rewind_secs = None;
+    let mut autosave_secs = None;
+    let mut accurate_ppu = false;
+    let mut rewind_interval = rewind::DEFAULT_REWIND_INTERVAL;
+    let mut scale = None;
+    let mut title_stats = true;
+    let mut headless = false;
+    let mut run_frames = None;
+    let mut framebuffer_path = None;
+    let mut sgb_border_path = None;
+    let mut sgb_border_hidden = false;
+    let mut camera_image_path = None;
+    let mut boot_rom_path = None;
+    let mut model = None;
+    /* Some(true): With color correction */
+    let mut print_crc = None;
+    let mut debug_vram = false;
+    let mut exit_dumps = Vec::new();
+    #[cfg(feature = "debugger")]
+    let mut breakpoints = Vec::new();
+    #[cfg(feature = "debugger")]
+    let mut watchpoints = Vec::new();
 
     let mut arg_iter = argv.iter();
     arg_iter.next(); /* skip argv[0] */
@@ -36,6 +95,62 @@ fn main() {
             let regex = Regex::new(r"^--([^=]*)(=(.*))?$").unwrap();
             let cap = regex.captures(arg).unwrap();
 
+            #[cfg(feature = "debugger")]
+            if &cap[1] == "break" {
+                if cap.get(3).is_none() {
+                    eprintln!("--break requires an address");
+                    exit(1);
+                }
+
+                match debugger::parse_addr(&cap[3]) {
+                    Some(addr) => breakpoints.push(addr),
+                    None => {
+                        eprintln!("Invalid address {}", &cap[3]);
+                        exit(1);
+                    }
+                }
+                continue;
+            } else if &cap[1] == "watch" {
+                if cap.get(3).is_none() {
+                    eprintln!("--watch requires an address");
+                    exit(1);
+                }
+
+                let (range, access) =
+                    match cap[3].split_once(':') {
+                        Some((range, access)) => (range, access),
+                        None => (&cap[3], "rw"),
+                    };
+
+                let kind =
+                    match access {
+                        "r" => debugger::WatchKind::Read,
+                        "w" => debugger::WatchKind::Write,
+                        "rw" => debugger::WatchKind::ReadWrite,
+                        _ => {
+                            eprintln!("--watch access must be one of: \
+                                       r, w, rw");
+                            exit(1);
+                        }
+                    };
+
+                let (start, end) =
+                    match range.split_once('-') {
+                        Some((start, end)) => (start, end),
+                        None => (range, range),
+                    };
+
+                match (debugger::parse_addr(start), debugger::parse_addr(end)) {
+                    (Some(start), Some(end)) if start <= end =>
+                        watchpoints.push((start, end, kind)),
+                    _ => {
+                        eprintln!("Invalid address range {}", range);
+                        exit(1);
+                    }
+                }
+                continue;
+            }
+
             if &cap[1] == "serial" {
                 if cap.get(3).is_none() ||
                    cap.get(3).unwrap().as_str() == "local-auto"
@@ -44,12 +159,270 @@ fn main() {
                 } else if cap[3].starts_with("local-shm:") {
                     let pid = cap[3].get(10..).unwrap();
                     scp = SerialConnParam::LocalSHM(pid.parse().unwrap());
+                } else if cap[3].starts_with("printer:") {
+                    let dir = cap[3].get(8..).unwrap();
+                    scp = SerialConnParam::Printer(String::from(dir));
                 } else if cap[3].starts_with("server:") {
                     let addr = cap[3].get(7..).unwrap();
                     scp = SerialConnParam::Server(String::from(addr));
                 } else {
                     scp = SerialConnParam::Client(String::from(&cap[3]));
                 }
+            } else if &cap[1] == "accurate-ppu" {
+                accurate_ppu = true;
+            } else if &cap[1] == "autosave" {
+                match cap.get(3).and_then(|m| m.as_str().parse::<f32>().ok()) {
+                    Some(secs) if secs >= 0.0 => autosave_secs = Some(secs),
+                    _ => {
+                        eprintln!("--autosave requires a number of seconds \
+                                   (0 to disable)");
+                        exit(1);
+                    }
+                }
+            } else if &cap[1] == "boot-rom" {
+                if cap.get(3).is_none() {
+                    eprintln!("--boot-rom requires a file name");
+                    exit(1);
+                }
+                boot_rom_path = Some(String::from(&cap[3]));
+            } else if &cap[1] == "camera-image" {
+                if cap.get(3).is_none() {
+                    eprintln!("--camera-image requires a file name");
+                    exit(1);
+                }
+                camera_image_path = Some(String::from(&cap[3]));
+            } else if &cap[1] == "compress-states" {
+                compress_states = true;
+            } else if &cap[1] == "coverage" {
+                if cap.get(3).is_none() {
+                    eprintln!("--coverage requires a file name");
+                    exit(1);
+                }
+                coverage_path = Some(String::from(&cap[3]));
+            } else if &cap[1] == "debug-vram" {
+                debug_vram = true;
+            } else if &cap[1] == "dmg-palette" {
+                match cap.get(3).and_then(|m| DmgPalette::parse(m.as_str())) {
+                    Some(palette) => dmg_palette = Some(palette),
+                    None => {
+                        eprintln!("--dmg-palette requires one of: grayscale, \
+                                   green, pocket, or four RRGGBB colors \
+                                   separated by commas");
+                        exit(1);
+                    }
+                }
+            } else if &cap[1] == "dump-framebuffer" {
+                if cap.get(3).is_none() {
+                    eprintln!("--dump-framebuffer requires a file name");
+                    exit(1);
+                }
+                framebuffer_path = Some(String::from(&cap[3]));
+            } else if &cap[1] == "dump-on-exit" {
+                let dump =
+                    cap.get(3).and_then(|m| {
+                        let (range, path) = m.as_str().split_once(':')?;
+                        let (start, end) = range.split_once('-')?;
+                        let start = debugger::parse_addr(start)?;
+                        let end = debugger::parse_addr(end)?;
+                        if start > end || path.is_empty() {
+                            return None;
+                        }
+                        Some((start, end, String::from(path)))
+                    });
+
+                match dump {
+                    Some(dump) => exit_dumps.push(dump),
+                    None => {
+                        eprintln!("--dump-on-exit requires an address range \
+                                   and a file name, e.g. c000-dfff:wram.bin");
+                        exit(1);
+                    }
+                }
+            } else if &cap[1] == "frame-dump" {
+                if cap.get(3).is_none() {
+                    eprintln!("--frame-dump requires a directory name");
+                    exit(1);
+                }
+                frame_dump_dir = Some(String::from(&cap[3]));
+            } else if &cap[1] == "frame-dump-every" {
+                match cap.get(3).and_then(|m| m.as_str().parse().ok()) {
+                    Some(n) if n > 0 => frame_dump_every = n,
+                    _ => {
+                        eprintln!("--frame-dump-every requires a positive \
+                                   number");
+                        exit(1);
+                    }
+                }
+            } else if &cap[1] == "frames" {
+                match cap.get(3).and_then(|m| m.as_str().parse().ok()) {
+                    Some(n) => run_frames = Some(n),
+                    None => {
+                        eprintln!("--frames requires a number");
+                        exit(1);
+                    }
+                }
+            } else if &cap[1] == "headless" {
+                headless = true;
+            } else if &cap[1] == "illegal-opcodes" {
+                illegal_opcode_mode =
+                    match cap.get(3).map(|m| m.as_str()) {
+                        Some("panic") => IllegalOpcodeMode::Panic,
+                        Some("log") => IllegalOpcodeMode::Log,
+                        Some("break") => IllegalOpcodeMode::Break,
+                        Some("lockup") => IllegalOpcodeMode::Lockup,
+                        _ => {
+                            eprintln!("--illegal-opcodes requires one of: \
+                                       panic, log, break, lockup");
+                            exit(1);
+                        }
+                    };
+            } else if &cap[1] == "io-break" {
+                if cap.get(3).is_none() {
+                    eprintln!("--io-break requires a register name");
+                    exit(1);
+                }
+
+                let (name, access) =
+                    match cap[3].split_once(':') {
+                        Some((name, access)) => (name, access),
+                        None => (&cap[3], "rw"),
+                    };
+
+                let reg =
+                    match debugger::ioreg_by_name(name) {
+                        Some(reg) => reg,
+                        None => {
+                            eprintln!("Unknown I/O register {}", name);
+                            exit(1);
+                        }
+                    };
+
+                io_breakpoints.push((reg, access.contains('r'),
+                                     access.contains('w')));
+            } else if &cap[1] == "ir" {
+                if cap.get(3).is_none() || &cap[3] == "local" {
+                    icp = IrConnParam::LocalAuto;
+                } else if cap[3].starts_with("server:") {
+                    let addr = cap[3].get(7..).unwrap();
+                    icp = IrConnParam::Server(String::from(addr));
+                } else {
+                    icp = IrConnParam::Client(String::from(&cap[3]));
+                }
+            } else if &cap[1] == "low-latency" {
+                low_latency = true;
+            } else if &cap[1] == "minimized" {
+                minimized_behavior =
+                    match cap.get(3).map(|m| m.as_str()) {
+                        Some("run") => MinimizedBehavior::Run,
+                        Some("pause") => MinimizedBehavior::Pause,
+                        Some("throttle") => MinimizedBehavior::Throttle,
+                        _ => {
+                            eprintln!("--minimized requires one of: run, \
+                                       pause, throttle");
+                            exit(1);
+                        }
+                    };
+            } else if &cap[1] == "model" {
+                match cap.get(3).map(|m| m.as_str()) {
+                    Some("auto") => model = None,
+                    Some(name) if Model::parse(name).is_some() =>
+                        model = Model::parse(name),
+                    _ => {
+                        eprintln!("--model requires one of: auto, dmg, mgb, \
+                                   sgb, cgb, agb");
+                        exit(1);
+                    }
+                }
+            } else if &cap[1] == "no-sgb-border" {
+                sgb_border_hidden = true;
+            } else if &cap[1] == "plain-title" {
+                title_stats = false;
+            } else if &cap[1] == "play" {
+                if cap.get(3).is_none() {
+                    eprintln!("--play requires a file name");
+                    exit(1);
+                }
+                play_movie_path = Some(String::from(&cap[3]));
+            } else if &cap[1] == "print-crc" {
+                print_crc =
+                    match cap.get(3).map(|m| m.as_str()) {
+                        None => Some(true),
+                        Some("raw") => Some(false),
+                        Some(_) => {
+                            eprintln!("--print-crc only accepts =raw");
+                            exit(1);
+                        }
+                    };
+            } else if &cap[1] == "record" {
+                if cap.get(3).is_none() {
+                    eprintln!("--record requires a file name");
+                    exit(1);
+                }
+                record_movie_path = Some(String::from(&cap[3]));
+            } else if &cap[1] == "replay-bundle" {
+                if cap.get(3).is_none() {
+                    eprintln!("--replay-bundle requires a file name");
+                    exit(1);
+                }
+                replay_bundle_path = Some(String::from(&cap[3]));
+            } else if &cap[1] == "rewind" {
+                match cap.get(3).map(|m| m.as_str().parse::<f32>()) {
+                    None => rewind_secs = Some(rewind::DEFAULT_REWIND_SECS),
+                    Some(Ok(secs)) if secs > 0.0 => rewind_secs = Some(secs),
+                    _ => {
+                        eprintln!("--rewind requires a positive number of \
+                                   seconds");
+                        exit(1);
+                    }
+                }
+            } else if &cap[1] == "rewind-interval" {
+                match cap.get(3).and_then(|m| m.as_str().parse().ok()) {
+                    Some(n) if n > 0 => rewind_interval = n,
+                    _ => {
+                        eprintln!("--rewind-interval requires a positive \
+                                   number");
+                        exit(1);
+                    }
+                }
+            } else if &cap[1] == "rtc-format" {
+                rtc_sidecar =
+                    match cap.get(3).map(|m| m.as_str()) {
+                        Some("appended") => false,
+                        Some("sidecar") => true,
+                        _ => {
+                            eprintln!("--rtc-format requires one of: \
+                                       appended, sidecar");
+                            exit(1);
+                        }
+                    };
+            } else if &cap[1] == "rumble" {
+                rumble = true;
+            } else if &cap[1] == "scale" {
+                match cap.get(3).and_then(|m| m.as_str().parse().ok()) {
+                    Some(n) if n > 0 => scale = Some(n),
+                    _ => {
+                        eprintln!("--scale requires a positive number");
+                        exit(1);
+                    }
+                }
+            } else if &cap[1] == "sgb-border" {
+                if cap.get(3).is_none() {
+                    eprintln!("--sgb-border requires a file name");
+                    exit(1);
+                }
+                sgb_border_path = Some(String::from(&cap[3]));
+            } else if &cap[1] == "trace" {
+                if cap.get(3).is_none() {
+                    eprintln!("--trace requires a file name");
+                    exit(1);
+                }
+                trace_path = Some(String::from(&cap[3]));
+            } else if &cap[1] == "dump-state-json" {
+                if cap.get(3).is_none() {
+                    eprintln!("--dump-state-json requires a file name");
+                    exit(1);
+                }
+                state_json_path = Some(String::from(&cap[3]));
             } else {
                 eprintln!("Unrecognized option --{}", &cap[1]);
                 exit(1);
@@ -72,13 +445,84 @@ fn main() {
 "Usage: {} [Options...] <ROM> [RAM]
 
 Options:
+  --accurate-ppu
+  --autosave=<seconds>
+  --boot-rom=<file>
+  --break=<address>                   (debugger feature only)
+  --camera-image=<png>
+  --compress-states
+  --coverage=<file>
+  --debug-vram
+  --dmg-palette=(grayscale|green|pocket|<c0>,<c1>,<c2>,<c3>)
+  --dump-framebuffer=<file>           (with --frames)
+  --dump-on-exit=<start>-<end>:<file> (may be given multiple times)
+  --dump-state-json=<file>            (with --frames, or on Shift+Alt+F9)
+  --frame-dump=<directory>
+  --frame-dump-every=<n>
+  --frames=<n>
+  --headless
+  --illegal-opcodes=(panic|log|break|lockup)
+  --io-break=<register>[:r|:w|:rw]
+  --ir[=local]
+  --ir=server:<addr>
+  --ir=<server addr>
+  --low-latency
+  --minimized=(run|pause|throttle)
+  --model=(auto|dmg|mgb|sgb|cgb|agb)
+  --no-sgb-border
+  --plain-title
+  --play=<file>
+  --print-crc[=raw]                   (with --frames)
+  --record=<file>
+  --replay-bundle=<file>
+  --rewind[=<seconds>]
+  --rewind-interval=<frames>
+  --rtc-format=(appended|sidecar)
+  --rumble
+  --scale=<n>
   --serial[=local-auto]
+  --serial=printer:<directory>
   --serial=server:<addr>
-  --serial=<server addr>",
+  --serial=<server addr>
+  --sgb-border=<png>
+  --trace=(<file>|-)                  (- for stderr)
+  --watch=<address>[-<end>][:r|:w|:rw]
+                                      (debugger feature only)",
                   argv[0]);
         exit(1);
     }
 
+    if framebuffer_path.is_some() && run_frames.is_none() {
+        eprintln!("--dump-framebuffer requires --frames");
+        exit(1);
+    }
+    if print_crc.is_some() && run_frames.is_none() {
+        eprintln!("--print-crc requires --frames");
+        exit(1);
+    }
+
+    if play_movie_path.is_some() && record_movie_path.is_some() {
+        eprintln!("--play and --record cannot be combined");
+        exit(1);
+    }
+    /* Movies start from power-on, bundles from a save state */
+    if (play_movie_path.is_some() || record_movie_path.is_some()) &&
+       replay_bundle_path.is_some()
+    {
+        eprintln!("Movies cannot be combined with --replay-bundle");
+        exit(1);
+    }
+
+    /* Headless runs should not depend on the user's settings */
+    let config = if headless { Config::default() } else { Config::load() };
+
+    let dmg_palette = dmg_palette.unwrap_or_else(|| {
+        DmgPalette::parse(&config.dmg_palette).unwrap_or_else(|| {
+            eprintln!("Invalid dmg-palette {} in config file",
+                      config.dmg_palette);
+            DmgPalette::Grayscale
+        })
+    });
 
     if ram_path.is_none() {
         ram_path = Some(format!("{}.sav", base_path.as_ref().unwrap()));
@@ -86,14 +530,159 @@ Options:
 
     let mut addr_space = Box::new(AddressSpace::new(rom_path.as_ref().unwrap(),
                                                     ram_path.as_ref().unwrap()));
+    if rtc_sidecar {
+        addr_space.rtc_path = Some(format!("{}.rtc",
+                                           base_path.as_ref().unwrap()));
+    }
     let mut sys_params = rom::load_rom(addr_space.as_mut());
     sys_params.serial_conn_param = scp;
+    sys_params.ir_conn_param = icp;
+    sys_params.audio_buffers = config.audio_buffers.clamp(1, 16);
+    if let Some(model) = model {
+        sys_params.force_model(model);
+    }
 
-    let mut ui = UI::new(&sys_params.cartridge_name);
+    let mut ui =
+        if headless {
+            UI::new_headless(&sys_params.cartridge_name)
+        } else {
+            UI::new(&sys_params.cartridge_name,
+                    scale.unwrap_or(config.scale).max(1))
+        };
+    ui.set_rumble_enabled(rumble);
+    ui.set_title_stats(title_stats);
+    ui.set_pause_overlay(config.pause_overlay);
+    ui.set_scale_mode(config.scale_mode);
+    ui.set_stretch(config.stretch);
+    ui.set_sgb_border_hidden(sgb_border_hidden);
+    if let Some(path) = sgb_border_path {
+        ui.load_custom_sgb_border(&path);
+    }
+    if debug_vram {
+        ui.set_vram_view(true);
+    }
 
-    let system_state = Box::new(SystemState::new(addr_space, sys_params, &mut ui));
+    let mut system_state = Box::new(SystemState::new(addr_space, sys_params,
+                                                     &mut ui));
+    system_state.sound.set_sample_rate(config.sample_rate as f32);
     let mut system = Box::new(System::new(system_state, ui,
                                           base_path.take().unwrap()));
 
+    if let Some(path) = boot_rom_path {
+        if let Err(e) = system.load_boot_rom(&path) {
+            eprintln!("Failed to load boot ROM {}: {}", path, e);
+            exit(1);
+        }
+    }
+
+    if let Some(path) = coverage_path {
+        system.enable_coverage(path);
+    }
+    if let Some(dir) = frame_dump_dir {
+        system.enable_frame_dump(dir, frame_dump_every);
+    }
+    if let Some(path) = state_json_path.clone() {
+        system.enable_state_json_dump(path);
+    }
+    for (start, end, path) in exit_dumps {
+        system.add_exit_dump(start, end, path);
+    }
+    system.load_cheat_file();
+    if let Some(path) = camera_image_path {
+        system.load_camera_image(&path);
+    }
+    system.set_illegal_opcode_mode(illegal_opcode_mode);
+    system.set_dmg_palette(dmg_palette);
+    system.set_color_correction(config.color_correction.unwrap_or(
+        if system.sys_state.model == Model::Agb {
+            ColorMode::Agb
+        } else {
+            ColorMode::Raw
+        }));
+    system.set_accurate_ppu(accurate_ppu || config.accurate_ppu);
+    system.set_fast_forward(config.speed);
+    system.set_wave_ram_access(config.wave_ram_access);
+    system.set_autosave_interval(autosave_secs.unwrap_or(config.autosave));
+    system.set_low_latency(low_latency);
+    system.set_compress_states(compress_states);
+    system.set_minimized_behavior(minimized_behavior);
+    if headless {
+        /* Nothing consumes the audio, so do not wait for it */
+        system.set_realtime(false);
+    }
+    if let Some(secs) = rewind_secs {
+        system.enable_rewind(secs, rewind_interval);
+    }
+
+    if let Some(path) = trace_path {
+        if path == "-" {
+            system.set_trace_sink(Some(Box::new(std::io::stderr())));
+        } else {
+            match std::fs::File::create(&path) {
+                Ok(f) =>
+                    system.set_trace_sink(Some(Box::new(BufWriter::new(f)))),
+                Err(e) => {
+                    eprintln!("Failed to create {}: {}", path, e);
+                    exit(1);
+                }
+            }
+        }
+    }
+
+    if config.auto_load_state && record_movie_path.is_none() &&
+       play_movie_path.is_none()
+    {
+        system.load_latest_state();
+    }
+    if let Some(path) = record_movie_path {
+        system.record_movie(path);
+    }
+    if let Some(path) = play_movie_path {
+        system.play_movie(&path);
+    }
+    if let Some(path) = replay_bundle_path {
+        system.replay_bundle(&path);
+    }
+    for (reg, on_read, on_write) in io_breakpoints {
+        system.add_io_breakpoint(reg, on_read, on_write);
+    }
+    #[cfg(feature = "debugger")]
+    for addr in breakpoints {
+        system.add_breakpoint(addr);
+    }
+    #[cfg(feature = "debugger")]
+    for (start, end, kind) in watchpoints {
+        system.add_watchpoint(start, end, kind);
+    }
+
+    if let Some(frames) = run_frames {
+        for _ in 0..frames {
+            system.main_loop(true);
+        }
+
+        if let Some(corrected) = print_crc {
+            println!("Framebuffer CRC32: {:08x}",
+                     system.framebuffer_crc(corrected));
+        }
+
+        if let Some(path) = framebuffer_path {
+            let rgb8 =
+                screenshot::lcd_rgb8(&system.sys_state.display.lcd_pixels);
+            if let Err(e) = screenshot::write_png(&path, 160, 144, &rgb8) {
+                eprintln!("Failed to write {}: {}", path, e);
+                system.exit(1);
+            }
+        }
+
+        if let Some(path) = state_json_path {
+            if let Err(e) = system.write_state_json(&path) {
+                eprintln!("Failed to write {}: {}", path, e);
+                system.exit(1);
+            }
+        }
+
+        system.quit();
+    }
+
     system.main_loop(false);
 }
@@ -0,0 +1,87 @@
+use crate::input_log::{self, InputLog, InputPlayback};
+use crate::rom::RtcSnapshot;
+
+
+/*
+ * An input movie: The keypad state on every frame (see InputLog),
+ * starting from power-on.  Unlike a bundle, there is no save state, so
+ * the cartridge RAM must be the same as when recording.
+ *
+ * As with bundles, replays will not be exact with --low-latency.  The RTC
+ * starts out as when recording, but then follows the host clock, so games
+ * using it may still diverge.
+ */
+#[derive(Serialize, Deserialize)]
+pub struct Movie {
+    /* Cartridge RAM at power-on */
+    extram_crc32: u32,
+    /* RTC at power-on (base time and latched registers), if there is one */
+    rtc: Option<RtcSnapshot>,
+
+    inputs: InputLog,
+}
+
+pub struct MovieRecorder {
+    path: String,
+    movie: Movie,
+}
+
+pub type MoviePlayback = InputPlayback;
+
+
+impl Movie {
+    pub fn load(path: &str) -> Result<Self, String> {
+        input_log::load(path)
+    }
+
+    /* Returns warnings about what may prevent an exact replay */
+    pub fn check(&self, rom_crc32: u32, extram_crc32: u32) -> Vec<String> {
+        let mut warnings = self.inputs.check("Movie", rom_crc32);
+
+        if self.extram_crc32 != extram_crc32 {
+            warnings.push(String::from("Movie was recorded with different \
+                                        cartridge RAM contents"));
+        }
+
+        warnings
+    }
+
+    pub fn rtc(&self) -> Option<&RtcSnapshot> {
+        self.rtc.as_ref()
+    }
+
+    pub fn into_playback(self) -> MoviePlayback {
+        self.inputs.into_playback()
+    }
+}
+
+
+impl MovieRecorder {
+    pub fn new(path: String, rom_crc32: u32, extram_crc32: u32,
+               rtc: Option<RtcSnapshot>)
+        -> Self
+    {
+        Self {
+            path,
+            movie: Movie {
+                extram_crc32,
+                rtc,
+
+                inputs: InputLog::new(rom_crc32),
+            },
+        }
+    }
+
+    /* To be called once per frame, after input has been polled */
+    pub fn frame(&mut self, lines: u8) {
+        self.movie.inputs.frame(lines);
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        input_log::save(&self.path, &self.movie)
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
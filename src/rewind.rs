@@ -0,0 +1,65 @@
+use std::collections::VecDeque;
+
+use crate::system_state::FRAMES_PER_SECOND;
+
+
+pub const DEFAULT_REWIND_SECS: f32 = 30.0;
+pub const DEFAULT_REWIND_INTERVAL: u64 = 4;
+
+/*
+ * Keeps save states (in memory, as exported by savestate::export_root())
+ * of the recent past, so emulation can be stepped backward.  The oldest
+ * snapshot is dropped once the buffer is full.
+ */
+pub struct RewindBuffer {
+    snapshots: VecDeque<Vec<u8>>,
+    capacity: usize,
+
+    /* A snapshot is taken every n-th frame */
+    interval: u64,
+    frame_i: u64,
+}
+
+
+impl RewindBuffer {
+    pub fn new(seconds: f32, interval: u64) -> Self {
+        let interval = std::cmp::max(interval, 1);
+        let frames = (seconds * FRAMES_PER_SECOND) as u64;
+        let capacity = std::cmp::max(frames / interval, 1) as usize;
+
+        Self {
+            snapshots: VecDeque::with_capacity(capacity),
+            capacity,
+
+            interval,
+            frame_i: 0,
+        }
+    }
+
+    /* To be called once per VBlank; returns whether a snapshot should be
+     * taken now */
+    pub fn next_frame(&mut self) -> bool {
+        self.frame_i += 1;
+        self.frame_i >= self.interval
+    }
+
+    pub fn push(&mut self, snapshot: Vec<u8>) {
+        if self.snapshots.len() >= self.capacity {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(snapshot);
+        self.frame_i = 0;
+    }
+
+    /* Returns the most recent snapshot */
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        self.frame_i = 0;
+        self.snapshots.pop_back()
+    }
+
+    /* How far back we can still go */
+    pub fn remaining_secs(&self) -> f32 {
+        (self.snapshots.len() as u64 * self.interval) as f32 /
+            FRAMES_PER_SECOND
+    }
+}
@@ -5,9 +5,15 @@ use std::time::SystemTime;
 #[cfg(target_arch = "wasm32")]
 use instant::SystemTime;
 
+use savestate::{SaveState, SaveStateError};
+
 use crate::address_space::AddressSpace;
+use crate::camera::{CAMERA_RAM_OFFSET, CameraState};
+use crate::io::ir::IrConnParam;
+use crate::io::sound::DEFAULT_BUFCOUNT;
 use crate::io::serial::SerialConnParam;
-use crate::system_state::SystemParams;
+use crate::state_dump::crc32;
+use crate::system_state::{Model, SystemParams};
 
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -48,17 +54,127 @@ struct RamRTCData {
     halted: bool,
 }
 
+/* The RTC as it was at some point in (host) time, e.g. for movies */
+#[derive(Serialize, Deserialize, Copy, Clone)]
+pub struct RtcSnapshot {
+    rtc: RamRTCData,
+    latched: Option<SerSystemTime>,
+    taken_at: SerSystemTime,
+}
+
+/* MMM01 registers, field widths as in hardware */
+#[derive(SaveState, Default)]
+struct MMM01State {
+    /* Once set, the game's ROM/RAM area is fixed and only the bits not
+     * covered by the masks can be changed */
+    locked: bool,
+
+    rom_bank_low: u8,
+    rom_bank_mid: u8,
+    rom_bank_high: u8,
+    /* Bits of rom_bank_low (>> 1) that are fixed */
+    rom_bank_mask: u8,
+
+    ram_bank_low: u8,
+    ram_bank_high: u8,
+    /* Bits of ram_bank_low that are fixed */
+    ram_bank_mask: u8,
+
+    mbc1_mode: bool,
+    mbc1_mode_disable: bool,
+    multiplex: bool,
+}
+
+/* MBC7 EEPROM (93LC56 in 16-bit mode) words, stored little-endian at the
+ * start of the RAM file */
+const MBC7_EEPROM_WORDS: usize = 128;
+
+/* Latched accelerometer value when level, and the change per g */
+const MBC7_ACCEL_CENTER: u16 = 0x81d0;
+const MBC7_ACCEL_1G: u16 = 0x70;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+enum EepromPhase {
+    /* Waiting for the start bit */
+    Idle,
+    /* Shifting in opcode and address */
+    Command,
+    /* Shifting out data words (preceded by a dummy 0 bit) */
+    Read,
+    /* Shifting in the data word for WRITE or WRAL */
+    WriteData,
+    /* Command complete; writes are done when CS goes low */
+    Done,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+enum EepromWrite {
+    Write,
+    Erase,
+    WriteAll,
+    EraseAll,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Mbc7State {
+    /* 0x0a written to 0x0000..0x2000 and 0x40 to 0x4000..0x6000 */
+    ram_enable1: bool,
+    ram_enable2: bool,
+
+    accel_x: u16,
+    accel_y: u16,
+    /* 0x55 has been written to Ax0x, so 0xaa to Ax1x will latch */
+    latch_armed: bool,
+
+    eeprom: Vec<u16>,
+    /* Pins as last written (and DO) */
+    cs: bool,
+    clk: bool,
+    di: bool,
+    do_: bool,
+
+    phase: EepromPhase,
+    shift: u16,
+    bits: u8,
+    addr: u8,
+    write_enabled: bool,
+    pending: Option<EepromWrite>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub enum TiltDirection {
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
 enum MbcType {
     NoMBC,
     MBC1,
     MBC2,
     MBC3,
     MBC5,
+    MBC7,
     MMM01,
+    Camera,
+}
+
+/*
+ * Identifies the cartridge a save state has been created for, so we can
+ * refuse loading states of other games.  Imported before anything else.
+ */
+#[derive(Default)]
+pub struct CartridgeId {
+    title_hash: u32,
+    extram_size: usize,
 }
 
 #[derive(SaveState)]
 pub struct Cartridge {
+    #[savestate(skip_if("version < 10"))]
+    id: CartridgeId,
+
     #[savestate(skip)]
     mbc: MbcType,
     #[savestate(skip)]
@@ -80,14 +196,291 @@ pub struct Cartridge {
     #[savestate(skip_if("version < 4"))]
     pub rumble_state: bool,
 
+    /* Only MMM01 can map anything but bank 0 to 0x0000..0x4000 */
+    #[savestate(skip_if("version < 9"))]
+    pub rom0_bank: usize,
+    #[savestate(skip_if("version < 9"))]
+    mmm01: MMM01State,
+
+    #[savestate(skip_if("version < 15"))]
+    mbc7: Mbc7State,
+    /* Directions the player is tilting towards (TiltDirection bits) */
+    #[savestate(skip)]
+    tilt: u8,
+    /* Analog tilt (x, y) in g, see tilt_analog_event() */
+    #[savestate(skip)]
+    tilt_analog: (f32, f32),
+
+    #[savestate(skip_if("version < 16"))]
+    camera: CameraState,
+
     #[allow(unused)]
     #[savestate(skip)]
     pub name: String,
 }
 
+impl CartridgeId {
+    fn new(title: &str, extram_size: usize) -> Self {
+        Self {
+            title_hash: crc32(title.as_bytes()),
+            extram_size,
+        }
+    }
+}
+
+impl SaveState for CartridgeId {
+    fn export<T: std::io::Write>(&self, stream: &mut T, version: u64)
+        -> std::io::Result<()>
+    {
+        SaveState::export(&self.title_hash, stream, version)?;
+        SaveState::export(&(self.extram_size as u64), stream, version)
+    }
+
+    fn import<T: std::io::Read>(&mut self, stream: &mut T, version: u64)
+        -> Result<(), SaveStateError>
+    {
+        let mut title_hash = 0u32;
+        let mut extram_size = 0u64;
+
+        SaveState::import(&mut title_hash, stream, version)?;
+        SaveState::import(&mut extram_size, stream, version)?;
+
+        if title_hash != self.title_hash {
+            return Err(SaveStateError::Incompatible(String::from(
+                "Save state belongs to a different game")));
+        }
+        if extram_size != self.extram_size as u64 {
+            return Err(SaveStateError::Incompatible(format!(
+                "Save state has {} kB of cartridge RAM, the cartridge has \
+                 {} kB", extram_size * 8, self.extram_size * 8)));
+        }
+
+        Ok(())
+    }
+}
+
+
+impl Mbc7State {
+    fn new() -> Self {
+        Self {
+            ram_enable1: false,
+            ram_enable2: false,
+
+            accel_x: 0x8000,
+            accel_y: 0x8000,
+            latch_armed: false,
+
+            eeprom: vec![0xffff; MBC7_EEPROM_WORDS],
+            cs: false,
+            clk: false,
+            di: false,
+            do_: true,
+
+            phase: EepromPhase::Idle,
+            shift: 0,
+            bits: 0,
+            addr: 0,
+            write_enabled: false,
+            pending: None,
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.ram_enable1 && self.ram_enable2
+    }
+
+    /* X decreases when tilting right, Y increases when tilting down
+     * (i.e. the bottom edge away from the player).  @tilt are the held
+     * directions (each 1 g), @analog the stick position (in g, right and
+     * down positive); the sum is limited to 1 g per axis. */
+    fn latch(&mut self, tilt: u8, analog: (f32, f32)) {
+        let held = |dir: TiltDirection| tilt & (1 << dir as u8) != 0;
+        let axis = |neg: bool, pos: bool, analog: f32| {
+            let digital = (pos as i32 - neg as i32) as f32;
+            let g = (digital + analog).clamp(-1.0, 1.0);
+            (MBC7_ACCEL_CENTER as f32 + g * MBC7_ACCEL_1G as f32).round()
+                as u16
+        };
+
+        self.accel_x = axis(held(TiltDirection::Right),
+                            held(TiltDirection::Left), -analog.0);
+        self.accel_y = axis(held(TiltDirection::Up),
+                            held(TiltDirection::Down), analog.1);
+    }
+
+    /* Ax8x: Bit 7 is CS, bit 6 CLK, bit 1 DI, bit 0 DO.  Returns whether
+     * the EEPROM contents have changed. */
+    fn eeprom_write(&mut self, val: u8) -> bool {
+        let cs = val & (1 << 7) != 0;
+        let clk = val & (1 << 6) != 0;
+        let di = val & (1 << 1) != 0;
+
+        let mut modified = false;
+        if !cs {
+            if self.cs {
+                modified = self.eeprom_commit();
+            }
+            self.phase = EepromPhase::Idle;
+            self.do_ = true;
+        } else if self.cs && !self.clk && clk {
+            self.eeprom_clock(di);
+        }
+
+        self.cs = cs;
+        self.clk = clk;
+        self.di = di;
+        modified
+    }
+
+    fn eeprom_read(&self) -> u8 {
+        (if self.cs { 1 << 7 } else { 0 }) |
+        (if self.clk { 1 << 6 } else { 0 }) |
+        (if self.di { 1 << 1 } else { 0 }) |
+        (if self.do_ { 1 << 0 } else { 0 })
+    }
+
+    /* Rising CLK edge while CS is high */
+    fn eeprom_clock(&mut self, di: bool) {
+        match self.phase {
+            EepromPhase::Idle => {
+                if di {
+                    self.phase = EepromPhase::Command;
+                    self.shift = 0;
+                    self.bits = 0;
+                }
+            },
+
+            EepromPhase::Command => {
+                self.shift = (self.shift << 1) | di as u16;
+                self.bits += 1;
+                if self.bits == 10 {
+                    self.eeprom_command();
+                }
+            },
+
+            EepromPhase::Read => {
+                let word = self.eeprom[self.addr as usize];
+                self.do_ = word & (1 << (15 - self.bits)) != 0;
+                self.bits += 1;
+                /* Sequential read */
+                if self.bits == 16 {
+                    self.addr = (self.addr + 1) % MBC7_EEPROM_WORDS as u8;
+                    self.bits = 0;
+                }
+            },
+
+            EepromPhase::WriteData => {
+                self.shift = (self.shift << 1) | di as u16;
+                self.bits += 1;
+                if self.bits == 16 {
+                    self.phase = EepromPhase::Done;
+                }
+            },
+
+            EepromPhase::Done => (),
+        }
+    }
+
+    /* Two opcode bits and eight address bits (the top one unused) have
+     * been shifted in */
+    fn eeprom_command(&mut self) {
+        let opcode = (self.shift >> 8) & 0x3;
+        self.addr = (self.shift & 0x7f) as u8;
+        self.bits = 0;
+        self.phase = EepromPhase::Done;
+
+        match opcode {
+            0b10 => {
+                self.phase = EepromPhase::Read;
+                self.do_ = false;
+            },
+
+            0b01 => {
+                self.pending = Some(EepromWrite::Write);
+                self.phase = EepromPhase::WriteData;
+            },
+
+            0b11 => self.pending = Some(EepromWrite::Erase),
+
+            _ => match (self.shift >> 6) & 0x3 {
+                0b00 => self.write_enabled = false,
+                0b01 => {
+                    self.pending = Some(EepromWrite::WriteAll);
+                    self.phase = EepromPhase::WriteData;
+                },
+                0b10 => self.pending = Some(EepromWrite::EraseAll),
+                0b11 => self.write_enabled = true,
+
+                _ => unreachable!(),
+            },
+        }
+
+        if self.phase == EepromPhase::WriteData {
+            self.shift = 0;
+        }
+    }
+
+    /* CS has gone low; writes only happen if the command was complete.
+     * They complete immediately, so DO reports ready afterwards. */
+    fn eeprom_commit(&mut self) -> bool {
+        let pending = self.pending.take();
+        if self.phase != EepromPhase::Done || !self.write_enabled {
+            return false;
+        }
+
+        let addr = self.addr as usize;
+        match pending {
+            Some(EepromWrite::Write) => self.eeprom[addr] = self.shift,
+            Some(EepromWrite::Erase) => self.eeprom[addr] = 0xffff,
+            Some(EepromWrite::WriteAll) => self.eeprom.fill(self.shift),
+            Some(EepromWrite::EraseAll) => self.eeprom.fill(0xffff),
+
+            None => return false,
+        }
+
+        true
+    }
+
+    /* Register layout in 0xa000..0xb000, repeating every 0x100 bytes */
+    fn page(&self) -> Box<[u8; 0x2000]> {
+        let mut page = Box::new([0xffu8; 0x2000]);
+        if !self.enabled() {
+            return page;
+        }
+
+        for (ofs, byte) in page[..0x1000].iter_mut().enumerate() {
+            *byte = match (ofs >> 4) & 0xf {
+                0x2 => self.accel_x as u8,
+                0x3 => (self.accel_x >> 8) as u8,
+                0x4 => self.accel_y as u8,
+                0x5 => (self.accel_y >> 8) as u8,
+                0x6 => 0x00,
+                0x8 => self.eeprom_read(),
+
+                _ => 0xff,
+            };
+        }
+
+        page
+    }
+
+    fn eeprom_bytes(&self) -> Vec<u8> {
+        self.eeprom.iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+
+    fn set_eeprom_bytes(&mut self, raw: &[u8]) {
+        for (word, bytes) in self.eeprom.iter_mut().zip(raw.chunks(2)) {
+            *word = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+    }
+}
+
+
 impl Cartridge {
     pub fn new() -> Self {
         Self {
+            id: Default::default(),
+
             mbc: MbcType::NoMBC,
             extram: false,
             rumble: false,
@@ -103,10 +496,63 @@ impl Cartridge {
 
             rumble_state: false,
 
+            rom0_bank: 0,
+            mmm01: Default::default(),
+
+            mbc7: Mbc7State::new(),
+            tilt: 0,
+            tilt_analog: (0.0, 0.0),
+
+            camera: CameraState::new(),
+
             name: "".into(),
         }
     }
 
+    pub fn rtc_snapshot(&self) -> Option<RtcSnapshot> {
+        Some(RtcSnapshot {
+            rtc: self.rtc?,
+            latched: self.rtc_latched,
+            taken_at: SystemTime::now().into(),
+        })
+    }
+
+    /* Sets the RTC to what it was when @snapshot was taken; the time that
+     * has passed since is not counted */
+    pub fn restore_rtc_snapshot(&mut self, snapshot: &RtcSnapshot) {
+        if self.rtc.is_none() {
+            return;
+        }
+
+        let now: SerSystemTime = SystemTime::now().into();
+        let shift = |t: SerSystemTime| t.shifted(&snapshot.taken_at, &now);
+
+        let mut rtc = snapshot.rtc;
+        rtc.set_at = shift(rtc.set_at);
+        self.rtc = Some(rtc);
+        self.rtc_latched = snapshot.latched.map(shift);
+    }
+
+    /* Only the mapper state; RAM, RTC, and EEPROM contents are kept */
+    pub fn reset(addr_space: &mut AddressSpace) {
+        let c = &mut addr_space.cartridge;
+
+        c.mbc1_ram_banking = false;
+        c.mbc3_hidden_ram_rw = false;
+        c.mbc3_clock_sel = 0;
+        c.rtc_latched = None;
+        c.rumble_state = false;
+        c.rom0_bank = 0;
+
+        let eeprom = std::mem::take(&mut c.mbc7.eeprom);
+        c.mbc7 = Mbc7State::new();
+        c.mbc7.eeprom = eeprom;
+
+        c.camera.reset();
+
+        Self::init_map(addr_space);
+    }
+
     pub fn init_map(addr_space: &mut AddressSpace) {
         let c = &mut addr_space.cartridge;
 
@@ -156,10 +602,40 @@ impl Cartridge {
                 }
             },
 
-            _ => panic!("MBC type not supported"),
+            MbcType::MBC7 => {
+                /* The registers are emulated through a virtual RAM page
+                 * (see mbc7_refresh()) */
+                addr_space.rom_bank = 1;
+                addr_space.extram_bank = Some(-1isize as usize);
+                addr_space.extram_rw = false;
+            },
+
+            MbcType::MMM01 => {
+                /* The menu is in the last 32 kB; it selects the game
+                 * and then locks the mapping */
+                c.mmm01 = Default::default();
+                c.rom0_bank = c.rom_size - 2;
+                addr_space.rom_bank = c.rom_size - 1;
+                if c.extram {
+                    addr_space.extram_bank = Some(0);
+                    addr_space.extram_rw = false;
+                } else {
+                    addr_space.extram_bank = None;
+                }
+            },
+
+            MbcType::Camera => {
+                addr_space.rom_bank = 1;
+                addr_space.extram_bank = Some(0);
+                addr_space.extram_rw = false;
+            },
         }
 
         addr_space.map();
+
+        if let MbcType::MBC7 = addr_space.cartridge.mbc {
+            Cartridge::mbc7_refresh(addr_space);
+        }
     }
 
     fn mbc1_write(addr_space: &mut AddressSpace, addr: u16, val: u8) {
@@ -402,21 +878,15 @@ impl Cartridge {
                 let raw_rtc_data = bincode::serialize(&rtc).unwrap();
 
                 #[cfg(not(target_arch = "wasm32"))]
-                {
-                    addr_space.extram_file.seek(SeekFrom::Start(pos as u64))
-                                          .unwrap();
-                    addr_space.extram_file.write_all(&raw_rtc_data).unwrap();
-                }
-                #[cfg(target_arch = "wasm32")]
-                {
-                    let len = raw_rtc_data.len();
-
-                    if addr_space.full_extram.len() < pos + len {
-                        addr_space.full_extram.resize(pos + len, 0);
+                if let Some(path) = addr_space.rtc_path.as_ref() {
+                    if let Err(e) = std::fs::write(path, &raw_rtc_data) {
+                        eprintln!("Failed to write RTC to {}: {}", path, e);
                     }
-                    addr_space.full_extram[pos..(pos + len)].clone_from_slice(&raw_rtc_data);
-                    addr_space.extram_dirty = true;
+                } else {
+                    write_extram_file(addr_space, pos, &raw_rtc_data);
                 }
+                #[cfg(target_arch = "wasm32")]
+                write_extram_file(addr_space, pos, &raw_rtc_data);
 
                 if addr_space.extram_bank == Some(-1isize as usize) {
                     /* So we can do a memset */
@@ -483,12 +953,290 @@ impl Cartridge {
         }
     }
 
+    fn mbc7_write(addr_space: &mut AddressSpace, addr: u16, val: u8) {
+        let c = &mut addr_space.cartridge;
+        let m = &mut c.mbc7;
+
+        match addr & 0xf000 {
+            0x0000 | 0x1000 => m.ram_enable1 = val == 0x0a,
+
+            0x2000 | 0x3000 => {
+                addr_space.rom_bank = val as usize % c.rom_size;
+                addr_space.remap_romn();
+                return;
+            },
+
+            0x4000 | 0x5000 => m.ram_enable2 = val == 0x40,
+
+            0x6000 | 0x7000 | 0xb000 => return,
+
+            0xa000 => {
+                if !m.enabled() {
+                    return;
+                }
+
+                match (addr >> 4) & 0xf {
+                    0x0 => {
+                        if val == 0x55 {
+                            m.accel_x = 0x8000;
+                            m.accel_y = 0x8000;
+                            m.latch_armed = true;
+                        }
+                    },
+
+                    0x1 => {
+                        if val == 0xaa && m.latch_armed {
+                            m.latch(c.tilt, c.tilt_analog);
+                            m.latch_armed = false;
+                        }
+                    },
+
+                    0x8 => {
+                        if m.eeprom_write(val) {
+                            let raw = m.eeprom_bytes();
+                            write_extram_file(addr_space, 0, &raw);
+                        }
+                    },
+
+                    _ => (),
+                }
+            },
+
+            _ => panic!("{:04x}", addr),
+        }
+
+        Cartridge::mbc7_refresh(addr_space);
+    }
+
+    fn fill_virtual_extram(addr_space: &mut AddressSpace,
+                           page: &[u8; 0x2000])
+    {
+        /* So we can write to it */
+        addr_space.extram_rw = true;
+        addr_space.remap_extram();
+
+        addr_space.set_virtual_extram_page(page);
+
+        /* Done writing */
+        addr_space.extram_rw = false;
+        addr_space.remap_extram();
+    }
+
+    fn mbc7_refresh(addr_space: &mut AddressSpace) {
+        let page = addr_space.cartridge.mbc7.page();
+        Cartridge::fill_virtual_extram(addr_space, &page);
+    }
+
+    /* Tilting is only seen by the game when it latches the accelerometer */
+    pub fn tilt_event(&mut self, dir: TiltDirection, down: bool) {
+        if down {
+            self.tilt |= 1 << dir as u8;
+        } else {
+            self.tilt &= !(1 << dir as u8);
+        }
+    }
+
+    /* @x and @y from -1.0 (left/up) to 1.0 (right/down), proportional to
+     * the tilt in g; added to the tilt_event() directions */
+    pub fn tilt_analog_event(&mut self, x: f32, y: f32) {
+        self.tilt_analog = (x.clamp(-1.0, 1.0), y.clamp(-1.0, 1.0));
+    }
+
+    /* Registers are mapped instead of RAM while bit 4 of the RAM bank is
+     * set; they are then presented through a virtual page */
+    fn camera_write(addr_space: &mut AddressSpace, addr: u16, val: u8) {
+        let c = &mut addr_space.cartridge;
+        let cam = &mut c.camera;
+
+        match addr & 0xe000 {
+            0x0000 => {
+                cam.ram_enable = val & 0x0f == 0x0a;
+                if !cam.regs_mapped {
+                    addr_space.extram_rw = cam.ram_enable;
+                    addr_space.remap_extram();
+                }
+            },
+
+            0x2000 => {
+                addr_space.rom_bank = (val as usize & 0x3f) % c.rom_size;
+                addr_space.remap_romn();
+            },
+
+            0x4000 => {
+                cam.regs_mapped = val & 0x10 != 0;
+                if cam.regs_mapped {
+                    addr_space.extram_bank = Some(-1isize as usize);
+                    Cartridge::camera_refresh(addr_space);
+                } else {
+                    let bank = val as usize & 0x0f;
+                    addr_space.extram_bank = Some(bank % c.extram_size);
+                    addr_space.extram_rw = cam.ram_enable;
+                    addr_space.remap_extram();
+                }
+            },
+
+            0x6000 => (),
+
+            0xa000 => {
+                /* Writes to disabled RAM */
+                if !cam.regs_mapped {
+                    return;
+                }
+
+                cam.write_reg(addr & 0x1fff, val);
+                Cartridge::camera_refresh(addr_space);
+            },
+
+            _ => unreachable!(),
+        }
+    }
+
+    fn camera_refresh(addr_space: &mut AddressSpace) {
+        let page = addr_space.cartridge.camera.regs_page();
+        Cartridge::fill_virtual_extram(addr_space, &page);
+    }
+
+    /* @count is in CPU cycles */
+    pub fn add_cycles(addr_space: &mut AddressSpace, count: u32) {
+        if let MbcType::Camera = addr_space.cartridge.mbc {
+            let cam = &mut addr_space.cartridge.camera;
+            if let Some(tiles) = cam.add_cycles(count) {
+                let regs_mapped = cam.regs_mapped;
+
+                addr_space.write_extram(CAMERA_RAM_OFFSET, &tiles);
+                if regs_mapped {
+                    /* Clear the busy flag */
+                    Cartridge::camera_refresh(addr_space);
+                }
+            }
+        }
+    }
+
+    pub fn set_camera_image(&mut self, width: usize, height: usize,
+                            rgb8: &[u8])
+    {
+        self.camera.set_image(width, height, rgb8);
+    }
+
+    /* Virtual register pages are not part of the save state, and the
+     * EEPROM goes to the RAM file like normal cartridge RAM does */
+    pub fn post_import(addr_space: &mut AddressSpace) {
+        match addr_space.cartridge.mbc {
+            MbcType::MBC7 => {
+                let raw = addr_space.cartridge.mbc7.eeprom_bytes();
+                write_extram_file(addr_space, 0, &raw);
+                Cartridge::mbc7_refresh(addr_space);
+            },
+
+            MbcType::Camera => {
+                if addr_space.cartridge.camera.regs_mapped {
+                    Cartridge::camera_refresh(addr_space);
+                }
+            },
+
+            _ => (),
+        }
+    }
+
+    fn mmm01_write(addr_space: &mut AddressSpace, addr: u16, val: u8) {
+        let c = &mut addr_space.cartridge;
+        let m = &mut c.mmm01;
+
+        match addr & 0xe000 {
+            0x0000 => {
+                addr_space.extram_rw = val & 0x0f == 0x0a;
+                if !m.locked {
+                    m.ram_bank_mask = (val >> 4) & 0x03;
+                    m.locked = val & 0x40 != 0;
+                }
+            },
+
+            0x2000 => {
+                if !m.locked {
+                    m.rom_bank_mid = (val >> 5) & 0x03;
+                }
+
+                let fixed = m.rom_bank_mask << 1;
+                m.rom_bank_low = (m.rom_bank_low & fixed) |
+                                 (val & !fixed & 0x1f);
+            },
+
+            0x4000 => {
+                m.ram_bank_low = (m.ram_bank_low & m.ram_bank_mask) |
+                                 (val & !m.ram_bank_mask & 0x03);
+                if !m.locked {
+                    m.ram_bank_high = (val >> 2) & 0x03;
+                    m.rom_bank_high = (val >> 4) & 0x03;
+                    m.mbc1_mode_disable = val & 0x40 != 0;
+                }
+            },
+
+            0x6000 => {
+                if !m.mbc1_mode_disable {
+                    m.mbc1_mode = val & 0x01 != 0;
+                }
+                if !m.locked {
+                    m.rom_bank_mask = (val >> 2) & 0x0f;
+                    m.multiplex = val & 0x40 != 0;
+                }
+            },
+
+            0xa000 => return,
+
+            _ => unreachable!(),
+        }
+
+        if !m.locked {
+            /* Until locked, the menu stays mapped */
+            addr_space.remap_extram();
+            return;
+        }
+
+        /* In multiplex mode, the RAM bank low bits and the ROM bank mid
+         * bits swap places */
+        let (rom_mid, ram_low) =
+            if m.multiplex {
+                (m.ram_bank_low, m.rom_bank_mid)
+            } else {
+                (m.rom_bank_mid, m.ram_bank_low)
+            };
+        let rom_outer = ((rom_mid as usize) << 5) |
+                        ((m.rom_bank_high as usize) << 7);
+
+        let mut rom_low = m.rom_bank_low as usize;
+        if rom_low & !((m.rom_bank_mask as usize) << 1) == 0 {
+            rom_low |= 1;
+        }
+
+        let rom0_low =
+            if m.multiplex && m.mbc1_mode {
+                0
+            } else {
+                (m.rom_bank_low & (m.rom_bank_mask << 1)) as usize
+            };
+
+        c.rom0_bank = (rom_outer | rom0_low) % c.rom_size;
+        addr_space.rom_bank = (rom_outer | rom_low) % c.rom_size;
+
+        if c.extram {
+            let bank = ram_low as usize | ((m.ram_bank_high as usize) << 2);
+            addr_space.extram_bank = Some(bank % c.extram_size);
+        }
+
+        addr_space.remap_rom0();
+        addr_space.remap_romn();
+        addr_space.remap_extram();
+    }
+
     pub fn cart_write(addr_space: &mut AddressSpace, addr: u16, val: u8) {
         match addr_space.cartridge.mbc {
             MbcType::MBC1 => Cartridge::mbc1_write(addr_space, addr, val),
             MbcType::MBC2 => Cartridge::mbc2_write(addr_space, addr, val),
             MbcType::MBC3 => Cartridge::mbc3_write(addr_space, addr, val),
             MbcType::MBC5 => Cartridge::mbc5_write(addr_space, addr, val),
+            MbcType::MBC7 => Cartridge::mbc7_write(addr_space, addr, val),
+            MbcType::MMM01 => Cartridge::mmm01_write(addr_space, addr, val),
+            MbcType::Camera => Cartridge::camera_write(addr_space, addr, val),
 
             _ => println!("ROM write {:02x} => {:04x} not handled", val, addr),
         }
@@ -496,18 +1244,121 @@ impl Cartridge {
 }
 
 
-pub fn load_rom(addr_space: &mut AddressSpace) -> SystemParams {
+fn rom_file_len(addr_space: &mut AddressSpace) -> usize {
+    #[cfg(target_arch = "wasm32")]
+    return addr_space.full_rom.len();
     #[cfg(not(target_arch = "wasm32"))]
-    addr_space.rom_file.seek(SeekFrom::Start(0x100)).unwrap();
+    return addr_space.rom_file.metadata().unwrap().len() as usize;
+}
 
+fn read_rom_data_area(addr_space: &mut AddressSpace, offset: usize)
+    -> Option<RomDataArea>
+{
     let mut raw_rda: [u8; 0x50] = [0u8; 0x50];
+
     #[cfg(target_arch = "wasm32")]
-    raw_rda[0x0..0x50].copy_from_slice(&addr_space.full_rom[0x100..0x150]);
+    raw_rda.copy_from_slice(
+        addr_space.full_rom.get(offset + 0x100..offset + 0x150)?);
+
     #[cfg(not(target_arch = "wasm32"))]
-    addr_space.rom_file.read_exact(&mut raw_rda).unwrap();
+    {
+        addr_space.rom_file.seek(SeekFrom::Start(offset as u64 + 0x100))
+                           .ok()?;
+        addr_space.rom_file.read_exact(&mut raw_rda).ok()?;
+    }
+
+    bincode::deserialize(&raw_rda).ok()
+}
 
-    let rom_data_area: RomDataArea =
-        bincode::deserialize(&raw_rda).unwrap();
+/* For data stored behind the cartridge RAM in the RAM file (bypassing the
+ * RAM mapping) */
+fn read_extram_file(addr_space: &mut AddressSpace, pos: usize, len: usize)
+    -> Option<Vec<u8>>
+{
+    let mut data = vec![0u8; len];
+
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        addr_space.extram_file.seek(SeekFrom::Start(pos as u64)).ok()?;
+        addr_space.extram_file.read_exact(&mut data).ok()?;
+    }
+    #[cfg(target_arch = "wasm32")]
+    data.clone_from_slice(addr_space.full_extram.get(pos..(pos + len))?);
+
+    Some(data)
+}
+
+fn write_extram_file(addr_space: &mut AddressSpace, pos: usize, data: &[u8]) {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        addr_space.extram_file.seek(SeekFrom::Start(pos as u64)).unwrap();
+        addr_space.extram_file.write_all(data).unwrap();
+    }
+    #[cfg(target_arch = "wasm32")]
+    {
+        let len = data.len();
+
+        if addr_space.full_extram.len() < pos + len {
+            addr_space.full_extram.resize(pos + len, 0);
+        }
+        addr_space.full_extram[pos..(pos + len)].clone_from_slice(data);
+        addr_space.extram_dirty = true;
+    }
+}
+
+fn load_rtc(addr_space: &mut AddressSpace, sidecar: Option<&String>,
+            pos: usize, len: usize)
+    -> RamRTCData
+{
+    if let Some(path) = sidecar {
+        match std::fs::read(path) {
+            Ok(raw_rtc_data) =>
+                return bincode::deserialize(&raw_rtc_data).unwrap_or_else(|e| {
+                    eprintln!("Failed to parse RTC from {}: {}", path, e);
+                    Default::default()
+                }),
+
+            /* Fall back to the RAM file, where older versions put it */
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+
+            Err(e) => {
+                eprintln!("Failed to read RTC from {}: {}", path, e);
+                return Default::default();
+            },
+        }
+    }
+
+    let raw_rtc_data =
+        match read_extram_file(addr_space, pos, len) {
+            Some(data) => data,
+            None => return Default::default(),
+        };
+
+    let rtc = bincode::deserialize(&raw_rtc_data).unwrap_or_default();
+
+    /* The RAM file is about to be truncated, so migrate right away */
+    if let Some(path) = sidecar {
+        if let Err(e) = std::fs::write(path, &raw_rtc_data) {
+            eprintln!("Failed to write RTC to {}: {}", path, e);
+        }
+    }
+
+    rtc
+}
+
+pub fn load_rom(addr_space: &mut AddressSpace) -> SystemParams {
+    let mut rom_data_area = read_rom_data_area(addr_space, 0).unwrap();
+
+    /* MMM01 images have the menu (with the actual MMM01 header) in the
+     * last 32 kB, and the first game at the start */
+    let rom_len = rom_file_len(addr_space);
+    let is_mmm01 = |rda: &RomDataArea| (0x0b..=0x0d).contains(&rda.cartridge);
+    if rom_len >= 0x10000 && !is_mmm01(&rom_data_area) {
+        let menu_rda = read_rom_data_area(addr_space, rom_len - 0x8000);
+        if let Some(menu_rda) = menu_rda.filter(is_mmm01) {
+            rom_data_area = menu_rda;
+        }
+    }
 
     let (mbc, extram, batt, rtc, rumble) = match rom_data_area.cartridge {
         0x00 => (MbcType::NoMBC, false, false, false, false),
@@ -538,6 +1389,10 @@ pub fn load_rom(addr_space: &mut AddressSpace) -> SystemParams {
         0x1d => (MbcType::MBC5,  false,  true, false,  true),
         0x1e => (MbcType::MBC5,  false,  true,  true,  true),
 
+        0x22 => (MbcType::MBC7,  false,  true, false, false),
+
+        0xfc => (MbcType::Camera, true,  true, false, false),
+
         _ => panic!("Unknown cartridge type {:#x}", rom_data_area.cartridge),
     };
 
@@ -560,6 +1415,7 @@ pub fn load_rom(addr_space: &mut AddressSpace) -> SystemParams {
     };
 
     let gbc_mode = rom_data_area.cgb_mode & 0x80 != 0;
+    let dmg_compatible = rom_data_area.cgb_mode == 0x80;
     let sgb_mode = rom_data_area.sgb_mode == 0x03;
     let cart_name = String::from_utf8_lossy(&rom_data_area.title).to_string();
 
@@ -584,7 +1440,9 @@ pub fn load_rom(addr_space: &mut AddressSpace) -> SystemParams {
                  MbcType::MBC2  => "+MBC2",
                  MbcType::MBC3  => "+MBC3",
                  MbcType::MBC5  => "+MBC5",
+                 MbcType::MBC7  => "+MBC7",
                  MbcType::MMM01 => "+MMM01",
+                 MbcType::Camera => "+CAMERA",
              },
              if extram { "+EXTRAM" } else { "" },
              if batt { "+BATTERY" } else { "" },
@@ -592,6 +1450,8 @@ pub fn load_rom(addr_space: &mut AddressSpace) -> SystemParams {
              if rumble { "+RUMBLE" } else { "" });
 
     addr_space.cartridge = Cartridge {
+        id: CartridgeId::new(&cart_name, extram_size),
+
         mbc,
         extram: extram || batt,
         rumble,
@@ -607,6 +1467,15 @@ pub fn load_rom(addr_space: &mut AddressSpace) -> SystemParams {
 
         rumble_state: false,
 
+        rom0_bank: 0,
+        mmm01: Default::default(),
+
+        mbc7: Mbc7State::new(),
+        tilt: 0,
+        tilt_analog: (0.0, 0.0),
+
+        camera: CameraState::new(),
+
         name: cart_name.clone(),
     };
 
@@ -616,11 +1485,36 @@ pub fn load_rom(addr_space: &mut AddressSpace) -> SystemParams {
     /* FIXME: Can you get this statically? */
     let rtc_data_length = bincode::serialize(&RamRTCData::default()).unwrap().len();
 
+    #[cfg(not(target_arch = "wasm32"))]
+    let rtc_sidecar = addr_space.rtc_path.clone();
+    #[cfg(target_arch = "wasm32")]
+    let rtc_sidecar: Option<String> = None;
+
     let mut extram_len = extram_size * 8192;
-    if rtc {
+    if rtc && rtc_sidecar.is_none() {
         extram_len += rtc_data_length;
     }
 
+    let mbc7 = matches!(addr_space.cartridge.mbc, MbcType::MBC7);
+    if mbc7 {
+        extram_len += MBC7_EEPROM_WORDS * 2;
+
+        if let Some(raw) = read_extram_file(addr_space, 0,
+                                            MBC7_EEPROM_WORDS * 2)
+        {
+            addr_space.cartridge.mbc7.set_eeprom_bytes(&raw);
+        }
+    }
+
+    /* Must be loaded before the RAM file is resized, because the RTC may
+     * have to be migrated from there to the sidecar file */
+    addr_space.cartridge.rtc = if rtc {
+            Some(load_rtc(addr_space, rtc_sidecar.as_ref(),
+                          extram_size * 8192, rtc_data_length))
+        } else {
+            None
+        };
+
     #[cfg(not(target_arch = "wasm32"))]
     {
         #[cfg(target_os = "linux")]
@@ -642,30 +1536,30 @@ pub fn load_rom(addr_space: &mut AddressSpace) -> SystemParams {
     #[cfg(target_arch = "wasm32")]
     addr_space.full_extram.resize(extram_len, 0);
 
-    addr_space.cartridge.rtc = if rtc {
-            let pos = extram_size * 8192;
-            let mut raw_rtc_data = vec![0u8; rtc_data_length];
-
-            #[cfg(not(target_arch = "wasm32"))]
-            {
-                addr_space.extram_file.seek(SeekFrom::Start(pos as u64)).unwrap();
-                addr_space.extram_file.read_exact(&mut raw_rtc_data).unwrap();
-            }
-            #[cfg(target_arch = "wasm32")]
-            {
-                raw_rtc_data.clone_from_slice(&addr_space.full_extram[pos..(pos+rtc_data_length)]);
-            }
+    /* Fresh RAM files are zero-filled, but an erased EEPROM is not */
+    if mbc7 {
+        let raw = addr_space.cartridge.mbc7.eeprom_bytes();
+        write_extram_file(addr_space, 0, &raw);
+    }
 
-            Some(bincode::deserialize::<RamRTCData>(&raw_rtc_data).unwrap())
+    let model =
+        if gbc_mode {
+            Model::Cgb
+        } else if sgb_mode {
+            Model::Sgb
         } else {
-            None
+            Model::Dmg
         };
 
     SystemParams {
+        model,
         cgb: gbc_mode,
+        dmg_compatible,
         sgb: sgb_mode,
         cartridge_name: cart_name,
         serial_conn_param: SerialConnParam::default(),
+        ir_conn_param: IrConnParam::default(),
+        audio_buffers: DEFAULT_BUFCOUNT,
     }
 }
 
@@ -687,4 +1581,209 @@ impl SerSystemTime {
             self.tv_sec - since.tv_sec - 1
         }
     }
+
+    fn nanos(&self) -> i128 {
+        self.tv_sec as i128 * 1_000_000_000 + self.tv_nsec as i128
+    }
+
+    /* Moves this time by as much as @to is after @from */
+    fn shifted(&self, from: &Self, to: &Self) -> Self {
+        let nanos = self.nanos() + to.nanos() - from.nanos();
+        SerSystemTime {
+            tv_sec: nanos.div_euclid(1_000_000_000) as i64,
+            tv_nsec: nanos.rem_euclid(1_000_000_000) as i64,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::{RamRTCData, SerSystemTime, TiltDirection};
+    use crate::testing::{fix_header_checksum, test_rom, test_system};
+
+    /* 128 kB: two 32 kB games, 32 kB unused, the menu in the last 32 kB;
+     * every bank has its index (plus 0x10) at 0x1000 */
+    fn mmm01_image() -> Vec<u8> {
+        let mut rom = vec![0u8; 0x20000];
+
+        for bank in 0..8 {
+            rom[bank * 0x4000 + 0x1000] = 0x10 + bank as u8;
+        }
+
+        for (game, name) in [(0usize, b"GAME1"), (1, b"GAME2")] {
+            let ofs = game * 0x8000;
+            rom[(ofs + 0x134)..(ofs + 0x139)].copy_from_slice(name);
+        }
+
+        let menu = 0x18000;
+        rom[(menu + 0x134)..(menu + 0x138)].copy_from_slice(b"MENU");
+        /* MMM01, 128 kB */
+        rom[menu + 0x147] = 0x0b;
+        rom[menu + 0x148] = 0x02;
+        fix_header_checksum(&mut rom[menu..]);
+
+        rom
+    }
+
+    fn title(sys: &mut crate::system_state::System) -> Vec<u8> {
+        (0x134..0x139).map(|addr| sys.peek(addr)).collect()
+    }
+
+    #[test]
+    fn mmm01_maps_menu_then_selected_game() {
+        let mut sys = test_system(mmm01_image());
+        assert_eq!(sys.sys_state.addr_space.cartridge.rom_size, 8);
+
+        /* The menu is mapped first */
+        assert_eq!(&title(&mut sys)[0..4], b"MENU");
+        assert_eq!(sys.peek(0x1000), 0x16);
+        assert_eq!(sys.peek(0x5000), 0x17);
+
+        /* Select the second game (banks 2 and 3, all of it fixed) */
+        sys.poke(0x2000, 0x02);
+        sys.poke(0x6000, 0x0f << 2);
+        assert_eq!(sys.peek(0x1000), 0x16);
+        sys.poke(0x0000, 0x40);
+
+        assert_eq!(title(&mut sys), b"GAME2");
+        assert_eq!(sys.peek(0x1000), 0x12);
+        assert_eq!(sys.peek(0x5000), 0x13);
+
+        /* The game cannot leave its area */
+        sys.poke(0x2000, 0x05);
+        assert_eq!(sys.peek(0x1000), 0x12);
+        assert_eq!(sys.peek(0x5000), 0x13);
+        sys.poke(0x6000, 0x00);
+        sys.poke(0x2000, 0x00);
+        assert_eq!(sys.peek(0x5000), 0x13);
+    }
+
+    #[test]
+    fn rtc_snapshot_ignores_time_passed() {
+        let mut rom = test_rom(&[0x18, 0xfe]);
+        /* MBC3+TIMER+BATTERY, 64 kB */
+        rom[0x147] = 0x0f;
+        rom[0x148] = 0x01;
+        rom.resize(0x10000, 0);
+        fix_header_checksum(&mut rom);
+
+        let mut sys = test_system(rom);
+        let c = &mut sys.sys_state.addr_space.cartridge;
+
+        let now: SerSystemTime = SystemTime::now().into();
+        let ago = |secs: i64| SerSystemTime {
+            tv_sec: now.tv_sec - secs,
+            tv_nsec: now.tv_nsec,
+        };
+
+        c.rtc = Some(RamRTCData {
+            set_at: ago(3600),
+            secs: 5,
+            ..Default::default()
+        });
+        /* Latched 50 minutes after it was set */
+        c.rtc_latched = Some(ago(600));
+        assert_eq!(c.mbc3_time(), (3005, false));
+
+        /* As if taken a day ago */
+        let mut snapshot = c.rtc_snapshot().unwrap();
+        snapshot.taken_at = ago(86400);
+
+        c.rtc = Some(Default::default());
+        c.rtc_latched = None;
+        c.restore_rtc_snapshot(&snapshot);
+
+        assert_eq!(c.mbc3_time(), (3005, false));
+        assert!(c.rtc_latched.unwrap().secs_since(&now) >= 86400 - 600);
+    }
+
+    /* MBC7 with the registers enabled */
+    fn mbc7_system() -> crate::testing::TestSystem {
+        let mut rom = test_rom(&[0x18, 0xfe]);
+        /* MBC7+SENSOR+RUMBLE+RAM+BATTERY, 64 kB */
+        rom[0x147] = 0x22;
+        rom[0x148] = 0x01;
+        rom.resize(0x10000, 0);
+        fix_header_checksum(&mut rom);
+
+        let mut sys = test_system(rom);
+        sys.poke(0x0000, 0x0a);
+        sys.poke(0x4000, 0x40);
+        sys
+    }
+
+    fn accel(sys: &mut crate::system_state::System) -> (u16, u16) {
+        let x = u16::from_le_bytes([sys.peek(0xa020), sys.peek(0xa030)]);
+        let y = u16::from_le_bytes([sys.peek(0xa040), sys.peek(0xa050)]);
+        (x, y)
+    }
+
+    #[test]
+    fn mbc7_accelerometer_latch() {
+        let mut sys = mbc7_system();
+
+        sys.poke(0xa000, 0x55);
+        assert_eq!(accel(&mut sys), (0x8000, 0x8000));
+        sys.poke(0xa010, 0xaa);
+        assert_eq!(accel(&mut sys), (0x81d0, 0x81d0));
+
+        /* Tilting only shows after the next latch */
+        let c = &mut sys.sys_state.addr_space.cartridge;
+        c.tilt_event(TiltDirection::Right, true);
+        c.tilt_event(TiltDirection::Down, true);
+        assert_eq!(accel(&mut sys), (0x81d0, 0x81d0));
+
+        /* 0xaa alone does not latch */
+        sys.poke(0xa010, 0xaa);
+        assert_eq!(accel(&mut sys), (0x81d0, 0x81d0));
+
+        sys.poke(0xa000, 0x55);
+        sys.poke(0xa010, 0xaa);
+        assert_eq!(accel(&mut sys), (0x81d0 - 0x70, 0x81d0 + 0x70));
+    }
+
+    #[test]
+    fn mbc7_analog_tilt() {
+        let mut sys = mbc7_system();
+        let c = &mut sys.sys_state.addr_space.cartridge;
+        c.tilt_analog_event(0.5, -0.25);
+
+        sys.poke(0xa000, 0x55);
+        sys.poke(0xa010, 0xaa);
+        assert_eq!(accel(&mut sys), (0x81d0 - 0x38, 0x81d0 - 0x1c));
+
+        /* Together with keys, still at most 1 g */
+        let c = &mut sys.sys_state.addr_space.cartridge;
+        c.tilt_event(TiltDirection::Left, true);
+        c.tilt_analog_event(-0.5, 0.0);
+        sys.poke(0xa000, 0x55);
+        sys.poke(0xa010, 0xaa);
+        assert_eq!(accel(&mut sys), (0x81d0 + 0x70, 0x81d0));
+    }
+
+    #[test]
+    fn camera_disabled_ram_write() {
+        let mut rom = test_rom(&[0x18, 0xfe]);
+        /* Pocket Camera, 64 kB ROM, 128 kB RAM */
+        rom[0x147] = 0xfc;
+        rom[0x148] = 0x01;
+        rom[0x149] = 0x04;
+        rom.resize(0x10000, 0);
+        fix_header_checksum(&mut rom);
+
+        let mut sys = test_system(rom);
+        sys.poke(0x4000, 0x00);
+        sys.poke(0x0000, 0x0a);
+        sys.poke(0xa000, 0x42);
+        assert_eq!(sys.peek(0xa000), 0x42);
+
+        /* Ignored, not taken as a register write */
+        sys.poke(0x0000, 0x00);
+        sys.poke(0xa000, 0x24);
+        sys.poke(0x0000, 0x0a);
+        assert_eq!(sys.peek(0xa000), 0x42);
+    }
 }
@@ -0,0 +1,72 @@
+/* Pixels are ABGR8888 */
+fn push_rgb8(data: &mut Vec<u8>, px: u32) {
+    data.push(px as u8);
+    data.push((px >> 8) as u8);
+    data.push((px >> 16) as u8);
+}
+
+/* The LCD as 160x144 RGB8 */
+pub fn lcd_rgb8(lcd: &[u32; 160 * 144]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(160 * 144 * 3);
+    for px in lcd.iter() {
+        push_rgb8(&mut data, *px);
+    }
+    data
+}
+
+/* The LCD downscaled to 80x72 RGB8, averaging each 2x2 block */
+pub fn thumbnail_rgb8(lcd: &[u32; 160 * 144]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(80 * 72 * 3);
+    for y in 0..72 {
+        for x in 0..80 {
+            let block = [
+                lcd[(y * 2) * 160 + x * 2],
+                lcd[(y * 2) * 160 + x * 2 + 1],
+                lcd[(y * 2 + 1) * 160 + x * 2],
+                lcd[(y * 2 + 1) * 160 + x * 2 + 1],
+            ];
+
+            for shift in [0, 8, 16] {
+                let sum: u32 =
+                    block.iter().map(|px| (px >> shift) & 0xff).sum();
+                data.push((sum / 4) as u8);
+            }
+        }
+    }
+    data
+}
+
+/* The LCD inside of the SGB border, as 256x224 RGB8 */
+pub fn sgb_rgb8(border: &[u32; 256 * 224], lcd: &[u32; 160 * 144])
+    -> Vec<u8>
+{
+    let mut data = Vec::with_capacity(256 * 224 * 3);
+    for y in 0..224 {
+        for x in 0..256 {
+            let px =
+                if (48..208).contains(&x) && (40..184).contains(&y) {
+                    lcd[(y - 40) * 160 + (x - 48)]
+                } else {
+                    border[y * 256 + x]
+                };
+
+            push_rgb8(&mut data, px);
+        }
+    }
+    data
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn write_png(path: &str, width: u32, height: u32, rgb8: &[u8])
+    -> Result<(), String>
+{
+    image::save_buffer(path, rgb8, width, height, image::ColorType::Rgb8)
+        .map_err(|e| e.to_string())
+}
+
+/* Returns width, height, and RGB8 data */
+#[cfg(not(target_arch = "wasm32"))]
+pub fn read_png(path: &str) -> Result<(u32, u32, Vec<u8>), String> {
+    let img = image::open(path).map_err(|e| e.to_string())?.to_rgb8();
+    Ok((img.width(), img.height(), img.into_raw()))
+}
@@ -0,0 +1,89 @@
+#![allow(unused_unsafe)]
+#![allow(dead_code)]
+
+use crate::mem;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::address_space::AddressSpace;
+#[cfg(target_os = "linux")]
+use crate::address_space::AS_BASE;
+use crate::cpu::{disassemble_range, Reg16};
+use crate::io::{io_read, io_write, IOSpace};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::rom;
+use crate::system_state::System;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::system_state::SystemState;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::ui::UI;
+
+
+/*
+ * Access to memory and registers for external tools (e.g. TAS scripts),
+ * meant to be used between frames.  Everything goes through the same
+ * paths as CPU accesses, so I/O side effects (and watchpoints) apply.
+ */
+impl System {
+    /*
+     * A system without window, audio, or input, running as fast as it is
+     * driven (through main_loop() or step()).  On Linux, there can only
+     * be one system per process at a time.
+     */
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn headless(rom: Vec<u8>, ram: Option<Vec<u8>>) -> Box<System> {
+        let mut addr_space = Box::new(AddressSpace::from_bytes(rom, ram));
+        let sys_params = rom::load_rom(addr_space.as_mut());
+
+        let mut ui = UI::new_headless(&sys_params.cartridge_name);
+        let sys_state = Box::new(SystemState::new(addr_space, sys_params,
+                                                  &mut ui));
+
+        let mut sys = Box::new(System::new(sys_state, ui, String::new()));
+        sys.set_realtime(false);
+        sys
+    }
+
+    pub fn peek(&mut self, addr: u16) -> u8 {
+        mem![&mut self.sys_state; addr]
+    }
+
+    pub fn poke(&mut self, addr: u16, val: u8) {
+        mem![&mut self.sys_state; val => addr];
+    }
+
+    /* Writes @start..=@end as raw bytes, as currently mapped */
+    pub fn dump_region(&mut self, start: u16, end: u16,
+                       out: &mut dyn std::io::Write)
+        -> std::io::Result<()>
+    {
+        let data: Vec<u8> =
+            (start..=end).map(|addr| {
+                /* Without read side effects (e.g. the wave RAM redirect) */
+                if (0xff00..0xff80).contains(&addr) {
+                    self.sys_state.io_get_addr(addr - 0xff00)
+                } else {
+                    self.peek(addr)
+                }
+            }).collect();
+
+        self.sys_state.debugger.discard_hits();
+        out.write_all(&data)
+    }
+
+    /* @count instructions from @start on, as (address, text) */
+    pub fn disassemble_range(&mut self, start: u16, count: usize)
+        -> Vec<(u16, String)>
+    {
+        let insns = disassemble_range(&mut self.sys_state, &self.cpu, start,
+                                      count);
+        self.sys_state.debugger.discard_hits();
+        insns
+    }
+
+    pub fn get_reg16(&self, reg: Reg16) -> u16 {
+        self.cpu.reg16(reg)
+    }
+
+    pub fn set_reg16(&mut self, reg: Reg16, val: u16) {
+        self.cpu.set_reg16(reg, val);
+    }
+}
@@ -49,7 +49,10 @@ impl SGBState {
     pub fn new() -> Self {
         Self {
             packet_index: 0,
-            packet_bit_index: 0,
+            /* No packet transfer in progress until we see a reset pulse;
+             * otherwise, normal joypad reads would be taken for packet
+             * bits */
+            packet_bit_index: 16 * 8,
 
             raw_packets: [[0u8; 16]; 7],
 
@@ -66,6 +69,10 @@ impl SGBState {
         }
     }
 
+    pub fn border_enabled(&self) -> bool {
+        self.border_enabled
+    }
+
     fn reload_border(&mut self) {
         if self.border_enabled {
             self.load_border = true;
@@ -82,6 +89,7 @@ fn sgb_palxy(sys_state: &mut SystemState, x: usize, y: usize) {
 
     for p in 0..8 {
         sys_state.display.set_bg_pal(p * 4, col0);
+        sys_state.display.set_obj_pal(p * 4, col0);
     }
 
     for i in 0..6 {
@@ -91,6 +99,7 @@ fn sgb_palxy(sys_state: &mut SystemState, x: usize, y: usize) {
         let p = if i < 3 { x } else { y };
 
         sys_state.display.set_bg_pal(p * 4 + (i % 3) + 1, col);
+        sys_state.display.set_obj_pal(p * 4 + (i % 3) + 1, col);
     }
 }
 
@@ -234,11 +243,14 @@ pub fn sgb_pulse(sys_state: &mut SystemState, np14: bool, np15: bool) {
     let s = &mut sys_state.sgb_state;
 
     if np14 && np15 {
+        /* Reset pulse: (Re-)start the current packet */
         s.packet_bit_index = 0;
         for x in &mut s.raw_packets[s.packet_index] {
             *x = 0;
         }
     } else if s.packet_bit_index < 16 * 8 {
+        /* Bits are sent LSb first; P14 low means 0, P15 low means 1.
+         * The stop bit after the 128th bit is ignored. */
         let pi = s.packet_index;
         if np15 {
             let i = s.packet_bit_index;
@@ -403,3 +415,187 @@ fn sgb_construct_border_image(sys_state: &mut SystemState) {
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::{TransferDest, sgb_buf_done, sgb_cmd};
+    use crate::io::lcd::rgb15_to_rgb24;
+    use crate::system_state::Model;
+    use crate::testing::{TestSystem, fix_header_checksum, test_rom,
+                         test_system_as};
+
+    const RED: u16 = 0x001f;
+    const GREEN: u16 = 0x03e0;
+    const BLUE: u16 = 0x7c00;
+    const YELLOW: u16 = 0x03ff;
+    const WHITE: u16 = 0x7fff;
+
+    fn sgb_system() -> TestSystem {
+        let mut rom = test_rom(&[0x18, 0xfe]);
+        rom[0x146] = 0x03;
+        rom[0x14b] = 0x33;
+        fix_header_checksum(&mut rom);
+
+        test_system_as(rom, Some(Model::Sgb))
+    }
+
+    fn send_cmd(sys: &mut TestSystem, cmd: u8, data: &[u8]) {
+        let packet = &mut sys.sys_state.sgb_state.raw_packets[0];
+        *packet = [0u8; 16];
+        packet[0] = (cmd << 3) | 1;
+        packet[1..(data.len() + 1)].copy_from_slice(data);
+
+        sgb_cmd(&mut sys.sys_state);
+    }
+
+    /* Sends @packet bit by bit through P1, as games do */
+    fn send_packet_via_p1(sys: &mut TestSystem, packet: &[u8; 16]) {
+        /* Reset pulse: P14 and P15 low */
+        sys.poke(0xff00, 0x00);
+        sys.poke(0xff00, 0x30);
+
+        /* LSb first; P15 low for 1, P14 low for 0 */
+        for i in 0..128 {
+            let bit = (packet[i / 8] >> (i % 8)) & 1;
+            sys.poke(0xff00, if bit != 0 { 0x10 } else { 0x20 });
+            sys.poke(0xff00, 0x30);
+        }
+
+        /* Stop bit (a 0) */
+        sys.poke(0xff00, 0x20);
+        sys.poke(0xff00, 0x30);
+    }
+
+    /* Sends PAL01 or PAL23 (or PAL03/PAL12) */
+    fn send_palxy(sys: &mut TestSystem, cmd: u8, colors: [u16; 7]) {
+        let data: Vec<u8> = colors.iter().flat_map(|c| c.to_le_bytes())
+                                  .collect();
+        send_cmd(sys, cmd, &data);
+    }
+
+    /* Renders @bytes into the screen the way the SGB reads it in *_TRN */
+    fn trn_screen(bytes: &[u8]) -> [u8; 160 * 144] {
+        let mut screen = [0u8; 160 * 144];
+
+        for (row, pair) in bytes.chunks(2).enumerate() {
+            let tile = row / 8;
+            let y = (tile / 20) * 8 + row % 8;
+            let x = (tile % 20) * 8;
+
+            for bit in 0..8 {
+                let lo = (pair[0] >> (7 - bit)) & 1;
+                let hi = (pair[1] >> (7 - bit)) & 1;
+                screen[y * 160 + x + bit] = lo | (hi << 1);
+            }
+        }
+
+        screen
+    }
+
+    /* Color 3 of the palette used for the given screen tile */
+    fn shown_color(sys: &mut TestSystem, tile_x: usize, tile_y: usize)
+        -> u32
+    {
+        /* Show color 3 everywhere */
+        sys.poke(0xff47, 0xff);
+        sys.main_loop(true);
+        sys.main_loop(true);
+
+        sys.sys_state.display.lcd_pixels[tile_y * 8 * 160 + tile_x * 8]
+    }
+
+    #[test]
+    fn packet_via_p1() {
+        let mut sys = sgb_system();
+
+        /* With one controller, P1 reads 0xf with no lines selected */
+        for val in [0x20, 0x10, 0x30] {
+            sys.poke(0xff00, val);
+        }
+        assert_eq!(sys.peek(0xff00) & 0x0f, 0x0f);
+
+        /* MLT_REQ for two controllers */
+        let mut packet = [0u8; 16];
+        packet[0] = (0x11 << 3) | 1;
+        packet[1] = 0x01;
+        send_packet_via_p1(&mut sys, &packet);
+        assert_eq!(sys.sys_state.sgb_state.raw_packets[0], packet);
+
+        /* Now the same sequence selects the second controller */
+        for val in [0x20, 0x10, 0x30] {
+            sys.poke(0xff00, val);
+        }
+        assert_eq!(sys.peek(0xff00) & 0x0f, 0x0e);
+    }
+
+    #[test]
+    fn pal01_pal23() {
+        let mut sys = sgb_system();
+
+        send_palxy(&mut sys, 0x00,
+                   [WHITE, 0, 0, RED, 0, 0, GREEN]);
+        send_palxy(&mut sys, 0x01,
+                   [WHITE, 0, 0, BLUE, 0, 0, YELLOW]);
+
+        /* Left to right: palettes 0, 1, 2, 3 */
+        for (i, pal_bi) in sys.sys_state.display.sgb_pal_bi.iter_mut()
+                              .enumerate()
+        {
+            *pal_bi = ((i % 20) / 5 * 4) as u8;
+        }
+
+        assert_eq!(shown_color(&mut sys, 0, 0), rgb15_to_rgb24(RED));
+        assert_eq!(shown_color(&mut sys, 5, 0), rgb15_to_rgb24(GREEN));
+        assert_eq!(shown_color(&mut sys, 10, 0), rgb15_to_rgb24(BLUE));
+        assert_eq!(shown_color(&mut sys, 15, 17), rgb15_to_rgb24(YELLOW));
+    }
+
+    #[test]
+    fn pal_trn_pal_set() {
+        let mut sys = sgb_system();
+
+        send_cmd(&mut sys, 0x0b, &[]);
+        assert!(sys.sys_state.sgb_state.trn_dst == TransferDest::Palette);
+        assert!(sys.sys_state.display.fill_for_sgb_buf);
+
+        /* System palettes 0x000, 0x005, 0x100, and 0x1ff */
+        let mut pal_data = vec![0u8; 0x1000];
+        for (pal, col3, col0) in [(0x000, RED, WHITE), (0x005, GREEN, BLUE),
+                                  (0x100, BLUE, BLUE), (0x1ff, YELLOW, BLUE)]
+        {
+            let ofs = pal * 8;
+            pal_data[ofs..(ofs + 2)].copy_from_slice(&col0.to_le_bytes());
+            pal_data[(ofs + 6)..(ofs + 8)]
+                .copy_from_slice(&col3.to_le_bytes());
+        }
+
+        sys.sys_state.display.for_sgb_buf = trn_screen(&pal_data);
+        sgb_buf_done(&mut sys.sys_state);
+        /* Done by hand, so the LCD does not need to */
+        sys.sys_state.display.fill_for_sgb_buf = false;
+        assert_eq!(&sys.sys_state.sgb_state.pal_data[..], &pal_data[..]);
+
+        /* Assign them to palettes 3, 2, 1, 0 */
+        send_cmd(&mut sys, 0x0a, &[0xff, 0x01, 0x00, 0x01, 0x05, 0x00,
+                                   0x00, 0x00, 0x00]);
+
+        for (i, pal_bi) in sys.sys_state.display.sgb_pal_bi.iter_mut()
+                              .enumerate()
+        {
+            *pal_bi = ((i % 20) / 5 * 4) as u8;
+        }
+
+        assert_eq!(shown_color(&mut sys, 0, 0), rgb15_to_rgb24(YELLOW));
+        assert_eq!(shown_color(&mut sys, 5, 0), rgb15_to_rgb24(BLUE));
+        assert_eq!(shown_color(&mut sys, 10, 0), rgb15_to_rgb24(GREEN));
+        assert_eq!(shown_color(&mut sys, 15, 0), rgb15_to_rgb24(RED));
+
+        /* Color 0 is shared, and taken from the first palette */
+        sys.poke(0xff47, 0x00);
+        sys.main_loop(true);
+        sys.main_loop(true);
+        let pixels = &sys.sys_state.display.lcd_pixels;
+        assert!(pixels.iter().all(|&p| p == rgb15_to_rgb24(BLUE)));
+    }
+}
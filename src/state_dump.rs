@@ -0,0 +1,185 @@
+#![allow(unused_unsafe)]
+
+use serde_json::{json, Map, Value};
+
+use crate::mem;
+#[cfg(target_os = "linux")]
+use crate::address_space::AS_BASE;
+use crate::cpu::ioreg_name;
+use crate::io::{IOSpace, io_read};
+use crate::system_state::{System, SystemState};
+
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ 0xedb88320
+                } else {
+                    crc >> 1
+                };
+        }
+    }
+
+    !crc
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn read_region(sys_state: &mut SystemState, start: u16, len: usize) -> Vec<u8> {
+    (0..len).map(|i| mem![sys_state; start + i as u16]).collect()
+}
+
+/* Small regions are dumped in full, larger ones only as a checksum */
+fn region_summary(sys_state: &mut SystemState, start: u16, len: usize)
+    -> Value
+{
+    let data = read_region(sys_state, start, len);
+
+    let mut summary = Map::new();
+    summary.insert("start".into(), format!("{:04x}", start).into());
+    summary.insert("length".into(), len.into());
+    summary.insert("crc32".into(), format!("{:08x}", crc32(&data)).into());
+    if len <= 0x100 {
+        summary.insert("data".into(), hex(&data).into());
+    }
+
+    Value::Object(summary)
+}
+
+fn io_regs(sys_state: &mut SystemState) -> Value {
+    let mut regs = Map::new();
+
+    for reg in (0x00..0x80).chain(std::iter::once(0xff)) {
+        let name = ioreg_name(reg);
+        if name.starts_with("unknown") {
+            continue;
+        }
+
+        /* Bypass io_read(), which has side effects on wave RAM */
+        let val = sys_state.io_get_addr(reg as u16);
+        regs.insert(name, format!("{:02x}", val).into());
+    }
+
+    Value::Object(regs)
+}
+
+impl System {
+    pub fn dump_state_json(&mut self) -> Value {
+        let sys_state = &mut self.sys_state;
+        let addr_space = &sys_state.addr_space;
+
+        let banks = json!({
+            "rom": addr_space.rom_bank,
+            "vram": addr_space.vram_bank,
+            "extram": addr_space.extram_bank.map(|b| b as isize),
+            "extram_rw": addr_space.extram_rw,
+            "wram": addr_space.wram_bank,
+        });
+        let extram_mapped = addr_space.extram_bank.is_some();
+
+        let mut memory = Map::new();
+        memory.insert("rom0".into(), region_summary(sys_state, 0x0000, 0x4000));
+        memory.insert("romn".into(), region_summary(sys_state, 0x4000, 0x4000));
+        memory.insert("vram".into(), region_summary(sys_state, 0x8000, 0x2000));
+        if extram_mapped {
+            memory.insert("extram".into(),
+                          region_summary(sys_state, 0xa000, 0x2000));
+        }
+        memory.insert("wram0".into(), region_summary(sys_state, 0xc000, 0x1000));
+        memory.insert("wramn".into(), region_summary(sys_state, 0xd000, 0x1000));
+        memory.insert("oam".into(), region_summary(sys_state, 0xfe00, 0xa0));
+        memory.insert("hram".into(), region_summary(sys_state, 0xff80, 0x7f));
+
+        let json = json!({
+            "cpu": self.cpu.dump_json(),
+            "ime": sys_state.ints_enabled,
+            "cgb": sys_state.cgb,
+            "sgb": sys_state.sgb,
+            "double_speed": sys_state.double_speed,
+            "io": io_regs(sys_state),
+            "banks": banks,
+            "memory": Value::Object(memory),
+        });
+
+        /* Reading memory must not make the next step() break */
+        sys_state.debugger.discard_hits();
+        json
+    }
+
+    pub fn write_state_json(&mut self, path: &str) -> std::io::Result<()> {
+        let json = self.dump_state_json();
+
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(file, &json)?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::crc32;
+    use crate::testing::{test_rom, test_system};
+
+    #[test]
+    fn json_contains_registers_and_checksums() {
+        /* ld a, 0x12; ld bc, 0x3456; ld hl, 0xc000; ld (hl), 0x99; jr -2 */
+        let rom = test_rom(&[0x3e, 0x12, 0x01, 0x56, 0x34, 0x21, 0x00, 0xc0,
+                             0x36, 0x99, 0x18, 0xfe]);
+        let mut sys = test_system(rom.clone());
+        sys.main_loop(true);
+
+        let json = sys.dump_state_json();
+
+        let cpu = &json["cpu"];
+        assert_eq!(cpu["a"], "12");
+        assert_eq!(cpu["b"], "34");
+        assert_eq!(cpu["c"], "56");
+        assert_eq!(cpu["h"], "c0");
+        assert_eq!(cpu["l"], "00");
+        assert_eq!(cpu["pc"], "015a");
+
+        let memory = &json["memory"];
+        assert_eq!(memory["rom0"]["crc32"],
+                   format!("{:08x}", crc32(&rom[0x0000..0x4000])));
+        assert_eq!(memory["romn"]["crc32"],
+                   format!("{:08x}", crc32(&rom[0x4000..0x8000])));
+
+        let wram0: Vec<u8> = (0xc000..0xd000).map(|a| sys.peek(a)).collect();
+        assert_eq!(wram0[0], 0x99);
+        assert_eq!(memory["wram0"]["crc32"],
+                   format!("{:08x}", crc32(&wram0)));
+
+        /* Small enough to be included in full */
+        assert_eq!(memory["oam"]["data"].as_str().unwrap().len(), 0xa0 * 2);
+        assert!(memory["wram0"].get("data").is_none());
+    }
+
+    #[cfg(feature = "debugger")]
+    #[test]
+    fn dump_does_not_hit_watchpoints() {
+        use crate::debugger::WatchKind;
+        use crate::system_state::StepResult;
+
+        /* jr -2 */
+        let mut sys = test_system(test_rom(&[0x18, 0xfe]));
+        sys.add_watchpoint(0xc000, 0xc000, WatchKind::Read);
+        sys.dump_state_json();
+
+        /* nop; jp; jr */
+        for _ in 0..3 {
+            assert!(matches!(sys.step(), StepResult::Executed(_)));
+        }
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+}
@@ -1,16 +1,47 @@
+use std::io::{Read, Seek, Write};
+
+use savestate::SaveStateError;
+
 use crate::address_space::AddressSpace;
-use crate::cpu::Cpu;
+use crate::bundle::{Bundle, BundleRecorder, BundleReplay};
+use crate::cheats::{Cheat, GameSharkCode};
+use crate::coverage::Coverage;
+use crate::cpu::{Cpu, CpuSnapshot, IllegalOpcodeMode};
+use crate::cpu::Reg16;
+use crate::debugger::{BreakReason, Debugger};
+#[cfg(feature = "debugger")]
+use crate::debugger::WatchKind;
+use crate::frame_dump::FrameDump;
+use crate::input_log::file_crc32;
 use crate::io;
+use crate::io::IOSpace;
 use crate::io::keypad::KeypadState;
-use crate::io::lcd::DisplayState;
+use crate::io::lcd::{ColorMode, DisplayState, DmgPalette};
+use crate::io::ir::{IrConnParam, IrState};
 use crate::io::serial::{SerialConnParam, SerialState};
-use crate::io::sound::SoundState;
+use crate::io::sound::{SoundState, WaveRamAccess};
 use crate::io::timer::TimerState;
+use crate::movie::{Movie, MoviePlayback, MovieRecorder};
+use crate::rewind::RewindBuffer;
+use crate::rom::Cartridge;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::screenshot;
 use crate::sgb::SGBState;
-use crate::ui::{UI, UIAction, UIEvent};
+use crate::ui::{ScaleMode, UI, UIAction, UIEvent};
+use crate::ui::frame_blend::FrameBlend;
+
+
+const SAVE_STATE_VERSION: u64 = 17;
 
+/* VBlanks per second on the real hardware */
+pub const FRAMES_PER_SECOND: f32 = 59.7275;
 
-const SAVE_STATE_VERSION: u64 = 8;
+/* How often to write the cartridge RAM to disk (in emulated time) */
+pub const DEFAULT_AUTOSAVE_SECS: f32 = 10.0;
+
+/* Bounds for UIAction::SetSpeed */
+const MIN_SPEED_MULT: f32 = 0.25;
+const MAX_SPEED_MULT: f32 = 8.0;
 
 #[allow(clippy::upper_case_acronyms)]
 #[allow(dead_code)]
@@ -76,6 +107,7 @@ pub enum IOReg {
     WX      = 0x4b,
     KEY1    = 0x4d,
     VBK     = 0x4f,
+    BOOT    = 0x50,
     HDMA1   = 0x51,
     HDMA2   = 0x52,
     HDMA3   = 0x53,
@@ -90,11 +122,75 @@ pub enum IOReg {
     IE      = 0xff,
 }
 
+/* What to do while the window is minimized */
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum MinimizedBehavior {
+    Run,
+    Pause,
+    /* Keep running, but only at a few frames per second */
+    Throttle,
+}
+
+/* The hardware to emulate.  CGB mode additionally requires a CGB
+ * cartridge, and SGB features an SGB cartridge. */
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Model {
+    Dmg,
+    /* Game Boy Pocket */
+    Mgb,
+    Sgb,
+    Cgb,
+    /* Game Boy Advance (defaults to its darker LCD, see ColorMode) */
+    Agb,
+}
+
+impl Model {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "dmg" => Some(Model::Dmg),
+            "mgb" => Some(Model::Mgb),
+            "sgb" => Some(Model::Sgb),
+            "cgb" => Some(Model::Cgb),
+            "agb" => Some(Model::Agb),
+            _ => None,
+        }
+    }
+
+    pub fn is_cgb(&self) -> bool {
+        matches!(self, Model::Cgb | Model::Agb)
+    }
+}
+
+pub enum StepResult {
+    /* Number of cycles the instruction took */
+    #[allow(dead_code)]
+    Executed(u32),
+    Break(BreakReason),
+}
+
 pub struct SystemParams {
+    pub model: Model,
     pub cgb: bool,
+    /* CGB game that also runs on DMGs (0x143 is 0x80, not 0xc0) */
+    pub dmg_compatible: bool,
     pub sgb: bool,
     pub cartridge_name: String,
     pub serial_conn_param: SerialConnParam,
+    pub ir_conn_param: IrConnParam,
+    /* See sound::DEFAULT_BUFCOUNT */
+    pub audio_buffers: usize,
+}
+
+impl SystemParams {
+    /* Overrides the model derived from the cartridge header; a CGB runs
+     * DMG games in DMG mode, an SGB ignores SGB commands from games that
+     * do not declare support */
+    pub fn force_model(&mut self, model: Model) {
+        self.cgb = self.cgb && model.is_cgb();
+        self.dmg_compatible = self.dmg_compatible && model.is_cgb();
+        self.sgb = self.sgb && model == Model::Sgb;
+        self.model = model;
+    }
 }
 
 #[derive(SaveState)]
@@ -114,6 +210,99 @@ pub struct System {
 
     #[savestate(skip)]
     pub extram_dirtying: bool,
+
+    #[savestate(skip)]
+    coverage: Option<Coverage>,
+    #[savestate(skip)]
+    state_json_path: Option<String>,
+    /* Address ranges (inclusive) to write to files on quit */
+    #[savestate(skip)]
+    exit_dumps: Vec<(u16, u16, String)>,
+    #[savestate(skip)]
+    frame_dump: Option<FrameDump>,
+
+    #[savestate(skip)]
+    bundle_rec: Option<BundleRecorder>,
+    #[savestate(skip)]
+    bundle_replay: Option<BundleReplay>,
+
+    #[savestate(skip)]
+    movie_rec: Option<MovieRecorder>,
+    #[savestate(skip)]
+    movie_playback: Option<MoviePlayback>,
+
+    /* Game Genie codes live in the address space */
+    #[savestate(skip)]
+    gameshark_codes: Vec<GameSharkCode>,
+
+    #[savestate(skip)]
+    rewind: Option<RewindBuffer>,
+    /* Whether the rewind button is held */
+    #[savestate(skip)]
+    rewinding: bool,
+
+    /* In frames; None to only save the RAM on quit (and whenever the
+     * game has stopped writing to it, if the RAM is not a plain file) */
+    #[savestate(skip)]
+    autosave_interval: Option<u64>,
+    #[savestate(skip)]
+    autosave_frame_i: u64,
+
+    #[savestate(skip)]
+    low_latency: bool,
+    #[savestate(skip)]
+    compress_states: bool,
+    /* Fast-forward multiplier that still keeps audio running, unlike
+     * skipping */
+    #[savestate(skip)]
+    speed_mult: f32,
+    /* Speed multiplier while fast-forwarding; 0 to just run as fast as
+     * possible (by dropping realtime) */
+    #[savestate(skip)]
+    fast_forward_mult: f32,
+    /* While fast-forwarding, the speed to return to afterwards */
+    #[savestate(skip)]
+    pre_fast_forward_speed: Option<f32>,
+
+    #[savestate(skip)]
+    trace_sink: Option<Box<dyn std::io::Write>>,
+
+    /* Path of the WAV file currently being recorded */
+    #[savestate(skip)]
+    audio_recording: Option<String>,
+
+    #[savestate(skip)]
+    minimized_behavior: MinimizedBehavior,
+    #[savestate(skip)]
+    paused_by_minimize: bool,
+    /* While throttled, holds the realtime setting to restore afterwards */
+    #[savestate(skip)]
+    throttled_realtime: Option<bool>,
+    /* Run again on every reset */
+    #[savestate(skip)]
+    boot_rom: Option<Vec<u8>>,
+}
+
+/*
+ * The CPU (and with it the timer) runs twice as fast in double speed
+ * mode, everything else (LCD, APU, serial clock, HDMA) does not.  The
+ * latter count in double-speed cycles, i.e. at 2 MHz regardless of the
+ * speed mode.
+ */
+pub const fn dcycles(cycles: u32, double_speed: bool) -> u32 {
+    if double_speed {
+        cycles
+    } else {
+        cycles * 2
+    }
+}
+
+pub const fn cpu_cycles(dcycles: u32, double_speed: bool) -> u32 {
+    if double_speed {
+        dcycles
+    } else {
+        dcycles / 2
+    }
 }
 
 #[derive(SaveState)]
@@ -121,15 +310,26 @@ pub struct SystemState {
     #[savestate(ref)]
     pub addr_space: Box<AddressSpace>,
 
+    #[savestate(skip)]
+    pub model: Model,
     #[savestate(skip_if("version >= 1"))]
     pub cgb: bool,
     #[savestate(skip)]
     pub sgb: bool,
+    /* Whether toggle_color_mode() is allowed */
+    #[savestate(skip)]
+    pub cgb_capable: bool,
     pub ints_enabled: bool,
     pub double_speed: bool,
     #[savestate(skip)]
     pub realtime: bool,
     pub vblanked: bool,
+    /* Set by a speed switch (through STOP), to notify the user */
+    #[savestate(skip)]
+    pub speed_switched: bool,
+    /* Set whenever emulation had to wait for the audio driver */
+    #[savestate(skip)]
+    pub audio_synced: bool,
 
     #[savestate(skip)]
     sound_postprocess: bool,
@@ -141,6 +341,10 @@ pub struct SystemState {
     pub timer: TimerState,
     #[savestate(skip)]
     pub serial: Option<SerialState>,
+    #[savestate(skip)]
+    pub ir: Option<IrState>,
+    #[savestate(skip)]
+    pub debugger: Debugger,
 
     #[savestate(skip_if("version < 1"), ref)]
     pub sgb_state: Box<SGBState>,
@@ -151,11 +355,12 @@ impl System {
     pub fn new(mut sys_state: Box<SystemState>, mut ui: UI, base_path: String)
         -> Self
     {
-        let cpu = Cpu::new(sys_state.cgb, sys_state.sgb);
+        let cpu = Cpu::new(sys_state.model, sys_state.cgb);
 
-        ui.setup_audio(sys_state.sound.get_audio_params());
+        let sample_rate = ui.setup_audio(sys_state.sound.get_audio_params());
+        sys_state.sound.set_sample_rate(sample_rate as f32);
 
-        Self {
+        let mut system = Self {
             sys_state,
             cpu,
 
@@ -165,218 +370,2014 @@ impl System {
 
             paused: false,
             extram_dirtying: false,
-        }
-    }
 
-    fn do_save_state(&mut self, index: usize, save: bool) {
-        let fname = format!("{}.ss{}", self.base_path, index);
+            coverage: None,
+            state_json_path: None,
+            exit_dumps: Vec::new(),
+            frame_dump: None,
 
-        let mut opts = std::fs::OpenOptions::new();
-        if save {
-            opts.write(true).create(true);
-        } else {
-            opts.read(true);
-        }
+            bundle_rec: None,
+            bundle_replay: None,
 
-        let mut file =
-            match opts.open(&fname) {
-                Ok(f) => f,
-                Err(e) => {
-                    let msg = format!("Failed to load SS {} ({}):\n{}",
-                                      index + 1, fname, e);
-                    self.ui.osd_message(msg);
-                    return;
-                }
-            };
+            movie_rec: None,
+            movie_playback: None,
 
-        if save {
-            savestate::export_root(self, &mut file, SAVE_STATE_VERSION);
-            self.ui.osd_message(format!("Created save state {}", index + 1));
-        } else {
-            savestate::import_root(self, &mut file, SAVE_STATE_VERSION);
-            self.sys_state.keypad.post_import(&mut self.sys_state.addr_space);
-            self.ui.osd_message(format!("Loaded save state {}", index + 1));
-        }
-    }
+            gameshark_codes: Vec::new(),
 
-    fn perform_ui_action(&mut self, action: UIAction) {
-        match action {
-            UIAction::Key(key, down) => {
-                let addr_space = &mut self.sys_state.addr_space;
-                self.sys_state.keypad.key_event(addr_space, key, down);
-            },
+            rewind: None,
+            rewinding: false,
 
-            UIAction::Skip(skip) =>
-                self.sys_state.realtime = !skip,
+            autosave_interval: None,
+            autosave_frame_i: 0,
 
-            UIAction::ToggleAudioPostprocessing => {
-                self.sys_state.toggle_sound_postprocess();
+            low_latency: false,
+            compress_states: cfg!(target_arch = "wasm32"),
+            speed_mult: 1.0,
+            fast_forward_mult: 0.0,
+            pre_fast_forward_speed: None,
 
-                let pp_state =
-                    if self.sys_state.sound_postprocess {
-                        "enabled"
-                    } else {
-                        "disabled"
-                    };
+            trace_sink: None,
 
-                self.ui.osd_message(format!("Sound postprocessing {}",
-                                            pp_state));
-            },
+            audio_recording: None,
 
-            UIAction::LoadState(index) => {
-                self.do_save_state(index, false);
-                self.ui.refresh_lcd(&self.sys_state);
-            },
+            minimized_behavior: MinimizedBehavior::Run,
+            paused_by_minimize: false,
+            throttled_realtime: None,
+            boot_rom: None,
+        };
 
-            UIAction::SaveState(index) =>
-                self.do_save_state(index, true),
+        system.set_autosave_interval(DEFAULT_AUTOSAVE_SECS);
+        system
+    }
 
-            UIAction::ToggleFullscreen =>
-                self.ui.toggle_fullscreen(),
+    /* Write a line per executed instruction to the given sink */
+    pub fn set_trace_sink(&mut self, sink: Option<Box<dyn std::io::Write>>) {
+        self.trace_sink = sink;
+    }
 
-            UIAction::TogglePause => {
-                self.paused = !self.paused;
-                self.ui.set_paused(self.paused);
+    pub fn set_illegal_opcode_mode(&mut self, mode: IllegalOpcodeMode) {
+        self.cpu.set_illegal_opcode_mode(mode);
+    }
 
-                if self.paused {
-                    self.ui.osd_message(String::from("Paused"));
-                } else {
-                    self.ui.osd_message(String::from("Resumed"));
-                }
-            }
+    /*
+     * In low-latency mode, input is not only polled once per frame (on
+     * VBlank), but also every time emulation has been waiting for the
+     * audio driver, i.e. right before emulation continues.
+     */
+    pub fn set_low_latency(&mut self, low_latency: bool) {
+        self.low_latency = low_latency;
+    }
 
-            UIAction::Quit =>
-                std::process::exit(0),
-        }
+    pub fn set_compress_states(&mut self, compress: bool) {
+        self.compress_states = compress;
     }
 
-    fn exec(&mut self) {
-        let cycles = self.cpu.exec(&mut self.sys_state);
-        self.sys_state.add_cycles(cycles);
+    /* Keep @seconds worth of snapshots, taking one every @interval
+     * frames */
+    pub fn enable_rewind(&mut self, seconds: f32, interval: u64) {
+        self.rewind = Some(RewindBuffer::new(seconds, interval));
     }
 
-    fn get_event(&mut self) -> Option<UIEvent> {
-        /* Pausing will cause us to always return Some() until the
-         * game is unpaused again.  So until then, we are caught up
-         * in the event loop and automatically will not exec anything. */
-        if self.paused {
-            Some(self.ui.wait_event(&self.sys_state))
-        } else {
-            self.ui.poll_event()
-        }
+    /* 0 disables autosaving */
+    pub fn set_autosave_interval(&mut self, seconds: f32) {
+        let frames = (seconds * FRAMES_PER_SECOND) as u64;
+        self.autosave_interval = if frames > 0 { Some(frames) } else { None };
+        self.autosave_frame_i = 0;
     }
 
-    fn handle_events(&mut self) {
-        self.ui.vblank_events(&self.sys_state);
+    fn autosave(&mut self) {
+        if !self.sys_state.addr_space.extram_unsaved {
+            return;
+        }
 
-        if self.sys_state.sgb_state.load_border {
-            self.sys_state.sgb_state.load_border = false;
-            self.ui.load_sgb_border(&self.sys_state);
+        self.sys_state.addr_space.flush_extram();
+        self.ui.osd_timed_message(String::from("Saved"),
+                                  std::time::Duration::from_secs(1));
+    }
+
+    /* Accepts Game Genie and GameShark codes */
+    pub fn add_cheat(&mut self, code: &str) -> Result<(), String> {
+        match Cheat::parse(code)? {
+            Cheat::GameGenie(gg) => self.sys_state.addr_space.add_rom_patch(gg),
+            Cheat::GameShark(gs) => self.gameshark_codes.push(gs),
         }
+        Ok(())
+    }
 
-        while let Some(evt) = self.get_event() {
-            if let Some(action) = self.ui.translate_event(evt) {
-                self.perform_ui_action(action);
+    /* Loads <base>.cht, if present: One code per line, # starts a
+     * comment */
+    pub fn load_cheat_file(&mut self) {
+        let path = format!("{}.cht", self.base_path);
+        let cheats =
+            match std::fs::read_to_string(&path) {
+                Ok(c) => c,
+                Err(_) => return,
+            };
+
+        for (i, line) in cheats.lines().enumerate() {
+            let code = line.split('#').next().unwrap().trim();
+            if code.is_empty() {
+                continue;
+            }
+
+            if let Err(e) = self.add_cheat(code) {
+                eprintln!("{}:{}: {}", path, i + 1, e);
             }
         }
     }
 
-    pub fn main_loop(&mut self, break_on_vblank: bool) {
-        loop {
-            self.exec();
+    /* Must be called before emulation starts.  The boot ROM must fit the
+     * mode the cartridge runs in, i.e. a CGB one for CGB games. */
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load_boot_rom(&mut self, path: &str) -> Result<(), String> {
+        let boot_rom = std::fs::read(path).map_err(|e| e.to_string())?;
+        self.set_boot_rom(boot_rom)
+    }
 
-            if let Some(serial) = self.sys_state.serial.as_mut() {
-                serial.check_remote(&mut self.sys_state.addr_space);
-            }
+    /* load_boot_rom() with the image already in memory */
+    pub fn set_boot_rom(&mut self, boot_rom: Vec<u8>) -> Result<(), String> {
+        let (size, model) =
+            if self.sys_state.cgb {
+                (0x900, "CGB")
+            } else {
+                (0x100, "DMG/SGB")
+            };
+        if boot_rom.len() != size {
+            return Err(format!("Expected a {} boot ROM of {} bytes, but \
+                                it has {} bytes",
+                               model, size, boot_rom.len()));
+        }
 
-            if self.sys_state.vblanked {
-                self.sys_state.vblanked = false;
+        self.boot_rom = Some(boot_rom.clone());
+        self.start_boot_rom(boot_rom);
+        Ok(())
+    }
 
-                #[cfg(target_arch = "wasm32")]
-                {
-                    if let Some(buf) = self.ui.get_vblank_sound_buf() {
-                        self.sys_state.sound.fill_outbuf(&mut self.sys_state.addr_space, buf.as_mut_slice());
-                        self.ui.submit_vblank_sound_buf();
-                    }
-                }
+    fn start_boot_rom(&mut self, boot_rom: Vec<u8>) {
+        io::init_boot_rom(&mut self.sys_state, boot_rom);
+        for reg in [Reg16::AF, Reg16::BC, Reg16::DE, Reg16::HL, Reg16::SP,
+                    Reg16::PC]
+        {
+            self.cpu.set_reg16(reg, 0x0000);
+        }
+    }
 
-                self.ui.refresh_lcd(&self.sys_state);
-                self.handle_events();
+    /* Like power cycling, but the cartridge RAM is kept (and written
+     * first), as are all settings and connections */
+    pub fn reset(&mut self) {
+        self.sys_state.addr_space.flush_extram();
 
-                if let Some(serial) = self.sys_state.serial.as_mut() {
-                    serial.vblank_check();
-                }
+        self.sys_state.reset();
+        self.cpu.reset(self.sys_state.model, self.sys_state.cgb);
 
-                if self.extram_dirtying && !self.sys_state.addr_space.extram_dirty {
-                    self.sys_state.addr_space.flush_extram();
-                    self.extram_dirtying = false;
-                }
+        if let Some(boot_rom) = self.boot_rom.clone() {
+            self.start_boot_rom(boot_rom);
+        }
+    }
 
-                if self.sys_state.addr_space.extram_dirty {
-                    self.extram_dirtying = true;
-                    self.sys_state.addr_space.extram_dirty = false;
-                }
+    /*
+     * CRC32 over the LCD as RGB8 (as written by --dump-framebuffer), for
+     * comparing frames in automated tests.  With @corrected false, the
+     * frame is taken before CGB color correction, so the result does not
+     * depend on the configured color mode.
+     */
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn framebuffer_crc(&self, corrected: bool) -> u32 {
+        let d = &self.sys_state.display;
+        let rgb8 =
+            if corrected || !DisplayState::cgb_colors(&self.sys_state) {
+                screenshot::lcd_rgb8(&d.lcd_pixels)
+            } else {
+                screenshot::lcd_rgb8(&d.raw_lcd_pixels())
+            };
 
-                if break_on_vblank {
-                    break;
-                }
-            }
+        crate::state_dump::crc32(&rgb8)
+    }
+
+    /* Picture seen by the Game Boy Camera */
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load_camera_image(&mut self, path: &str) {
+        match screenshot::read_png(path) {
+            Ok((width, height, rgb8)) =>
+                self.sys_state.addr_space.cartridge
+                    .set_camera_image(width as usize, height as usize, &rgb8),
+
+            Err(e) =>
+                self.ui.osd_message(format!("Failed to load camera image \
+                                             {}:\n{}", path, e)),
         }
     }
-}
 
-impl SystemState {
-    pub fn new(addr_space: Box<AddressSpace>, params: SystemParams, ui: &mut UI)
-        -> Self
-    {
-        let mut state = Self {
-            addr_space,
+    pub fn set_dmg_palette(&mut self, palette: DmgPalette) {
+        DisplayState::set_dmg_palette(&mut self.sys_state, palette);
+    }
 
-            cgb: params.cgb,
-            sgb: params.sgb && !params.cgb,
-            ints_enabled: true,
-            double_speed: false,
-            realtime: true,
-            vblanked: false,
+    pub fn set_color_correction(&mut self, mode: ColorMode) {
+        DisplayState::set_color_correction(&mut self.sys_state, mode);
+    }
 
-            sound_postprocess: false,
+    /* See DisplayState::accurate_ppu */
+    pub fn set_accurate_ppu(&mut self, accurate: bool) {
+        self.sys_state.display.set_accurate_ppu(accurate);
+    }
 
-            display: Box::new(DisplayState::new()),
-            keypad: KeypadState::new(),
-            sound: SoundState::new(),
-            timer: TimerState::new(),
-            serial: SerialState::new(ui, &params.serial_conn_param),
+    pub fn set_speed(&mut self, speed_mult: f32) {
+        self.speed_mult = speed_mult.clamp(MIN_SPEED_MULT, MAX_SPEED_MULT);
+        self.sys_state.sound.set_speed(self.speed_mult);
+    }
 
-            sgb_state: Box::new(SGBState::new()),
+    pub fn set_wave_ram_access(&mut self, access: WaveRamAccess) {
+        let dmg = match access {
+            WaveRamAccess::Auto => !self.sys_state.model.is_cgb(),
+            WaveRamAccess::Dmg => true,
+            WaveRamAccess::Cgb => false,
         };
+        self.sys_state.sound.set_dmg_wave_ram(dmg);
+    }
 
-        DisplayState::init_system_state(&mut state);
-        KeypadState::init_system_state(&mut state);
-        io::init_dma(&mut state);
+    /* Used by Skip; 0 means unlimited speed */
+    pub fn set_fast_forward(&mut self, speed_mult: f32) {
+        self.fast_forward_mult = speed_mult.max(0.0);
+    }
 
-        state
+    fn fast_forward(&mut self, enable: bool) {
+        if enable {
+            if self.pre_fast_forward_speed.is_none() {
+                self.pre_fast_forward_speed = Some(self.speed_mult);
+                self.set_speed(self.fast_forward_mult);
+            }
+        } else if let Some(speed_mult) = self.pre_fast_forward_speed.take() {
+            self.set_speed(speed_mult);
+        }
     }
 
-    pub fn add_cycles(&mut self, count: u32) {
-        let dcycles =
-            if self.double_speed {
-                count
-            } else {
-                count * 2
-            };
+    /* Pauses emulation whenever the given I/O register (0xff00 + reg) is
+     * accessed accordingly */
+    pub fn add_io_breakpoint(&mut self, reg: u8, on_read: bool,
+                             on_write: bool)
+    {
+        self.sys_state.debugger.add_io_breakpoint(reg, on_read, on_write);
+    }
 
-        io::lcd::add_cycles(self, dcycles);
-        #[cfg(not(target_arch = "wasm32"))]
-        self.sound.add_cycles(&mut self.addr_space, dcycles, self.realtime);
-        io::timer::add_cycles(self, count);
+    /* Pauses emulation before executing the instruction at @addr */
+    #[cfg(feature = "debugger")]
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.sys_state.debugger.add_breakpoint(addr);
+    }
 
-        if let Some(serial) = self.serial.as_mut() {
-            serial.add_cycles(&mut self.addr_space, dcycles);
+    /* Returns whether there was a breakpoint at @addr */
+    #[cfg(feature = "debugger")]
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        self.sys_state.debugger.remove_breakpoint(addr)
+    }
+
+    /* Pauses emulation after an instruction accessed @start..=@end
+     * accordingly */
+    #[cfg(feature = "debugger")]
+    pub fn add_watchpoint(&mut self, start: u16, end: u16, kind: WatchKind) {
+        self.sys_state.debugger.add_watchpoint(start, end, kind);
+    }
+
+    pub fn set_minimized_behavior(&mut self, behavior: MinimizedBehavior) {
+        self.minimized_behavior = behavior;
+    }
+
+    pub fn enable_coverage(&mut self, path: String) {
+        let rom_size = self.sys_state.addr_space.cartridge.rom_size;
+        self.coverage = Some(Coverage::new(path, rom_size));
+    }
+
+    pub fn enable_frame_dump(&mut self, dir: String, every: u64) {
+        match FrameDump::new(dir.clone(), every) {
+            Ok(fd) => self.frame_dump = Some(fd),
+            Err(e) => {
+                eprintln!("Failed to set up frame dump in {}: {}", dir, e);
+                std::process::exit(1);
+            }
         }
     }
 
+    pub fn enable_state_json_dump(&mut self, path: String) {
+        self.state_json_path = Some(path);
+    }
+
+    /* To the --dump-state-json file, or to <base>-<timestamp>.json */
+    #[cfg(not(target_arch = "wasm32"))]
+    fn export_state_json(&mut self) {
+        let path = self.state_json_path.clone().unwrap_or_else(|| {
+            let stamp = std::time::SystemTime::now()
+                            .duration_since(std::time::UNIX_EPOCH)
+                            .map(|d| d.as_millis()).unwrap_or(0);
+            format!("{}-{}.json", self.base_path, stamp)
+        });
+
+        let msg =
+            match self.write_state_json(&path) {
+                Ok(()) => format!("Exported state to {}", path),
+                Err(e) => format!("Failed to save {}:\n{}", path, e),
+            };
+        self.ui.osd_message(msg);
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn export_state_json(&mut self) {
+    }
+
+    pub fn add_exit_dump(&mut self, start: u16, end: u16, path: String) {
+        self.exit_dumps.push((start, end, path));
+    }
+
+    fn write_region(&mut self, start: u16, end: u16, path: &str)
+        -> std::io::Result<()>
+    {
+        let mut file = std::fs::File::create(path)?;
+        self.dump_region(start, end, &mut file)
+    }
+
+    fn dump_exit_regions(&mut self) {
+        for (start, end, path) in std::mem::take(&mut self.exit_dumps) {
+            if let Err(e) = self.write_region(start, end, &path) {
+                eprintln!("Failed to dump memory to {}: {}", path, e);
+            }
+        }
+    }
+
+    /* The whole address space, to <base>-<timestamp>.bin */
+    #[cfg(not(target_arch = "wasm32"))]
+    fn dump_memory(&mut self) {
+        let stamp = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map(|d| d.as_millis()).unwrap_or(0);
+        let path = format!("{}-{}.bin", self.base_path, stamp);
+
+        let msg =
+            match self.write_region(0x0000, 0xffff, &path) {
+                Ok(()) => format!("Dumped memory to {}", path),
+                Err(e) => format!("Failed to save {}:\n{}", path, e),
+            };
+        self.ui.osd_message(msg);
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn dump_memory(&mut self) {
+    }
+
+    fn dump_coverage(&mut self) {
+        if let Some(coverage) = self.coverage.as_ref() {
+            if let Err(e) = coverage.dump() {
+                eprintln!("Failed to write coverage to {}: {}",
+                          coverage.path(), e);
+            }
+        }
+    }
+
+    fn do_save_state(&mut self, index: usize, save: bool) {
+        let fname = format!("{}.ss{}", self.base_path, index);
+
+        let mut opts = std::fs::OpenOptions::new();
+        if save {
+            opts.write(true).create(true);
+        } else {
+            opts.read(true);
+        }
+
+        let mut file =
+            match opts.open(&fname) {
+                Ok(f) => f,
+                Err(e) => {
+                    let msg = format!("Failed to load SS {} ({}):\n{}",
+                                      index + 1, fname, e);
+                    self.ui.osd_message(msg);
+                    return;
+                }
+            };
+
+        let msg =
+            if save {
+                self.sys_state.addr_space.sync_extram();
+
+                match savestate::export_root(self, &mut file,
+                                             SAVE_STATE_VERSION,
+                                             self.compress_states)
+                {
+                    Ok(()) => {
+                        self.save_state_thumbnail(index);
+                        format!("Created save state {}", index + 1)
+                    },
+                    Err(e) => format!("Failed to create SS {} ({}):\n{}",
+                                      index + 1, fname, e),
+                }
+            } else {
+                match self.import_state(&mut file) {
+                    Ok(()) => {
+                        self.show_state_thumbnail(index);
+                        format!("Loaded save state {}", index + 1)
+                    },
+                    Err(SaveStateError::Corrupt(e)) =>
+                        format!("Save state slot {} is corrupt:\n{}",
+                                index + 1, e),
+                    Err(e) => format!("Failed to load SS {} ({}):\n{}",
+                                      index + 1, fname, e),
+                }
+            };
+        self.ui.osd_message(msg);
+    }
+
+    /* Loads whichever save state slot has been written last, if any */
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load_latest_state(&mut self) {
+        let latest = (0..8).filter_map(|index| {
+            let fname = format!("{}.ss{}", self.base_path, index);
+            let mtime = std::fs::metadata(fname).ok()?.modified().ok()?;
+            Some((mtime, index))
+        }).max();
+
+        if let Some((_, index)) = latest {
+            self.do_save_state(index, false);
+        }
+    }
+
+    /* Stored next to the save state, as <base>.ss<index>.png */
+    #[cfg(not(target_arch = "wasm32"))]
+    fn save_state_thumbnail(&mut self, index: usize) {
+        let path = format!("{}.ss{}.png", self.base_path, index);
+        let lcd = &self.sys_state.display.lcd_pixels;
+        let rgb8 = screenshot::thumbnail_rgb8(lcd);
+
+        /* Not worth bothering the user on the OSD */
+        if let Err(e) = screenshot::write_png(&path, 80, 72, &rgb8) {
+            eprintln!("Failed to write save state thumbnail {}: {}", path, e);
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn show_state_thumbnail(&mut self, index: usize) {
+        let path = format!("{}.ss{}.png", self.base_path, index);
+
+        /* Older save states may not have one */
+        if let Ok((width, height, rgb8)) = screenshot::read_png(&path) {
+            self.ui.osd_image(width, height, &rgb8);
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn save_state_thumbnail(&mut self, _index: usize) {
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn show_state_thumbnail(&mut self, _index: usize) {
+    }
+
+    /* Keeps the current state if the save state cannot be loaded */
+    fn import_state<S: Read + Seek>(&mut self, stream: &mut S)
+        -> Result<(), SaveStateError>
+    {
+        let mut backup = Vec::new();
+        savestate::export_root(self, &mut backup, SAVE_STATE_VERSION, false)
+            .unwrap();
+
+        if let Err(e) = savestate::import_root(self, stream,
+                                               SAVE_STATE_VERSION)
+        {
+            let mut backup = std::io::Cursor::new(backup);
+            savestate::import_root(self, &mut backup, SAVE_STATE_VERSION)
+                .unwrap();
+            return Err(e);
+        }
+
+        self.sys_state.keypad.post_import(&mut self.sys_state.addr_space);
+        self.sys_state.timer.post_import(&self.sys_state.addr_space);
+        self.sys_state.sound.post_import();
+        Cartridge::post_import(&mut self.sys_state.addr_space);
+        DisplayState::apply_dmg_palette(&mut self.sys_state);
+        /* The boot ROM cannot be mapped back in */
+        if self.sys_state.io_get_reg(IOReg::BOOT) & 0x01 != 0 {
+            self.sys_state.addr_space.set_boot_rom(None);
+        }
+        Ok(())
+    }
+
+    fn toggle_bundle_recording(&mut self) {
+        if let Some(rec) = self.bundle_rec.take() {
+            let path = format!("{}.bundle", self.base_path);
+
+            let msg =
+                match rec.save(&path) {
+                    Ok(()) => format!("Saved bug report bundle to {}", path),
+                    Err(e) => format!("Failed to save {}:\n{}", path, e),
+                };
+            self.ui.osd_message(msg);
+        } else {
+            let mut state = Vec::new();
+            savestate::export_root(self, &mut state, SAVE_STATE_VERSION,
+                                   self.compress_states).unwrap();
+
+            let crc = file_crc32(&self.sys_state.addr_space.rom_file);
+            self.bundle_rec = Some(BundleRecorder::new(crc, state));
+            self.ui.osd_message(String::from("Recording bug report bundle"));
+        }
+    }
+
+    fn take_rewind_snapshot(&mut self) {
+        let due =
+            match self.rewind.as_mut() {
+                Some(rewind) => rewind.next_frame(),
+                None => false,
+            };
+        if !due {
+            return;
+        }
+
+        let mut snapshot = Vec::new();
+        if let Err(e) = savestate::export_root(self, &mut snapshot,
+                                               SAVE_STATE_VERSION, true)
+        {
+            eprintln!("Failed to take rewind snapshot: {}", e);
+            return;
+        }
+        self.rewind.as_mut().unwrap().push(snapshot);
+    }
+
+    /* Goes back to the most recent rewind snapshot; returns false if
+     * there is none left */
+    pub fn rewind_step(&mut self) -> bool {
+        let snapshot =
+            match self.rewind.as_mut().and_then(|r| r.pop()) {
+                Some(s) => s,
+                None => return false,
+            };
+
+        let mut stream = std::io::Cursor::new(snapshot);
+        if let Err(e) = self.import_state(&mut stream) {
+            self.ui.osd_message(format!("Failed to rewind:\n{}", e));
+            return false;
+        }
+
+        let remaining = self.rewind.as_ref().unwrap().remaining_secs();
+        self.ui.osd_message(format!("Rewind: {:.1} s left", remaining));
+        true
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn screenshot(&mut self, with_border: bool) {
+        let sgb_border = self.ui.sgb_border(&self.sys_state);
+        let display = &self.sys_state.display;
+
+        let (width, height, rgb8) =
+            match sgb_border {
+                Some(border) if with_border =>
+                    (256, 224, screenshot::sgb_rgb8(border,
+                                                    &display.lcd_pixels)),
+
+                _ => (160, 144, screenshot::lcd_rgb8(&display.lcd_pixels)),
+            };
+
+        let stamp = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map(|d| d.as_millis()).unwrap_or(0);
+        let path = format!("{}-{}.png", self.base_path, stamp);
+
+        let msg =
+            match screenshot::write_png(&path, width, height, &rgb8) {
+                Ok(()) => format!("Saved screenshot to {}", path),
+                Err(e) => format!("Failed to save {}:\n{}", path, e),
+            };
+        self.ui.osd_message(msg);
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn toggle_audio_recording(&mut self) {
+        if let Some(path) = self.audio_recording.take() {
+            let msg =
+                match self.sys_state.sound.set_wav_sink(None) {
+                    Ok(()) => format!("Saved audio recording to {}", path),
+                    Err(e) => format!("Failed to save {}:\n{}", path, e),
+                };
+            self.ui.osd_message(msg);
+            return;
+        }
+
+        let stamp = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map(|d| d.as_millis()).unwrap_or(0);
+        let path = format!("{}-{}.wav", self.base_path, stamp);
+
+        match hound::WavWriter::create(&path, self.sys_state.sound.wav_params()) {
+            Ok(writer) => {
+                self.sys_state.sound.set_wav_sink(Some(writer)).unwrap();
+                self.ui.osd_message(String::from("Recording audio"));
+                self.audio_recording = Some(path);
+            },
+
+            Err(e) => {
+                self.ui.osd_message(format!("Failed to create {}:\n{}",
+                                            path, e));
+            },
+        }
+    }
+
+    /* The sound code has already dropped the sink */
+    #[cfg(not(target_arch = "wasm32"))]
+    fn audio_recording_failed(&mut self, error: String) {
+        let path = self.audio_recording.take().unwrap_or_default();
+        self.ui.osd_message(format!("Failed to write {}, stopped \
+                                     recording:\n{}", path, error));
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn toggle_audio_recording(&mut self) {
+    }
+
+    /* JS can get the frame through XGBCSystem::screenshot() instead */
+    #[cfg(target_arch = "wasm32")]
+    fn screenshot(&mut self, _with_border: bool) {
+    }
+
+    pub fn replay_bundle(&mut self, path: &str) {
+        let bundle =
+            match Bundle::load(path) {
+                Ok(b) => b,
+                Err(e) => {
+                    eprintln!("Failed to load bundle {}: {}", path, e);
+                    std::process::exit(1);
+                }
+            };
+
+        let crc = file_crc32(&self.sys_state.addr_space.rom_file);
+        for warning in bundle.check(crc) {
+            eprintln!("Warning: {}", warning);
+        }
+
+        let mut state = std::io::Cursor::new(bundle.state());
+        if let Err(e) = self.import_state(&mut state) {
+            eprintln!("Failed to load state from bundle {}: {}", path, e);
+            std::process::exit(1);
+        }
+
+        /* The first frame was recorded right when the state was taken */
+        self.bundle_replay = Some(bundle.into_replay());
+        self.bundle_frame();
+    }
+
+    fn movie_crc32s(&self) -> (u32, u32) {
+        let addr_space = &self.sys_state.addr_space;
+        (file_crc32(&addr_space.rom_file),
+         file_crc32(&addr_space.extram_file))
+    }
+
+    /* Must be called before the first frame */
+    pub fn record_movie(&mut self, path: String) {
+        let (rom_crc, extram_crc) = self.movie_crc32s();
+        let rtc = self.sys_state.addr_space.cartridge.rtc_snapshot();
+        self.movie_rec = Some(MovieRecorder::new(path, rom_crc, extram_crc,
+                                                 rtc));
+    }
+
+    /* Must be called before the first frame */
+    pub fn play_movie(&mut self, path: &str) {
+        let movie =
+            match Movie::load(path) {
+                Ok(m) => m,
+                Err(e) => {
+                    eprintln!("Failed to load movie {}: {}", path, e);
+                    std::process::exit(1);
+                }
+            };
+
+        let (rom_crc, extram_crc) = self.movie_crc32s();
+        for warning in movie.check(rom_crc, extram_crc) {
+            eprintln!("Warning: {}", warning);
+        }
+
+        if let Some(rtc) = movie.rtc() {
+            self.sys_state.addr_space.cartridge.restore_rtc_snapshot(rtc);
+        }
+
+        self.movie_playback = Some(movie.into_playback());
+    }
+
+    fn save_movie(&mut self) {
+        if let Some(rec) = self.movie_rec.as_ref() {
+            if let Err(e) = rec.save() {
+                eprintln!("Failed to save movie {}: {}", rec.path(), e);
+            }
+        }
+    }
+
+    fn movie_frame(&mut self) {
+        if let Some(rec) = self.movie_rec.as_mut() {
+            rec.frame(self.sys_state.keypad.lines());
+        }
+
+        let playback =
+            match self.movie_playback.as_mut() {
+                Some(p) => p,
+                None => return,
+            };
+
+        match playback.next_frame() {
+            Some(lines) => {
+                let addr_space = &mut self.sys_state.addr_space;
+                self.sys_state.keypad.set_lines(addr_space, lines);
+            },
+
+            None => {
+                self.movie_playback = None;
+                let addr_space = &mut self.sys_state.addr_space;
+                self.sys_state.keypad.set_lines(addr_space, 0);
+                self.ui.osd_message(String::from("Movie finished"));
+            },
+        }
+    }
+
+    fn bundle_frame(&mut self) {
+        if let Some(rec) = self.bundle_rec.as_mut() {
+            rec.frame(self.sys_state.keypad.lines());
+        }
+
+        let replay =
+            match self.bundle_replay.as_mut() {
+                Some(r) => r,
+                None => return,
+            };
+
+        match replay.next_frame() {
+            Some(lines) => {
+                let addr_space = &mut self.sys_state.addr_space;
+                self.sys_state.keypad.set_lines(addr_space, lines);
+            },
+
+            None => {
+                self.bundle_replay = None;
+                let addr_space = &mut self.sys_state.addr_space;
+                self.sys_state.keypad.set_lines(addr_space, 0);
+                self.ui.osd_message(String::from("Replay finished"));
+            },
+        }
+    }
+
+    fn perform_ui_action(&mut self, action: UIAction) {
+        match action {
+            UIAction::Key(key, down) => {
+                /* Input comes from the bundle or movie during replay */
+                if self.bundle_replay.is_some() || self.movie_playback.is_some()
+                {
+                    return;
+                }
+
+                let addr_space = &mut self.sys_state.addr_space;
+                self.sys_state.keypad.key_event(addr_space, key, down);
+            },
+
+            UIAction::Tilt(dir, down) =>
+                self.sys_state.addr_space.cartridge.tilt_event(dir, down),
+
+            UIAction::TiltAnalog(x, y) =>
+                self.sys_state.addr_space.cartridge.tilt_analog_event(x, y),
+
+            UIAction::ToggleChannel(channel) => {
+                let mute_mask = self.sys_state.sound.toggle_channel_mute(channel);
+
+                let muted: Vec<String> =
+                    (0..4).filter(|i| mute_mask & (1 << i) != 0)
+                          .map(|i| format!("CH{}", i + 1))
+                          .collect();
+
+                if muted.is_empty() {
+                    self.ui.osd_message(String::from("No channels muted"));
+                } else {
+                    self.ui.osd_message(format!("Muted: {}", muted.join(", ")));
+                }
+            },
+
+            UIAction::ToggleAudioRecording =>
+                self.toggle_audio_recording(),
+
+            UIAction::Screenshot(with_border) =>
+                self.screenshot(with_border),
+
+            UIAction::ToggleBundleRecording =>
+                self.toggle_bundle_recording(),
+
+            UIAction::Skip(skip) => {
+                if self.fast_forward_mult > 0.0 {
+                    self.fast_forward(skip);
+                } else if let Some(realtime) =
+                    self.throttled_realtime.as_mut()
+                {
+                    *realtime = !skip;
+                } else {
+                    self.sys_state.realtime = !skip;
+                }
+            },
+
+            UIAction::SetSpeed(speed_mult) => {
+                self.set_speed(speed_mult);
+                self.ui.osd_message(format!("Speed: {}x", self.speed_mult));
+            },
+
+            UIAction::ToggleAudioPostprocessing => {
+                self.sys_state.toggle_sound_postprocess();
+
+                let pp_state =
+                    if self.sys_state.sound_postprocess {
+                        "enabled"
+                    } else {
+                        "disabled"
+                    };
+
+                self.ui.osd_message(format!("Sound postprocessing {}",
+                                            pp_state));
+            },
+
+            UIAction::Rewind(rewinding) => {
+                if !rewinding {
+                    self.rewinding = false;
+                } else if self.rewind.is_none() {
+                    self.ui.osd_message(String::from("Rewinding is not \
+                                                      enabled"));
+                } else if self.bundle_rec.is_some() ||
+                          self.bundle_replay.is_some()
+                {
+                    /* Would break the bundle's input timeline */
+                    self.ui.osd_message(String::from("Cannot rewind while \
+                                                      recording or replaying \
+                                                      a bundle"));
+                } else if self.movie_rec.is_some() ||
+                          self.movie_playback.is_some()
+                {
+                    self.ui.osd_message(String::from("Cannot rewind while \
+                                                      recording or playing \
+                                                      a movie"));
+                } else {
+                    self.rewinding = true;
+                }
+            },
+
+            UIAction::LoadState(index) => {
+                /* Movies always start from power-on */
+                if self.movie_rec.is_some() || self.movie_playback.is_some() {
+                    self.ui.osd_message(String::from("Cannot load a state \
+                                                      while recording or \
+                                                      playing a movie"));
+                    return;
+                }
+
+                self.do_save_state(index, false);
+                self.ui.refresh_lcd(&self.sys_state);
+            },
+
+            UIAction::SaveState(index) =>
+                self.do_save_state(index, true),
+
+            UIAction::ToggleColorMode => {
+                let msg =
+                    match self.sys_state.toggle_color_mode() {
+                        Ok(true) => String::from("Switched to CGB mode"),
+                        Ok(false) => String::from("Switched to DMG mode"),
+                        Err(e) => String::from(e),
+                    };
+
+                self.ui.osd_message(msg);
+            },
+
+            UIAction::CycleColorCorrection => {
+                let (mode, name) =
+                    match self.sys_state.display.color_correction() {
+                        ColorMode::Raw => (ColorMode::Cgb, "CGB LCD"),
+                        ColorMode::Cgb => (ColorMode::Agb, "GBA LCD"),
+                        ColorMode::Agb => (ColorMode::Raw, "raw"),
+                    };
+
+                DisplayState::set_color_correction(&mut self.sys_state, mode);
+                self.ui.osd_message(format!("Color correction: {}", name));
+            },
+
+            UIAction::CycleDmgPalette => {
+                let (palette, name) =
+                    match self.sys_state.display.dmg_palette() {
+                        DmgPalette::Grayscale =>
+                            (DmgPalette::DmgGreen, "green"),
+                        DmgPalette::DmgGreen =>
+                            (DmgPalette::Pocket, "pocket"),
+                        DmgPalette::Pocket | DmgPalette::Custom(_) =>
+                            (DmgPalette::Grayscale, "grayscale"),
+                    };
+
+                self.set_dmg_palette(palette);
+                self.ui.osd_message(format!("DMG palette: {}", name));
+            },
+
+            UIAction::Reset => {
+                /* Would break their input timeline */
+                if self.bundle_rec.is_some() || self.bundle_replay.is_some() {
+                    self.ui.osd_message(String::from("Cannot reset while \
+                                                      recording or replaying \
+                                                      a bundle"));
+                    return;
+                }
+                if self.movie_rec.is_some() || self.movie_playback.is_some() {
+                    self.ui.osd_message(String::from("Cannot reset while \
+                                                      recording or playing \
+                                                      a movie"));
+                    return;
+                }
+
+                self.reset();
+                self.ui.osd_message(String::from("Reset"));
+            },
+
+            UIAction::ToggleSpriteLimit => {
+                let display = &mut self.sys_state.display;
+                display.set_sprite_limit(!display.sprite_limit());
+
+                let msg =
+                    if display.sprite_limit() {
+                        "Sprite limit: 10 per line"
+                    } else {
+                        "Sprite limit: disabled"
+                    };
+                self.ui.osd_message(String::from(msg));
+            },
+
+            UIAction::ToggleFullscreen =>
+                self.ui.toggle_fullscreen(),
+
+            UIAction::ToggleStretch =>
+                self.ui.toggle_stretch(),
+
+            UIAction::CycleScaleMode => {
+                let name =
+                    match self.ui.cycle_scale_mode() {
+                        ScaleMode::Fit => "fit",
+                        ScaleMode::IntegerFit => "integer",
+                        ScaleMode::IntegerPixelPerfect =>
+                            "integer, pixel-perfect",
+                    };
+                self.ui.osd_message(format!("Scaling: {}", name));
+            },
+
+            UIAction::ToggleSgbBorder => {
+                let msg =
+                    if self.ui.toggle_sgb_border(&self.sys_state) {
+                        "SGB border hidden"
+                    } else {
+                        "SGB border shown"
+                    };
+                self.ui.osd_message(String::from(msg));
+            },
+
+            UIAction::ToggleFrameBlend => {
+                let name =
+                    match self.ui.cycle_frame_blend() {
+                        FrameBlend::Off => "off",
+                        FrameBlend::TwoFrames => "2 frames",
+                        FrameBlend::ThreeFrames => "3 frames",
+                    };
+                self.ui.osd_message(format!("Frame blending: {}", name));
+            },
+
+            UIAction::TogglePause => {
+                self.paused = !self.paused;
+                self.ui.set_paused(self.paused);
+
+                if self.paused {
+                    self.ui.osd_message(String::from("Paused"));
+                } else {
+                    self.ui.osd_message(String::from("Resumed"));
+                }
+            }
+
+            UIAction::DumpMemory =>
+                self.dump_memory(),
+
+            UIAction::ExportStateJson =>
+                self.export_state_json(),
+
+            UIAction::ShowAudioLatency => {
+                let sound = &self.sys_state.sound;
+                let msg = format!("Audio latency: {:.0} ms\n\
+                                   Underruns: {}",
+                                  sound.latency_ms(), sound.underruns());
+                self.ui.osd_message(msg);
+            },
+
+            UIAction::OpenKeyConfig =>
+                self.ui.start_key_config(),
+
+            UIAction::ReloadInputMapping =>
+                self.ui.reload_input_mapping(),
+
+            UIAction::Unbound => (),
+
+            #[cfg(not(target_arch = "wasm32"))]
+            UIAction::ToggleVramView => {
+                if self.ui.toggle_vram_view() {
+                    self.ui.osd_message(String::from("VRAM view opened"));
+                } else {
+                    self.ui.osd_message(String::from("VRAM view closed"));
+                }
+            },
+
+            #[cfg(target_arch = "wasm32")]
+            UIAction::ToggleVramView => (),
+
+            UIAction::Minimized(minimized) =>
+                self.window_minimized(minimized),
+
+            UIAction::Quit =>
+                self.quit(),
+        }
+    }
+
+    /* Writes everything that is still pending, then exits */
+    pub fn quit(&mut self) -> ! {
+        self.exit(0)
+    }
+
+    /* Like quit(), but with the given exit status */
+    pub fn exit(&mut self, status: i32) -> ! {
+        self.sys_state.addr_space.flush_extram();
+        self.dump_coverage();
+        self.dump_exit_regions();
+        self.save_movie();
+        #[cfg(not(target_arch = "wasm32"))]
+        self.ui.save_window_state();
+        if let Some(fd) = self.frame_dump.as_mut() {
+            fd.finish();
+        }
+        if let Some(sink) = self.trace_sink.as_mut() {
+            sink.flush().unwrap_or(());
+        }
+        if self.audio_recording.is_some() {
+            self.toggle_audio_recording();
+        }
+        std::process::exit(status);
+    }
+
+    fn window_minimized(&mut self, minimized: bool) {
+        match self.minimized_behavior {
+            MinimizedBehavior::Run => (),
+
+            MinimizedBehavior::Pause => {
+                if minimized && !self.paused {
+                    self.paused = true;
+                    self.paused_by_minimize = true;
+                    self.ui.set_paused(true);
+                } else if !minimized && self.paused_by_minimize {
+                    self.paused = false;
+                    self.paused_by_minimize = false;
+                    self.ui.set_paused(false);
+                }
+            },
+
+            MinimizedBehavior::Throttle => {
+                if minimized && self.throttled_realtime.is_none() {
+                    /* Audio is muted, so we must not sync to it; instead,
+                     * handle_events() limits the frame rate */
+                    self.throttled_realtime = Some(self.sys_state.realtime);
+                    self.sys_state.realtime = false;
+                    self.ui.set_paused(true);
+                } else if !minimized {
+                    if let Some(realtime) = self.throttled_realtime.take() {
+                        self.sys_state.realtime = realtime;
+                        self.ui.set_paused(self.paused);
+                    }
+                }
+            },
+        }
+    }
+
+    fn poll_input(&mut self) {
+        while let Some(evt) = self.get_event() {
+            if let Some(action) = self.ui.translate_event(evt) {
+                self.perform_ui_action(action);
+            }
+        }
+    }
+
+    fn exec(&mut self) {
+        if self.sys_state.audio_synced {
+            self.sys_state.audio_synced = false;
+
+            if self.low_latency {
+                self.poll_input();
+            }
+        }
+
+        let pc = self.cpu.pc();
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.mark(&self.sys_state.addr_space, pc);
+        }
+
+        let was_locked = self.cpu.is_locked();
+        let result = self.step();
+
+        if self.cpu.is_locked() && !was_locked {
+            let msg = format!("CPU locked at PC={:04x}", self.cpu.pc());
+            self.ui.osd_timed_message(msg, std::time::Duration::from_secs(10));
+        }
+
+        if self.cpu.is_stopped() {
+            self.stopped_frame();
+        }
+
+        if let StepResult::Break(reason) = result {
+            self.debug_break(reason);
+        }
+    }
+
+    /*
+     * There will be no VBlank while the CPU is stopped, so input must be
+     * handled here.  Every round counts as a frame for movies and
+     * bundles, so a replay can provide the button press that ends STOP.
+     */
+    fn stopped_frame(&mut self) {
+        if self.bundle_replay.is_some() || self.movie_playback.is_some() {
+            self.poll_input();
+        } else {
+            let evt = self.ui.wait_event(&self.sys_state);
+            if let Some(action) = self.ui.translate_event(evt) {
+                self.perform_ui_action(action);
+            }
+        }
+
+        self.movie_frame();
+        self.bundle_frame();
+    }
+
+    fn debug_break(&mut self, reason: BreakReason) {
+        let msg = reason.to_string();
+        eprintln!("{}", msg);
+        self.ui.osd_message(msg);
+
+        #[cfg(feature = "debugger")]
+        {
+            self.ui.set_paused(true);
+            self.debugger_repl();
+            self.ui.set_paused(self.paused);
+        }
+
+        #[cfg(not(feature = "debugger"))]
+        {
+            /* Stop right here instead of at the next VBlank */
+            self.paused = true;
+            self.ui.set_paused(true);
+            self.poll_input();
+        }
+    }
+
+    /*
+     * Executes a single instruction (including a possible interrupt
+     * dispatch), lets the rest of the system catch up, and returns the
+     * number of cycles that took, or why emulation should break instead.
+     * Does not handle any UI events.
+     * Use set_realtime(false) to not wait for the audio driver.
+     */
+    pub fn step(&mut self) -> StepResult {
+        let pc = self.cpu.pc();
+
+        #[cfg(feature = "debugger")]
+        if self.sys_state.debugger.check_exec(pc) {
+            return StepResult::Break(BreakReason::Breakpoint(pc));
+        }
+
+        if let Some(sink) = self.trace_sink.as_mut() {
+            if let Some(line) = self.cpu.trace_line(&mut self.sys_state) {
+                if let Err(e) = writeln!(sink, "{}", line) {
+                    eprintln!("Failed to write trace, disabling: {}", e);
+                    self.trace_sink = None;
+                }
+            }
+        }
+
+        let cycles = self.cpu.exec(&mut self.sys_state);
+        self.sys_state.add_cycles(cycles);
+
+        match self.sys_state.debugger.take_break_reason(pc) {
+            Some(reason) => StepResult::Break(reason),
+            None => StepResult::Executed(cycles),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn cpu_state(&self) -> CpuSnapshot {
+        self.cpu.snapshot()
+    }
+
+    pub fn set_realtime(&mut self, realtime: bool) {
+        self.sys_state.realtime = realtime;
+    }
+
+    fn get_event(&mut self) -> Option<UIEvent> {
+        /* Pausing will cause us to always return Some() until the
+         * game is unpaused again.  So until then, we are caught up
+         * in the event loop and automatically will not exec anything. */
+        if self.paused {
+            Some(self.ui.wait_event(&self.sys_state))
+        } else {
+            self.ui.poll_event()
+        }
+    }
+
+    fn handle_events(&mut self) {
+        self.ui.vblank_events(&self.sys_state);
+
+        if self.sys_state.sgb_state.load_border {
+            self.sys_state.sgb_state.load_border = false;
+            self.ui.load_sgb_border(&self.sys_state);
+        }
+
+        self.poll_input();
+        self.movie_frame();
+        self.bundle_frame();
+
+        if self.rewinding {
+            if !self.rewind_step() {
+                self.rewinding = false;
+            }
+        } else {
+            self.take_rewind_snapshot();
+        }
+
+        if self.throttled_realtime.is_some() {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+    }
+
+    pub fn main_loop(&mut self, break_on_vblank: bool) {
+        loop {
+            self.exec();
+
+            if let Some(serial) = self.sys_state.serial.as_mut() {
+                serial.check_remote(&mut self.sys_state.addr_space);
+            }
+            if let Some(ir) = self.sys_state.ir.as_mut() {
+                ir.check_remote(&mut self.sys_state.addr_space);
+            }
+
+            if self.sys_state.speed_switched {
+                self.sys_state.speed_switched = false;
+
+                let msg =
+                    if self.sys_state.double_speed {
+                        "Double speed mode"
+                    } else {
+                        "Normal speed mode"
+                    };
+                self.ui.osd_message(String::from(msg));
+            }
+
+            if self.sys_state.vblanked {
+                self.sys_state.vblanked = false;
+
+                #[cfg(not(target_arch = "wasm32"))]
+                if let Some(e) = self.sys_state.sound.take_wav_error() {
+                    self.audio_recording_failed(e);
+                }
+
+                for code in &self.gameshark_codes {
+                    code.apply(&mut self.sys_state);
+                }
+
+                #[cfg(target_arch = "wasm32")]
+                {
+                    if let Some(buf) = self.ui.get_vblank_sound_buf() {
+                        self.sys_state.sound.fill_outbuf(&mut self.sys_state.addr_space, buf.as_mut_slice());
+                        self.ui.submit_vblank_sound_buf();
+                    }
+                }
+
+                self.ui.refresh_lcd(&self.sys_state);
+                if let Some(fd) = self.frame_dump.as_mut() {
+                    fd.frame(&self.sys_state.display.lcd_pixels);
+                }
+                self.handle_events();
+
+                if let Some(serial) = self.sys_state.serial.as_mut() {
+                    serial.vblank_check();
+                }
+                if let Some(ir) = self.sys_state.ir.as_mut() {
+                    ir.vblank_check();
+                }
+
+                self.sys_state.addr_space.sync_extram();
+
+                if self.extram_dirtying && !self.sys_state.addr_space.extram_dirty {
+                    self.sys_state.addr_space.flush_extram();
+                    self.extram_dirtying = false;
+                }
+
+                if self.sys_state.addr_space.extram_dirty {
+                    self.extram_dirtying = true;
+                    self.sys_state.addr_space.extram_dirty = false;
+                }
+
+                if let Some(interval) = self.autosave_interval {
+                    self.autosave_frame_i += 1;
+                    if self.autosave_frame_i >= interval {
+                        self.autosave_frame_i = 0;
+                        self.autosave();
+                    }
+                }
+
+                if break_on_vblank {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl SystemState {
+    pub fn new(addr_space: Box<AddressSpace>, params: SystemParams, ui: &mut UI)
+        -> Self
+    {
+        let mut state = Self {
+            addr_space,
+
+            model: params.model,
+            cgb: params.cgb,
+            sgb: params.sgb && !params.cgb,
+            cgb_capable: params.dmg_compatible,
+            ints_enabled: true,
+            double_speed: false,
+            realtime: true,
+            vblanked: false,
+            speed_switched: false,
+            audio_synced: false,
+
+            sound_postprocess: false,
+
+            display: Box::new(DisplayState::new()),
+            keypad: KeypadState::new(),
+            sound: SoundState::new(params.audio_buffers),
+            timer: TimerState::new(),
+            serial: SerialState::new(ui, &params.serial_conn_param),
+            ir: IrState::new(ui, &params.ir_conn_param),
+            debugger: Debugger::new(),
+
+            sgb_state: Box::new(SGBState::new()),
+        };
+
+        io::init_power_on(&mut state);
+        DisplayState::init_system_state(&mut state);
+        KeypadState::init_system_state(&mut state);
+        SoundState::init_system_state(&mut state);
+        state.sound.set_dmg_wave_ram(!state.model.is_cgb());
+        TimerState::init_system_state(&mut state);
+        io::init_dma(&mut state);
+        io::init_boot(&mut state);
+
+        state
+    }
+
+    /* Back to the state new() produces, except for the cartridge RAM,
+     * connections, and settings */
+    pub fn reset(&mut self) {
+        self.ints_enabled = true;
+        self.double_speed = false;
+        self.io_set_reg(IOReg::KEY1, 0x00);
+        self.vblanked = false;
+        self.speed_switched = false;
+        self.audio_synced = false;
+
+        self.addr_space.vram_bank = 0;
+        self.addr_space.wram_bank = 1;
+        Cartridge::reset(&mut self.addr_space);
+
+        self.display.reset();
+        self.keypad.reset();
+        self.timer = TimerState::new();
+        self.sgb_state = Box::new(SGBState::new());
+
+        io::init_power_on(self);
+        DisplayState::init_system_state(self);
+        DisplayState::apply_dmg_palette(self);
+        KeypadState::init_system_state(self);
+        SoundState::reset(self);
+        TimerState::init_system_state(self);
+        io::init_dma(self);
+        io::init_boot(self);
+    }
+
+    /* @count is in CPU cycles */
+    pub fn add_cycles(&mut self, count: u32) {
+        let dcycles = dcycles(count, self.double_speed);
+
+        io::lcd::add_cycles(self, dcycles);
+        #[cfg(not(target_arch = "wasm32"))]
+        if self.sound.add_cycles(&mut self.addr_space, dcycles, self.realtime) {
+            self.audio_synced = true;
+        }
+        io::timer::add_cycles(self, count);
+        Cartridge::add_cycles(&mut self.addr_space, count);
+
+        if let Some(serial) = self.serial.as_mut() {
+            serial.add_cycles(&mut self.addr_space, dcycles);
+        }
+    }
+
+    /* KEY1 speed switch */
+    pub fn set_double_speed(&mut self, double_speed: bool) {
+        self.double_speed = double_speed;
+        self.speed_switched = true;
+
+        /* The switch is done, so it is no longer armed */
+        let key1 = self.io_get_reg(IOReg::KEY1) & !0x01;
+        if double_speed {
+            self.io_set_reg(IOReg::KEY1, key1 | 0x80);
+        } else {
+            self.io_set_reg(IOReg::KEY1, key1 & !0x80);
+        }
+    }
+
+    /* Switches between CGB and DMG mode for games that support both */
+    pub fn toggle_color_mode(&mut self) -> Result<bool, &'static str> {
+        if !self.cgb_capable {
+            return Err("Game does not support CGB mode");
+        }
+        if self.double_speed {
+            return Err("Cannot switch to DMG mode in double speed mode");
+        }
+
+        self.cgb = !self.cgb;
+
+        if self.cgb {
+            self.addr_space.vram_bank =
+                self.io_get_reg(IOReg::VBK) as usize & 0x01;
+            let wram_bank = self.io_get_reg(IOReg::SVBK) as usize & 0x07;
+            self.addr_space.wram_bank = if wram_bank == 0 { 1 } else { wram_bank };
+        } else {
+            self.addr_space.vram_bank = 0;
+            self.addr_space.wram_bank = 1;
+        }
+        self.addr_space.remap_vram();
+        self.addr_space.remap_wramn();
+
+        DisplayState::color_mode_changed(self);
+
+        Ok(self.cgb)
+    }
+
     fn toggle_sound_postprocess(&mut self) {
         self.sound_postprocess = !self.sound_postprocess;
         self.sound.set_postprocessing(self.sound_postprocess);
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use crate::cpu::Reg16;
+    use crate::io::{IOReg, IOSpace};
+    use super::{MinimizedBehavior, Model};
+    use crate::io::lcd::{ColorMode, DmgPalette};
+    use crate::testing::{cgb_test_rom, fix_header_checksum,
+                         mock_test_system, test_rom, test_system,
+                         test_system_as, TestSystem};
+    use crate::ui::{UIAction, UIEvent, UIScancode};
+
+    #[test]
+    fn coverage_marks_executed_opcodes() {
+        /* ld a, 5; inc a; jr -3 (back to the inc) */
+        let mut sys = test_system(test_rom(&[0x3e, 0x05, 0x3c, 0x18, 0xfd]));
+
+        let path = std::env::temp_dir()
+                       .join(format!("xgbcrew-coverage-{}",
+                                     std::process::id()));
+        sys.enable_coverage(path.to_string_lossy().into_owned());
+
+        sys.main_loop(true);
+        sys.dump_coverage();
+
+        let result = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        /* Operand bytes (0x0151, 0x0154) are never executed */
+        assert_eq!(result, "00:0100-0101\n00:0150-0150\n00:0152-0153\n");
+    }
+
+    #[test]
+    fn toggle_color_mode_switches_palettes() {
+        let mut sys = test_system(cgb_test_rom(&[0x18, 0xfe]));
+        assert!(sys.sys_state.cgb);
+
+        /* LCD off, so VRAM and palettes are always accessible */
+        sys.poke(0xff40, 0x00);
+
+        /* Tile 0 all color 3, used everywhere with attributes 0 */
+        for addr in 0x8000..0x8010 {
+            sys.poke(addr, 0xff);
+        }
+        for bank in [1, 0] {
+            sys.poke(0xff4f, bank);
+            for addr in 0x9800..0x9c00 {
+                sys.poke(addr, 0x00);
+            }
+        }
+
+        /* BG palette 0, color 3: pure red */
+        sys.poke(0xff68, 0x86);
+        sys.poke(0xff69, 0x1f);
+        sys.poke(0xff69, 0x00);
+
+        sys.poke(0xff40, 0x91);
+        sys.main_loop(true);
+        sys.main_loop(true);
+        let cgb_frame = sys.sys_state.display.lcd_pixels;
+        assert!(cgb_frame.iter().all(|&p| p == cgb_frame[0]));
+
+        assert_eq!(sys.sys_state.toggle_color_mode(), Ok(false));
+        sys.main_loop(true);
+        sys.main_loop(true);
+        let dmg_frame = sys.sys_state.display.lcd_pixels;
+        let black = DmgPalette::Grayscale.shades()[3];
+        assert!(dmg_frame.iter().all(|&p| p == black));
+        assert!(cgb_frame[0] != black);
+
+        assert_eq!(sys.sys_state.toggle_color_mode(), Ok(true));
+        sys.main_loop(true);
+        sys.main_loop(true);
+        assert!(sys.sys_state.display.lcd_pixels == cgb_frame);
+    }
+
+    #[test]
+    fn toggle_color_mode_requires_dmg_compatibility() {
+        /* CGB only */
+        let mut rom = cgb_test_rom(&[0x18, 0xfe]);
+        rom[0x143] = 0xc0;
+        crate::testing::fix_header_checksum(&mut rom);
+
+        let mut sys = test_system(rom);
+        assert!(sys.sys_state.cgb);
+        assert!(sys.sys_state.toggle_color_mode().is_err());
+        drop(sys);
+
+        let mut sys = test_system(test_rom(&[0x18, 0xfe]));
+        assert!(sys.sys_state.toggle_color_mode().is_err());
+    }
+
+    #[test]
+    fn fast_forward_uses_configured_speed() {
+        let mut sys = test_system(test_rom(&[0x18, 0xfe]));
+        sys.set_realtime(true);
+        sys.set_speed(2.0);
+
+        /* Unlimited by default */
+        sys.perform_ui_action(UIAction::Skip(true));
+        assert!(!sys.sys_state.realtime);
+        sys.perform_ui_action(UIAction::Skip(false));
+        assert!(sys.sys_state.realtime);
+
+        sys.set_fast_forward(4.0);
+        sys.perform_ui_action(UIAction::Skip(true));
+        sys.perform_ui_action(UIAction::Skip(true));
+        assert!(sys.sys_state.realtime);
+        assert_eq!(sys.speed_mult, 4.0);
+
+        sys.perform_ui_action(UIAction::Skip(false));
+        assert_eq!(sys.speed_mult, 2.0);
+    }
+
+    const LOGO: [u8; 48] = [
+        0xce, 0xed, 0x66, 0x66, 0xcc, 0x0d, 0x00, 0x0b,
+        0x03, 0x73, 0x00, 0x83, 0x00, 0x0c, 0x00, 0x0d,
+        0x00, 0x08, 0x11, 0x1f, 0x88, 0x89, 0x00, 0x0e,
+        0xdc, 0xcc, 0x6e, 0xe6, 0xdd, 0xdd, 0xd9, 0x99,
+        0xbb, 0xbb, 0x67, 0x63, 0x6e, 0x0e, 0xec, 0xcc,
+        0xdd, 0xdc, 0x99, 0x9f, 0xbb, 0xb9, 0x33, 0x3e,
+    ];
+
+    /* Checks the logo and header checksum like the DMG boot ROM, locking
+     * up at 0x0022 if either is wrong */
+    fn boot_rom() -> Vec<u8> {
+        let mut boot_rom = vec![0u8; 0x100];
+        boot_rom[..0x24].copy_from_slice(&[
+            0x31, 0xfe, 0xff,   /* ld sp, 0xfffe */
+            0x21, 0x04, 0x01,   /* ld hl, 0x0104 */
+            0x11, 0xa8, 0x00,   /* ld de, 0x00a8 */
+            0x06, 0x30,         /* ld b, 48 */
+            0x1a,               /* ld a, (de) */
+            0xbe,               /* cp (hl) */
+            0x20, 0x13,         /* jr nz, lock */
+            0x13,               /* inc de */
+            0x23,               /* inc hl */
+            0x05,               /* dec b */
+            0x20, 0xf7,         /* jr nz, -9 */
+            0x06, 0x19,         /* ld b, 0x19 */
+            0x78,               /* ld a, b */
+            0x86,               /* add (hl) */
+            0x23,               /* inc hl */
+            0x05,               /* dec b */
+            0x20, 0xfb,         /* jr nz, -5 */
+            0x86,               /* add (hl) */
+            0x20, 0x03,         /* jr nz, lock */
+            0xc3, 0xfc, 0x00,   /* jp 0x00fc */
+            0x18, 0xfe,         /* lock: jr lock */
+        ]);
+        boot_rom[0xa8..0xd8].copy_from_slice(&LOGO);
+        /* ld a, 1; ldh (0x50), a; (continues at 0x0100) */
+        boot_rom[0xfc..].copy_from_slice(&[0x3e, 0x01, 0xe0, 0x50]);
+        boot_rom
+    }
+
+    fn boot(rom: Vec<u8>) -> TestSystem {
+        let mut sys = test_system(rom);
+        sys.set_boot_rom(boot_rom()).unwrap();
+        assert_eq!(sys.get_reg16(Reg16::PC), 0x0000);
+        assert_eq!(sys.peek(0x0000), 0x31);
+        assert_eq!(sys.sys_state.io_get_reg(IOReg::LCDC), 0x00);
+
+        for _ in 0..1000 {
+            let pc = sys.get_reg16(Reg16::PC);
+            if pc == 0x0100 || pc == 0x0022 {
+                break;
+            }
+            sys.step();
+        }
+        sys
+    }
+
+    fn rom_with_logo() -> Vec<u8> {
+        let mut rom = test_rom(&[0x18, 0xfe]);
+        rom[0x104..0x134].copy_from_slice(&LOGO);
+        rom
+    }
+
+    #[test]
+    fn boot_rom_checks_header() {
+        let mut sys = boot(rom_with_logo());
+        assert_eq!(sys.get_reg16(Reg16::PC), 0x0100);
+        assert_eq!(sys.sys_state.io_get_reg(IOReg::BOOT), 0xff);
+        /* The cartridge is visible again */
+        assert_eq!(sys.peek(0x0000), 0x00);
+        drop(sys);
+
+        let mut rom = rom_with_logo();
+        rom[0x110] ^= 0x01;
+        let sys = boot(rom);
+        assert_eq!(sys.get_reg16(Reg16::PC), 0x0022);
+        drop(sys);
+
+        let mut rom = rom_with_logo();
+        rom[0x14d] ^= 0x01;
+        let mut sys = boot(rom);
+        assert_eq!(sys.get_reg16(Reg16::PC), 0x0022);
+        assert_eq!(sys.peek(0x0000), 0x31);
+    }
+
+    #[test]
+    fn boot_rom_size_must_fit() {
+        let mut sys = test_system(cgb_test_rom(&[0x18, 0xfe]));
+        assert!(sys.set_boot_rom(boot_rom()).is_err());
+        assert_eq!(sys.get_reg16(Reg16::PC), 0x0100);
+    }
+
+    #[test]
+    fn fast_boot_defaults() {
+        let mut sys = test_system(rom_with_logo());
+
+        assert_eq!(sys.get_reg16(Reg16::AF), 0x01b0);
+        assert_eq!(sys.get_reg16(Reg16::BC), 0x0013);
+        assert_eq!(sys.get_reg16(Reg16::DE), 0x00d8);
+        assert_eq!(sys.get_reg16(Reg16::HL), 0x014d);
+        assert_eq!(sys.get_reg16(Reg16::SP), 0xfffe);
+        assert_eq!(sys.get_reg16(Reg16::PC), 0x0100);
+
+        assert_eq!(sys.sys_state.io_get_reg(IOReg::BOOT), 0xff);
+        assert_eq!(sys.sys_state.io_get_reg(IOReg::LCDC), 0x91);
+        /* No boot ROM in the way */
+        assert_eq!(sys.peek(0x0000), 0x00);
+
+        /* Even with a broken header */
+        let mut rom = rom_with_logo();
+        rom[0x104] = 0x00;
+        rom[0x14d] ^= 0x01;
+        drop(sys);
+        let sys = test_system(rom);
+        assert_eq!(sys.get_reg16(Reg16::PC), 0x0100);
+        assert_eq!(sys.sys_state.io_get_reg(IOReg::BOOT), 0xff);
+    }
+
+    /* (uncorrected, corrected) CRCs of the next full frame */
+    fn frame_crcs(sys: &mut TestSystem, mode: ColorMode) -> (u32, u32) {
+        sys.set_color_correction(mode);
+        sys.main_loop(true);
+        sys.main_loop(true);
+        (sys.framebuffer_crc(false), sys.framebuffer_crc(true))
+    }
+
+    #[test]
+    fn framebuffer_crc_without_color_correction() {
+        let mut sys = test_system(cgb_test_rom(&[0x18, 0xfe]));
+        /* BG palette 0, color 0: orange */
+        sys.poke(0xff68, 0x80);
+        sys.poke(0xff69, 0x1f);
+        sys.poke(0xff69, 0x02);
+
+        let (raw, raw_corrected) = frame_crcs(&mut sys, ColorMode::Raw);
+        assert_eq!(raw, raw_corrected);
+        let (cgb, cgb_corrected) = frame_crcs(&mut sys, ColorMode::Cgb);
+        assert_eq!(cgb, raw);
+        assert_ne!(cgb_corrected, raw);
+        drop(sys);
+
+        /* Same for DMG games with the CGB's compatibility palettes */
+        let mut sys = test_system_as(test_rom(&[0x18, 0xfe]),
+                                     Some(Model::Cgb));
+        /* Everything in color 1 (which is not white) */
+        sys.poke(0xff47, 0x55);
+
+        let (raw, raw_corrected) = frame_crcs(&mut sys, ColorMode::Raw);
+        assert_eq!(raw, raw_corrected);
+        let (agb, agb_corrected) = frame_crcs(&mut sys, ColorMode::Agb);
+        assert_eq!(agb, raw);
+        assert_ne!(agb_corrected, raw);
+    }
+
+    #[test]
+    fn reset_restarts_at_entry_point() {
+        /* ld a, 0x42; ld (0xc000), a; jr -2 */
+        let mut sys = test_system(test_rom(&[0x3e, 0x42, 0xea, 0x00, 0xc0,
+                                             0x18, 0xfe]));
+        sys.main_loop(true);
+        assert_ne!(sys.get_reg16(Reg16::PC), 0x0100);
+        assert_eq!(sys.peek(0xc000), 0x42);
+
+        sys.perform_ui_action(UIAction::Reset);
+        assert_eq!(sys.get_reg16(Reg16::PC), 0x0100);
+        assert_eq!(sys.get_reg16(Reg16::SP), 0xfffe);
+        assert_eq!(sys.sys_state.io_get_reg(IOReg::LCDC), 0x91);
+
+        /* Not while recording a movie */
+        sys.main_loop(true);
+        let path = std::env::temp_dir()
+                       .join(format!("xgbcrew-reset-movie-{}",
+                                     std::process::id()));
+        sys.record_movie(path.to_string_lossy().into_owned());
+        sys.perform_ui_action(UIAction::Reset);
+        assert_ne!(sys.get_reg16(Reg16::PC), 0x0100);
+        sys.movie_rec = None;
+    }
+
+    /* Select the buttons: ld a, 0x10; ldh (P1), a; stop; ld a, 0x42;
+     * jr -2 */
+    const STOP_CODE: [u8; 10] = [0x3e, 0x10, 0xe0, 0x00, 0x10, 0x00,
+                                 0x3e, 0x42, 0x18, 0xfe];
+
+    /* X is A by default */
+    const PRESS_A: UIEvent = UIEvent::Key { key: UIScancode::X, down: true };
+
+    fn exec_until_stopped(sys: &mut TestSystem) {
+        while !sys.cpu.is_stopped() {
+            sys.exec();
+        }
+        assert_eq!(sys.get_reg16(Reg16::PC), 0x0156);
+    }
+
+    #[test]
+    fn stop_ends_on_button_press() {
+        let (mut sys, log) = mock_test_system(test_rom(&STOP_CODE));
+
+        /* Anything but a button press keeps the CPU stopped (with the
+         * mock frontend, the event queue must never run dry while
+         * stopped, or we would wait forever) */
+        log.borrow_mut().events.push_back(UIEvent::Minimized(false));
+        exec_until_stopped(&mut sys);
+        assert!(log.borrow().events.is_empty());
+
+        log.borrow_mut().events.push_back(PRESS_A);
+        sys.exec();
+        assert!(log.borrow().events.is_empty());
+        assert_eq!(sys.get_reg16(Reg16::PC), 0x0156);
+
+        sys.exec();
+        assert!(!sys.cpu.is_stopped());
+        assert_eq!(sys.get_reg16(Reg16::AF) >> 8, 0x42);
+    }
+
+    #[test]
+    fn movie_ends_stop() {
+        let path = std::env::temp_dir()
+                       .join(format!("xgbcrew-stop-movie-{}",
+                                     std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+
+        {
+            let (mut sys, log) = mock_test_system(test_rom(&STOP_CODE));
+            sys.record_movie(path.clone());
+            log.borrow_mut().events.push_back(PRESS_A);
+            exec_until_stopped(&mut sys);
+            sys.save_movie();
+        }
+
+        /* No events at all, the movie has to provide the press */
+        let (mut sys, _log) = mock_test_system(test_rom(&STOP_CODE));
+        sys.play_movie(&path);
+        exec_until_stopped(&mut sys);
+        sys.exec();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(!sys.cpu.is_stopped());
+        assert_eq!(sys.get_reg16(Reg16::AF) >> 8, 0x42);
+    }
+
+    #[test]
+    fn stop_after_speed_switch_stops() {
+        /* ld a, 1; ldh (KEY1), a; stop; stop; jr -2 */
+        let mut sys = test_system(cgb_test_rom(&[0x3e, 0x01, 0xe0, 0x4d,
+                                                 0x10, 0x00, 0x10, 0x00,
+                                                 0x18, 0xfe]));
+
+        /* nop; jp; ld; ldh; stop */
+        for _ in 0..5 {
+            sys.step();
+        }
+        assert!(sys.sys_state.double_speed);
+        assert!(!sys.cpu.is_stopped());
+        assert_eq!(sys.sys_state.io_get_reg(IOReg::KEY1) & 0x81, 0x80);
+
+        sys.step();
+        assert!(sys.cpu.is_stopped());
+        assert!(sys.sys_state.double_speed);
+    }
+
+    #[test]
+    fn autosave_flushes_periodically() {
+        /* Leaves cartridge RAM enabled, so writes are invisible to us:
+         * ld a, 0x0a; ld (0x0000), a; ld a, 0x42; ld (0xa000), a; jr -2 */
+        let mut rom = test_rom(&[0x3e, 0x0a, 0xea, 0x00, 0x00,
+                                 0x3e, 0x42, 0xea, 0x00, 0xa0,
+                                 0x18, 0xfe]);
+        /* MBC1+RAM+BATTERY, 8 kB RAM */
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x02;
+        fix_header_checksum(&mut rom);
+        let mut sys = test_system(rom);
+
+        let flushes = Rc::new(Cell::new(0));
+        let counter = flushes.clone();
+        sys.sys_state.addr_space.set_extram_flush(Box::new(move |_| {
+            counter.set(counter.get() + 1);
+        }));
+        sys.set_autosave_interval(5.5 / super::FRAMES_PER_SECOND);
+
+        for _ in 0..4 {
+            sys.main_loop(true);
+        }
+        assert!(sys.sys_state.addr_space.extram_unsaved);
+        assert_eq!(flushes.get(), 0);
+
+        sys.main_loop(true);
+        assert_eq!(flushes.get(), 1);
+
+        for _ in 0..5 {
+            sys.main_loop(true);
+        }
+        assert_eq!(flushes.get(), 2);
+    }
+
+    #[test]
+    fn bundle_round_trip() {
+        /* Stores P1 (with the buttons selected) once per frame:
+         * di; ld a, 1; ldh (IE), a; ld hl, 0xc000;
+         * loop: xor a; ldh (IF), a; halt; ld a, 0x10; ldh (P1), a;
+         * ldh a, (P1); ld (hl+), a; jr loop */
+        const CODE: [u8; 21] = [0xf3, 0x3e, 0x01, 0xe0, 0xff,
+                                0x21, 0x00, 0xc0,
+                                0xaf, 0xe0, 0x0f, 0x76, 0x3e, 0x10,
+                                0xe0, 0x00, 0xf0, 0x00, 0x22, 0x18, 0xf3];
+        const RELEASE_A: UIEvent =
+            UIEvent::Key { key: UIScancode::X, down: false };
+
+        let path = std::env::temp_dir()
+                       .join(format!("xgbcrew-bundle-{}",
+                                     std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+
+        let (recorded_wram, recorded_hl) = {
+            let (mut sys, log) = mock_test_system(test_rom(&CODE));
+            for _ in 0..2 {
+                sys.main_loop(true);
+            }
+
+            /* Shift+F12 */
+            log.borrow_mut().events.extend([
+                UIEvent::Key { key: UIScancode::Shift, down: true },
+                UIEvent::Key { key: UIScancode::F12, down: true },
+                UIEvent::Key { key: UIScancode::F12, down: false },
+                UIEvent::Key { key: UIScancode::Shift, down: false },
+            ]);
+            sys.main_loop(true);
+            assert!(sys.bundle_rec.is_some());
+
+            for i in 0..20 {
+                match i {
+                    3 | 12 => log.borrow_mut().events.push_back(PRESS_A),
+                    8 | 15 => log.borrow_mut().events.push_back(RELEASE_A),
+                    _ => (),
+                }
+                sys.main_loop(true);
+            }
+
+            /* base_path is empty here, so do not save through the UI
+             * action */
+            sys.bundle_rec.take().unwrap().save(&path).unwrap();
+
+            let wram: Vec<u8> =
+                (0xc000..0xc020).map(|a| sys.peek(a)).collect();
+            (wram, sys.get_reg16(Reg16::HL))
+        };
+
+        /* Make sure the input actually shows up */
+        assert!(recorded_wram.iter().any(|&p1| p1 & 0x01 == 0));
+        assert!(recorded_wram.iter().any(|&p1| p1 & 0x01 != 0));
+
+        /* No events, all input must come from the bundle */
+        let (mut sys, _log) = mock_test_system(test_rom(&CODE));
+        sys.replay_bundle(&path);
+        for _ in 0..20 {
+            sys.main_loop(true);
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        let wram: Vec<u8> =
+            (0xc000..0xc020).map(|a| sys.peek(a)).collect();
+        assert_eq!(wram, recorded_wram);
+        assert_eq!(sys.get_reg16(Reg16::HL), recorded_hl);
+    }
+
+    /* Returns whether input was polled right after the audio sync */
+    fn polls_after_audio_sync(low_latency: bool) -> bool {
+        /* di; jr -2 */
+        let (mut sys, log) = mock_test_system(test_rom(&[0xf3, 0x18, 0xfe]));
+        sys.set_low_latency(low_latency);
+
+        /* Not yet at any VBlank */
+        for _ in 0..4 {
+            sys.exec();
+        }
+        log.borrow_mut().events.push_back(PRESS_A);
+
+        /* As though we had just waited for the audio driver */
+        sys.sys_state.audio_synced = true;
+        sys.exec();
+        assert!(!sys.sys_state.audio_synced);
+        assert!(!sys.sys_state.vblanked);
+
+        let polled = log.borrow().polls > 0;
+        assert_eq!(sys.sys_state.keypad.lines() != 0, polled);
+        polled
+    }
+
+    #[test]
+    fn low_latency_polls_after_audio_sync() {
+        assert!(polls_after_audio_sync(true));
+        assert!(!polls_after_audio_sync(false));
+    }
+
+    #[test]
+    fn minimize_pauses() {
+        let (mut sys, log) = mock_test_system(test_rom(&[0x18, 0xfe]));
+        sys.set_minimized_behavior(MinimizedBehavior::Pause);
+
+        sys.perform_ui_action(UIAction::Minimized(true));
+        assert!(sys.paused && log.borrow().paused);
+        sys.perform_ui_action(UIAction::Minimized(false));
+        assert!(!sys.paused && !log.borrow().paused);
+
+        /* Restoring must not resume what the user has paused */
+        sys.perform_ui_action(UIAction::TogglePause);
+        sys.perform_ui_action(UIAction::Minimized(true));
+        sys.perform_ui_action(UIAction::Minimized(false));
+        assert!(sys.paused && log.borrow().paused);
+    }
+
+    #[test]
+    fn minimize_throttles() {
+        let (mut sys, log) = mock_test_system(test_rom(&[0x18, 0xfe]));
+        sys.set_minimized_behavior(MinimizedBehavior::Throttle);
+        sys.set_realtime(true);
+
+        sys.perform_ui_action(UIAction::Minimized(true));
+        assert!(!sys.sys_state.realtime);
+        /* Audio is muted */
+        assert!(log.borrow().paused);
+        assert!(!sys.paused);
+
+        sys.perform_ui_action(UIAction::Minimized(false));
+        assert!(sys.sys_state.realtime);
+        assert!(!log.borrow().paused);
+
+        /* Skipping while minimized takes effect on restore */
+        sys.perform_ui_action(UIAction::Minimized(true));
+        sys.perform_ui_action(UIAction::Skip(true));
+        assert!(!sys.sys_state.realtime);
+        sys.perform_ui_action(UIAction::Minimized(false));
+        assert!(!sys.sys_state.realtime);
+    }
+}
@@ -0,0 +1,230 @@
+/*
+ * Helpers for unit tests that need a whole system.  On Linux, the address
+ * space is mapped at a fixed location, so there can only be one system per
+ * process at a time; TestSystem holds a lock for as long as it lives.
+ */
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::address_space::AddressSpace;
+use crate::rom;
+use crate::system_state::{Model, System, SystemState};
+use crate::ui::{AudioOutputParams, Frontend, ScaleMode, UIEvent, UI};
+
+
+static SYSTEM_LOCK: Mutex<()> = Mutex::new(());
+
+/* Where test_rom() puts the code; the entry point jumps here */
+pub const CODE_START: u16 = 0x0150;
+
+pub struct TestSystem {
+    /* Dropped first, so the lock is held until the system is gone */
+    pub sys: Box<System>,
+    _guard: MutexGuard<'static, ()>,
+}
+
+impl Deref for TestSystem {
+    type Target = System;
+
+    fn deref(&self) -> &System {
+        &self.sys
+    }
+}
+
+impl DerefMut for TestSystem {
+    fn deref_mut(&mut self) -> &mut System {
+        &mut self.sys
+    }
+}
+
+/* 32 kB ROM-only image with a valid header that runs the given code */
+pub fn test_rom(code: &[u8]) -> Vec<u8> {
+    let mut rom = vec![0u8; 0x8000];
+
+    /* nop; jp CODE_START */
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xc3,
+                                        CODE_START as u8,
+                                        (CODE_START >> 8) as u8]);
+    rom[0x134..0x138].copy_from_slice(b"TEST");
+
+    let start = CODE_START as usize;
+    rom[start..(start + code.len())].copy_from_slice(code);
+
+    fix_header_checksum(&mut rom);
+    rom
+}
+
+/* Like test_rom(), but declaring CGB support */
+pub fn cgb_test_rom(code: &[u8]) -> Vec<u8> {
+    let mut rom = test_rom(code);
+    rom[0x143] = 0x80;
+    fix_header_checksum(&mut rom);
+    rom
+}
+
+pub fn fix_header_checksum(rom: &mut [u8]) {
+    rom[0x14d] = rom[0x134..0x14d].iter()
+                     .fold(0u8, |x, b| x.wrapping_sub(*b).wrapping_sub(1));
+}
+
+pub fn test_system(rom: Vec<u8>) -> TestSystem {
+    test_system_as(rom, None)
+}
+
+pub fn test_system_as(rom: Vec<u8>, model: Option<Model>) -> TestSystem {
+    build_test_system(rom, model, None)
+}
+
+/* Like test_system(), but with a MockFrontend; returns its log */
+pub fn mock_test_system(rom: Vec<u8>) -> (TestSystem, Rc<RefCell<MockLog>>)
+{
+    let (frontend, log) = MockFrontend::new();
+    (build_test_system(rom, None, Some(Box::new(frontend))), log)
+}
+
+/* Just the UI, with a MockFrontend; returns its log */
+pub fn mock_ui() -> (UI, Rc<RefCell<MockLog>>) {
+    let (frontend, log) = MockFrontend::new();
+    (UI::new_with_frontend(Box::new(frontend), &String::from("TEST")), log)
+}
+
+fn build_test_system(rom: Vec<u8>, model: Option<Model>,
+                     frontend: Option<Box<dyn Frontend>>)
+    -> TestSystem
+{
+    let guard = SYSTEM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut addr_space = Box::new(AddressSpace::from_bytes(rom, None));
+    let mut sys_params = rom::load_rom(addr_space.as_mut());
+    if let Some(model) = model {
+        sys_params.force_model(model);
+    }
+
+    let name = &sys_params.cartridge_name;
+    let mut ui =
+        match frontend {
+            Some(frontend) => UI::new_with_frontend(frontend, name),
+            None => UI::new_headless(name),
+        };
+    let sys_state = Box::new(SystemState::new(addr_space, sys_params,
+                                              &mut ui));
+    let mut sys = Box::new(System::new(sys_state, ui, String::new()));
+    sys.set_realtime(false);
+
+    TestSystem {
+        sys,
+        _guard: guard,
+    }
+}
+
+
+/* What MockFrontend has been asked to do, and what it should do */
+#[derive(Default)]
+pub struct MockLog {
+    /* Handed out by poll_event() and wait_event(), in order */
+    pub events: VecDeque<UIEvent>,
+    /* Number of poll_event() calls */
+    pub polls: usize,
+    pub osd_messages: Vec<String>,
+    pub paused: bool,
+}
+
+/* Frontend for tests: Never blocks, and logs what the UI does */
+pub struct MockFrontend {
+    log: Rc<RefCell<MockLog>>,
+    /* Like HeadlessUi: Nobody consumes the audio buffer */
+    #[allow(dead_code)]
+    audio_params: Option<AudioOutputParams>,
+}
+
+impl MockFrontend {
+    fn new() -> (Self, Rc<RefCell<MockLog>>) {
+        let log = Rc::new(RefCell::new(MockLog::default()));
+        let frontend = Self {
+            log: log.clone(),
+            audio_params: None,
+        };
+        (frontend, log)
+    }
+}
+
+impl Frontend for MockFrontend {
+    fn poll_event(&mut self) -> Option<UIEvent> {
+        let mut log = self.log.borrow_mut();
+        log.polls += 1;
+        log.events.pop_front()
+    }
+
+    fn wait_event(&mut self, _timeout: std::time::Duration)
+        -> Option<UIEvent>
+    {
+        self.log.borrow_mut().events.pop_front()
+    }
+
+    fn setup_audio(&mut self, params: AudioOutputParams) -> usize {
+        let freq = params.freq;
+        self.audio_params = Some(params);
+        freq
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        self.log.borrow_mut().paused = paused;
+    }
+
+    fn set_rumble(&mut self, _state: bool) {
+    }
+
+    fn present_frame(&mut self, _pixels: &[u32; 160 * 144]) {
+    }
+
+    fn count_frame(&mut self) {
+    }
+
+    fn set_title_stats(&mut self, _enabled: bool) {
+    }
+
+    fn set_pause_overlay(&mut self, _enabled: bool) {
+    }
+
+    fn enable_sgb_border(&mut self) {
+    }
+
+    fn disable_sgb_border(&mut self) {
+    }
+
+    fn set_sgb_border(&mut self, _pixels: &[u32; 256 * 224]) {
+    }
+
+    fn set_fullscreen(&mut self, _state: bool) {
+    }
+
+    fn save_window_state(&mut self) {
+    }
+
+    fn set_stretch(&mut self, _state: bool) {
+    }
+
+    fn set_scale_mode(&mut self, _mode: ScaleMode) {
+    }
+
+    fn osd_timed_message(&mut self, text: String,
+                         _duration: std::time::Duration)
+    {
+        self.log.borrow_mut().osd_messages.push(text);
+    }
+
+    fn osd_timed_image(&mut self, _width: u32, _height: u32, _rgb8: &[u8],
+                       _duration: std::time::Duration)
+    {
+    }
+
+    fn set_vram_view(&mut self, _enabled: bool) {
+    }
+
+    fn present_vram_view(&mut self, _pixels: &[u32]) {
+    }
+}
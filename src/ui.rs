@@ -1,3 +1,6 @@
+pub mod frame_blend;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod headless;
 #[cfg(not(target_arch = "wasm32"))]
 pub mod sc;
 #[cfg(not(target_arch = "wasm32"))]
@@ -7,12 +10,23 @@ pub mod sdl;
 pub mod web;
 
 use std::collections::HashMap;
+use std::sync::atomic::AtomicUsize;
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc::Sender;
 
 use crate::io::keypad::KeypadKey;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::io::lcd::vram_view::{self, VRAM_VIEW_HEIGHT, VRAM_VIEW_WIDTH};
+use crate::rom::TiltDirection;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::screenshot;
 use crate::system_state::SystemState;
 
+use frame_blend::{FrameBlend, FrameBlender};
+#[cfg(not(target_arch = "wasm32"))]
+use headless::HeadlessUi;
 #[cfg(not(target_arch = "wasm32"))]
 use sdl::SdlUi;
 #[cfg(not(target_arch = "wasm32"))]
@@ -22,12 +36,92 @@ use sc::SC;
 use web::WebUi;
 
 
+/* Controller battery charge (in percent) at which to warn the user */
+#[cfg(not(target_arch = "wasm32"))]
+const LOW_BATTERY_LEVEL: u8 = 15;
+
+
+const INPUT_MAP_FILE: &str = "input-map.json";
+
+/* Next to the configuration file */
+#[cfg(not(target_arch = "wasm32"))]
+fn input_map_path() -> std::path::PathBuf {
+    crate::config::config_file_path(INPUT_MAP_FILE)
+}
+
+#[cfg(target_arch = "wasm32")]
+fn input_map_path() -> std::path::PathBuf {
+    std::path::PathBuf::from(INPUT_MAP_FILE)
+}
+
+/* The buttons key configuration asks for, in order */
+const KEY_CONFIG_ORDER: [(KeypadKey, &str); 8] = [
+    (KeypadKey::Up, "Up"),
+    (KeypadKey::Down, "Down"),
+    (KeypadKey::Left, "Left"),
+    (KeypadKey::Right, "Right"),
+    (KeypadKey::A, "A"),
+    (KeypadKey::B, "B"),
+    (KeypadKey::Start, "Start"),
+    (KeypadKey::Select, "Select"),
+];
+
+
+/* Set on SIGINT and SIGTERM, which are then handled like closing the
+ * window, so everything pending (e.g. the cartridge RAM or a movie being
+ * recorded) is written before quitting */
+#[cfg(not(target_arch = "wasm32"))]
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(not(target_arch = "wasm32"))]
+extern "C" fn sigint_handler(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+/* Makes @signal set INTERRUPTED */
+#[cfg(not(target_arch = "wasm32"))]
+fn catch_signal(signal: libc::c_int, restart_reads: bool) {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction =
+            sigint_handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = if restart_reads { libc::SA_RESTART } else { 0 };
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(signal, &action, std::ptr::null_mut());
+    }
+}
+
+/* Usually, SIGINT does not disturb anything but sets INTERRUPTED.  When
+ * blocking on stdin (e.g. in the debugger), nobody would see that, so
+ * there, SIGINT can instead be made to abort the read (with EINTR). */
+#[cfg(not(target_arch = "wasm32"))]
+pub fn sigint_aborts_reads(abort: bool) {
+    catch_signal(libc::SIGINT, !abort);
+}
+
+/* Whether there has been a SIGINT or SIGTERM since the last call */
+#[cfg(not(target_arch = "wasm32"))]
+pub fn take_interrupt() -> bool {
+    INTERRUPTED.swap(false, Ordering::Relaxed)
+}
+
+
 #[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum UIScancode {
+    I,
+    J,
+    K,
+    L,
     P,
+    R,
     X,
     Z,
 
+    Num1,
+    Num2,
+    Num3,
+    Num4,
+
     Shift,
     Alt,
     Control,
@@ -35,6 +129,7 @@ pub enum UIScancode {
     Space,
     Return,
     Backspace,
+    Escape,
 
     Left,
     Right,
@@ -75,6 +170,18 @@ pub enum UIScancode {
     CAction,
 }
 
+impl UIScancode {
+    fn is_controller(&self) -> bool {
+        use UIScancode::*;
+
+        matches!(self,
+                 CA | CB | CX | CY | CLeft | CRight | CUp | CDown |
+                 CLBump | CRBump | CLTrigger | CRTrigger |
+                 CLSoftTrigger | CRSoftTrigger | CLGrip | CRGrip |
+                 CPrevious | CNext | CAction)
+    }
+}
+
 #[derive(Serialize, Deserialize, Default, PartialEq, Eq, Hash, Clone, Copy)]
 enum UIInputEdge {
     #[default]
@@ -99,15 +206,61 @@ struct UIInput {
 #[derive(Serialize, Deserialize, Clone)]
 pub enum UIAction {
     Key(KeypadKey, bool),
+    /* MBC7 accelerometer; true while tilting */
+    Tilt(TiltDirection, bool),
+    /* MBC7 accelerometer from an analog stick: x and y from -1.0
+     * (left/up) to 1.0 (right/down) */
+    TiltAnalog(f32, f32),
 
     Skip(bool),
+    /* Emulation speed multiplier (1.0 is realtime); unlike Skip, this
+     * keeps audio running */
+    SetSpeed(f32),
     ToggleAudioPostprocessing,
-
+    ToggleAudioRecording,
+    /* Mutes/unmutes CH1..CH4 (given as 0..3) */
+    ToggleChannel(usize),
+    ToggleColorMode,
+    CycleColorCorrection,
+    CycleDmgPalette,
+    ToggleSpriteLimit,
+    /* Power cycle, keeping the cartridge RAM */
+    Reset,
+
+    /* true while the rewind button is held */
+    Rewind(bool),
     LoadState(usize),
     SaveState(usize),
+    ToggleBundleRecording,
+    /* true: Include the SGB border, if any */
+    Screenshot(bool),
 
     ToggleFullscreen,
+    ToggleStretch,
+    CycleScaleMode,
+    ToggleSgbBorder,
+    /* Cycles through off, two frames, three frames */
+    ToggleFrameBlend,
     TogglePause,
+    /* Debug window showing tiles, tile maps, and OAM */
+    ToggleVramView,
+    /* Writes 0x0000..=0xffff (as currently mapped) to a file */
+    DumpMemory,
+    /* Writes registers and memory checksums as JSON (see state_dump) */
+    ExportStateJson,
+    /* Shows audio buffer latency and underrun count on the OSD */
+    ShowAudioLatency,
+    /* Asks for a new key for every button, then saves the mapping for
+     * the current cartridge */
+    OpenKeyConfig,
+    /* Re-reads input-map.json */
+    ReloadInputMapping,
+    /* Only in input-map.json, to remove a default binding */
+    Unbound,
+
+    /* Not bindable, generated from window events */
+    #[serde(skip)]
+    Minimized(bool),
 
     Quit,
 }
@@ -120,9 +273,30 @@ struct UIMap {
     action: UIAction,
 }
 
+/* input-map.json: Mappings per cartridge name (or "default") */
+type InputMaps = HashMap::<String, Vec::<UIMap>>;
+
 pub enum UIEvent {
     Quit,
     Key { key: UIScancode, down: bool },
+    Minimized(bool),
+    /* The user has closed the VRAM view window */
+    VramViewClosed,
+    /* Analog tilt input (see UIAction::TiltAnalog) */
+    Tilt { x: f32, y: f32 },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ScaleMode {
+    /* Scale to fill the window (keeping the aspect ratio unless
+     * stretching) */
+    Fit,
+    /* Scale by the largest integer factor that fits the window, per axis
+     * when stretching */
+    IntegerFit,
+    /* Like IntegerFit, but always with the same factor for both axes */
+    IntegerPixelPerfect,
 }
 
 pub struct AudioOutputParams {
@@ -132,8 +306,56 @@ pub struct AudioOutputParams {
     pub buf: Arc<Mutex<Vec<f32>>>,
     pub buf_step: usize,
     pub buf_done: Sender<usize>,
+    /* Total samples put into @buf so far, to detect underruns */
+    pub written: Arc<AtomicUsize>,
+    /* To be incremented by the driver on every underrun */
+    pub underruns: Arc<AtomicUsize>,
 }
 
+/* What UI needs from a window system (or a lack thereof) */
+#[cfg(not(target_arch = "wasm32"))]
+pub trait Frontend {
+    fn poll_event(&mut self) -> Option<UIEvent>;
+    fn wait_event(&mut self, timeout: std::time::Duration)
+        -> Option<UIEvent>;
+
+    /* Returns the sample rate actually used */
+    fn setup_audio(&mut self, params: AudioOutputParams) -> usize;
+    fn set_paused(&mut self, paused: bool);
+    fn set_rumble(&mut self, state: bool);
+
+    fn present_frame(&mut self, pixels: &[u32; 160 * 144]);
+    /* To be called once per emulated frame */
+    fn count_frame(&mut self);
+    fn set_title_stats(&mut self, enabled: bool);
+    /* Whether to dim the LCD and show "PAUSED" while paused */
+    fn set_pause_overlay(&mut self, enabled: bool);
+
+    fn enable_sgb_border(&mut self);
+    fn disable_sgb_border(&mut self);
+    fn set_sgb_border(&mut self, pixels: &[u32; 256 * 224]);
+    fn set_fullscreen(&mut self, state: bool);
+    /* Stores window geometry and fullscreen state for the next run */
+    fn save_window_state(&mut self);
+    fn set_stretch(&mut self, state: bool);
+    fn set_scale_mode(&mut self, mode: ScaleMode);
+
+    fn osd_timed_message(&mut self, text: String,
+                         duration: std::time::Duration);
+    fn osd_timed_image(&mut self, width: u32, height: u32, rgb8: &[u8],
+                       duration: std::time::Duration);
+
+    /* Opens/closes the VRAM view window */
+    fn set_vram_view(&mut self, enabled: bool);
+    /* VRAM_VIEW_WIDTH x VRAM_VIEW_HEIGHT pixels */
+    fn present_vram_view(&mut self, pixels: &[u32]);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+type FrontendImpl = Box<dyn Frontend>;
+#[cfg(target_arch = "wasm32")]
+type FrontendImpl = WebUi;
+
 struct KeyboardState {
     shift: bool,
     alt: bool,
@@ -142,19 +364,38 @@ struct KeyboardState {
 
 
 pub struct UI {
-    #[cfg(not(target_arch = "wasm32"))]
-    frontend: SdlUi,
+    frontend: FrontendImpl,
     #[cfg(not(target_arch = "wasm32"))]
     sc: Option<SC>,
 
-    #[cfg(target_arch = "wasm32")]
-    frontend: WebUi,
-
     keyboard_state: KeyboardState,
     fullscreen: bool,
+    stretch: bool,
+    scale_mode: ScaleMode,
+    frame_blend: FrameBlender,
     paused: bool,
+    /* Never show a border, not even for SGB games */
+    sgb_border_hidden: bool,
+    /* Shown instead of the border transferred by the game */
+    #[cfg(not(target_arch = "wasm32"))]
+    custom_sgb_border: Option<Box<[u32; 256 * 224]>>,
+    /* Whether we have already warned about the current low battery */
+    #[cfg(not(target_arch = "wasm32"))]
+    battery_warned: bool,
+    /* Whether to drive the rumble motor of SDL game controllers */
+    #[cfg(not(target_arch = "wasm32"))]
+    rumble: bool,
+    /* Rendered every VBlank while the VRAM view is open */
+    #[cfg(not(target_arch = "wasm32"))]
+    vram_view: Option<Vec<u32>>,
 
     input_map: HashMap<UIInput, UIAction>,
+    /* To select the entry in input-map.json */
+    cart_name: String,
+    /* Index into KEY_CONFIG_ORDER of the button to be bound next */
+    key_config: Option<usize>,
+    /* Mapping from before key configuration, restored on cancel */
+    pre_key_config_map: HashMap<UIInput, UIAction>,
 }
 
 
@@ -182,14 +423,13 @@ macro_rules! binding {
 }
 
 impl UI {
-    pub fn new(cart_name: &String) -> Self {
-        #[cfg(not(target_arch = "wasm32"))]
-        let mut frontend = SdlUi::new();
-
-        #[cfg(target_arch = "wasm32")]
-        let frontend = WebUi::new();
+    /* @scale: Initial window size, in multiples of 160x144 */
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn new(cart_name: &String, scale: u32) -> Self {
+        let mut frontend = SdlUi::new(cart_name, scale);
+        /* May have been restored from the last run */
+        let fullscreen = frontend.is_fullscreen();
 
-        #[cfg(not(target_arch = "wasm32"))]
         let sc = match SC::new() {
             Ok(sc) => sc,
             Err(msg) => {
@@ -199,11 +439,42 @@ impl UI {
             },
         };
 
+        let mut ui = Self::with_frontend(Box::new(frontend), cart_name);
+        ui.sc = sc;
+        ui.fullscreen = fullscreen;
+        ui
+    }
+
+    /* No window, no audio, no input; OSD messages go to stderr */
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn new_headless(cart_name: &String) -> Self {
+        Self::with_frontend(Box::new(HeadlessUi::new()), cart_name)
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn new(cart_name: &String) -> Self {
+        Self::with_frontend(WebUi::new(), cart_name)
+    }
+
+    #[cfg(test)]
+    pub fn new_with_frontend(frontend: Box<dyn Frontend>, cart_name: &String)
+        -> Self
+    {
+        Self::with_frontend(frontend, cart_name)
+    }
+
+    fn with_frontend(frontend: FrontendImpl, cart_name: &String) -> Self {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            catch_signal(libc::SIGINT, true);
+            catch_signal(libc::SIGTERM, true);
+        }
+
         Self {
             frontend,
 
             #[cfg(not(target_arch = "wasm32"))]
-            sc,
+            sc: None,
 
             keyboard_state: KeyboardState {
                 shift: false,
@@ -212,28 +483,48 @@ impl UI {
             },
 
             fullscreen: false,
+            stretch: false,
+            scale_mode: ScaleMode::Fit,
+            frame_blend: FrameBlender::new(),
             paused: false,
+            sgb_border_hidden: false,
+            #[cfg(not(target_arch = "wasm32"))]
+            custom_sgb_border: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            battery_warned: false,
+            #[cfg(not(target_arch = "wasm32"))]
+            rumble: false,
+            #[cfg(not(target_arch = "wasm32"))]
+            vram_view: None,
 
             input_map: Self::load_input_mapping(cart_name),
+            cart_name: cart_name.clone(),
+            key_config: None,
+            pre_key_config_map: HashMap::new(),
         }
     }
 
-    fn load_input_mapping(cart_name: &String) -> HashMap::<UIInput, UIAction> {
-        let mut opts = std::fs::OpenOptions::new();
-        opts.read(true);
-
+    fn read_input_maps() -> Result<InputMaps, String> {
         let map_file =
-            match opts.open("input-map.json") {
-                Ok(f) => f,
-                Err(e) => {
-                    eprintln!("Failed to load input-map.json: {}", e);
-                    return Self::default_input_mapping();
-                }
-            };
+            std::fs::File::open(input_map_path()).map_err(|e| e.to_string())?;
 
-        type InputMaps = HashMap::<String, Vec::<UIMap>>;
-        let mut cfg: InputMaps = serde_json::from_reader(map_file).unwrap();
+        serde_json::from_reader(map_file).map_err(|e| e.to_string())
+    }
 
+    fn load_input_mapping(cart_name: &String) -> HashMap::<UIInput, UIAction> {
+        match Self::read_input_maps() {
+            Ok(cfg) => Self::select_input_mapping(cfg, cart_name),
+            Err(e) => {
+                eprintln!("Failed to load {}: {}",
+                          input_map_path().display(), e);
+                Self::default_input_mapping()
+            },
+        }
+    }
+
+    fn select_input_mapping(mut cfg: InputMaps, cart_name: &String)
+        -> HashMap::<UIInput, UIAction>
+    {
         if let Some(map) = cfg.remove(cart_name) {
             Self::translate_input_mapping(map)
         } else if let Some(map) = cfg.remove("default") {
@@ -243,13 +534,168 @@ impl UI {
         }
     }
 
+    /* Keeps the current mapping if the file cannot be read */
+    pub fn reload_input_mapping(&mut self) {
+        match Self::read_input_maps() {
+            Ok(cfg) => {
+                self.input_map =
+                    Self::select_input_mapping(cfg, &self.cart_name);
+                self.osd_message(String::from("Input mapping reloaded"));
+            },
+
+            Err(e) => {
+                self.osd_message(format!("Failed to load {}: {}",
+                                         input_map_path().display(), e));
+            },
+        }
+    }
+
+    /* The button bindings in @map that differ from the defaults, plus
+     * Unbound for the default button bindings that it lacks */
+    fn key_binding_changes(map: &HashMap<UIInput, UIAction>) -> Vec<UIMap> {
+        let defaults = Self::default_input_mapping();
+        let same = |a: &UIAction, b: Option<&UIAction>| {
+            match (a, b) {
+                (UIAction::Key(ka, da), Some(UIAction::Key(kb, db))) =>
+                    ka == kb && da == db,
+                _ => false,
+            }
+        };
+
+        let mut changes: Vec<UIMap> =
+            map.iter()
+               .filter(|(input, action)| {
+                   matches!(action, UIAction::Key(_, _)) &&
+                   !same(action, defaults.get(*input))
+               })
+               .map(|(input, action)| UIMap {
+                   input: *input,
+                   action: action.clone(),
+               })
+               .collect();
+
+        changes.extend(
+            defaults.iter()
+                    .filter(|(input, action)| {
+                        matches!(action, UIAction::Key(_, _)) &&
+                        !map.contains_key(*input)
+                    })
+                    .map(|(input, _)| UIMap {
+                        input: *input,
+                        action: UIAction::Unbound,
+                    }));
+
+        changes
+    }
+
+    /* Stores the button bindings that differ from the defaults under the
+     * cartridge name, keeping the other entries and any other bindings
+     * (hotkeys) in this one */
+    fn save_input_mapping(&self) -> Result<(), String> {
+        let mut cfg =
+            match Self::read_input_maps() {
+                Ok(cfg) => cfg,
+                Err(_) if !input_map_path().exists() => InputMaps::new(),
+                /* Do not overwrite what we could not parse */
+                Err(e) => return Err(e),
+            };
+
+        let mut map: Vec<UIMap> =
+            cfg.remove(&self.cart_name).unwrap_or_default().into_iter()
+               .filter(|m| !matches!(m.action,
+                                     UIAction::Key(_, _) | UIAction::Unbound))
+               .collect();
+        map.extend(Self::key_binding_changes(&self.input_map));
+        cfg.insert(self.cart_name.clone(), map);
+
+        let path = input_map_path();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        let map_file =
+            std::fs::File::create(path).map_err(|e| e.to_string())?;
+        serde_json::to_writer_pretty(map_file, &cfg).map_err(|e| e.to_string())
+    }
+
+    pub fn start_key_config(&mut self) {
+        self.key_config = Some(0);
+        self.pre_key_config_map = self.input_map.clone();
+        self.key_config_prompt();
+    }
+
+    fn key_config_prompt(&mut self) {
+        let i = self.key_config.unwrap();
+        let msg = format!("Key configuration ({}/{})\nPress a key for {}\n\
+                           (Esc to cancel)",
+                          i + 1, KEY_CONFIG_ORDER.len(),
+                          KEY_CONFIG_ORDER[i].1);
+        self.osd_timed_message(msg, std::time::Duration::from_secs(60));
+    }
+
+    fn cancel_key_config(&mut self) {
+        self.key_config = None;
+        self.input_map = std::mem::take(&mut self.pre_key_config_map);
+        self.osd_message(String::from("Key configuration canceled"));
+    }
+
+    /* Replaces the button's bindings on the same kind of device (keyboard
+     * or controller) by @scancode, without modifiers */
+    fn key_config_bind(&mut self, scancode: UIScancode) {
+        let i = self.key_config.unwrap();
+        let key = KEY_CONFIG_ORDER[i].0.clone();
+        let controller = scancode.is_controller();
+
+        self.input_map.retain(|inp, act| {
+            let bare = !inp.shift && !inp.alt && !inp.control;
+            let same_key =
+                matches!(act, UIAction::Key(k, _) if *k == key) &&
+                inp.scancode.is_controller() == controller;
+
+            !same_key && !(bare && inp.scancode == scancode)
+        });
+
+        let input = |edge| UIInput {
+            scancode,
+            shift: false,
+            alt: false,
+            control: false,
+            edge,
+        };
+        self.input_map.insert(input(UIInputEdge::Down),
+                              UIAction::Key(key.clone(), true));
+        self.input_map.insert(input(UIInputEdge::Up),
+                              UIAction::Key(key, false));
+
+        if i + 1 < KEY_CONFIG_ORDER.len() {
+            self.key_config = Some(i + 1);
+            self.key_config_prompt();
+            return;
+        }
+
+        self.key_config = None;
+        self.pre_key_config_map.clear();
+        match self.save_input_mapping() {
+            Ok(()) =>
+                self.osd_message(format!("Key mapping saved to {}",
+                                         input_map_path().display())),
+            Err(e) =>
+                self.osd_message(format!("Failed to save key mapping: {}",
+                                         e)),
+        }
+    }
+
+    /* Entries modify the default mapping */
     fn translate_input_mapping(json_map: Vec::<UIMap>)
         -> HashMap::<UIInput, UIAction>
     {
-        let mut im = HashMap::new();
+        let mut im = Self::default_input_mapping();
 
         for uim in json_map {
-            im.insert(uim.input, uim.action);
+            if let UIAction::Unbound = uim.action {
+                im.remove(&uim.input);
+            } else {
+                im.insert(uim.input, uim.action);
+            }
         }
 
         im
@@ -275,17 +721,106 @@ impl UI {
         binding!(im, Up, false, false, false, Up);
         binding!(im, Down, false, false, false, Down);
 
+        binding!(im, J, false, false, false, Down,
+                 UIAction::Tilt(TiltDirection::Left, true));
+        binding!(im, J, false, false, false, Up,
+                 UIAction::Tilt(TiltDirection::Left, false));
+        binding!(im, L, false, false, false, Down,
+                 UIAction::Tilt(TiltDirection::Right, true));
+        binding!(im, L, false, false, false, Up,
+                 UIAction::Tilt(TiltDirection::Right, false));
+        binding!(im, I, false, false, false, Down,
+                 UIAction::Tilt(TiltDirection::Up, true));
+        binding!(im, I, false, false, false, Up,
+                 UIAction::Tilt(TiltDirection::Up, false));
+        binding!(im, K, false, false, false, Down,
+                 UIAction::Tilt(TiltDirection::Down, true));
+        binding!(im, K, false, false, false, Up,
+                 UIAction::Tilt(TiltDirection::Down, false));
+
         binding!(im, Space, false, false, false, Down, UIAction::Skip(true));
         binding!(im, Space, false, false, false, Up, UIAction::Skip(false));
 
         binding!(im, P, false, false, false, Down, UIAction::TogglePause);
 
+        binding!(im, R, false, false, false, Down, UIAction::Rewind(true));
+        binding!(im, R, false, false, false, Up, UIAction::Rewind(false));
+
+        binding!(im, R, false, false, true, Down, UIAction::Reset);
+
+        binding!(im, Num1, false, false, true, Down, UIAction::SetSpeed(1.0));
+        binding!(im, Num2, false, false, true, Down, UIAction::SetSpeed(2.0));
+        binding!(im, Num3, false, false, true, Down, UIAction::SetSpeed(3.0));
+        binding!(im, Num4, false, false, true, Down, UIAction::SetSpeed(4.0));
+
         binding!(im, F9, false, false, false, Down,
                  UIAction::ToggleAudioPostprocessing);
 
+        binding!(im, F9, true, false, false, Down,
+                 UIAction::ToggleAudioRecording);
+
+        binding!(im, F9, false, false, true, Down,
+                 UIAction::ToggleVramView);
+
+        binding!(im, F9, true, false, true, Down,
+                 UIAction::DumpMemory);
+
+        binding!(im, F9, true, true, false, Down,
+                 UIAction::ExportStateJson);
+
+        binding!(im, F9, false, true, false, Down,
+                 UIAction::ShowAudioLatency);
+
+        binding!(im, Num1, false, false, false, Down,
+                 UIAction::ToggleChannel(0));
+        binding!(im, Num2, false, false, false, Down,
+                 UIAction::ToggleChannel(1));
+        binding!(im, Num3, false, false, false, Down,
+                 UIAction::ToggleChannel(2));
+        binding!(im, Num4, false, false, false, Down,
+                 UIAction::ToggleChannel(3));
+
+        binding!(im, F10, false, false, false, Down,
+                 UIAction::ToggleColorMode);
+
+        binding!(im, F10, true, false, false, Down,
+                 UIAction::CycleColorCorrection);
+
+        binding!(im, F10, false, false, true, Down,
+                 UIAction::CycleDmgPalette);
+
+        binding!(im, F10, true, false, true, Down,
+                 UIAction::ToggleSpriteLimit);
+
+        binding!(im, F10, false, true, false, Down,
+                 UIAction::OpenKeyConfig);
+
+        binding!(im, F10, true, true, false, Down,
+                 UIAction::ReloadInputMapping);
+
         binding!(im, F11, false, false, false, Down,
                  UIAction::ToggleFullscreen);
 
+        binding!(im, F11, true, false, false, Down,
+                 UIAction::CycleScaleMode);
+
+        binding!(im, F11, false, false, true, Down,
+                 UIAction::ToggleSgbBorder);
+
+        binding!(im, F11, true, false, true, Down,
+                 UIAction::ToggleFrameBlend);
+
+        binding!(im, F12, false, false, false, Down,
+                 UIAction::ToggleStretch);
+
+        binding!(im, F12, true, false, false, Down,
+                 UIAction::ToggleBundleRecording);
+
+        binding!(im, F12, false, false, true, Down,
+                 UIAction::Screenshot(false));
+        binding!(im, F12, true, false, true, Down,
+                 UIAction::Screenshot(true));
+
         binding!(im, F1, false, false, false, Down, UIAction::LoadState(0));
         binding!(im, F2, false, false, false, Down, UIAction::LoadState(1));
         binding!(im, F3, false, false, false, Down, UIAction::LoadState(2));
@@ -322,6 +857,9 @@ impl UI {
         binding!(im, CPrevious, false, false, false, Down,
                  UIAction::TogglePause);
 
+        binding!(im, CLBump, false, false, false, Down, UIAction::Rewind(true));
+        binding!(im, CLBump, false, false, false, Up, UIAction::Rewind(false));
+
         binding!(im, CAction, false, false, false, Down,
                  UIAction::ToggleFullscreen);
 
@@ -338,6 +876,19 @@ impl UI {
         match event {
             UIEvent::Quit => Some(UIAction::Quit),
 
+            UIEvent::Minimized(minimized) =>
+                Some(UIAction::Minimized(minimized)),
+
+            UIEvent::VramViewClosed => {
+                #[cfg(not(target_arch = "wasm32"))]
+                {
+                    self.vram_view = None;
+                }
+                None
+            },
+
+            UIEvent::Tilt { x, y } => Some(UIAction::TiltAnalog(x, y)),
+
             UIEvent::Key { key, down } => {
                 match key {
                     UIScancode::Shift => {
@@ -355,6 +906,20 @@ impl UI {
                         None
                     },
 
+                    UIScancode::Escape if self.key_config.is_some() => {
+                        if down {
+                            self.cancel_key_config();
+                        }
+                        None
+                    },
+
+                    _ if self.key_config.is_some() => {
+                        if down {
+                            self.key_config_bind(key);
+                        }
+                        None
+                    },
+
                     _ => {
                         let edge =
                             if down {
@@ -390,8 +955,24 @@ impl UI {
         None
     }
 
+    #[cfg(not(target_arch = "wasm32"))]
+    fn poll_interrupt(&mut self) -> Option<UIEvent> {
+        if take_interrupt() {
+            Some(UIEvent::Quit)
+        } else {
+            None
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn poll_interrupt(&mut self) -> Option<UIEvent> {
+        None
+    }
+
     pub fn poll_event(&mut self) -> Option<UIEvent> {
-        if let Some(evt) = self.poll_sc_event() {
+        if let Some(evt) = self.poll_interrupt() {
+            Some(evt)
+        } else if let Some(evt) = self.poll_sc_event() {
             Some(evt)
         } else {
             self.frontend.poll_event()
@@ -404,7 +985,11 @@ impl UI {
         loop {
             /* TODO: Maybe this shouldn’t be here, but we need it for
              *       OSD messages when paused */
-            self.refresh_lcd(sys_state);
+            self.represent_lcd(sys_state);
+
+            if let Some(evt) = self.poll_interrupt() {
+                return evt;
+            }
 
             #[cfg(not(target_arch = "wasm32"))]
             if let Some(sc) = &mut self.sc {
@@ -423,7 +1008,8 @@ impl UI {
         }
     }
 
-    pub fn setup_audio(&mut self, params: AudioOutputParams) {
+    /* Returns the sample rate actually used */
+    pub fn setup_audio(&mut self, params: AudioOutputParams) -> usize {
         self.frontend.setup_audio(params)
     }
 
@@ -447,15 +1033,87 @@ impl UI {
         self.frontend.get_sound_ringbuf_ptrs()
     }
 
+    #[cfg(target_arch = "wasm32")]
+    pub fn get_sound_sample_rate(&self) -> usize {
+        self.frontend.get_sound_sample_rate()
+    }
+
+    /* To be called with every new frame */
     pub fn refresh_lcd(&mut self, sys_state: &SystemState) {
-        self.frontend.present_frame(&sys_state.display.lcd_pixels);
+        let frame = &sys_state.display.lcd_pixels;
+
+        /* Blending would smear while fast-forwarding */
+        if self.frame_blend.mode() == FrameBlend::Off || !sys_state.realtime {
+            self.frame_blend.reset();
+            self.frontend.present_frame(frame);
+        } else {
+            let blended = self.frame_blend.blend(frame);
+            self.frontend.present_frame(blended);
+        }
+    }
+
+    /* Shows the last frame again, without advancing the blend history */
+    fn represent_lcd(&mut self, sys_state: &SystemState) {
+        match self.frame_blend.last() {
+            Some(blended) => self.frontend.present_frame(blended),
+            None => self.frontend.present_frame(&sys_state.display.lcd_pixels),
+        }
+    }
+
+    pub fn cycle_frame_blend(&mut self) -> FrameBlend {
+        self.frame_blend.set_mode(
+            match self.frame_blend.mode() {
+                FrameBlend::Off => FrameBlend::TwoFrames,
+                FrameBlend::TwoFrames => FrameBlend::ThreeFrames,
+                FrameBlend::ThreeFrames => FrameBlend::Off,
+            });
+
+        self.frame_blend.mode()
     }
 
     #[cfg(not(target_arch = "wasm32"))]
     pub fn vblank_events(&mut self, sys_state: &SystemState) {
+        self.frontend.count_frame();
+
+        let rumble = sys_state.addr_space.cartridge.rumble_state &&
+                     !self.paused;
+
         if let Some(sc) = &mut self.sc {
-            sc.rumble(sys_state.addr_space.cartridge.rumble_state &&
-                      !self.paused);
+            sc.rumble(rumble);
+        }
+        if self.rumble {
+            self.frontend.set_rumble(rumble);
+        }
+
+        self.check_battery();
+
+        if let Some(pixels) = self.vram_view.as_mut() {
+            vram_view::render(sys_state, pixels);
+            self.frontend.present_vram_view(pixels);
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn check_battery(&mut self) {
+        let level = self.sc.as_ref().and_then(|sc| sc.battery_level());
+        self.battery_level_update(level);
+    }
+
+    /* Warns once whenever @level drops to LOW_BATTERY_LEVEL */
+    #[cfg(not(target_arch = "wasm32"))]
+    fn battery_level_update(&mut self, level: Option<u8>) {
+        match level {
+            Some(level) if level <= LOW_BATTERY_LEVEL => {
+                if !self.battery_warned {
+                    self.battery_warned = true;
+                    let msg = format!("Controller battery low ({} %)", level);
+                    let d = std::time::Duration::from_secs(5);
+                    self.frontend.osd_timed_message(msg, d);
+                }
+            },
+
+            /* Warn again when it drops low after having been charged */
+            _ => self.battery_warned = false,
         }
     }
 
@@ -463,9 +1121,90 @@ impl UI {
     pub fn vblank_events(&mut self, _sys_state: &SystemState) {
     }
 
+    /* The border currently shown, if any */
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn sgb_border<'a>(&'a self, sys_state: &'a SystemState)
+        -> Option<&'a [u32; 256 * 224]>
+    {
+        if self.sgb_border_hidden {
+            None
+        } else if let Some(border) = &self.custom_sgb_border {
+            Some(border)
+        } else if sys_state.sgb_state.border_enabled() {
+            Some(&sys_state.sgb_state.border_pixels)
+        } else {
+            None
+        }
+    }
+
     pub fn load_sgb_border(&mut self, sys_state: &SystemState) {
-        self.frontend.enable_sgb_border();
-        self.frontend.set_sgb_border(&sys_state.sgb_state.border_pixels);
+        if self.sgb_border_hidden {
+            return;
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(border) = &self.custom_sgb_border {
+            self.frontend.enable_sgb_border();
+            self.frontend.set_sgb_border(border);
+            return;
+        }
+
+        if sys_state.sgb_state.border_enabled() {
+            self.frontend.enable_sgb_border();
+            self.frontend.set_sgb_border(&sys_state.sgb_state.border_pixels);
+        }
+    }
+
+    /* Loads a 256x224 image to be shown instead of the game's border (or
+     * as a border for non-SGB games) */
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load_custom_sgb_border(&mut self, path: &str) {
+        let (width, height, rgb8) =
+            match screenshot::read_png(path) {
+                Ok(img) => img,
+                Err(e) => {
+                    self.osd_message(format!("Failed to load SGB border \
+                                              {}:\n{}", path, e));
+                    return;
+                }
+            };
+
+        if width != 256 || height != 224 {
+            self.osd_message(format!("SGB border {} must be 256x224 \
+                                      pixels (is {}x{})",
+                                     path, width, height));
+            return;
+        }
+
+        let mut border = Box::new([0u32; 256 * 224]);
+        for (px, rgb) in border.iter_mut().zip(rgb8.chunks_exact(3)) {
+            *px = 0xff000000 |
+                  rgb[0] as u32 |
+                  ((rgb[1] as u32) << 8) |
+                  ((rgb[2] as u32) << 16);
+        }
+
+        if !self.sgb_border_hidden {
+            self.frontend.enable_sgb_border();
+            self.frontend.set_sgb_border(&border);
+        }
+        self.custom_sgb_border = Some(border);
+    }
+
+    pub fn set_sgb_border_hidden(&mut self, hidden: bool) {
+        self.sgb_border_hidden = hidden;
+        if hidden {
+            self.frontend.disable_sgb_border();
+        }
+    }
+
+    /* Returns whether the border is hidden now */
+    pub fn toggle_sgb_border(&mut self, sys_state: &SystemState) -> bool {
+        self.set_sgb_border_hidden(!self.sgb_border_hidden);
+        if !self.sgb_border_hidden {
+            self.load_sgb_border(sys_state);
+        }
+        self.sgb_border_hidden
     }
 
     pub fn toggle_fullscreen(&mut self) {
@@ -473,6 +1212,36 @@ impl UI {
         self.frontend.set_fullscreen(self.fullscreen);
     }
 
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn save_window_state(&mut self) {
+        self.frontend.save_window_state();
+    }
+
+    pub fn set_stretch(&mut self, stretch: bool) {
+        self.stretch = stretch;
+        self.frontend.set_stretch(stretch);
+    }
+
+    pub fn toggle_stretch(&mut self) {
+        self.set_stretch(!self.stretch);
+    }
+
+    pub fn set_scale_mode(&mut self, mode: ScaleMode) {
+        self.scale_mode = mode;
+        self.frontend.set_scale_mode(mode);
+    }
+
+    pub fn cycle_scale_mode(&mut self) -> ScaleMode {
+        self.set_scale_mode(
+            match self.scale_mode {
+                ScaleMode::Fit => ScaleMode::IntegerFit,
+                ScaleMode::IntegerFit => ScaleMode::IntegerPixelPerfect,
+                ScaleMode::IntegerPixelPerfect => ScaleMode::Fit,
+            });
+
+        self.scale_mode
+    }
+
     pub fn set_paused(&mut self, paused: bool) {
         self.paused = paused;
         self.frontend.set_paused(paused);
@@ -483,6 +1252,46 @@ impl UI {
                 sc.rumble(false);
             }
         }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        if paused && self.rumble {
+            self.frontend.set_rumble(false);
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn set_rumble_enabled(&mut self, enabled: bool) {
+        self.rumble = enabled;
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn set_vram_view(&mut self, enabled: bool) {
+        self.vram_view =
+            if enabled {
+                Some(vec![0u32; VRAM_VIEW_WIDTH * VRAM_VIEW_HEIGHT])
+            } else {
+                None
+            };
+        self.frontend.set_vram_view(enabled);
+    }
+
+    /* Returns whether the view is open now */
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn toggle_vram_view(&mut self) -> bool {
+        let enabled = self.vram_view.is_none();
+        self.set_vram_view(enabled);
+        enabled
+    }
+
+    /* Whether to show FPS and emulation speed in the window title */
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn set_title_stats(&mut self, enabled: bool) {
+        self.frontend.set_title_stats(enabled);
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn set_pause_overlay(&mut self, enabled: bool) {
+        self.frontend.set_pause_overlay(enabled);
     }
 
     pub fn osd_timed_message(&mut self, text: String,
@@ -494,4 +1303,137 @@ impl UI {
     pub fn osd_message(&mut self, text: String) {
         self.osd_timed_message(text, std::time::Duration::from_secs(3));
     }
+
+    /* Shows an RGB8 image in the top right corner of the screen */
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn osd_image(&mut self, width: u32, height: u32, rgb8: &[u8]) {
+        self.frontend.osd_timed_image(width, height, rgb8,
+                                      std::time::Duration::from_secs(3));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{LOW_BATTERY_LEVEL, UI, UIAction, UIInput, UIInputEdge, UIMap,
+                UIScancode, sc};
+    use crate::io::keypad::KeypadKey;
+    use crate::testing::mock_ui;
+
+    fn action_json(action: &UIAction) -> String {
+        serde_json::to_string(action).unwrap()
+    }
+
+    fn same_mapping(a: &HashMap<UIInput, UIAction>,
+                    b: &HashMap<UIInput, UIAction>) -> bool
+    {
+        a.len() == b.len() &&
+            a.iter().all(|(input, action)| {
+                b.get(input).map(action_json) == Some(action_json(action))
+            })
+    }
+
+    #[test]
+    fn uimap_round_trip() {
+        let input = |scancode, shift, alt, control, edge| UIInput {
+            scancode, shift, alt, control, edge,
+        };
+        let maps = vec![
+            UIMap {
+                input: input(UIScancode::X, true, false, false,
+                             UIInputEdge::Up),
+                action: UIAction::Key(KeypadKey::A, false),
+            },
+            UIMap {
+                input: input(UIScancode::CA, false, true, true,
+                             UIInputEdge::Down),
+                action: UIAction::SetSpeed(2.5),
+            },
+            UIMap {
+                input: input(UIScancode::Escape, false, false, false,
+                             UIInputEdge::Down),
+                action: UIAction::Unbound,
+            },
+        ];
+
+        let json = serde_json::to_string(&maps).unwrap();
+        let parsed: Vec<UIMap> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.len(), maps.len());
+        for (a, b) in maps.iter().zip(parsed.iter()) {
+            assert!(a.input == b.input);
+            assert_eq!(action_json(&a.action), action_json(&b.action));
+        }
+
+        /* Modifiers and edge are optional */
+        let parsed: Vec<UIMap> =
+            serde_json::from_str(r#"[{ "scancode": "Z",
+                                       "action": { "Key": ["B", true] } }]"#)
+                .unwrap();
+        assert!(parsed[0].input == input(UIScancode::Z, false, false, false,
+                                          UIInputEdge::Down));
+        assert!(matches!(parsed[0].action, UIAction::Key(KeypadKey::B, true)));
+    }
+
+    #[test]
+    fn only_changed_buttons_are_saved() {
+        let defaults = UI::default_input_mapping();
+        assert!(UI::key_binding_changes(&defaults).is_empty());
+
+        /* Up on P instead of the arrow key */
+        let mut map = defaults.clone();
+        for edge in [UIInputEdge::Down, UIInputEdge::Up] {
+            let arrow = UIInput {
+                scancode: UIScancode::Up,
+                shift: false, alt: false, control: false,
+                edge,
+            };
+            map.remove(&arrow);
+            map.insert(UIInput { scancode: UIScancode::P, ..arrow },
+                       UIAction::Key(KeypadKey::Up,
+                                     edge == UIInputEdge::Down));
+        }
+
+        let changes = UI::key_binding_changes(&map);
+        assert_eq!(changes.len(), 4);
+        assert!(changes.iter().all(|m| {
+            match m.action {
+                UIAction::Key(_, _) => m.input.scancode == UIScancode::P,
+                UIAction::Unbound => m.input.scancode == UIScancode::Up,
+                _ => false,
+            }
+        }));
+
+        /* Loading them on top of the defaults gives the same mapping */
+        assert!(same_mapping(&UI::translate_input_mapping(changes), &map));
+    }
+
+    #[test]
+    fn low_battery_warning() {
+        let (mut ui, log) = mock_ui();
+
+        let mut report = [0u8; 64];
+        report[2] = 0x04;
+        report[14] = LOW_BATTERY_LEVEL + 1;
+        ui.battery_level_update(sc::battery_report_level(&report));
+        assert!(log.borrow().osd_messages.is_empty());
+
+        report[14] = LOW_BATTERY_LEVEL - 5;
+        ui.battery_level_update(sc::battery_report_level(&report));
+        assert_eq!(log.borrow().osd_messages,
+                   [format!("Controller battery low ({} %)",
+                            LOW_BATTERY_LEVEL - 5)]);
+
+        /* Once is enough, until it has been charged */
+        ui.battery_level_update(sc::battery_report_level(&report));
+        assert_eq!(log.borrow().osd_messages.len(), 1);
+        ui.battery_level_update(Some(80));
+        ui.battery_level_update(Some(LOW_BATTERY_LEVEL));
+        assert_eq!(log.borrow().osd_messages.len(), 2);
+
+        /* Not a status report */
+        report[2] = 0x01;
+        assert_eq!(sc::battery_report_level(&report), None);
+    }
 }
@@ -0,0 +1,93 @@
+/* Emulates the slow DMG/CGB LCD, which games use for transparency
+ * effects by flickering objects on and off */
+#[derive(Clone, Copy, PartialEq)]
+pub enum FrameBlend {
+    Off,
+    /* Current and previous frame, 1:1 */
+    TwoFrames,
+    /* Current and two previous frames, 2:1:1 */
+    ThreeFrames,
+}
+
+pub struct FrameBlender {
+    mode: FrameBlend,
+
+    /* Previous frames, most recent first; only the first history_len
+     * entries are valid */
+    history: [Box<[u32; 160 * 144]>; 2],
+    history_len: usize,
+
+    /* Last blended frame */
+    output: Box<[u32; 160 * 144]>,
+}
+
+
+/* Channel-wise average of two ABGR8888 pixels */
+fn average(a: u32, b: u32) -> u32 {
+    ((a & 0xfefefefe) >> 1) + ((b & 0xfefefefe) >> 1) + (a & b & 0x01010101)
+}
+
+impl FrameBlender {
+    pub fn new() -> Self {
+        Self {
+            mode: FrameBlend::Off,
+
+            history: [Box::new([0u32; 160 * 144]),
+                      Box::new([0u32; 160 * 144])],
+            history_len: 0,
+
+            output: Box::new([0u32; 160 * 144]),
+        }
+    }
+
+    pub fn mode(&self) -> FrameBlend {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: FrameBlend) {
+        self.mode = mode;
+        self.reset();
+    }
+
+    /* Forget previous frames (e.g. while fast-forwarding, so they do not
+     * smear) */
+    pub fn reset(&mut self) {
+        self.history_len = 0;
+    }
+
+    /* The frame returned by the last blend(), unless reset since */
+    pub fn last(&self) -> Option<&[u32; 160 * 144]> {
+        if self.history_len > 0 {
+            Some(&self.output)
+        } else {
+            None
+        }
+    }
+
+    /* Returns what to show instead of @frame */
+    pub fn blend(&mut self, frame: &[u32; 160 * 144]) -> &[u32; 160 * 144] {
+        let frames =
+            match self.mode {
+                FrameBlend::Off => 0,
+                FrameBlend::TwoFrames => 1,
+                FrameBlend::ThreeFrames => 2,
+            };
+        let prev = std::cmp::min(self.history_len, frames);
+
+        for i in 0..(160 * 144) {
+            self.output[i] =
+                match prev {
+                    0 => frame[i],
+                    1 => average(frame[i], self.history[0][i]),
+                    _ => average(frame[i], average(self.history[0][i],
+                                                   self.history[1][i])),
+                };
+        }
+
+        self.history.rotate_right(1);
+        self.history[0].copy_from_slice(frame);
+        self.history_len = std::cmp::min(self.history_len + 1, 2);
+
+        &self.output
+    }
+}
@@ -0,0 +1,97 @@
+use crate::ui::{AudioOutputParams, Frontend, ScaleMode, UIEvent};
+
+
+/*
+ * For running without any window or audio device (e.g. in CI).  There
+ * is no input, so emulation should not be run in realtime mode: Nothing
+ * ever consumes the audio buffer.
+ */
+pub struct HeadlessUi {
+    /* Keep the audio channel alive, so the sound code just sees nobody
+     * consuming its output */
+    #[allow(dead_code)]
+    audio_params: Option<AudioOutputParams>,
+}
+
+impl HeadlessUi {
+    pub fn new() -> Self {
+        Self {
+            audio_params: None,
+        }
+    }
+}
+
+impl Frontend for HeadlessUi {
+    fn poll_event(&mut self) -> Option<UIEvent> {
+        None
+    }
+
+    fn wait_event(&mut self, timeout: std::time::Duration)
+        -> Option<UIEvent>
+    {
+        std::thread::sleep(timeout);
+        None
+    }
+
+    fn setup_audio(&mut self, params: AudioOutputParams) -> usize {
+        let freq = params.freq;
+        self.audio_params = Some(params);
+        freq
+    }
+
+    fn set_paused(&mut self, _paused: bool) {
+    }
+
+    fn set_rumble(&mut self, _state: bool) {
+    }
+
+    fn present_frame(&mut self, _pixels: &[u32; 160 * 144]) {
+    }
+
+    fn count_frame(&mut self) {
+    }
+
+    fn set_title_stats(&mut self, _enabled: bool) {
+    }
+
+    fn set_pause_overlay(&mut self, _enabled: bool) {
+    }
+
+    fn enable_sgb_border(&mut self) {
+    }
+
+    fn disable_sgb_border(&mut self) {
+    }
+
+    fn set_sgb_border(&mut self, _pixels: &[u32; 256 * 224]) {
+    }
+
+    fn set_fullscreen(&mut self, _state: bool) {
+    }
+
+    fn save_window_state(&mut self) {
+    }
+
+    fn set_stretch(&mut self, _state: bool) {
+    }
+
+    fn set_scale_mode(&mut self, _mode: ScaleMode) {
+    }
+
+    fn osd_timed_message(&mut self, text: String,
+                         _duration: std::time::Duration)
+    {
+        eprintln!("{}", text);
+    }
+
+    fn osd_timed_image(&mut self, _width: u32, _height: u32, _rgb8: &[u8],
+                       _duration: std::time::Duration)
+    {
+    }
+
+    fn set_vram_view(&mut self, _enabled: bool) {
+    }
+
+    fn present_vram_view(&mut self, _pixels: &[u32]) {
+    }
+}
@@ -1,16 +1,32 @@
 use hidapi::HidApi;
 use std::collections::HashMap;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use std::sync::mpsc::{channel, Sender, Receiver};
 
 use crate::ui::{UIEvent, UIScancode};
 
 
+/* Report type (third byte) of the wireless status report */
+const SC_BATTERY_REPORT: u8 = 0x04;
+/* Battery level value for when no status report has been received yet */
+const SC_BATTERY_UNKNOWN: u8 = 0xff;
+
+/* Battery charge in percent if @report is a wireless status report */
+pub fn battery_report_level(report: &[u8]) -> Option<u8> {
+    if report[2] == SC_BATTERY_REPORT {
+        /* Bytes 12..14 are the voltage, byte 14 is the charge */
+        Some(std::cmp::min(report[14], 100))
+    } else {
+        None
+    }
+}
+
 pub struct SC {
     events: Receiver<UIEvent>,
     rumble_on: Arc<AtomicBool>,
     rumble_off: Arc<AtomicBool>,
+    battery: Arc<AtomicU8>,
 
     #[allow(dead_code)]
     event_thread: std::thread::JoinHandle<()>,
@@ -22,6 +38,7 @@ struct SCThread {
     rumble_on: Arc<AtomicBool>,
     rumble_off: Arc<AtomicBool>,
     rumble_state: bool,
+    battery: Arc<AtomicU8>,
 
     input_state: HashMap<UIScancode, bool>,
     button_map: HashMap<SCButton, UIScancode>,
@@ -211,13 +228,16 @@ impl SC {
         let (events_s, events_r) = channel();
         let rumble_on = Arc::new(AtomicBool::new(false));
         let rumble_off = Arc::new(AtomicBool::new(false));
+        let battery = Arc::new(AtomicU8::new(SC_BATTERY_UNKNOWN));
         let evt_thr = SCThread::spawn(dev, events_s,
-                                      rumble_on.clone(), rumble_off.clone());
+                                      rumble_on.clone(), rumble_off.clone(),
+                                      battery.clone());
 
         Ok(Some(Self {
             events: events_r,
             rumble_on,
             rumble_off,
+            battery,
             event_thread: evt_thr,
         }))
     }
@@ -243,6 +263,15 @@ impl SC {
             self.rumble_off.store(true, Ordering::Relaxed);
         }
     }
+
+    /* Battery charge in percent, if the controller has reported it (only
+     * wireless controllers do) */
+    pub fn battery_level(&self) -> Option<u8> {
+        match self.battery.load(Ordering::Relaxed) {
+            SC_BATTERY_UNKNOWN => None,
+            level => Some(level),
+        }
+    }
 }
 
 
@@ -255,7 +284,8 @@ macro_rules! binding {
 
 impl SCThread {
     fn spawn(dev: hidapi::HidDevice, events: Sender<UIEvent>,
-             rumble_on: Arc<AtomicBool>, rumble_off: Arc<AtomicBool>)
+             rumble_on: Arc<AtomicBool>, rumble_off: Arc<AtomicBool>,
+             battery: Arc<AtomicU8>)
         -> std::thread::JoinHandle<()>
     {
         let mut is = HashMap::new();
@@ -287,6 +317,7 @@ impl SCThread {
             rumble_on,
             rumble_off,
             rumble_state: false,
+            battery,
             input_state: is,
             button_map: bm,
         };
@@ -346,6 +377,11 @@ impl SCThread {
                 }
             }
 
+            if let Some(level) = battery_report_level(&rbuf) {
+                self.battery.store(level, Ordering::Relaxed);
+                continue;
+            }
+
             let mut data = SCInputData::from_raw(&rbuf);
             if !data.is_valid() {
                 continue;
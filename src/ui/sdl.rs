@@ -1,42 +1,188 @@
+use std::collections::{HashMap, VecDeque};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::Sender;
 
-use crate::ui::{AudioOutputParams, UIEvent, UIScancode};
+use crate::io::lcd::vram_view::{VRAM_VIEW_HEIGHT, VRAM_VIEW_WIDTH};
+use crate::ui::{AudioOutputParams, Frontend, ScaleMode, UIEvent, UIScancode};
+
+
+/* Stick deflection beyond which it counts as a D-pad press */
+const STICK_DEADZONE: i16 = 0x4000;
+/* Right stick deflection below which there is no tilt */
+const TILT_DEADZONE: i16 = 0x1000;
+/* Trigger positions for the soft and full trigger press */
+const TRIGGER_SOFT: i16 = 0x2000;
+const TRIGGER_FULL: i16 = 0x6000;
+/* Rumble is refreshed every frame, so it stops by itself when emulation
+ * does */
+const RUMBLE_DURATION_MS: u32 = 100;
+
+/* Native Game Boy frame rate, for the speed shown in the window title */
+const NATIVE_FPS: f32 = 59.7275;
+
+/* Window geometry and fullscreen state, kept across runs */
+const WINDOW_STATE_PATH: &str = "window-state.json";
+
+/* What the window shows before the first frame (RGB) */
+const STARTUP_COLOR: [u8; 3] = [0, 0, 0];
+
+#[derive(Serialize, Deserialize)]
+struct WindowState {
+    x: i32,
+    y: i32,
+    width: u32,
+    height: u32,
+    fullscreen: bool,
+}
 
+/* The texture is declared first so it is dropped before its renderer */
+struct VramViewWindow {
+    txt: sdl2::render::Texture<'static>,
+    cvs: sdl2::render::Canvas<sdl2::video::Window>,
+}
 
 pub struct SdlUi {
+    sdl_video: sdl2::VideoSubsystem,
     sdl_audio: sdl2::AudioSubsystem,
     sdl_evt_pump: sdl2::EventPump,
+    sdl_controller: sdl2::GameControllerSubsystem,
 
     #[allow(dead_code)]
     sdl_ttf: sdl2_ttf::Sdl2TtfContext,
 
     wnd_cvs: sdl2::render::Canvas<sdl2::video::Window>,
+    /* Position and size of the window when not fullscreen */
+    windowed_rect: sdl2::rect::Rect,
     lcd_txt: sdl2::render::Texture<'static>,
     lcd_rect: sdl2::rect::Rect,
     sgb_border: bool,
     sgb_border_txt: sdl2::render::Texture<'static>,
     border_rect: sdl2::rect::Rect,
+    /* Fill the whole window instead of keeping the aspect ratio */
+    stretch: bool,
+    scale_mode: ScaleMode,
+    /* Initial window size, in multiples of the native resolution */
+    scale: u32,
 
     font: Option<sdl2_ttf::Font<'static>>,
     osd_sfc: Option<sdl2::surface::Surface<'static>>,
     osd_txt: Option<sdl2::render::Texture<'static>>,
     current_osd_text: Option<String>,
     osd_timeout: Option<std::time::Instant>,
+    osd_img_txt: Option<sdl2::render::Texture<'static>>,
+    /* Size in LCD pixels */
+    osd_img_size: (u32, u32),
+    osd_img_timeout: Option<std::time::Instant>,
+
+    paused: bool,
+    /* Dim the LCD and show "PAUSED" while paused */
+    pause_overlay: bool,
+    /* The lines of the pause overlay, centered on the LCD */
+    pause_txts: Vec<sdl2::render::Texture<'static>>,
+
+    vram_view: Option<VramViewWindow>,
 
     audio_dev: Option<sdl2::audio::AudioDevice<AudioOutput>>,
+
+    /* Controllers are closed when dropped, so we need to keep them */
+    controllers: Vec<sdl2::controller::GameController>,
+    /* Per controller (by instance ID) and axis, the button it is
+     * currently holding down */
+    axis_keys: HashMap<u32, [Option<UIScancode>; 6]>,
+    /* Per controller, the right stick position (see translate_tilt_axis) */
+    tilt_sticks: HashMap<u32, (f32, f32)>,
+    /* A single SDL event may translate into multiple UI events */
+    pending_events: VecDeque<UIEvent>,
+    rumbling: bool,
+
+    cart_title: String,
+    frame_count: u32,
+    fps_since: std::time::Instant,
+    /* Whether to show FPS and speed in the window title */
+    title_stats: bool,
+}
+
+/* Fills the window and the LCD texture (shown in @lcd_rect) with
+ * STARTUP_COLOR; also leaves it set as the draw color */
+fn draw_startup_frame<T: sdl2::render::RenderTarget>(
+    cvs: &mut sdl2::render::Canvas<T>, lcd_txt: &mut sdl2::render::Texture,
+    lcd_rect: sdl2::rect::Rect)
+{
+    let [r, g, b] = STARTUP_COLOR;
+    /* ABGR8888, i.e. RGBA in memory */
+    let pixels = [r, g, b, 0xff].repeat(160 * 144);
+    lcd_txt.update(None, &pixels, 160 * 4).unwrap();
+
+    cvs.set_draw_color(sdl2::pixels::Color::RGB(r, g, b));
+    cvs.clear();
+    cvs.copy(lcd_txt, None, Some(lcd_rect)).unwrap();
+}
+
+/* The cartridge title from the header is NUL-padded */
+fn cart_title(cart_name: &str) -> String {
+    cart_name.trim_end_matches('\0').replace('\0', ".")
+}
+
+/* With the given frame rate (if any) */
+fn window_title(cart_title: &str, fps: Option<f32>) -> String {
+    let mut title = String::from("xgbcrew");
+
+    if !cart_title.is_empty() {
+        title += &format!(" - {}", cart_title);
+    }
+    if let Some(fps) = fps {
+        title += &format!(" ({} FPS, {} %)",
+                          fps.round(), (fps * 100.0 / NATIVE_FPS).round());
+    }
+
+    title
+}
+
+/*
+ * Returns the size of the picture (of @raw_w x @raw_h pixels) in a window
+ * of @w x @h pixels
+ */
+fn picture_size(w: u32, h: u32, raw_w: u32, raw_h: u32,
+                scale_mode: ScaleMode, stretch: bool)
+    -> (u32, u32)
+{
+    /* Integer modes fall back to fitting when the window is smaller
+     * than the unscaled picture */
+    let (int_w, int_h) = (w / raw_w, h / raw_h);
+    let integer = scale_mode != ScaleMode::Fit && int_w > 0 && int_h > 0;
+    let stretch = stretch && scale_mode != ScaleMode::IntegerPixelPerfect;
+
+    if integer && stretch {
+        (raw_w * int_w, raw_h * int_h)
+    } else if integer {
+        let factor = std::cmp::min(int_w, int_h);
+        (raw_w * factor, raw_h * factor)
+    } else if stretch {
+        (w, h)
+    } else if h * raw_w / raw_h < w {
+        (h * raw_w / raw_h, h)
+    } else {
+        (w, w * raw_h / raw_w)
+    }
 }
 
 impl SdlUi {
-    pub fn new() -> Self {
+    pub fn new(cart_name: &str, scale: u32) -> Self {
         let sdl = sdl2::init().unwrap();
 
         let video = sdl.video().unwrap();
         let audio = sdl.audio().unwrap();
+        let controller = sdl.game_controller().unwrap();
         let evt_pump = sdl.event_pump().unwrap();
 
-        let wnd = video.window("xgbcrew", 160, 144).opengl().resizable().build()
-                       .unwrap();
+        /* Only show the window once it has defined content */
+        /* The cartridge title is NUL-padded */
+        let cart_title = cart_title(cart_name);
+
+        let wnd = video.window(&window_title(&cart_title, None),
+                               160 * scale, 144 * scale)
+                       .opengl().resizable().hidden().build().unwrap();
         let cvs = wnd.into_canvas().accelerated().build().unwrap();
         let txtc = cvs.texture_creator();
 
@@ -58,51 +204,128 @@ impl SdlUi {
         };
 
         let mut this = Self {
+            sdl_video: video,
             sdl_audio: audio,
             sdl_evt_pump: evt_pump,
+            sdl_controller: controller,
             sdl_ttf: sdl2_ttf::init().unwrap(),
 
             wnd_cvs: cvs,
+            windowed_rect: sdl2::rect::Rect::new(0, 0, 160, 144),
             lcd_txt,
             lcd_rect: sdl2::rect::Rect::new(0, 0, 160, 144),
             sgb_border: false,
             sgb_border_txt,
             border_rect: sdl2::rect::Rect::new(0, 0, 160, 144),
+            stretch: false,
+            scale_mode: ScaleMode::Fit,
+            scale,
 
             font: None,
             osd_sfc: None,
             osd_txt: None,
             current_osd_text: None,
             osd_timeout: None,
+            osd_img_txt: None,
+            osd_img_size: (0, 0),
+            osd_img_timeout: None,
+
+            paused: false,
+            pause_overlay: true,
+            pause_txts: Vec::new(),
+
+            vram_view: None,
 
             audio_dev: None,
+
+            /* Already attached controllers are reported through
+             * ControllerDeviceAdded events, too */
+            controllers: Vec::new(),
+            axis_keys: HashMap::new(),
+            tilt_sticks: HashMap::new(),
+            pending_events: VecDeque::new(),
+            rumbling: false,
+
+            cart_title,
+            frame_count: 0,
+            fps_since: std::time::Instant::now(),
+            title_stats: true,
         };
 
-        this.update_rects(160, 144);
+        let restore_fullscreen = this.restore_window_state();
+        let (w, h) = this.wnd_cvs.output_size().unwrap();
+        this.update_rects(w, h);
+
+        /* So there is no uninitialized garbage on screen until the
+         * emulation delivers its first frame */
+        draw_startup_frame(&mut this.wnd_cvs, &mut this.lcd_txt,
+                           this.lcd_rect);
+        this.wnd_cvs.present();
+        this.wnd_cvs.window_mut().show();
+        if restore_fullscreen {
+            this.set_fullscreen(true);
+        }
 
         this
     }
 
-    pub fn setup_audio(&mut self, params: AudioOutputParams) {
-        let sound_spec = sdl2::audio::AudioSpecDesired {
-            freq: Some(params.freq as i32),
-            channels: Some(params.channels as u8),
-            samples: Some((params.buf_step / params.channels) as u16),
-        };
+    fn window_rect(&self) -> sdl2::rect::Rect {
+        let wnd = self.wnd_cvs.window();
+        let (x, y) = wnd.position();
+        let (w, h) = wnd.size();
+        sdl2::rect::Rect::new(x, y, w, h)
+    }
 
-        let adev_obj_gen = |_| {
-            AudioOutput {
-                buf: params.buf,
-                buf_i: 0,
-                buf_done: params.buf_done,
-            }
-        };
+    pub fn is_fullscreen(&self) -> bool {
+        self.wnd_cvs.window().fullscreen_state() !=
+            sdl2::video::FullscreenType::Off
+    }
 
-        let adev = self.sdl_audio.open_playback(None, &sound_spec,
-                                                adev_obj_gen).unwrap();
-        adev.resume();
+    /* Applies the saved window geometry, moved and shrunk to fit on a
+     * display (the first one if it is on none of them anymore).  Returns
+     * whether the window was fullscreen. */
+    fn restore_window_state(&mut self) -> bool {
+        let ws: WindowState =
+            match std::fs::File::open(WINDOW_STATE_PATH) {
+                Ok(f) => match serde_json::from_reader(f) {
+                    Ok(ws) => ws,
+                    Err(e) => {
+                        eprintln!("Failed to parse {}: {}",
+                                  WINDOW_STATE_PATH, e);
+                        return false;
+                    },
+                },
 
-        self.audio_dev = Some(adev);
+                Err(_) => return false,
+            };
+
+        let displays: Vec<sdl2::rect::Rect> =
+            (0..self.sdl_video.num_video_displays().unwrap_or(0))
+                .filter_map(|i| self.sdl_video.display_bounds(i).ok())
+                .collect();
+
+        let center = sdl2::rect::Point::new(ws.x + ws.width as i32 / 2,
+                                            ws.y + ws.height as i32 / 2);
+        let display =
+            match displays.iter().find(|d| d.contains_point(center))
+                          .or_else(|| displays.first())
+            {
+                Some(d) => *d,
+                None => return ws.fullscreen,
+            };
+
+        let w = ws.width.max(160).min(display.width());
+        let h = ws.height.max(144).min(display.height());
+        let x = ws.x.min(display.right() - w as i32).max(display.x());
+        let y = ws.y.min(display.bottom() - h as i32).max(display.y());
+
+        let wnd = self.wnd_cvs.window_mut();
+        wnd.set_size(w, h).unwrap_or(());
+        wnd.set_position(sdl2::video::WindowPos::Positioned(x),
+                         sdl2::video::WindowPos::Positioned(y));
+        self.windowed_rect = sdl2::rect::Rect::new(x, y, w, h);
+
+        ws.fullscreen
     }
 
     pub fn osd_drop_message(&mut self) {
@@ -112,45 +335,92 @@ impl SdlUi {
         self.osd_timeout = None;
     }
 
-    pub fn osd_timed_message(&mut self, text: String,
-                             duration: std::time::Duration)
-    {
-        let txtc = self.wnd_cvs.texture_creator();
+    /* Scaled like the LCD, in its top right corner */
+    fn show_osd_image(&mut self) {
+        let txt =
+            match self.osd_img_txt.as_mut() {
+                Some(t) => t,
+                None => return,
+            };
 
-        if self.font.is_none() {
-            println!("{}", text);
+        let (w, h) = self.osd_img_size;
+        let w = w * self.lcd_rect.width() / 160;
+        let h = h * self.lcd_rect.height() / 144;
+        let x = self.lcd_rect.right() - w as i32;
+        let rect = sdl2::rect::Rect::new(x, self.lcd_rect.top(), w, h);
+
+        self.wnd_cvs.copy(txt, None, Some(rect)).unwrap();
+
+        let now = std::time::Instant::now();
+        let timeout = self.osd_img_timeout.unwrap();
+        if now >= timeout {
+            self.osd_img_txt = None;
+            self.osd_img_timeout = None;
+        } else {
+            let diff = (timeout - now).as_millis() as u32;
+            txt.set_alpha_mod(if diff < 512 { (diff / 2) as u8 } else { 255 });
+        }
+    }
+
+    fn render_pause_overlay(&mut self) {
+        self.pause_txts.clear();
+        if !self.paused || !self.pause_overlay {
             return;
         }
-        let font = self.font.as_ref().unwrap();
 
-        self.osd_txt = None;
+        let font =
+            match self.font.as_ref() {
+                Some(f) => f,
+                None => return,
+            };
+        let txtc = self.wnd_cvs.texture_creator();
+        let white = sdl2::pixels::Color::RGB(255, 255, 255);
 
-        let osd_sfc = unsafe {
-            std::mem::transmute::<sdl2::surface::Surface,
-                                  sdl2::surface::Surface<'static>>(
-                font.render(text.as_ref())
-                    .blended_wrapped(sdl2::pixels::Color::RGB(255, 0, 0),
-                                     self.wnd_cvs.output_size().unwrap().0)
-                    .unwrap()
-            )
-        };
-        self.osd_sfc = Some(osd_sfc);
+        for line in ["PAUSED", self.cart_title.as_str()] {
+            /* Rendering an empty string fails */
+            if line.is_empty() {
+                continue;
+            }
 
-        let osd_txt = unsafe {
-            let sfc_ref = self.osd_sfc.as_ref().unwrap();
-            std::mem::transmute::<sdl2::render::Texture,
-                                  sdl2::render::Texture<'static>>(
-                txtc.create_texture_from_surface(sfc_ref).unwrap()
-            )
-        };
-        self.osd_txt = Some(osd_txt);
+            let sfc = font.render(line).blended(white).unwrap();
+            let txt = unsafe {
+                std::mem::transmute::<sdl2::render::Texture,
+                                      sdl2::render::Texture<'static>>(
+                    txtc.create_texture_from_surface(&sfc).unwrap()
+                )
+            };
+            self.pause_txts.push(txt);
+        }
+    }
 
-        self.current_osd_text = Some(text);
-        self.osd_timeout = Some(std::time::Instant::now() + duration);
+    fn show_pause_overlay(&mut self) {
+        let heights = self.pause_txts.iter().map(|t| t.query().height);
+        let center = self.lcd_rect.center();
+        let mut y = center.y() - heights.sum::<u32>() as i32 / 2;
+
+        for txt in &self.pause_txts {
+            let q = txt.query();
+            let x = center.x() - q.width as i32 / 2;
+            let rect = sdl2::rect::Rect::new(x, y, q.width, q.height);
+
+            self.wnd_cvs.copy(txt, None, Some(rect)).unwrap();
+            y += q.height as i32;
+        }
     }
 
     fn show_lcd(&mut self) {
+        let dim = self.paused && self.pause_overlay;
+
+        if dim {
+            self.lcd_txt.set_color_mod(96, 96, 96);
+        }
         self.wnd_cvs.copy(&self.lcd_txt, None, Some(self.lcd_rect)).unwrap();
+        if dim {
+            self.lcd_txt.set_color_mod(255, 255, 255);
+            self.show_pause_overlay();
+        }
+
+        self.show_osd_image();
 
         if let Some(sfc) = self.osd_sfc.as_ref() {
             let txt = self.osd_txt.as_mut().unwrap();
@@ -171,18 +441,6 @@ impl SdlUi {
         self.wnd_cvs.present();
     }
 
-    pub fn present_frame(&mut self, pixels: &[u32; 160 * 144]) {
-        let pixels8 = unsafe {
-            std::slice::from_raw_parts(pixels.as_ptr() as *const u8,
-                                       pixels.len() * 4)
-        };
-
-        self.lcd_txt.update(None, pixels8, 160 * 4).unwrap();
-
-        self.update_bg();
-        self.show_lcd();
-    }
-
     fn update_bg(&mut self) {
         self.wnd_cvs.clear();
 
@@ -201,11 +459,7 @@ impl SdlUi {
             };
 
         let (aspect_w, aspect_h) =
-            if h * raw_w / raw_h < w {
-                (h * raw_w / raw_h, h)
-            } else {
-                (w, w * raw_h / raw_w)
-            };
+            picture_size(w, h, raw_w, raw_h, self.scale_mode, self.stretch);
 
         let (lcd_w, lcd_h) = (aspect_w * 160 / raw_w, aspect_h * 144 / raw_h);
         let (border_w, border_h) = (aspect_w, aspect_h);
@@ -244,6 +498,9 @@ impl SdlUi {
                 self.osd_drop_message();
             }
         }
+
+        /* The font size has changed */
+        self.render_pause_overlay();
     }
 
     fn sdl_sc_to_ui_sc(sdl_sc: sdl2::keyboard::Scancode) -> Option<UIScancode> {
@@ -251,10 +508,20 @@ impl SdlUi {
 
         let ui_sc =
             match sdl_sc {
+                Scancode::I         => UIScancode::I,
+                Scancode::J         => UIScancode::J,
+                Scancode::K         => UIScancode::K,
+                Scancode::L         => UIScancode::L,
                 Scancode::P         => UIScancode::P,
+                Scancode::R         => UIScancode::R,
                 Scancode::X         => UIScancode::X,
                 Scancode::Z         => UIScancode::Z,
 
+                Scancode::Num1      => UIScancode::Num1,
+                Scancode::Num2      => UIScancode::Num2,
+                Scancode::Num3      => UIScancode::Num3,
+                Scancode::Num4      => UIScancode::Num4,
+
                 Scancode::LShift    => UIScancode::Shift,
                 Scancode::RShift    => UIScancode::Shift,
                 Scancode::LAlt      => UIScancode::Alt,
@@ -265,6 +532,7 @@ impl SdlUi {
                 Scancode::Space     => UIScancode::Space,
                 Scancode::Return    => UIScancode::Return,
                 Scancode::Backspace => UIScancode::Backspace,
+                Scancode::Escape    => UIScancode::Escape,
 
                 Scancode::Left      => UIScancode::Left,
                 Scancode::Right     => UIScancode::Right,
@@ -290,6 +558,123 @@ impl SdlUi {
         Some(ui_sc)
     }
 
+    fn sdl_button_to_ui_sc(button: sdl2::controller::Button)
+        -> Option<UIScancode>
+    {
+        use sdl2::controller::Button;
+
+        let ui_sc =
+            match button {
+                Button::A               => UIScancode::CA,
+                Button::B               => UIScancode::CB,
+                Button::X               => UIScancode::CX,
+                Button::Y               => UIScancode::CY,
+
+                Button::DPadLeft        => UIScancode::CLeft,
+                Button::DPadRight       => UIScancode::CRight,
+                Button::DPadUp          => UIScancode::CUp,
+                Button::DPadDown        => UIScancode::CDown,
+
+                Button::LeftShoulder    => UIScancode::CLBump,
+                Button::RightShoulder   => UIScancode::CRBump,
+
+                Button::Back            => UIScancode::CPrevious,
+                Button::Start           => UIScancode::CNext,
+                Button::Guide           => UIScancode::CAction,
+
+                Button::Paddle1         => UIScancode::CRGrip,
+                Button::Paddle2         => UIScancode::CLGrip,
+
+                _ => { return None; },
+            };
+
+        Some(ui_sc)
+    }
+
+    fn sdl_axis_to_ui_sc(axis: sdl2::controller::Axis, value: i16)
+        -> Option<UIScancode>
+    {
+        use sdl2::controller::Axis;
+
+        match axis {
+            Axis::LeftX if value < -STICK_DEADZONE => Some(UIScancode::CLeft),
+            Axis::LeftX if value > STICK_DEADZONE => Some(UIScancode::CRight),
+            Axis::LeftY if value < -STICK_DEADZONE => Some(UIScancode::CUp),
+            Axis::LeftY if value > STICK_DEADZONE => Some(UIScancode::CDown),
+
+            Axis::TriggerLeft if value > TRIGGER_FULL =>
+                Some(UIScancode::CLTrigger),
+            Axis::TriggerLeft if value > TRIGGER_SOFT =>
+                Some(UIScancode::CLSoftTrigger),
+            Axis::TriggerRight if value > TRIGGER_FULL =>
+                Some(UIScancode::CRTrigger),
+            Axis::TriggerRight if value > TRIGGER_SOFT =>
+                Some(UIScancode::CRSoftTrigger),
+
+            _ => None,
+        }
+    }
+
+    /* Axes are translated into virtual buttons; report a release of the
+     * previous one and a press of the new one when that changes */
+    fn translate_axis(&mut self, which: u32, axis: sdl2::controller::Axis,
+                      value: i16)
+        -> Option<UIEvent>
+    {
+        use sdl2::controller::Axis;
+
+        if let Axis::RightX | Axis::RightY = axis {
+            return self.translate_tilt_axis(which, axis, value);
+        }
+
+        let new_key = Self::sdl_axis_to_ui_sc(axis, value);
+        let axis_keys = self.axis_keys.entry(which).or_insert([None; 6]);
+        let old_key = std::mem::replace(&mut axis_keys[axis as usize],
+                                        new_key);
+
+        if new_key == old_key {
+            return None;
+        }
+
+        if let Some(key) = old_key {
+            self.pending_events.push_back(UIEvent::Key { key, down: false });
+        }
+        if let Some(key) = new_key {
+            self.pending_events.push_back(UIEvent::Key { key, down: true });
+        }
+
+        self.pending_events.pop_front()
+    }
+
+    /* The right stick tilts proportionally (from the edge of the deadzone
+     * up to full deflection) */
+    fn translate_tilt_axis(&mut self, which: u32,
+                           axis: sdl2::controller::Axis, value: i16)
+        -> Option<UIEvent>
+    {
+        let magnitude = (value as i32).abs() - TILT_DEADZONE as i32;
+        let pos =
+            if magnitude > 0 {
+                let range = (i16::MAX - TILT_DEADZONE) as f32;
+                (magnitude as f32 / range).min(1.0) * (value.signum() as f32)
+            } else {
+                0.0
+            };
+
+        let stick = self.tilt_sticks.entry(which).or_insert((0.0, 0.0));
+        let old = *stick;
+        if axis == sdl2::controller::Axis::RightX {
+            stick.0 = pos;
+        } else {
+            stick.1 = pos;
+        }
+
+        if *stick == old {
+            return None;
+        }
+        Some(UIEvent::Tilt { x: stick.0, y: stick.1 })
+    }
+
     fn translate_event(&mut self, evt: sdl2::event::Event) -> Option<UIEvent> {
         match evt {
             sdl2::event::Event::Quit { timestamp: _ } =>
@@ -313,25 +698,109 @@ impl SdlUi {
                 repeat: _,
             } => Self::sdl_sc_to_ui_sc(scancode).map(|ui_sc| UIEvent::Key { key: ui_sc, down: false }),
 
+            sdl2::event::Event::ControllerDeviceAdded {
+                timestamp: _,
+                which,
+            } => {
+                match self.sdl_controller.open(which) {
+                    Ok(controller) => {
+                        self.osd_timed_message(
+                            format!("Controller connected: {}",
+                                    controller.name()),
+                            std::time::Duration::from_secs(3));
+                        self.controllers.push(controller);
+                    },
+
+                    Err(e) => {
+                        eprintln!("Failed to open controller {}: {}",
+                                  which, e);
+                    },
+                }
+                None
+            },
+
+            sdl2::event::Event::ControllerDeviceRemoved {
+                timestamp: _,
+                which,
+            } => {
+                /* @which is the instance ID here, not the device index */
+                self.controllers.retain(|c| c.instance_id() != which);
+
+                /* Release whatever its axes were holding down */
+                let held = self.axis_keys.remove(&which).unwrap_or_default();
+                for key in held.into_iter().flatten() {
+                    self.pending_events.push_back(UIEvent::Key { key,
+                                                                 down: false });
+                }
+                if self.tilt_sticks.remove(&which).is_some() {
+                    self.pending_events.push_back(UIEvent::Tilt { x: 0.0,
+                                                                  y: 0.0 });
+                }
+                self.pending_events.pop_front()
+            },
+
+            sdl2::event::Event::ControllerButtonDown {
+                timestamp: _,
+                which: _,
+                button,
+            } => Self::sdl_button_to_ui_sc(button).map(|ui_sc| UIEvent::Key { key: ui_sc, down: true }),
+
+            sdl2::event::Event::ControllerButtonUp {
+                timestamp: _,
+                which: _,
+                button,
+            } => Self::sdl_button_to_ui_sc(button).map(|ui_sc| UIEvent::Key { key: ui_sc, down: false }),
+
+            sdl2::event::Event::ControllerAxisMotion {
+                timestamp: _,
+                which,
+                axis,
+                value,
+            } => self.translate_axis(which, axis, value),
+
+            sdl2::event::Event::Window {
+                timestamp: _,
+                window_id,
+                win_event,
+            } if window_id != self.wnd_cvs.window().id() => {
+                /* The VRAM view; with it open, closing the main window no
+                 * longer generates a Quit event */
+                if let sdl2::event::WindowEvent::Close = win_event {
+                    self.vram_view = None;
+                    Some(UIEvent::VramViewClosed)
+                } else {
+                    None
+                }
+            },
+
             sdl2::event::Event::Window {
                 timestamp: _,
                 window_id: _,
                 win_event,
             } => {
                 match win_event {
+                    sdl2::event::WindowEvent::Close =>
+                        Some(UIEvent::Quit),
+
                     sdl2::event::WindowEvent::Resized(w, h) => {
                         self.update_rects(w as u32, h as u32);
+                        None
                     },
 
                     sdl2::event::WindowEvent::Exposed => {
                         self.update_bg();
                         self.show_lcd();
+                        None
                     },
 
-                    _ => (),
-                }
+                    sdl2::event::WindowEvent::Minimized =>
+                        Some(UIEvent::Minimized(true)),
 
-                None
+                    sdl2::event::WindowEvent::Restored =>
+                        Some(UIEvent::Minimized(false)),
+
+                    _ => None,
+                }
             },
 
             _ => {
@@ -339,8 +808,138 @@ impl SdlUi {
             },
         }
     }
+}
+
+impl Frontend for SdlUi {
+    fn count_frame(&mut self) {
+        if !self.title_stats {
+            return;
+        }
+
+        self.frame_count += 1;
+
+        let elapsed = self.fps_since.elapsed();
+        if elapsed >= std::time::Duration::from_secs(1) {
+            let fps = self.frame_count as f32 / elapsed.as_secs_f32();
+            let title = window_title(&self.cart_title, Some(fps));
+            self.wnd_cvs.window_mut().set_title(&title).unwrap();
+
+            self.frame_count = 0;
+            self.fps_since = std::time::Instant::now();
+        }
+    }
+
+    fn set_title_stats(&mut self, enabled: bool) {
+        self.title_stats = enabled;
+        self.frame_count = 0;
+        self.fps_since = std::time::Instant::now();
+
+        let title = window_title(&self.cart_title, None);
+        self.wnd_cvs.window_mut().set_title(&title).unwrap();
+    }
+
+    fn setup_audio(&mut self, params: AudioOutputParams) -> usize {
+        let sound_spec = sdl2::audio::AudioSpecDesired {
+            freq: Some(params.freq as i32),
+            channels: Some(params.channels as u8),
+            samples: Some((params.buf_step / params.channels) as u16),
+        };
+
+        let adev_obj_gen = |_| {
+            AudioOutput {
+                buf: params.buf,
+                buf_i: 0,
+                buf_done: params.buf_done,
+
+                written: params.written,
+                read: 0,
+                underruns: params.underruns,
+                last: [0.0; 2],
+            }
+        };
+
+        let adev = self.sdl_audio.open_playback(None, &sound_spec,
+                                                adev_obj_gen).unwrap();
+        adev.resume();
+
+        let freq = adev.spec().freq as usize;
+        self.audio_dev = Some(adev);
+        freq
+    }
+
+    fn osd_timed_message(&mut self, text: String,
+                             duration: std::time::Duration)
+    {
+        let txtc = self.wnd_cvs.texture_creator();
+
+        if self.font.is_none() {
+            println!("{}", text);
+            return;
+        }
+        let font = self.font.as_ref().unwrap();
+
+        self.osd_txt = None;
+
+        let osd_sfc = unsafe {
+            std::mem::transmute::<sdl2::surface::Surface,
+                                  sdl2::surface::Surface<'static>>(
+                font.render(text.as_ref())
+                    .blended_wrapped(sdl2::pixels::Color::RGB(255, 0, 0),
+                                     self.wnd_cvs.output_size().unwrap().0)
+                    .unwrap()
+            )
+        };
+        self.osd_sfc = Some(osd_sfc);
+
+        let osd_txt = unsafe {
+            let sfc_ref = self.osd_sfc.as_ref().unwrap();
+            std::mem::transmute::<sdl2::render::Texture,
+                                  sdl2::render::Texture<'static>>(
+                txtc.create_texture_from_surface(sfc_ref).unwrap()
+            )
+        };
+        self.osd_txt = Some(osd_txt);
+
+        self.current_osd_text = Some(text);
+        self.osd_timeout = Some(std::time::Instant::now() + duration);
+    }
+
+    fn osd_timed_image(&mut self, width: u32, height: u32, rgb8: &[u8],
+                           duration: std::time::Duration)
+    {
+        let txtc = self.wnd_cvs.texture_creator();
+        let pixel_fmt = sdl2::pixels::PixelFormatEnum::RGB24;
+
+        let mut txt = unsafe {
+            std::mem::transmute::<sdl2::render::Texture,
+                                  sdl2::render::Texture<'static>>(
+                txtc.create_texture_static(pixel_fmt, width, height).unwrap()
+            )
+        };
+        txt.update(None, rgb8, width as usize * 3).unwrap();
+
+        self.osd_img_txt = Some(txt);
+        self.osd_img_size = (width, height);
+        self.osd_img_timeout = Some(std::time::Instant::now() + duration);
+    }
+
+    fn present_frame(&mut self, pixels: &[u32; 160 * 144]) {
+        let pixels8 = unsafe {
+            std::slice::from_raw_parts(pixels.as_ptr() as *const u8,
+                                       pixels.len() * 4)
+        };
+
+        self.lcd_txt.update(None, pixels8, 160 * 4).unwrap();
+
+        self.update_bg();
+        self.show_lcd();
+    }
+
+    fn poll_event(&mut self) -> Option<UIEvent> {
+        if let Some(evt) = self.pending_events.pop_front() {
+            return Some(evt);
+        }
 
-    pub fn poll_event(&mut self) -> Option<UIEvent> {
         if let Some(evt) = self.sdl_evt_pump.poll_event() {
             if let Some(ui_event) = self.translate_event(evt) {
                 Some(ui_event)
@@ -352,9 +951,13 @@ impl SdlUi {
         }
     }
 
-    pub fn wait_event(&mut self, timeout: std::time::Duration)
+    fn wait_event(&mut self, timeout: std::time::Duration)
         -> Option<UIEvent>
     {
+        if let Some(evt) = self.pending_events.pop_front() {
+            return Some(evt);
+        }
+
         let toms = timeout.as_millis() as u32;
 
         if let Some(evt) = self.sdl_evt_pump.wait_event_timeout(toms) {
@@ -368,18 +971,28 @@ impl SdlUi {
         }
     }
 
-    pub fn enable_sgb_border(&mut self) {
+    fn enable_sgb_border(&mut self) {
         self.sgb_border = true;
 
         let (mut w, mut h) = self.wnd_cvs.output_size().unwrap();
-        w = std::cmp::max(w, 256);
-        h = std::cmp::max(h, 224);
+        w = std::cmp::max(w, 256 * self.scale);
+        h = std::cmp::max(h, 224 * self.scale);
 
         self.wnd_cvs.window_mut().set_size(w, h).unwrap();
         self.update_rects(w, h);
     }
 
-    pub fn set_sgb_border(&mut self, pixels: &[u32; 256 * 224]) {
+    fn disable_sgb_border(&mut self) {
+        if !self.sgb_border {
+            return;
+        }
+        self.sgb_border = false;
+
+        let (w, h) = self.wnd_cvs.output_size().unwrap();
+        self.update_rects(w, h);
+    }
+
+    fn set_sgb_border(&mut self, pixels: &[u32; 256 * 224]) {
         let pixels8 = unsafe {
             std::slice::from_raw_parts(pixels.as_ptr() as *const u8,
                                        pixels.len() * 4)
@@ -388,7 +1001,7 @@ impl SdlUi {
         self.sgb_border_txt.update(None, pixels8, 256 * 4).unwrap();
     }
 
-    pub fn set_fullscreen(&mut self, state: bool) {
+    fn set_fullscreen(&mut self, state: bool) {
         let fs_mode =
             if state {
                 sdl2::video::FullscreenType::Desktop
@@ -396,16 +1009,132 @@ impl SdlUi {
                 sdl2::video::FullscreenType::Off
             };
 
+        /* Remember where to go back to (and what to save) */
+        if state && !self.is_fullscreen() {
+            self.windowed_rect = self.window_rect();
+        }
+
         self.wnd_cvs.window_mut().set_fullscreen(fs_mode).unwrap();
     }
 
-    pub fn set_paused(&mut self, paused: bool) {
+    fn save_window_state(&mut self) {
+        let fullscreen = self.is_fullscreen();
+        let rect =
+            if fullscreen {
+                self.windowed_rect
+            } else {
+                self.window_rect()
+            };
+
+        let ws = WindowState {
+            x: rect.x(),
+            y: rect.y(),
+            width: rect.width(),
+            height: rect.height(),
+            fullscreen,
+        };
+
+        let res = std::fs::File::create(WINDOW_STATE_PATH)
+            .map_err(|e| e.to_string())
+            .and_then(|f| serde_json::to_writer(f, &ws)
+                              .map_err(|e| e.to_string()));
+        if let Err(e) = res {
+            eprintln!("Failed to save {}: {}", WINDOW_STATE_PATH, e);
+        }
+    }
+
+    fn set_stretch(&mut self, state: bool) {
+        self.stretch = state;
+
+        let (w, h) = self.wnd_cvs.output_size().unwrap();
+        self.update_rects(w, h);
+        self.update_bg();
+        self.show_lcd();
+    }
+
+    fn set_rumble(&mut self, state: bool) {
+        if !state && !self.rumbling {
+            return;
+        }
+        self.rumbling = state;
+
+        let (strength, duration) =
+            if state { (0xffff, RUMBLE_DURATION_MS) } else { (0, 0) };
+
+        for controller in self.controllers.iter_mut() {
+            /* Fails for controllers without rumble, which is fine */
+            let _ = controller.set_rumble(strength, strength, duration);
+        }
+    }
+
+    fn set_scale_mode(&mut self, mode: ScaleMode) {
+        self.scale_mode = mode;
+
+        let (w, h) = self.wnd_cvs.output_size().unwrap();
+        self.update_rects(w, h);
+        self.update_bg();
+        self.show_lcd();
+    }
+
+    fn set_paused(&mut self, paused: bool) {
         let dev = self.audio_dev.as_mut().unwrap();
         if paused {
             dev.pause();
         } else {
             dev.resume();
         }
+
+        self.paused = paused;
+        self.render_pause_overlay();
+    }
+
+    fn set_pause_overlay(&mut self, enabled: bool) {
+        self.pause_overlay = enabled;
+        self.render_pause_overlay();
+    }
+
+    fn set_vram_view(&mut self, enabled: bool) {
+        if !enabled {
+            self.vram_view = None;
+            return;
+        }
+        if self.vram_view.is_some() {
+            return;
+        }
+
+        let (w, h) = (VRAM_VIEW_WIDTH as u32, VRAM_VIEW_HEIGHT as u32);
+        let wnd = self.sdl_video.window("xgbcrew - VRAM", w, h)
+                                .resizable().build().unwrap();
+        let cvs = wnd.into_canvas().accelerated().build().unwrap();
+        let txtc = cvs.texture_creator();
+
+        let pixel_fmt = sdl2::pixels::PixelFormatEnum::ABGR8888;
+        let access = sdl2::render::TextureAccess::Streaming;
+        let txt = unsafe {
+            std::mem::transmute::<sdl2::render::Texture,
+                                  sdl2::render::Texture<'static>>(
+                txtc.create_texture(pixel_fmt, access, w, h).unwrap()
+            )
+        };
+
+        self.vram_view = Some(VramViewWindow { txt, cvs });
+    }
+
+    fn present_vram_view(&mut self, pixels: &[u32]) {
+        let vv =
+            match self.vram_view.as_mut() {
+                Some(vv) => vv,
+                None => return,
+            };
+
+        let pixels8 = unsafe {
+            std::slice::from_raw_parts(pixels.as_ptr() as *const u8,
+                                       pixels.len() * 4)
+        };
+
+        vv.txt.update(None, pixels8, VRAM_VIEW_WIDTH * 4).unwrap();
+        vv.cvs.copy(&vv.txt, None, None).unwrap();
+        vv.cvs.present();
     }
 }
 
@@ -414,20 +1143,129 @@ struct AudioOutput {
     buf: Arc<Mutex<Vec<f32>>>,
     buf_i: usize,
     buf_done: Sender<usize>,
+
+    /* Total samples written by emulation and read by us */
+    written: Arc<AtomicUsize>,
+    read: usize,
+    underruns: Arc<AtomicUsize>,
+    /* Last output frame, faded out on underruns */
+    last: [f32; 2],
 }
 
 impl sdl2::audio::AudioCallback for AudioOutput {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
+        let written = self.written.load(Ordering::Acquire);
+
         let inp_guard = self.buf.lock().unwrap();
         let inp = &*inp_guard;
 
+        /* When not running in realtime, emulation laps us; skip ahead by
+         * whole laps, so the position in the buffer stays the same */
+        while written > self.read + inp.len() {
+            self.read += inp.len();
+        }
+
+        /*
+         * Emulation has not produced the next buffer yet.  Instead of
+         * playing the stale one again, fade out and wait for it (nothing
+         * is produced before emulation starts, which is no underrun).
+         */
+        if written < self.read + out.len() {
+            for (i, sample) in out.iter_mut().enumerate() {
+                self.last[i % 2] *= 0.995;
+                *sample = self.last[i % 2];
+            }
+            if written > 0 {
+                self.underruns.fetch_add(1, Ordering::Relaxed);
+            }
+            return;
+        }
+
         for i in 0..out.len() {
             out[i] = inp[self.buf_i + i];
         }
+        self.last.copy_from_slice(&out[out.len() - 2..]);
         self.buf_i = (self.buf_i + out.len()) % inp.len();
+        self.read += out.len();
 
         self.buf_done.send(self.buf_i).unwrap();
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::{STARTUP_COLOR, cart_title, draw_startup_frame, picture_size,
+                window_title};
+    use crate::ui::ScaleMode;
+
+    #[test]
+    fn fit_keeps_aspect_ratio() {
+        /* Too wide and too tall */
+        assert_eq!(picture_size(800, 288, 160, 144, ScaleMode::Fit, false),
+                   (320, 288));
+        assert_eq!(picture_size(320, 600, 160, 144, ScaleMode::Fit, false),
+                   (320, 288));
+        assert_eq!(picture_size(800, 288, 160, 144, ScaleMode::Fit, true),
+                   (800, 288));
+    }
+
+    #[test]
+    fn integer_scaling() {
+        let (w, h) = (500, 300);
+
+        assert_eq!(picture_size(w, h, 160, 144, ScaleMode::IntegerFit,
+                                false),
+                   (320, 288));
+        /* Per axis */
+        assert_eq!(picture_size(w, h, 160, 144, ScaleMode::IntegerFit,
+                                true),
+                   (480, 288));
+        /* Never stretched */
+        assert_eq!(picture_size(w, h, 160, 144,
+                                ScaleMode::IntegerPixelPerfect, true),
+                   (320, 288));
+
+        /* Window smaller than the SGB border */
+        assert_eq!(picture_size(200, 180, 256, 224, ScaleMode::IntegerFit,
+                                false),
+                   (200, 175));
+    }
+
+    #[test]
+    fn title() {
+        assert_eq!(cart_title("POKEMON RED\0\0\0\0\0"), "POKEMON RED");
+        assert_eq!(cart_title("A\0B\0\0"), "A.B");
+
+        assert_eq!(window_title("", None), "xgbcrew");
+        assert_eq!(window_title("TETRIS", None), "xgbcrew - TETRIS");
+        assert_eq!(window_title("TETRIS", Some(59.7275)),
+                   "xgbcrew - TETRIS (60 FPS, 100 %)");
+        assert_eq!(window_title("", Some(119.6)),
+                   "xgbcrew (120 FPS, 200 %)");
+    }
+
+    #[test]
+    fn startup_frame() {
+        let pixel_fmt = sdl2::pixels::PixelFormatEnum::RGB24;
+        let sfc = sdl2::surface::Surface::new(320, 200, pixel_fmt).unwrap();
+        let mut cvs = sfc.into_canvas().unwrap();
+        let txtc = cvs.texture_creator();
+        let mut lcd_txt =
+            txtc.create_texture_streaming(
+                sdl2::pixels::PixelFormatEnum::ABGR8888, 160, 144).unwrap();
+
+        /* Garbage */
+        cvs.set_draw_color(sdl2::pixels::Color::RGB(0x12, 0x34, 0x56));
+        cvs.clear();
+
+        let lcd_rect = sdl2::rect::Rect::new(80, 28, 160, 144);
+        draw_startup_frame(&mut cvs, &mut lcd_txt, lcd_rect);
+
+        let pixels = cvs.read_pixels(None, pixel_fmt).unwrap();
+        assert_eq!(pixels.len(), 320 * 200 * 3);
+        assert!(pixels.chunks(3).all(|rgb| rgb == STARTUP_COLOR));
+    }
+}
@@ -3,10 +3,11 @@ use std::collections::LinkedList;
 use std::rc::Rc;
 use wasm_bindgen::closure::Closure;
 use wasm_bindgen::JsCast;
-use web_sys::{AudioBuffer, AudioBufferSourceNode, AudioContext, CanvasRenderingContext2d,
-              HtmlCanvasElement, ImageData, KeyboardEvent, MouseEvent};
+use web_sys::{AudioBuffer, AudioBufferSourceNode, AudioContext, AudioContextOptions,
+              CanvasRenderingContext2d, HtmlCanvasElement, ImageData, KeyboardEvent,
+              MouseEvent};
 
-use crate::ui::{AudioOutputParams, UIEvent, UIScancode};
+use crate::ui::{AudioOutputParams, ScaleMode, UIEvent, UIScancode};
 
 
 pub struct WebBufferAudio {
@@ -36,6 +37,7 @@ pub struct WebWorkletAudio {
     ptrs: Vec<u32>,
 
     channels: usize,
+    sample_rate: usize,
 }
 
 pub struct WebUi {
@@ -129,10 +131,20 @@ impl WebUi {
 
     fn translate_key_event(event: &KeyboardEvent, down: bool) -> Option<UIEvent> {
         let ui_sc = match event.key().as_ref() {
+            "i" | "I" => UIScancode::I,
+            "j" | "J" => UIScancode::J,
+            "k" | "K" => UIScancode::K,
+            "l" | "L" => UIScancode::L,
             "p" | "P" => UIScancode::P,
+            "r" | "R" => UIScancode::R,
             "x" | "X" => UIScancode::X,
             "z" | "Z" => UIScancode::Z,
 
+            "1" => UIScancode::Num1,
+            "2" => UIScancode::Num2,
+            "3" => UIScancode::Num3,
+            "4" => UIScancode::Num4,
+
             "Alt"       => UIScancode::Alt,
             "Control"   => UIScancode::Control,
             "Shift"     => UIScancode::Shift,
@@ -140,6 +152,7 @@ impl WebUi {
             " "         => UIScancode::Space,
             "Enter"     => UIScancode::Return,
             "Backspace" => UIScancode::Backspace,
+            "Escape"    => UIScancode::Escape,
 
             "ArrowLeft"     => UIScancode::Left,
             "ArrowRight"    => UIScancode::Right,
@@ -165,8 +178,10 @@ impl WebUi {
         Some(UIEvent::Key { key: ui_sc, down })
     }
 
-    pub fn setup_audio(&mut self, params: AudioOutputParams) {
+    pub fn setup_audio(&mut self, params: AudioOutputParams) -> usize {
+        let freq = params.freq;
         self.audio = WebWorkletAudio::new(params);
+        freq
     }
 
     pub fn get_vblank_sound_buf(&mut self) -> Option<&mut Vec<f32>> {
@@ -214,12 +229,21 @@ impl WebUi {
     pub fn enable_sgb_border(&mut self) {
     }
 
+    pub fn disable_sgb_border(&mut self) {
+    }
+
     pub fn set_sgb_border(&mut self, _pixels: &[u32; 256 * 224]) {
     }
 
     pub fn set_fullscreen(&mut self, _state: bool) {
     }
 
+    pub fn set_stretch(&mut self, _state: bool) {
+    }
+
+    pub fn set_scale_mode(&mut self, _mode: ScaleMode) {
+    }
+
     pub fn set_paused(&mut self, _paused: bool) {
     }
 
@@ -230,11 +254,18 @@ impl WebUi {
     pub fn get_sound_ringbuf_ptrs(&mut self) -> Option<&mut [u32]> {
         self.audio.as_mut().map(|a| a.get_sound_ringbuf_ptrs())
     }
+
+    /* The AudioContext playing the ring buffer must run at this rate */
+    pub fn get_sound_sample_rate(&self) -> usize {
+        self.audio.as_ref().map(|a| a.sample_rate).unwrap_or(0)
+    }
 }
 
 impl WebBufferAudio {
     fn new(params: AudioOutputParams) -> Option<Self> {
-        let ctx = AudioContext::new().ok()?;
+        let opts = AudioContextOptions::new();
+        opts.set_sample_rate(params.freq as f32);
+        let ctx = AudioContext::new_with_context_options(&opts).ok()?;
         let source = ctx.create_buffer_source().ok()?;
         let buf_len = params.freq as u32;
         // FIXME: Support params.channels
@@ -254,7 +285,7 @@ impl WebBufferAudio {
 
             vblank_buf: Default::default(),
             vblank_single_channel_buf: Default::default(),
-            cache_size: 44100 / 30, // 1/30th of a second
+            cache_size: buf_len / 30, // 1/30th of a second
 
             time_ofs,
             last_time: time_ofs,
@@ -271,8 +302,9 @@ impl WebBufferAudio {
         let cur_pos = (((now - self.time_ofs) * self.sample_rate) as u32) % self.buf_len;
 
         let min_cache_size = ((now - self.last_time) * self.sample_rate) as u32;
-        let cached = if self.cache_size < min_cache_size + 44100 / 60 {
-            self.cache_size = min_cache_size + 44100 / 60;
+        let frame_samples = (self.sample_rate / 60.0) as u32;
+        let cached = if self.cache_size < min_cache_size + frame_samples {
+            self.cache_size = min_cache_size + frame_samples;
             0
         } else {
             (self.last_end + self.buf_len - cur_pos) % self.buf_len
@@ -319,13 +351,13 @@ impl WebWorkletAudio {
         Some(WebWorkletAudio {
             vblank_buf: Default::default(),
             vblank_single_channel_buf: Default::default(),
-            cache_size: 44100 / 30, // 1/30th of a second
+            cache_size: params.freq / 30, // 1/30th of a second
 
-            buffer: vec![0.0; 44100],
+            buffer: vec![0.0; params.freq],
             ptrs: vec![0; 2],
 
-            // FIXME: DO NOT IGNORE params.freq
             channels: params.channels,
+            sample_rate: params.freq,
         })
     }
 
@@ -0,0 +1,66 @@
+use std::sync::Mutex;
+
+use xgbcrew::{Reg16, System};
+
+
+/* Only one system may exist per process at a time */
+static SYSTEM_LOCK: Mutex<()> = Mutex::new(());
+
+/* 32 kB ROM-only image that runs @code from 0x0150 */
+fn rom_with_code(code: &[u8]) -> Vec<u8> {
+    let mut rom = vec![0u8; 0x8000];
+
+    /* nop; jp 0x0150 */
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xc3, 0x50, 0x01]);
+    rom[0x134..0x13a].copy_from_slice(b"SCRIPT");
+    rom[0x150..(0x150 + code.len())].copy_from_slice(code);
+
+    rom[0x14d] = rom[0x134..0x14d].iter()
+                     .fold(0u8, |x, b| x.wrapping_sub(*b).wrapping_sub(1));
+    rom
+}
+
+#[test]
+fn registers_and_memory() {
+    let _guard = SYSTEM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    /* ld a, (0xc000); inc a; ld (0xc001), a; jr -2 */
+    let mut sys = System::headless(rom_with_code(&[0xfa, 0x00, 0xc0, 0x3c,
+                                                   0xea, 0x01, 0xc0,
+                                                   0x18, 0xfe]),
+                                   None);
+
+    sys.poke(0xc000, 0x41);
+    sys.main_loop(true);
+
+    assert_eq!(sys.peek(0xc001), 0x42);
+    assert_eq!(sys.get_reg16(Reg16::AF) >> 8, 0x42);
+    assert_eq!(sys.get_reg16(Reg16::PC), 0x0157);
+
+    sys.set_reg16(Reg16::HL, 0xbeef);
+    assert_eq!(sys.get_reg16(Reg16::HL), 0xbeef);
+
+    /* Restart the program with a different input */
+    sys.poke(0xc000, 0x10);
+    sys.set_reg16(Reg16::PC, 0x0150);
+    sys.main_loop(true);
+    assert_eq!(sys.peek(0xc001), 0x11);
+}
+
+#[test]
+fn io_pokes_have_side_effects() {
+    let _guard = SYSTEM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut sys = System::headless(rom_with_code(&[0x18, 0xfe]), None);
+    sys.main_loop(true);
+
+    /* Writing DIV resets it, whatever the value */
+    sys.poke(0xff04, 0x12);
+    assert_eq!(sys.peek(0xff04), 0x00);
+
+    /* Only the writable STAT bits change; the mode stays as is */
+    let stat = sys.peek(0xff41);
+    sys.poke(0xff41, 0x00);
+    assert_eq!(sys.peek(0xff41) & 0x07, stat & 0x07);
+    assert_eq!(sys.peek(0xff41) & 0x78, 0x00);
+}